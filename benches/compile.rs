@@ -0,0 +1,53 @@
+//! Time to compile a large generated script, which is dominated by resolving identifiers.
+//!
+//!     cargo bench --bench compile
+
+use std::{hint::black_box, time::Instant};
+
+use lox::prelude::Compiler;
+
+const FUNCTIONS: usize = 2_000;
+const RUNS: u32 = 20;
+
+/// Many functions, each with locals, globals, closures and classes referring to each other
+fn large_source() -> String {
+    let mut source = String::new();
+    for i in 0..FUNCTIONS {
+        source.push_str(&format!("
+            var total{i} = 0;
+            class Counter{i} {{
+                init(start) {{ this.count = start; }}
+                add(amount) {{ this.count = this.count + amount; return this.count; }}
+            }}
+            fun compute{i}(first, second) {{
+                var product = first * second;
+                var counter = Counter{i}(product);
+                fun adjust(delta) {{ product = product + delta; return counter.add(product); }}
+                for (var step = 0; step < 10; step = step + 1) {{
+                    total{i} = total{i} + adjust(step) + first - second;
+                }}
+                return total{i};
+            }}
+        "));
+    }
+    source
+}
+
+fn main() {
+    let source = large_source();
+
+    // Warm up caches and the allocator before measuring
+    black_box(Compiler::new(source.clone()).compile().unwrap());
+
+    // The fastest run is the one least disturbed by whatever else the machine is doing
+    let elapsed = (0..RUNS)
+        .map(|_| {
+            let source = source.clone();
+            let start = Instant::now();
+            black_box(Compiler::new(source).compile().unwrap());
+            start.elapsed()
+        })
+        .min()
+        .unwrap();
+    println!("{:<32} {:>8.2} ms ({} KiB of source)", "compile large script", elapsed.as_secs_f64() * 1000.0, source.len() / 1024);
+}
@@ -0,0 +1,83 @@
+//! Compares the VM's enum stack with the experimental struct-of-arrays stack on
+//! the push/pop patterns the interpreter loop produces.
+//!
+//!     cargo bench --features soa-stack --bench stack_layout
+
+use std::{hint::black_box, time::{Duration, Instant}};
+
+use lox::experimental::{SoaStack, Stack};
+use lox::prelude::Value;
+
+const ITERATIONS: usize = 1_000_000;
+
+fn time<F: FnMut()>(name: &str, mut run: F) -> Duration {
+    // Warm up caches and the allocator before measuring
+    run();
+
+    let start = Instant::now();
+    run();
+    let elapsed = start.elapsed();
+    println!("{:<32} {:>8.2} ns/iter", name, elapsed.as_nanos() as f64 / ITERATIONS as f64);
+    elapsed
+}
+
+fn arithmetic_enum() {
+    let mut stack = Stack::with_capacity(16);
+    stack.push(Value::Number(0.0)).unwrap();
+    for i in 0..ITERATIONS {
+        stack.push(Value::Number(i as f64)).unwrap();
+        let b = stack.pop().unwrap();
+        let a = stack.pop().unwrap();
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => stack.push(Value::Number(a + b)).unwrap(),
+            _ => unreachable!()
+        }
+    }
+    black_box(stack.pop().unwrap());
+}
+
+fn arithmetic_soa() {
+    let mut stack = SoaStack::new();
+    stack.push_number(0.0);
+    for i in 0..ITERATIONS {
+        stack.push_number(i as f64);
+        let b = stack.pop_number().unwrap().unwrap();
+        let a = stack.pop_number().unwrap().unwrap();
+        stack.push_number(a + b);
+    }
+    black_box(stack.pop().unwrap());
+}
+
+fn mixed_enum() {
+    let name = Value::String("name".to_string());
+    let mut stack = Stack::with_capacity(16);
+    for i in 0..ITERATIONS {
+        stack.push(name.clone()).unwrap();
+        stack.push(Value::Number(i as f64)).unwrap();
+        stack.push(Value::Boolean(i % 2 == 0)).unwrap();
+        stack.truncate(stack.len() - 3);
+    }
+    black_box(stack.len());
+}
+
+fn mixed_soa() {
+    let name = Value::String("name".to_string());
+    let mut stack = SoaStack::new();
+    for i in 0..ITERATIONS {
+        stack.push(name.clone());
+        stack.push_number(i as f64);
+        stack.push(Value::Boolean(i % 2 == 0));
+        stack.truncate(stack.len() - 3);
+    }
+    black_box(stack.len());
+}
+
+fn main() {
+    let enum_time = time("numbers, enum stack", arithmetic_enum);
+    let soa_time = time("numbers, struct-of-arrays stack", arithmetic_soa);
+    println!("  speedup {:.2}x", enum_time.as_secs_f64() / soa_time.as_secs_f64());
+
+    let enum_time = time("mixed, enum stack", mixed_enum);
+    let soa_time = time("mixed, struct-of-arrays stack", mixed_soa);
+    println!("  speedup {:.2}x", enum_time.as_secs_f64() / soa_time.as_secs_f64());
+}
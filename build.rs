@@ -0,0 +1,100 @@
+//! Precompiles the standard library modules written in Lox, so the binary embeds their
+//! bytecode and importing them needn't compile anything.
+//!
+//! The compiler and serializer are the library's own, included from `src` since a build
+//! script can't depend on the crate it builds. Only some of what they bring along is used.
+
+#![allow(dead_code)]
+
+#[path = "src/vm.rs"] mod vm;
+#[path = "src/chunk.rs"] mod chunk;
+#[path = "src/capability.rs"] mod capability;
+#[path = "src/constant_pool.rs"] mod constant_pool;
+#[path = "src/disassembler.rs"] mod disassembler;
+#[path = "src/instruction.rs"] mod instruction;
+#[path = "src/stack.rs"] mod stack;
+#[path = "src/scanner.rs"] mod scanner;
+#[path = "src/compiler.rs"] mod compiler;
+#[path = "src/error_code.rs"] mod error_code;
+#[path = "src/suggestion.rs"] mod suggestion;
+#[path = "src/peephole.rs"] mod peephole;
+#[path = "src/dialect.rs"] mod dialect;
+#[path = "src/debugger.rs"] mod debugger;
+#[path = "src/interner.rs"] mod interner;
+#[path = "src/value.rs"] mod value;
+#[path = "src/function.rs"] mod function;
+#[path = "src/class.rs"] mod class;
+#[path = "src/native.rs"] mod native;
+#[path = "src/foreign.rs"] mod foreign;
+#[path = "src/format.rs"] mod format;
+#[path = "src/bytes.rs"] mod bytes;
+#[path = "src/map.rs"] mod map;
+#[path = "src/ordered_map.rs"] mod ordered_map;
+#[path = "src/operand_source.rs"] mod operand_source;
+#[path = "src/output.rs"] mod output;
+#[path = "src/hooks.rs"] mod hooks;
+#[path = "src/index.rs"] mod index;
+#[path = "src/eval.rs"] mod eval;
+#[path = "src/module.rs"] mod module;
+#[path = "src/serialize.rs"] mod serialize;
+#[path = "src/checkpoint.rs"] mod checkpoint;
+#[path = "src/global_history.rs"] mod global_history;
+#[path = "src/allocations.rs"] mod allocations;
+
+/// The modules compiled here can't import other standard library modules and have no use
+/// for its natives, so the real ones are left out
+#[cfg(feature = "stdlib")]
+mod stdlib {
+    pub fn define_natives(_vm: &mut crate::vm::Vm) {}
+
+    pub fn precompiled(_path: &str) -> Option<&'static [u8]> {
+        None
+    }
+
+    pub fn module_paths() -> std::iter::Empty<&'static str> {
+        std::iter::empty()
+    }
+}
+#[cfg(feature = "stack-check")]
+#[path = "src/stack_check.rs"] mod stack_check;
+
+use std::{collections::hash_map::DefaultHasher, env, fs, hash::Hasher, path::PathBuf, rc::Rc};
+
+use compiler::Compiler;
+use function::Function;
+use serialize::ValueWriter;
+use value::Value;
+
+/// Names of the modules in `src/stdlib/lox`, imported as `std/<name>`
+const STD_MODULES: &[&str] = &["list", "string"];
+
+/// Sources that decide what bytecode a script compiles to and how it's stored, so that a
+/// change to any of them recompiles the modules and invalidates cached chunks
+const COMPILER_SOURCES: &[&str] = &["src/scanner.rs", "src/compiler.rs", "src/peephole.rs", "src/dialect.rs", "src/instruction.rs",
+    "src/chunk.rs", "src/constant_pool.rs", "src/value.rs", "src/function.rs", "src/serialize.rs"];
+
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR not set"));
+
+    let mut compiler_hash = DefaultHasher::new();
+    for source_path in COMPILER_SOURCES {
+        println!("cargo:rerun-if-changed={}", source_path);
+        compiler_hash.write(&fs::read(source_path).unwrap_or_else(|e| panic!("Failed to read {}: {}", source_path, e)));
+    }
+    println!("cargo:rustc-env=LOX_COMPILER_HASH={:016x}", compiler_hash.finish());
+
+    for name in STD_MODULES {
+        let source_path = format!("src/stdlib/lox/{}.lox", name);
+        println!("cargo:rerun-if-changed={}", source_path);
+
+        let source = fs::read_to_string(&source_path).unwrap_or_else(|e| panic!("Failed to read {}: {}", source_path, e));
+        let chunk = Compiler::new(source).compile().unwrap_or_else(|e| panic!("Failed to compile {}: {}", source_path, e));
+
+        let mut writer = ValueWriter::new();
+        let mut roots = Vec::new();
+        let bytes = writer.write_value(&mut roots, &Value::Function(Rc::new(Function::script(chunk))))
+            .and_then(|_| writer.finish(&roots))
+            .unwrap_or_else(|e| panic!("Failed to serialize {}: {}", source_path, e));
+        fs::write(out_dir.join(format!("{}.loxc", name)), bytes).expect("Failed to write precompiled module");
+    }
+}
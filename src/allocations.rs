@@ -0,0 +1,59 @@
+use std::{fmt::Display, mem::size_of};
+
+use crate::{class::{BoundMethod, Class, Instance}, function::Closure, map::MapKey, value::Value};
+
+/// Kinds of object in the order they're reported
+const KINDS: [&str; 8] = ["strings", "closures", "instances", "lists", "maps", "bound methods", "classes", "bytes"];
+
+#[derive(Debug, Clone, Copy, Default)]
+struct KindTotals {
+    count: usize,
+    bytes: usize
+}
+
+/// Objects the VM created during a run, by kind, with an estimate of the memory each took
+/// when created. Objects made by natives are counted once they're returned.
+#[derive(Debug, Default)]
+pub struct AllocationReport {
+    totals: [KindTotals; KINDS.len()]
+}
+
+impl AllocationReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, value: &Value) {
+        let (kind, bytes) = match value {
+            Value::String(s) => (0, size_of::<String>() + s.len()),
+            Value::Closure(closure) => (1, size_of::<Closure>() + closure.upvalues.len() * size_of::<usize>()),
+            Value::Instance(_) => (2, size_of::<Instance>()),
+            Value::List(items) => (3, size_of::<Vec<Value>>() + items.borrow().len() * size_of::<Value>()),
+            Value::Map(map) => (4, map.borrow().len() * (size_of::<MapKey>() + size_of::<Value>())),
+            Value::BoundMethod(_) => (5, size_of::<BoundMethod>()),
+            Value::Class(_) => (6, size_of::<Class>()),
+            Value::Bytes(b) => (7, size_of::<Vec<u8>>() + b.len()),
+            _ => return
+        };
+
+        self.totals[kind].count += 1;
+        self.totals[kind].bytes += bytes;
+    }
+
+    pub fn count(&self, kind: &str) -> usize {
+        KINDS.iter().position(|k| *k == kind).map_or(0, |i| self.totals[i].count)
+    }
+}
+
+impl Display for AllocationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Allocations:")?;
+        for (kind, totals) in KINDS.iter().zip(&self.totals) {
+            writeln!(f, "  {:<14}{:>10} {:>12} bytes", kind, totals.count, totals.bytes)?;
+        }
+
+        let count: usize = self.totals.iter().map(|t| t.count).sum();
+        let bytes: usize = self.totals.iter().map(|t| t.bytes).sum();
+        writeln!(f, "  {:<14}{:>10} {:>12} bytes", "total", count, bytes)
+    }
+}
@@ -0,0 +1,209 @@
+//! Natives and literal parsing for the `Bytes` value type
+
+use std::{rc::Rc, fmt::Write};
+
+use anyhow::{Result, bail, anyhow};
+
+use crate::{native::Args, value::Value, vm::Vm};
+
+pub fn define_natives(vm: &mut Vm) {
+    vm.define_native("bytes", 1, Rc::new(bytes));
+    vm.define_native("encode", 2, Rc::new(encode));
+    vm.define_native("decode", 2, Rc::new(decode));
+    vm.define_native("slice", 3, Rc::new(slice));
+    vm.define_native("len", 1, Rc::new(len));
+}
+
+/// Decodes the body of a `b"..."` literal. Besides printable characters it accepts the
+/// escapes `\xHH`, `\n`, `\r`, `\t`, `\0`, `\\` and `\"`.
+pub fn parse_literal(body: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = body.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            if !c.is_ascii() {
+                bail!("Non-ASCII character '{}' in bytes literal; use a \\x escape.", c);
+            }
+            bytes.push(c as u8);
+            continue;
+        }
+
+        let byte = match chars.next() {
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 => byte,
+                    _ => bail!("Invalid escape '\\x{}' in bytes literal.", hex)
+                }
+            },
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some(c) => bail!("Invalid escape '\\{}' in bytes literal.", c),
+            None => bail!("Unterminated escape in bytes literal.")
+        };
+        bytes.push(byte);
+    }
+
+    Ok(bytes)
+}
+
+/// Formats bytes the way they'd be written as a literal
+pub fn format(bytes: &[u8]) -> String {
+    let mut text = String::from("b\"");
+    for byte in bytes {
+        match byte {
+            b'"' => text.push_str("\\\""),
+            b'\\' => text.push_str("\\\\"),
+            0x20..=0x7e => text.push(*byte as char),
+            _ => { let _ = write!(text, "\\x{:02x}", byte); }
+        }
+    }
+    text.push('"');
+    text
+}
+
+/// Reads the byte at `index`, which must be a whole number within bounds
+pub fn get(bytes: &[u8], index: &Value) -> Result<Value> {
+    let index = to_index(index, bytes.len(), "Bytes index")?;
+    match bytes.get(index) {
+        Some(byte) => Ok(Value::Int(*byte as i64)),
+        None => bail!("Bytes index {} out of range for length {}", index, bytes.len())
+    }
+}
+
+fn to_index(value: &Value, len: usize, what: &str) -> Result<usize> {
+    match value {
+        Value::Int(n) if *n >= 0 && (*n as u64) <= len as u64 => Ok(*n as usize),
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && *n <= len as f64 => Ok(*n as usize),
+        Value::Int(_) | Value::Number(_) => bail!("{} {} out of range for length {}", what, value, len),
+        _ => bail!("{} must be a number", what)
+    }
+}
+
+/// `bytes(list)`: builds bytes from a list of numbers between 0 and 255
+fn bytes(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let items = match args {
+        [Value::List(items)] => items.borrow(),
+        [Value::Bytes(bytes)] => return Ok(Value::Bytes(bytes.clone())),
+        _ => bail!("bytes expects a list of numbers")
+    };
+
+    let bytes = items.iter()
+        .map(|item| match item {
+            Value::Int(n) if (0..=255).contains(n) => Ok(*n as u8),
+            Value::Number(n) if n.fract() == 0.0 && (0.0..=255.0).contains(n) => Ok(*n as u8),
+            other => Err(anyhow!("bytes expects numbers between 0 and 255, got {}", other))
+        })
+        .collect::<Result<Vec<u8>>>()?;
+
+    Ok(Value::Bytes(Rc::new(bytes)))
+}
+
+/// `encode(string, encoding)`: the string's bytes in "utf8", "ascii", "latin1" or "hex"
+fn encode(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let args = Args::new("encode", args);
+    let (text, encoding) = (args.get_string(0)?, args.get_string(1)?);
+
+    let bytes = match encoding {
+        "utf8" | "utf-8" => text.as_bytes().to_vec(),
+        "ascii" | "latin1" => {
+            let limit = if encoding == "ascii" { 0x7f } else { 0xff };
+            text.chars()
+                .map(|c| u8::try_from(c).ok().filter(|b| *b <= limit).ok_or_else(|| anyhow!("Can't encode '{}' as {}", c, encoding)))
+                .collect::<Result<Vec<u8>>>()?
+        },
+        "hex" => {
+            if text.len() % 2 != 0 || !text.chars().all(|c| c.is_ascii_hexdigit()) {
+                bail!("Invalid hex string '{}'", text);
+            }
+            (0..text.len()).step_by(2)
+                .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|e| anyhow!(e)))
+                .collect::<Result<Vec<u8>>>()?
+        },
+        _ => bail!("Unknown encoding '{}'", encoding)
+    };
+
+    Ok(Value::Bytes(Rc::new(bytes)))
+}
+
+/// `decode(bytes, encoding)`: the inverse of `encode`
+fn decode(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let args = Args::new("decode", args);
+    let (bytes, encoding) = (args.get_bytes(0)?, args.get_string(1)?);
+
+    let text = match encoding {
+        "utf8" | "utf-8" => String::from_utf8(bytes.to_vec()).map_err(|_| anyhow!("Bytes are not valid utf8"))?,
+        "ascii" => {
+            if !bytes.is_ascii() {
+                bail!("Bytes are not valid ascii");
+            }
+            bytes.iter().map(|b| *b as char).collect()
+        },
+        "latin1" => bytes.iter().map(|b| *b as char).collect(),
+        "hex" => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        _ => bail!("Unknown encoding '{}'", encoding)
+    };
+
+    Ok(Value::String(text))
+}
+
+/// `slice(bytes, start, end)`: the bytes from `start` up to but not including `end`
+fn slice(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let args = Args::new("slice", args);
+    let (bytes, start, end) = (args.get_bytes(0)?, args.get(1)?, args.get(2)?);
+
+    let start = to_index(start, bytes.len(), "Slice start")?;
+    let end = to_index(end, bytes.len(), "Slice end")?;
+    if start > end {
+        bail!("Slice start {} is after end {}", start, end);
+    }
+
+    Ok(Value::Bytes(Rc::new(bytes[start..end].to_vec())))
+}
+
+/// `len(value)`: the number of bytes, list items, map entries or characters of a string
+fn len(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let len = match args {
+        [Value::String(s)] => s.chars().count(),
+        [Value::Bytes(bytes)] => bytes.len(),
+        [Value::List(items)] => items.borrow().len(),
+        [Value::Map(map)] => map.borrow().len(),
+        [other] => bail!("Can't take the length of {}", other),
+        _ => bail!("len expects one argument")
+    };
+
+    Ok(Value::Int(len as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_literal_escapes() {
+        assert_eq!(parse_literal(r#"a\x00\xff\n\"\\"#).unwrap(), vec![b'a', 0, 0xff, b'\n', b'"', b'\\']);
+        assert!(parse_literal(r"\xg0").is_err());
+        assert!(parse_literal(r"\x0").is_err());
+        assert!(parse_literal(r"\q").is_err());
+        assert!(parse_literal("é").is_err());
+    }
+
+    #[test]
+    fn builds_from_lists() {
+        let list = Value::list(vec![Value::Number(0.0), Value::Number(255.0)]);
+        assert_eq!(bytes(&mut Vm::new(Default::default()), &[list]).unwrap(), Value::Bytes(Rc::new(vec![0, 255])));
+
+        let out_of_range = Value::list(vec![Value::Number(256.0)]);
+        assert!(bytes(&mut Vm::new(Default::default()), &[out_of_range]).is_err());
+    }
+
+    #[test]
+    fn formats_as_literal() {
+        assert_eq!(format(&[b'h', b'i', 0, b'"', 0x7f]), r#"b"hi\x00\"\x7f""#);
+    }
+}
@@ -0,0 +1,40 @@
+//! Access to the world outside the VM that some natives need, recorded in the chunks of
+//! scripts that use them so hosts can ask for permission before running a script rather
+//! than have it fail halfway through
+
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{Result, bail};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Capability {
+    /// Reading and writing files
+    Fs,
+    /// Making network connections
+    Net,
+    /// Running other programs
+    Exec
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Capability::Fs => "fs",
+            Capability::Net => "net",
+            Capability::Exec => "exec"
+        })
+    }
+}
+
+impl FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fs" => Ok(Capability::Fs),
+            "net" => Ok(Capability::Net),
+            "exec" => Ok(Capability::Exec),
+            _ => bail!("Unknown capability '{}', expected fs, net or exec", s)
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! The execution state of a VM as it's saved at a checkpoint, so a long-running script can be
+//! stopped and carried on later, possibly by another process.
+
+use std::{cell::RefCell, collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}, rc::Rc};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{function::{Closure, Upvalue}, module::Module, native::NativeFunction, ordered_map::OrderedMap, serialize::{NativeLookup, ValueReader, ValueWriter, put_str, put_u8, put_u32}, value::Value};
+
+pub struct FrameState {
+    pub closure: Rc<Closure>,
+    pub ip: usize,
+    pub slot_base: usize
+}
+
+pub struct HandlerState {
+    pub frame_count: usize,
+    pub stack_len: usize,
+    pub catch_ip: usize
+}
+
+pub struct VmState {
+    pub stack: Vec<Value>,
+    pub globals: OrderedMap<String, Value>,
+    pub const_globals: HashSet<String>,
+    pub modules: Vec<Module>,
+    pub frames: Vec<FrameState>,
+    /// Upvalues still pointing into the stack, ordered by stack slot
+    pub open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    pub handlers: Vec<HandlerState>
+}
+
+impl VmState {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut writer = ValueWriter::new();
+        let mut roots = Vec::new();
+
+        put_u32(&mut roots, self.stack.len() as u32);
+        for value in &self.stack {
+            writer.write_value(&mut roots, value)?;
+        }
+
+        write_globals(&mut writer, &mut roots, &self.globals)?;
+        write_names(&mut roots, &self.const_globals);
+
+        put_u32(&mut roots, self.modules.len() as u32);
+        for module in &self.modules {
+            put_str(&mut roots, &module.path.to_string_lossy());
+            write_globals(&mut writer, &mut roots, &module.globals)
+                .with_context(|| format!("Failed to save module {}", module.path.display()))?;
+            write_names(&mut roots, &module.const_globals);
+            match &module.exports {
+                Some(exports) => {
+                    put_u8(&mut roots, 1);
+                    put_u32(&mut roots, exports.len() as u32);
+                    for name in exports {
+                        put_str(&mut roots, name);
+                    }
+                },
+                None => put_u8(&mut roots, 0)
+            }
+        }
+
+        put_u32(&mut roots, self.frames.len() as u32);
+        for frame in &self.frames {
+            let closure_id = writer.closure_id(&frame.closure)?;
+            put_u32(&mut roots, closure_id);
+            put_u32(&mut roots, frame.ip as u32);
+            put_u32(&mut roots, frame.slot_base as u32);
+        }
+
+        put_u32(&mut roots, self.open_upvalues.len() as u32);
+        for upvalue in &self.open_upvalues {
+            let upvalue_id = writer.upvalue_id(upvalue);
+            put_u32(&mut roots, upvalue_id);
+        }
+
+        put_u32(&mut roots, self.handlers.len() as u32);
+        for handler in &self.handlers {
+            put_u32(&mut roots, handler.frame_count as u32);
+            put_u32(&mut roots, handler.stack_len as u32);
+            put_u32(&mut roots, handler.catch_ip as u32);
+        }
+
+        writer.finish(&roots)
+    }
+
+    /// Reads a state back, finding natives by name with `natives`
+    pub fn from_bytes(data: &[u8], natives: &NativeLookup) -> Result<Self> {
+        let mut reader = ValueReader::new(data, natives)?;
+
+        let mut stack = Vec::new();
+        for _ in 0..reader.u32()? {
+            stack.push(reader.read_value()?);
+        }
+
+        let globals = read_globals(&mut reader)?;
+        let const_globals = read_names(&mut reader)?;
+
+        let mut modules = Vec::new();
+        for _ in 0..reader.u32()? {
+            let path = PathBuf::from(reader.string()?);
+            let globals = read_globals(&mut reader)?;
+        let const_globals = read_names(&mut reader)?;
+            let exports = if reader.u8()? == 1 {
+                let mut exports = Vec::new();
+                for _ in 0..reader.u32()? {
+                    exports.push(reader.string()?);
+                }
+                Some(exports)
+            } else {
+                None
+            };
+            modules.push(Module { path, globals, const_globals, exports });
+        }
+
+        let mut frames = Vec::new();
+        for _ in 0..reader.u32()? {
+            let closure = reader.read_closure()?;
+            let ip = reader.u32()? as usize;
+            let slot_base = reader.u32()? as usize;
+            if slot_base > stack.len() || ip > closure.function.chunk.len() {
+                bail!("Checkpoint has an invalid call frame");
+            }
+            frames.push(FrameState { closure, ip, slot_base });
+        }
+
+        let mut open_upvalues = Vec::new();
+        for _ in 0..reader.u32()? {
+            open_upvalues.push(reader.read_upvalue()?);
+        }
+
+        let mut handlers = Vec::new();
+        for _ in 0..reader.u32()? {
+            let handler = HandlerState { frame_count: reader.u32()? as usize, stack_len: reader.u32()? as usize, catch_ip: reader.u32()? as usize };
+            if handler.frame_count == 0 || handler.frame_count > frames.len() || handler.stack_len > stack.len() {
+                bail!("Checkpoint has an invalid exception handler");
+            }
+            handlers.push(handler);
+        }
+
+        if !reader.is_at_end() {
+            bail!("Checkpoint has trailing data");
+        }
+
+        Ok(Self { stack, globals, const_globals, modules, frames, open_upvalues, handlers })
+    }
+}
+
+fn write_globals(writer: &mut ValueWriter, out: &mut Vec<u8>, globals: &OrderedMap<String, Value>) -> Result<()> {
+    // In the order they were defined, which reading them back keeps
+    put_u32(out, globals.len() as u32);
+    for (name, value) in globals.iter() {
+        put_str(out, name);
+        writer.write_value(out, value).with_context(|| format!("Failed to save global '{}'", name))?;
+    }
+
+    Ok(())
+}
+
+fn read_globals(reader: &mut ValueReader) -> Result<OrderedMap<String, Value>> {
+    let mut globals = OrderedMap::new();
+    for _ in 0..reader.u32()? {
+        let name = reader.string()?;
+        let value = reader.read_value()?;
+        globals.insert(name, value);
+    }
+
+    Ok(globals)
+}
+
+fn write_names(out: &mut Vec<u8>, names: &HashSet<String>) {
+    let mut names: Vec<&String> = names.iter().collect();
+    names.sort();
+    put_u32(out, names.len() as u32);
+    for name in names {
+        put_str(out, name);
+    }
+}
+
+fn read_names(reader: &mut ValueReader) -> Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    for _ in 0..reader.u32()? {
+        names.insert(reader.string()?);
+    }
+
+    Ok(names)
+}
+
+/// Writes a checkpoint so that a crash halfway through never leaves a truncated file behind
+pub fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    fs::write(&temp_path, data).with_context(|| format!("Failed to write checkpoint to {}", path.display()))?;
+    fs::rename(&temp_path, path).with_context(|| format!("Failed to write checkpoint to {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Natives registered with a VM, looked up by name when a checkpoint is resumed
+pub type NativeRegistry = HashMap<String, Rc<NativeFunction>>;
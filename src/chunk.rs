@@ -1,66 +1,251 @@
+use std::{cell::RefCell, rc::Rc};
+
 use anyhow::{Result, anyhow, bail};
 
-use crate::value::Value;
+use crate::{capability::Capability, class::PropertyCache, constant_pool::SharedConstantPool, value::Value};
 
-#[derive(Debug)]
+/// Compiled code with its constants and debug info. Clones share the contents instead of
+/// copying them, so a chunk can be run or put in a function without copying its code; a
+/// clone that's changed gets a copy of its own first.
+#[derive(Debug, Clone, Default)]
 pub struct Chunk {
+    contents: Rc<Contents>
+}
+
+#[derive(Debug, Clone, Default)]
+struct Contents {
     code: Vec<u8>,
     src_line_numbers: Vec<i32>,
-    constants: Vec<Value>
+    constants: Vec<Value>,
+    /// The shared pool the constants were interned in, if any
+    pool: Option<SharedConstantPool>,
+    /// For each entry of `constants`, its index in `pool`. The values themselves are
+    /// copied into `constants` so reading one doesn't have to borrow the pool.
+    pool_indices: Vec<u32>,
+    /// Globals a script makes visible to scripts importing it. Always empty for functions
+    exports: Vec<String>,
+    /// Capabilities of the gated natives a script refers to, in any of its functions. Always
+    /// empty for functions
+    required_capabilities: Vec<Capability>,
+    /// Names and live ranges of the local variables, for debuggers and watchpoints
+    local_vars: Vec<LocalVar>,
+    /// Names of the variables the function's upvalues capture, by upvalue index, for watchpoints
+    upvalue_names: Vec<String>,
+    /// The slot each global the code refers to was found at, by the index of its name among
+    /// the constants, so that it's only looked up by name the first time
+    global_slots: RefCell<Vec<Option<GlobalSlot>>>,
+    /// What each property instruction found the last time it ran, by the instruction's offset
+    property_caches: RefCell<Vec<Option<PropertyCache>>>
+}
+
+/// Where a global was found, in the table of globals identified by `table`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlobalSlot {
+    pub table: (u64, Option<usize>),
+    pub slot: usize
+}
+
+/// A local variable and the code in which it occupies its stack slot
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalVar {
+    pub name: String,
+    /// Slot relative to the start of the function's frame
+    pub slot: usize,
+    /// Offset of the first instruction after the variable is initialized
+    pub start: usize,
+    /// Offset just past the last instruction the variable is in scope for
+    pub end: usize
 }
 
 impl Chunk {
     pub fn new() -> Self { 
-        Self { code: Vec::new(), src_line_numbers: Vec::new(), constants: Vec::new() }
+        Self::default()
+    }
+
+    /// A chunk whose constants are interned in a pool shared with other chunks
+    pub fn with_pool(pool: SharedConstantPool) -> Self {
+        Self { contents: Rc::new(Contents { pool: Some(pool), ..Contents::default() }) }
+    }
+
+    /// A chunk rebuilt from its parts, as when deserializing one
+    pub fn from_parts(code: Vec<u8>, src_line_numbers: Vec<i32>, constants: Vec<Value>) -> Self {
+        Self { contents: Rc::new(Contents { code, src_line_numbers, constants, ..Contents::default() }) }
+    }
+
+    pub fn pool(&self) -> Option<&SharedConstantPool> {
+        self.contents.pool.as_ref()
     }
 
     pub fn read(&self, offset: usize) -> Result<u8> {
-        if offset >= self.code.len() {
+        if offset >= self.contents.code.len() {
             return Err(anyhow!("Offset {} is out range", offset));
         }
 
-        Ok(self.code[offset].clone())
+        Ok(self.contents.code[offset])
     }
 
     pub fn get_src_line_number(&self, offset: usize) -> Result<i32>  {
-        if offset >= self.code.len() {
+        if offset >= self.contents.code.len() {
             return Err(anyhow!("Offset {} is out range", offset));
         }
 
-        Ok(self.src_line_numbers[offset])
+        Ok(self.contents.src_line_numbers[offset])
     }
     
     pub fn write<B: Into<u8>>(&mut self, code_byte: B, src_line_number: i32) -> usize  {
-        self.code.push(code_byte.into());
-        self.src_line_numbers.push(src_line_number);
-        self.code.len() - 1
+        let contents = Rc::make_mut(&mut self.contents);
+        contents.code.push(code_byte.into());
+        contents.src_line_numbers.push(src_line_number);
+        contents.code.len() - 1
     }
 
 
     pub fn set<B: Into<u8>>(&mut self, loc: usize, code_byte: B) -> Result<()> {
-        if loc >= self.code.len() {
+        let contents = Rc::make_mut(&mut self.contents);
+        if loc >= contents.code.len() {
             bail!("Chunk overflow");
         }
 
-        self.code[loc] = code_byte.into();
+        contents.code[loc] = code_byte.into();
 
         Ok(())
     }
 
-    pub fn add_constant(&mut self, constant: Value) -> u8 {
-        self.constants.push(constant);
-        (self.constants.len() - 1) as u8
+    /// Replaces the code, along with the source line of each of its bytes
+    pub fn set_code(&mut self, code: Vec<u8>, src_line_numbers: Vec<i32>) {
+        let contents = Rc::make_mut(&mut self.contents);
+        contents.code = code;
+        contents.src_line_numbers = src_line_numbers;
+    }
+
+    pub fn add_constant(&mut self, constant: Value) -> usize {
+        let contents = Rc::make_mut(&mut self.contents);
+        if let Some(pool) = &contents.pool {
+            let pool_index = pool.borrow_mut().intern(constant.clone());
+
+            // A constant already interned for this chunk gets the same local index
+            if let Some(index) = contents.pool_indices.iter().position(|i| *i == pool_index) {
+                return index;
+            }
+            contents.pool_indices.push(pool_index);
+        }
+
+        contents.constants.push(constant);
+        contents.constants.len() - 1
+    }
+
+    /// Index in the shared pool of the constant at `index`, if the chunk has a pool
+    pub fn pool_index(&self, index: usize) -> Option<u32> {
+        self.contents.pool_indices.get(index).copied()
     }
 
     pub fn get_constant(&self, index: usize) -> Result<Value> {
-        if index >= self.constants.len() {
+        if index >= self.contents.constants.len() {
             return Err(anyhow!("Index {} is out range", index));
         }
 
-        Ok(self.constants[index].clone())
+        Ok(self.contents.constants[index].clone())
+    }
+
+    /// The slot the global named by the constant at `name_index` was found at in `table`, if
+    /// it has been looked up there before
+    pub fn global_slot(&self, name_index: usize, table: (u64, Option<usize>)) -> Option<usize> {
+        match self.contents.global_slots.borrow().get(name_index) {
+            Some(Some(found)) if found.table == table => Some(found.slot),
+            _ => None
+        }
+    }
+
+    pub fn set_global_slot(&self, name_index: usize, table: (u64, Option<usize>), slot: usize) {
+        let mut global_slots = self.contents.global_slots.borrow_mut();
+        if global_slots.len() <= name_index {
+            global_slots.resize(name_index + 1, None);
+        }
+        global_slots[name_index] = Some(GlobalSlot { table, slot });
+    }
+
+    pub fn property_cache(&self, offset: usize) -> Option<PropertyCache> {
+        self.contents.property_caches.borrow().get(offset).cloned().flatten()
+    }
+
+    pub fn set_property_cache(&self, offset: usize, cache: PropertyCache) {
+        let mut property_caches = self.contents.property_caches.borrow_mut();
+        if property_caches.len() <= offset {
+            property_caches.resize(self.contents.code.len().max(offset + 1), None);
+        }
+        property_caches[offset] = Some(cache);
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.contents.constants
+    }
+
+    pub fn exports(&self) -> &[String] {
+        &self.contents.exports
+    }
+
+    pub fn set_exports(&mut self, exports: Vec<String>) {
+        Rc::make_mut(&mut self.contents).exports = exports;
+    }
+
+    /// What the script needs the host to allow for it to run, in a fixed order without repeats
+    pub fn required_capabilities(&self) -> &[Capability] {
+        &self.contents.required_capabilities
+    }
+
+    pub fn set_required_capabilities(&mut self, required_capabilities: Vec<Capability>) {
+        Rc::make_mut(&mut self.contents).required_capabilities = required_capabilities;
+    }
+
+    pub fn local_vars(&self) -> &[LocalVar] {
+        &self.contents.local_vars
+    }
+
+    pub fn set_local_vars(&mut self, local_vars: Vec<LocalVar>) {
+        Rc::make_mut(&mut self.contents).local_vars = local_vars;
+    }
+
+    pub fn add_local_var(&mut self, local_var: LocalVar) {
+        Rc::make_mut(&mut self.contents).local_vars.push(local_var);
+    }
+
+    pub fn upvalue_names(&self) -> &[String] {
+        &self.contents.upvalue_names
+    }
+
+    pub fn set_upvalue_names(&mut self, upvalue_names: Vec<String>) {
+        Rc::make_mut(&mut self.contents).upvalue_names = upvalue_names;
+    }
+
+    pub fn add_upvalue_name(&mut self, name: String) {
+        Rc::make_mut(&mut self.contents).upvalue_names.push(name);
+    }
+
+    /// Name of the variable captured by the upvalue at `index`
+    pub fn upvalue_name(&self, index: usize) -> Option<&str> {
+        self.contents.upvalue_names.get(index).map(String::as_str)
+    }
+
+    /// Name of the local in `slot` when the instruction at `offset` runs
+    pub fn local_name(&self, slot: usize, offset: usize) -> Option<&str> {
+        self.contents.local_vars.iter()
+            .find(|local| local.slot == slot && (local.start..local.end).contains(&offset))
+            .map(|local| local.name.as_str())
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.contents.code
+    }
+
+    pub fn src_line_numbers(&self) -> &[i32] {
+        &self.contents.src_line_numbers
     }
 
     pub fn len(&self) -> usize {
-        self.code.len()
+        self.contents.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.code.is_empty()
     }
 }
\ No newline at end of file
@@ -0,0 +1,115 @@
+//! Compiled scripts kept on disk, so running an unchanged script again needn't compile it.
+//! Entries are found by a hash of the source and hold the source too, so a collision is
+//! just a miss. Anything built by another compiler is a miss as well, and is replaced.
+
+use std::{collections::hash_map::DefaultHasher, env, fs, hash::{Hash, Hasher}, path::{Path, PathBuf}, process, rc::Rc};
+
+use anyhow::{Context, Result};
+
+use crate::{chunk::Chunk, dialect::Dialect, function::Function, serialize::{self, ValueWriter, put_str}, value::Value};
+
+/// Identifies the compiler and serialization format, changing with any change to either
+const COMPILER_ID: &str = concat!(env!("CARGO_PKG_VERSION"), "-", env!("LOX_COMPILER_HASH"));
+
+#[derive(Debug, Clone)]
+pub struct ChunkCache {
+    dir: PathBuf
+}
+
+impl ChunkCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `lox` in `$XDG_CACHE_HOME`, or in `~/.cache` if that isn't set
+    pub fn default_dir() -> Option<PathBuf> {
+        let cache_home = env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+        Some(cache_home.join("lox"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The chunk stored for this source compiled in this dialect, if there's one this compiler
+    /// can use. An unreadable entry is treated as missing.
+    pub fn get(&self, source: &str, dialect: Dialect) -> Option<Chunk> {
+        let data = fs::read(self.entry_path(source, dialect)).ok()?;
+        let mut rest = data.as_slice();
+        if take_str(&mut rest)? != COMPILER_ID || take_str(&mut rest)? != dialect_key(dialect) || take_str(&mut rest)? != source {
+            return None;
+        }
+
+        let no_natives = |_: &str| None;
+        let script = serialize::script_from_bytes(rest, &no_natives).ok()?;
+        Some(script.chunk.clone())
+    }
+
+    /// Stores the chunk compiled from the source, replacing what was stored for it before
+    pub fn put(&self, source: &str, dialect: Dialect, chunk: &Chunk) -> Result<()> {
+        let mut data = Vec::new();
+        put_str(&mut data, COMPILER_ID);
+        put_str(&mut data, &dialect_key(dialect));
+        put_str(&mut data, source);
+        let mut writer = ValueWriter::new();
+        let mut roots = Vec::new();
+        writer.write_value(&mut roots, &Value::Function(Rc::new(Function::script(chunk.clone()))))?;
+        data.extend(writer.finish(&roots)?);
+
+        fs::create_dir_all(&self.dir).with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        // Written under another name first so that another run never reads half an entry
+        let path = self.entry_path(source, dialect);
+        let partial_path = path.with_extension(format!("{}.tmp", process::id()));
+        fs::write(&partial_path, data).with_context(|| format!("Failed to write {}", partial_path.display()))?;
+        fs::rename(&partial_path, &path).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn entry_path(&self, source: &str, dialect: Dialect) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        dialect_key(dialect).hash(&mut hasher);
+        source.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.loxc", hasher.finish()))
+    }
+}
+
+fn dialect_key(dialect: Dialect) -> String {
+    format!("{:?}", dialect)
+}
+
+/// Reads a string written by `put_str` off the front of `data`
+fn take_str<'a>(data: &mut &'a [u8]) -> Option<&'a str> {
+    let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let bytes = data.get(4..4 + len)?;
+    *data = &data[4 + len..];
+    std::str::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    #[test]
+    fn chunks_come_back_for_the_same_source_and_dialect_only() {
+        let cache = ChunkCache::new(env::temp_dir().join(format!("lox-cache-{}", process::id())));
+        let source = "var a = 1; fun f(x) { return x + a; } print f(2);";
+        let chunk = Compiler::new(source.to_string()).compile().unwrap();
+        assert!(cache.get(source, Dialect::default()).is_none());
+
+        cache.put(source, Dialect::default(), &chunk).unwrap();
+        let cached = cache.get(source, Dialect::default()).unwrap();
+        assert_eq!(cached.code(), chunk.code());
+        assert_eq!(cached.constants().len(), chunk.constants().len());
+        assert!(cache.get("print 1;", Dialect::default()).is_none());
+        assert!(cache.get(source, Dialect { word_operators: true, ..Dialect::default() }).is_none());
+
+        // A damaged entry is a miss rather than an error
+        let path = cache.entry_path(source, Dialect::default());
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 3]).unwrap();
+        assert!(cache.get(source, Dialect::default()).is_none());
+
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+}
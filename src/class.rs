@@ -0,0 +1,220 @@
+use std::{cell::{Cell, RefCell}, collections::HashMap, fmt::{Debug, Display}, rc::{Rc, Weak}};
+
+use crate::{function::Closure, ordered_map::OrderedMap, value::Value};
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<String, Rc<Closure>>>,
+    /// Methods without parameters that are called when the property is read
+    pub getters: RefCell<HashMap<String, Rc<Closure>>>,
+    /// Changes whenever a method or getter is added, so that what was looked up before is
+    /// known to be out of date
+    version: Cell<u32>
+}
+
+impl Class {
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        Self { name: name.into(), methods: RefCell::new(HashMap::new()), getters: RefCell::new(HashMap::new()), version: Cell::new(0) }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version.get()
+    }
+
+    pub fn find_method(&self, name: &str) -> Option<Rc<Closure>> {
+        self.methods.borrow().get(name).cloned()
+    }
+
+    pub fn find_getter(&self, name: &str) -> Option<Rc<Closure>> {
+        self.getters.borrow().get(name).cloned()
+    }
+
+    /// Copies the superclass's methods and getters down, so lookups never have to walk the
+    /// superclass chain
+    pub fn inherit(&self, superclass: &Class) {
+        let inherited = superclass.methods.borrow().clone();
+        self.methods.borrow_mut().extend(inherited);
+        let inherited = superclass.getters.borrow().clone();
+        self.getters.borrow_mut().extend(inherited);
+        self.version.set(self.version.get().wrapping_add(1));
+    }
+
+    /// Adds a method, replacing any getter of the same name, such as one inherited
+    pub fn add_method<N: Into<String>>(&self, name: N, method: Rc<Closure>) {
+        let name = name.into();
+        self.getters.borrow_mut().remove(&name);
+        self.methods.borrow_mut().insert(name, method);
+        self.version.set(self.version.get().wrapping_add(1));
+    }
+
+    /// Adds a getter, replacing any method of the same name
+    pub fn add_getter<N: Into<String>>(&self, name: N, getter: Rc<Closure>) {
+        let name = name.into();
+        self.methods.borrow_mut().remove(&name);
+        self.getters.borrow_mut().insert(name, getter);
+        self.version.set(self.version.get().wrapping_add(1));
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    /// In the order they were first set, which is usually the same for every instance of a
+    /// class, so that a field tends to be at the same slot in each
+    pub fields: RefCell<OrderedMap<String, Value>>
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Self {
+        Self { class, fields: RefCell::new(OrderedMap::new()) }
+    }
+
+    pub fn get_field(&self, name: &str) -> Option<Value> {
+        self.fields.borrow().get(name).cloned()
+    }
+
+    pub fn field_slot(&self, name: &str) -> Option<usize> {
+        self.fields.borrow().slot(name)
+    }
+
+    /// The field at `slot`, if that's the one called `name`
+    pub fn field_at(&self, slot: usize, name: &str) -> Option<Value> {
+        match self.fields.borrow().get_slot(slot) {
+            Some((field_name, value)) if field_name == name => Some(value.clone()),
+            _ => None
+        }
+    }
+
+    /// Sets the field, returning its slot
+    pub fn set_field<N: Into<String>>(&self, name: N, value: Value) -> usize {
+        self.fields.borrow_mut().insert_full(name.into(), value).0
+    }
+
+    /// Sets the field at `slot` if that's the one called `name`, or gives the value back if it isn't
+    pub fn set_field_at(&self, slot: usize, name: &str, value: Value) -> Result<(), Value> {
+        let mut fields = self.fields.borrow_mut();
+        match fields.get_slot(slot) {
+            Some((field_name, _)) if field_name == name => {},
+            _ => return Err(value)
+        }
+        if let Some(field) = fields.get_slot_mut(slot) {
+            *field = value;
+        }
+        Ok(())
+    }
+}
+
+/// What a property instruction found the last time it ran, so that running it again on an
+/// instance of the same class needn't look the name up. It refers to the class and method
+/// weakly since they hold the chunk it's kept in.
+#[derive(Clone)]
+pub enum PropertyCache {
+    /// A field at this slot of the instance's fields
+    Field { class: Weak<Class>, slot: usize },
+    /// A method of the class, found while the class was at `version`
+    Method { class: Weak<Class>, version: u32, method: Weak<Closure> }
+}
+
+impl PropertyCache {
+    pub fn field(class: &Rc<Class>, slot: usize) -> Self {
+        Self::Field { class: Rc::downgrade(class), slot }
+    }
+
+    pub fn method(class: &Rc<Class>, method: &Rc<Closure>) -> Self {
+        Self::Method { class: Rc::downgrade(class), version: class.version(), method: Rc::downgrade(method) }
+    }
+
+    /// The slot of the field this found before, if it was found on an instance of `class`
+    pub fn field_slot(&self, class: &Rc<Class>) -> Option<usize> {
+        match self {
+            Self::Field { class: cached, slot } if Weak::as_ptr(cached) == Rc::as_ptr(class) => Some(*slot),
+            _ => None
+        }
+    }
+
+    /// The method this found before, if it was on `class` and the class hasn't changed since
+    pub fn method_of(&self, class: &Rc<Class>) -> Option<Rc<Closure>> {
+        match self {
+            Self::Method { class: cached, version, method } if Weak::as_ptr(cached) == Rc::as_ptr(class) && *version == class.version() => method.upgrade(),
+            _ => None
+        }
+    }
+}
+
+impl Debug for PropertyCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Field { slot, .. } => write!(f, "Field({})", slot),
+            Self::Method { version, .. } => write!(f, "Method(version {})", version)
+        }
+    }
+}
+
+/// A method together with the instance it was accessed on
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: Rc<Closure>) -> Self {
+        Self { receiver, method }
+    }
+}
+
+// Classes, instances and bound methods are compared by identity
+impl PartialEq for Class {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Class {
+    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
+        None
+    }
+}
+
+impl PartialEq for Instance {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Instance {
+    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
+        None
+    }
+}
+
+impl PartialEq for BoundMethod {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for BoundMethod {
+    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
+        None
+    }
+}
+
+impl Display for Class {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Display for Instance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} instance", self.class.name)
+    }
+}
+
+impl Display for BoundMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.method)
+    }
+}
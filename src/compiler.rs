@@ -1,9 +1,11 @@
-use core::panic;
-use std::{fmt::Display, collections::HashMap, rc::Rc};
+use std::{fmt::Display, collections::{BTreeSet, HashMap}, rc::Rc, mem};
 
 use anyhow::{Result, bail, Context, anyhow};
 use thiserror::Error;
-use crate::{scanner::{Scanner, Token, ScanError, TokenType}, chunk::Chunk, instruction::{OpCode, InstructionWriter}, value::Value};
+use crate::{bytes, capability::Capability, error_code::{self, CodedError}, constant_pool::SharedConstantPool, dialect::Dialect, scanner::{Scanner, Token, ScanError, TokenType}, chunk::{Chunk, LocalVar}, instruction::{MAX_LONG_OPERAND, OpCode, InstructionReader, InstructionWriter}, value::Value, function::{Function, UpvalueDescriptor}, interner::{Interner, Symbol, SymbolMap}, peephole, suggestion};
+
+/// How many compile errors are collected before compilation stops, unless overridden with `Compiler::with_max_errors`
+pub const DEFAULT_MAX_ERRORS: usize = 20;
 
 pub struct Compiler{
     scanner: Scanner,
@@ -12,8 +14,51 @@ pub struct Compiler{
     prev_token: Option<Token>,
     scope_depth: i32,
     locals: Vec<Local>,
+    upvalues: Vec<UpvalueDescriptor>,
+    function_type: FunctionType,
+    function_name: Option<String>,
+    arity: u8,
+    /// Whether the function being compiled has a rest parameter
+    variadic: bool,
+    loops: Vec<LoopState>,
+    /// Number of `try` blocks the code being compiled is inside, within the current function
+    try_depth: usize,
+    /// Names the script exports
+    exports: Vec<String>,
+    /// Natives that need a capability, by name
+    gated_natives: HashMap<String, Capability>,
+    /// Capabilities of the gated natives referred to so far
+    required_capabilities: BTreeSet<Capability>,
+    enclosing: Vec<FunctionState>,
+    classes: Vec<ClassState>,
     errors: Vec<CompileError>,
+    max_errors: usize,
+    eval_mode: bool,
+    constant_pool: Option<SharedConstantPool>,
+    interner: Interner,
+    /// Where the current function's chunk already holds identifier names as constants
+    identifier_constants: SymbolMap<usize>,
+    /// The imported module being compiled, recorded on every function it declares
+    module: Option<usize>,
     panic_mode: bool,
+    /// How many statements enclose the one being compiled, counting itself
+    statement_depth: usize,
+    /// Where in the chunk the statement being compiled starts, and how many locals there were
+    /// before it
+    statement_start: (usize, usize),
+    dialect: Dialect,
+    /// Whether operators on constants are worked out at compile time and code that can't be
+    /// reached is dropped
+    optimize: bool,
+    warnings: Vec<CompileWarning>,
+    /// Whether the last statement compiled leaves the block it's in, so that any statement after
+    /// it in the block can't be reached
+    exits_block: bool,
+    /// Globals the script declares, in the order it declares them
+    declared_globals: Vec<Symbol>,
+    /// Names that resolved to globals, kept until all the script's declarations are known to
+    /// warn about those that look misspelt
+    global_uses: Vec<GlobalUse>,
     parse_rules: ParseRuleTable
 }
 
@@ -22,27 +67,94 @@ impl Compiler {
         let parse_rules = Self::set_up_parse_rules();
         Self { scanner: Scanner::new(source), writer: InstructionWriter::with_new_chunk(),
             current_token: None, prev_token: None, scope_depth: 0,
-            locals: Vec::new(), errors: Vec::new(), panic_mode: false, parse_rules }
+            locals: Vec::new(), upvalues: Vec::new(), function_type: FunctionType::Script, function_name: None, arity: 0, variadic: false,
+            loops: Vec::new(), try_depth: 0, exports: Vec::new(), gated_natives: HashMap::new(), required_capabilities: BTreeSet::new(), enclosing: Vec::new(), classes: Vec::new(), errors: Vec::new(), max_errors: DEFAULT_MAX_ERRORS, eval_mode: false, constant_pool: None, interner: Interner::new(), identifier_constants: SymbolMap::default(), module: None, panic_mode: false, statement_depth: 0, statement_start: (0, 0), dialect: Dialect::default(), optimize: false, warnings: Vec::new(), exits_block: false, declared_globals: Vec::new(), global_uses: Vec::new(), parse_rules }
+    }
+
+    pub fn with_max_errors(self, max_errors: usize) -> Self {
+        Self { max_errors, ..self }
+    }
+
+    /// In eval mode the script may `return` a value from top-level code
+    pub fn with_eval_mode(self, eval_mode: bool) -> Self {
+        Self { eval_mode, ..self }
+    }
+
+    /// Interns the constants of every compiled chunk in a pool shared with other compilations
+    pub fn with_constant_pool(self, pool: SharedConstantPool) -> Self {
+        Self { writer: InstructionWriter::new(Chunk::with_pool(pool.clone())), constant_pool: Some(pool), ..self }
+    }
+
+    /// Compiles the source as written in a dialect with the given syntax options
+    pub fn with_dialect(self, dialect: Dialect) -> Self {
+        Self { scanner: self.scanner.with_dialect(dialect), dialect, ..self }
+    }
+
+    /// Records in the compiled chunk the capabilities of the natives given by name that the
+    /// script refers to as globals
+    pub fn with_gated_natives(self, gated_natives: HashMap<String, Capability>) -> Self {
+        Self { gated_natives, ..self }
+    }
+
+    pub fn with_module(self, module: usize) -> Self {
+        Self { module: Some(module), ..self }
+    }
+
+    /// Replaces operators applied to constants, such as `2 * 3` or `!true`, with the constant
+    /// they give, and drops code that can't be reached
+    pub fn with_optimizations(self, optimize: bool) -> Self {
+        Self { optimize, ..self }
+    }
+
+    fn new_writer(&self) -> InstructionWriter {
+        match &self.constant_pool {
+            Some(pool) => InstructionWriter::new(Chunk::with_pool(pool.clone())),
+            None => InstructionWriter::with_new_chunk()
+        }
     }
 
     pub fn compile(mut self) -> Result<Chunk> {
         self.advance();
+        self.compile_declarations()
+    }
 
+    /// Like `compile`, but gives `None` for a source with nothing in it besides whitespace and
+    /// comments, so that callers can treat it as having nothing to run
+    pub fn compile_nonempty(mut self) -> Result<Option<Chunk>> {
+        self.compile_nonempty_source()
+    }
+
+    /// Like `compile_nonempty`, also giving the warnings about the source, which are found
+    /// whether or not it compiles
+    pub fn compile_nonempty_with_warnings(mut self) -> (Result<Option<Chunk>>, Vec<CompileWarning>) {
+        let compiled = self.compile_nonempty_source();
+        (compiled, self.warnings)
+    }
+
+    fn compile_nonempty_source(&mut self) -> Result<Option<Chunk>> {
+        self.advance();
+        if self.check(&TokenType::Eof) && self.errors.is_empty() {
+            return Ok(None);
+        }
+        self.compile_declarations().map(Some)
+    }
+
+    fn compile_declarations(&mut self) -> Result<Chunk> {
         loop {
-            if self.matches(&TokenType::Eof) {
+            if self.matches(&TokenType::Eof) || self.has_too_many_errors() {
                 break
             }
 
-            match self.declaration() {
-                Ok(_) => {},
-                Err(e) => for err in e.chain().rev() {
-                    self.push_current_parse_error(format!("{}", err));
-                }
-            }
+            self.recovering_declaration();
         }
+        self.warn_about_misspelt_globals();
 
         if !self.errors.is_empty() {
-            bail!(CompileErrorCollection { errors: self.errors.clone() })
+            if self.has_too_many_errors() {
+                self.errors.truncate(self.max_errors);
+                self.errors.push(CompileError::TooMany(self.max_errors));
+            }
+            bail!(CompileErrorCollection { errors: mem::take(&mut self.errors) })
         }
 
         let line = match &self.current_token {
@@ -50,14 +162,66 @@ impl Compiler {
             None => 0,
         };
 
-        self.writer.write_op_code(OpCode::Return, line as i32);
+        self.write_return(line);
 
-        Ok(self.writer.to_chunk())
+        let new_writer = self.new_writer();
+        let mut chunk = mem::replace(&mut self.writer, new_writer).into_chunk();
+        peephole::optimize(&mut chunk, self.optimize)?;
+        chunk.set_exports(mem::take(&mut self.exports));
+        chunk.set_required_capabilities(mem::take(&mut self.required_capabilities).into_iter().collect());
+        Ok(chunk)
     } 
 
+    /// Compiles a declaration, and if it fails reports the error and skips to the next
+    /// statement, so that later errors get reported too
+    fn recovering_declaration(&mut self) {
+        self.recovering(Self::declaration)
+    }
+
+    /// Compiles with `compile`, reporting the error and skipping to the next statement if it fails
+    fn recovering(&mut self, compile: impl FnOnce(&mut Self) -> Result<()>) {
+        if let Err(e) = compile(self) {
+            match e.downcast::<CompileError>() {
+                Ok(compile_error) => self.push_error(compile_error),
+                // Errors returned rather than pushed are about the token just parsed
+                Err(e) => {
+                    let code = e.downcast_ref::<CodedError>().map_or(error_code::SYNTAX_ERROR, |err| err.code);
+                    for err in e.chain().rev() {
+                        self.push_prev_parse_error(code, format!("{}", err));
+                    }
+                }
+            }
+            self.synchronize();
+        }
+    }
+
     fn declaration(&mut self) -> Result<()> {
-        if self.matches(&TokenType::Var) {
+        if mem::take(&mut self.exits_block) {
+            let line = self.current()?.0.line;
+            self.warnings.push(CompileWarning::UnreachableCode { line });
+        }
+
+        // A block leaves the one it's in when its own last statement does
+        let may_exit = [TokenType::Return, TokenType::Throw, TokenType::Break, TokenType::Continue, TokenType::LeftBrace]
+            .iter().any(|t| self.check(t));
+        let enclosing_start = self.begin_statement()?;
+        let result = self.declaration_kind();
+        self.statement_start = enclosing_start;
+        self.exits_block &= may_exit;
+        result
+    }
+
+    fn declaration_kind(&mut self) -> Result<()> {
+        if self.matches(&TokenType::Export) {
+            self.export_declaration()?;
+        } else if self.matches(&TokenType::Class) {
+            self.class_declaration()?;
+        } else if self.matches(&TokenType::Fun) {
+            self.fun_declaration()?;
+        } else if self.matches(&TokenType::Var) {
             self.var_declaration()?;
+        } else if self.matches(&TokenType::Const) {
+            self.const_declaration()?;
         } else {
             self.statement()?;
         }
@@ -69,24 +233,329 @@ impl Compiler {
         Ok(())
     }
 
+    /// `export` before a top-level declaration makes the name visible to scripts importing this one
+    fn export_declaration(&mut self) -> Result<()> {
+        if self.function_type != FunctionType::Script || self.scope_depth > 0 {
+            self.push_prev_parse_error(error_code::MISPLACED_EXPORT, "Can only export top-level declarations.");
+        }
+
+        let declares = [TokenType::Class, TokenType::Fun, TokenType::Var, TokenType::Const].iter().any(|t| self.check(t));
+        if !declares {
+            self.push_current_parse_error(error_code::EXPECTED_TOKEN, "Expected a declaration after 'export'.");
+            return Ok(());
+        }
+        self.advance();
+
+        if self.check(&TokenType::Identifier) {
+            let name = self.current()?.1.to_string();
+            if self.exports.contains(&name) {
+                self.push_current_parse_error(error_code::DUPLICATE_EXPORT, format!("'{}' is already exported.", name));
+            } else {
+                self.exports.push(name);
+            }
+        }
+
+        match self.prev()?.0.token_type {
+            TokenType::Class => self.class_declaration(),
+            TokenType::Fun => self.fun_declaration(),
+            TokenType::Const => self.const_declaration(),
+            _ => self.var_declaration()
+        }
+    }
+
+    fn class_declaration(&mut self) -> Result<()> {
+        self.consume(&TokenType::Identifier, "Expected class name.");
+        let class_name = self.prev_symbol()?;
+        let line = self.prev()?.0.line;
+
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable()?;
+
+        self.writer.write_op_code_with_index(OpCode::Class, name_constant, line as i32)?;
+        self.define_variable(name_constant)?;
+
+        self.classes.push(ClassState { has_superclass: false });
+        let body_result = self.class_body(class_name);
+        self.classes.pop();
+
+        body_result
+    }
+
+    fn class_body(&mut self, class_name: Symbol) -> Result<()> {
+        if self.matches(&TokenType::Less) {
+            self.superclass(class_name)?;
+        }
+
+        // The class is kept on the stack while its methods are bound to it
+        self.named_variable(class_name, false)?;
+
+        self.consume(&TokenType::LeftBrace, "Expected '{' before class body.");
+        while !self.check(&TokenType::RightBrace) && !self.check(&TokenType::Eof) {
+            self.method()?;
+        }
+        self.consume(&TokenType::RightBrace, "Expected '}' after class body.");
+
+        let line = self.prev()?.0.line;
+        self.writer.write_op_code(OpCode::Pop, line as i32);
+
+        if self.classes.last().map(|c| c.has_superclass).unwrap_or(false) {
+            self.end_scope()?;
+        }
+
+        Ok(())
+    }
+
+    fn superclass(&mut self, class_name: Symbol) -> Result<()> {
+        self.consume(&TokenType::Identifier, "Expected superclass name.");
+        self.variable(false)?;
+
+        if self.prev_symbol()? == class_name {
+            self.push_prev_parse_error(error_code::SELF_INHERITANCE, "A class can't inherit from itself.");
+        }
+
+        // 'super' lives in a scope wrapping the class body so that methods can capture it
+        self.begin_scope();
+        let super_name = self.interner.intern("super");
+        self.add_local(super_name);
+        self.define_variable(0)?;
+        // Methods needn't call the superclass's
+        self.mark_read(super_name);
+
+        let line = self.prev()?.0.line;
+        self.named_variable(class_name, false)?;
+        self.writer.write_op_code(OpCode::Inherit, line as i32);
+
+        if let Some(class) = self.classes.last_mut() {
+            class.has_superclass = true;
+        }
+
+        Ok(())
+    }
+
+    fn method(&mut self) -> Result<()> {
+        self.consume(&TokenType::Identifier, "Expected method name.");
+        let name = self.prev_symbol()?;
+        let name_constant = self.identifier_constant(name);
+
+        let name = self.interner.resolve(name).to_string();
+        // A method without a parameter list is a getter, run whenever the property is read
+        let is_getter = self.check(&TokenType::LeftBrace);
+        if is_getter && name == "init" {
+            self.push_prev_parse_error(error_code::GETTER_INITIALIZER, "An initializer can't be a getter.");
+        }
+        let function_type = match (is_getter, name == "init") {
+            (true, _) => FunctionType::Getter,
+            (false, true) => FunctionType::Initializer,
+            (false, false) => FunctionType::Method
+        };
+        self.function(name, function_type)?;
+
+        let line = self.prev()?.0.line;
+        let op_code = if is_getter { OpCode::Getter } else { OpCode::Method };
+        self.writer.write_op_code_with_index(op_code, name_constant, line as i32)?;
+
+        Ok(())
+    }
+
+    fn fun_declaration(&mut self) -> Result<()> {
+        let global = self.parse_variable("Expected function name.")?;
+        // A function can refer to itself in its body so it's initialized right away
+        self.mark_initialized();
+
+        let name = self.prev_lexeme_str()?.to_string();
+        self.function(name, FunctionType::Function)?;
+
+        self.define_variable(global)
+    }
+
+    fn function(&mut self, name: String, function_type: FunctionType) -> Result<()> {
+        self.begin_function(name, function_type);
+        let body_result = self.function_body();
+        let function = self.end_function();
+        body_result?;
+        let mut function = function?;
+        peephole::optimize(&mut function.chunk, self.optimize)?;
+
+        let line = self.prev()?.0.line;
+        let index = self.make_constant(Value::Function(Rc::new(function)));
+        self.writer.write_op_code_with_index(OpCode::Closure, index, line as i32)?;
+
+        Ok(())
+    }
+
+    fn function_body(&mut self) -> Result<()> {
+        self.begin_scope();
+
+        if self.function_type != FunctionType::Getter {
+            self.parameters()?;
+        }
+        self.consume(&TokenType::LeftBrace, "Expected '{' before function body.");
+
+        self.block()
+    }
+
+    fn parameters(&mut self) -> Result<()> {
+        self.consume(&TokenType::LeftParen, "Expected '(' after function name.");
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                // `...name` collects the remaining arguments into a list
+                if self.matches(&TokenType::DotDotDot) {
+                    self.variadic = true;
+                } else if self.arity == u8::MAX {
+                    self.push_current_parse_error(error_code::TOO_MANY_PARAMETERS, "Can't have more than 255 parameters.");
+                } else {
+                    self.arity += 1;
+                }
+
+                self.consume(&TokenType::Identifier, "Expected parameter name.");
+                let name = self.prev_symbol()?;
+                // Parameters are the only locals so far, besides the callee's slot
+                if self.locals.iter().skip(1).any(|local| local.name == name) {
+                    let msg = format!("Duplicate parameter name '{}'.", self.interner.resolve(name));
+                    self.push_prev_parse_error(error_code::DUPLICATE_PARAMETER, msg);
+                }
+                self.add_local(name);
+                self.mark_initialized();
+                // A function may have to take parameters it has no use for, as a callback does
+                self.mark_read(name);
+
+                if !self.matches(&TokenType::Comma) {
+                    break;
+                }
+                if self.variadic {
+                    self.push_prev_parse_error(error_code::MISPLACED_REST_PARAMETER, "A rest parameter must be the last parameter.");
+                }
+            }
+        }
+        self.consume(&TokenType::RightParen, "Expected ')' after parameters.");
+
+        Ok(())
+    }
+
+    fn begin_function(&mut self, name: String, function_type: FunctionType) {
+        let new_writer = self.new_writer();
+        let enclosing = FunctionState {
+            writer: mem::replace(&mut self.writer, new_writer),
+            locals: mem::take(&mut self.locals),
+            upvalues: mem::take(&mut self.upvalues),
+            scope_depth: mem::replace(&mut self.scope_depth, 0),
+            function_type: mem::replace(&mut self.function_type, function_type),
+            function_name: self.function_name.replace(name),
+            arity: mem::replace(&mut self.arity, 0),
+            variadic: mem::replace(&mut self.variadic, false),
+            loops: mem::take(&mut self.loops),
+            try_depth: mem::replace(&mut self.try_depth, 0),
+            identifier_constants: mem::take(&mut self.identifier_constants)
+        };
+        self.enclosing.push(enclosing);
+
+        // Slot zero holds the function being called, or the receiver in the case of methods
+        let slot_zero_name = match function_type {
+            FunctionType::Method | FunctionType::Getter | FunctionType::Initializer => self.interner.intern("this"),
+            _ => self.interner.intern("")
+        };
+        self.locals.push(Local { name: slot_zero_name, depth: 0, initialized: true, is_captured: false, is_const: false, is_read: true, start: 0, line: 0 });
+    }
+
+    fn end_function(&mut self) -> Result<Function> {
+        let line = self.prev().map(|(t, _)| t.line).unwrap_or(0);
+        self.write_return(line);
+        for slot in 0..self.locals.len() {
+            self.record_local_var(slot);
+            self.check_local_read(slot);
+        }
+
+        let enclosing = self.enclosing.pop().context("No enclosing function state to restore")?;
+        let writer = mem::replace(&mut self.writer, enclosing.writer);
+        self.locals = enclosing.locals;
+        let upvalues = mem::replace(&mut self.upvalues, enclosing.upvalues);
+        self.scope_depth = enclosing.scope_depth;
+        self.function_type = enclosing.function_type;
+        let name = mem::replace(&mut self.function_name, enclosing.function_name);
+        let arity = mem::replace(&mut self.arity, enclosing.arity);
+        let variadic = mem::replace(&mut self.variadic, enclosing.variadic);
+        self.loops = enclosing.loops;
+        self.try_depth = enclosing.try_depth;
+        self.identifier_constants = enclosing.identifier_constants;
+
+        Ok(Function::new(name.unwrap_or_default(), arity, writer.into_chunk()).with_variadic(variadic).with_upvalues(upvalues).with_module(self.module))
+    }
+
+    fn write_return(&mut self, line: usize) {
+        // Initializers always return the instance being initialized
+        if self.function_type == FunctionType::Initializer {
+            self.writer.write_op_code_with_operand(OpCode::GetLocal, 0, line as i32);
+        } else {
+            self.writer.write_op_code(OpCode::Nil, line as i32);
+        }
+        self.writer.write_op_code(OpCode::Return, line as i32);
+    }
+
     fn var_declaration(&mut self) -> Result<()> {
         let global = self.parse_variable("Expected variable name")?;
 
-        if self.matches(&TokenType::Equal) {
-            self.expression()?;
+        let initializer = if self.matches(&TokenType::Equal) {
+            self.expression()
         } else {
             let line = self.prev()?.0.line;
             self.writer.write_op_code(OpCode::Nil, line as i32);
+            Ok(())
+        };
+
+        if initializer.is_ok() {
+            self.consume(&TokenType::Semicolon, "Expected ';' after variable declaration.");
         }
 
-        self.consume(&TokenType::Semicolon, "Expected ';' after variable declaration.");
+        // Defined even when the initializer has an error, so uses of the variable further
+        // on don't report errors of their own
+        self.define_variable(global)?;
+        initializer
+    }
 
-        self.define_variable(global)
+    /// `const x = value;` declares a variable that can't be assigned again
+    fn const_declaration(&mut self) -> Result<()> {
+        let global = self.parse_variable("Expected constant name")?;
+
+        self.consume(&TokenType::Equal, "Expected '=' after constant name.");
+        let initializer = self.expression();
+        if initializer.is_ok() {
+            self.consume(&TokenType::Semicolon, "Expected ';' after constant declaration.");
+        }
+
+        // Defined even when the initializer has an error, as variables are
+        if self.scope_depth > 0 {
+            if let Some(local) = self.locals.last_mut() {
+                local.is_const = true;
+            }
+            self.mark_initialized();
+            return initializer;
+        }
+        let line = self.prev()?.0.line;
+        self.writer.write_op_code_with_index(OpCode::DefineConstGlobal, global, line as i32)?;
+        initializer
     }
     
     fn statement(&mut self) -> Result<()> {
+        self.statement_depth += 1;
+        let enclosing_start = self.begin_statement()?;
+        let result = self.statement_kind();
+        self.statement_start = enclosing_start;
+        self.statement_depth -= 1;
+        result
+    }
+
+    /// Records that a statement starts at the end of the code so far, returning where the
+    /// enclosing one did. Forward jumps that need to are sent through an island first.
+    fn begin_statement(&mut self) -> Result<(usize, usize)> {
+        self.writer.write_jump_islands()?;
+        Ok(mem::replace(&mut self.statement_start, (self.writer.len(), self.locals.len())))
+    }
+
+    fn statement_kind(&mut self) -> Result<()> {
         if self.matches(&TokenType::Print) {
-            self.print_statement()?;
+            self.print_statement(OpCode::Print)?;
+        } else if self.matches(&TokenType::PrintErr) {
+            self.print_statement(OpCode::PrintErr)?;
         } else if self.matches(&TokenType::LeftBrace) {
             self.begin_scope();
             self.block()?;
@@ -95,6 +564,24 @@ impl Compiler {
             self.if_statement()?;
         } else if self.matches(&TokenType::While) {
             self.while_statement()?;
+        } else if self.matches(&TokenType::For) {
+            self.for_statement()?;
+        } else if self.matches(&TokenType::Switch) {
+            self.switch_statement()?;
+        } else if self.matches(&TokenType::Try) {
+            self.try_statement()?;
+        } else if self.matches(&TokenType::Throw) {
+            self.throw_statement()?;
+        } else if self.matches(&TokenType::Assert) {
+            self.assert_statement()?;
+        } else if self.matches(&TokenType::Import) {
+            self.import_statement()?;
+        } else if self.matches(&TokenType::Return) {
+            self.return_statement()?;
+        } else if self.matches(&TokenType::Break) {
+            self.break_statement()?;
+        } else if self.matches(&TokenType::Continue) {
+            self.continue_statement()?;
         } else {
             self.expression_statement()?;
         }
@@ -140,6 +627,7 @@ impl Compiler {
         let exit_jump_addr = self.writer.write_jump_if_false(line as i32);
         self.writer.write_op_code(OpCode::Pop, line as i32); // Pops if expression result
 
+        self.begin_loop(loop_start);
         self.statement()?;
 
         self.writer.write_loop(loop_start, line as i32)?;
@@ -147,51 +635,521 @@ impl Compiler {
         self.writer.patch_jump_to_chunk_end(exit_jump_addr)?;
         self.writer.write_op_code(OpCode::Pop, line as i32); // Pops if expression result
 
+        self.end_loop()
+    }
+
+    fn for_statement(&mut self) -> Result<()> {
+        // The initializer's variable is scoped to the loop
+        self.begin_scope();
+
+        self.consume(&TokenType::LeftParen, "Expected '(' after 'for'.");
+        if self.matches(&TokenType::Semicolon) {
+            // No initializer
+        } else if self.matches(&TokenType::Var) {
+            self.var_declaration()?;
+        } else {
+            self.expression_statement()?;
+        }
+
+        let mut loop_start = self.writer.len();
+
+        let mut exit_jump_addr = None;
+        if !self.matches(&TokenType::Semicolon) {
+            self.expression()?;
+            self.consume(&TokenType::Semicolon, "Expected ';' after loop condition.");
+
+            let line = self.prev()?.0.line;
+            exit_jump_addr = Some(self.writer.write_jump_if_false(line as i32));
+            self.writer.write_op_code(OpCode::Pop, line as i32); // Pops condition result
+        }
+
+        if !self.matches(&TokenType::RightParen) {
+            // The increment is compiled here but runs after the body, so jump over it
+            // into the body and have the body loop back to it
+            let line = self.prev()?.0.line;
+            let body_jump_addr = self.writer.write_jump(line as i32);
+            let increment_start = self.writer.len();
+
+            self.expression()?;
+            let line = self.prev()?.0.line;
+            self.writer.write_op_code(OpCode::Pop, line as i32); // Pops increment result
+            self.consume(&TokenType::RightParen, "Expected ')' after for clauses.");
+
+            self.writer.write_loop(loop_start, line as i32)?;
+            loop_start = increment_start;
+            self.writer.patch_jump_to_chunk_end(body_jump_addr)?;
+        }
+
+        self.begin_loop(loop_start);
+        self.statement()?;
+
+        let line = self.prev()?.0.line;
+        self.writer.write_loop(loop_start, line as i32)?;
+
+        if let Some(exit_jump_addr) = exit_jump_addr {
+            self.writer.patch_jump_to_chunk_end(exit_jump_addr)?;
+            self.writer.write_op_code(OpCode::Pop, line as i32); // Pops condition result
+        }
+
+        self.end_loop()?;
+        self.end_scope()
+    }
+
+    fn pop_handlers_deeper_than(&mut self, try_depth: usize, line: usize) {
+        for _ in try_depth..self.try_depth {
+            self.writer.write_op_code(OpCode::PopHandler, line as i32);
+        }
+    }
+
+    fn try_statement(&mut self) -> Result<()> {
+        let line = self.prev()?.0.line;
+        let handler = self.writer.write_push_handler(line as i32);
+
+        self.consume(&TokenType::LeftBrace, "Expected '{' after 'try'.");
+        self.try_depth += 1;
+        self.begin_scope();
+        self.block()?;
+        self.end_scope()?;
+        self.try_depth -= 1;
+
+        let line = self.prev()?.0.line;
+        self.writer.write_op_code(OpCode::PopHandler, line as i32);
+        let end_jump = self.writer.write_jump(line as i32);
+
+        // The handler starts with the thrown value on the stack, where it becomes the catch variable
+        self.writer.patch_jump_to_chunk_end(handler)?;
+        self.consume(&TokenType::Catch, "Expected 'catch' after try block.");
+        self.consume(&TokenType::LeftParen, "Expected '(' after 'catch'.");
+        self.consume(&TokenType::Identifier, "Expected exception variable name.");
+        let name = self.prev_symbol()?;
+        self.consume(&TokenType::RightParen, "Expected ')' after exception variable.");
+
+        self.begin_scope();
+        self.add_local(name);
+        self.mark_initialized();
+        self.consume(&TokenType::LeftBrace, "Expected '{' after catch clause.");
+        self.block()?;
+        self.end_scope()?;
+
+        self.writer.patch_jump_to_chunk_end(end_jump)
+    }
+
+    fn throw_statement(&mut self) -> Result<()> {
+        let line = self.prev()?.0.line;
+        self.expression()?;
+        self.consume(&TokenType::Semicolon, "Expected ';' after thrown value.");
+        self.writer.write_op_code(OpCode::Throw, line as i32);
+        self.exits_block = true;
+
+        Ok(())
+    }
+
+    /// `assert condition, message;` fails with the message, which may be left out, if the condition is false
+    fn assert_statement(&mut self) -> Result<()> {
+        let line = self.prev()?.0.line;
+        self.expression()?;
+        if self.matches(&TokenType::Comma) {
+            self.expression()?;
+        } else {
+            self.writer.write_op_code(OpCode::Nil, line as i32);
+        }
+        self.consume(&TokenType::Semicolon, "Expected ';' after assertion.");
+        self.writer.write_op_code(OpCode::Assert, line as i32);
+
+        Ok(())
+    }
+
+    /// `import "path";` runs the file at path once, binding the names it exports as globals
+    fn import_statement(&mut self) -> Result<()> {
+        let line = self.prev()?.0.line;
+        if !self.check(&TokenType::String) {
+            self.push_current_parse_error(error_code::EXPECTED_TOKEN, "Expected a path string after 'import'.");
+            return Ok(());
+        }
+        self.advance();
+        let lexeme = self.prev_lexeme_str()?;
+        let path = lexeme[1..lexeme.len()-1].to_string();
+        self.consume(&TokenType::Semicolon, "Expected ';' after import path.");
+
+        let index = self.make_constant(Value::String(path));
+        self.writer.write_op_code_with_index(OpCode::Import, index, line as i32)?;
+
+        Ok(())
+    }
+
+    fn switch_statement(&mut self) -> Result<()> {
+        self.consume(&TokenType::LeftParen, "Expected '(' after 'switch'.");
+
+        // The subject is kept in a hidden local so every case can compare against it
+        self.begin_scope();
+        self.expression()?;
+        // Not a valid identifier, so the script can't refer to it
+        let subject_name = self.interner.intern(" switch");
+        self.add_local(subject_name);
+        self.mark_initialized();
+        let subject_slot = self.locals.len() - 1;
+
+        self.consume(&TokenType::RightParen, "Expected ')' after switch value.");
+        self.consume(&TokenType::LeftBrace, "Expected '{' before switch cases.");
+
+        let mut end_jumps = Vec::new();
+        let mut seen_default = false;
+        while !self.check(&TokenType::RightBrace) && !self.check(&TokenType::Eof) {
+            if self.matches(&TokenType::Case) {
+                if seen_default {
+                    self.push_prev_parse_error(error_code::MISPLACED_CASE, "Can't have a case after the default case.");
+                }
+
+                let line = self.prev()?.0.line as i32;
+                self.writer.write_op_code_with_index(OpCode::GetLocal, subject_slot, line)?;
+                self.expression()?;
+                self.consume(&TokenType::Colon, "Expected ':' after case value.");
+
+                let line = self.prev()?.0.line as i32;
+                self.writer.write_op_code(OpCode::Equal, line);
+                let next_case_jump = self.writer.write_jump_if_false(line);
+                self.writer.write_op_code(OpCode::Pop, line); // Pops comparison result
+
+                self.case_body()?;
+                let line = self.prev()?.0.line as i32;
+                end_jumps.push(self.writer.write_jump(line));
+
+                self.writer.patch_jump_to_chunk_end(next_case_jump)?;
+                self.writer.write_op_code(OpCode::Pop, line); // Pops comparison result
+            } else if self.matches(&TokenType::Default) {
+                if seen_default {
+                    self.push_prev_parse_error(error_code::MISPLACED_CASE, "Can't have more than one default case.");
+                }
+                seen_default = true;
+
+                self.consume(&TokenType::Colon, "Expected ':' after 'default'.");
+                self.case_body()?;
+            } else {
+                self.push_current_parse_error(error_code::EXPECTED_TOKEN, "Expected 'case' or 'default' in switch.");
+                self.advance();
+            }
+        }
+
+        self.consume(&TokenType::RightBrace, "Expected '}' after switch cases.");
+
+        for end_jump in end_jumps {
+            self.writer.patch_jump_to_chunk_end(end_jump)?;
+        }
+
+        self.end_scope()
+    }
+
+    /// The statements of one case, which get their own scope. Execution never falls through to the next case.
+    fn case_body(&mut self) -> Result<()> {
+        self.begin_scope();
+        while !self.check(&TokenType::Case) && !self.check(&TokenType::Default)
+            && !self.check(&TokenType::RightBrace) && !self.check(&TokenType::Eof) {
+            self.declaration()?;
+        }
+        self.end_scope()
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        self.loops.push(LoopState { start, scope_depth: self.scope_depth, try_depth: self.try_depth, break_jumps: Vec::new() });
+    }
+
+    fn end_loop(&mut self) -> Result<()> {
+        let loop_state = self.loops.pop().context("No loop to end")?;
+        for break_jump in loop_state.break_jumps {
+            self.writer.patch_jump_to_chunk_end(break_jump)?;
+        }
+
+        Ok(())
+    }
+
+    fn break_statement(&mut self) -> Result<()> {
+        self.consume(&TokenType::Semicolon, "Expected ';' after 'break'.");
+
+        let (scope_depth, try_depth) = match self.loops.last() {
+            Some(loop_state) => (loop_state.scope_depth, loop_state.try_depth),
+            None => {
+                self.push_prev_parse_error(error_code::BREAK_OUTSIDE_LOOP, "Can't use 'break' outside of a loop.");
+                return Ok(());
+            }
+        };
+
+        self.discard_locals_deeper_than(scope_depth)?;
+        let line = self.prev()?.0.line;
+        self.pop_handlers_deeper_than(try_depth, line);
+        let jump = self.writer.write_jump(line as i32);
+        self.loops.last_mut().context("No enclosing loop")?.break_jumps.push(jump);
+        self.exits_block = true;
+
+        Ok(())
+    }
+
+    fn continue_statement(&mut self) -> Result<()> {
+        self.consume(&TokenType::Semicolon, "Expected ';' after 'continue'.");
+
+        let (start, scope_depth, try_depth) = match self.loops.last() {
+            Some(loop_state) => (loop_state.start, loop_state.scope_depth, loop_state.try_depth),
+            None => {
+                self.push_prev_parse_error(error_code::CONTINUE_OUTSIDE_LOOP, "Can't use 'continue' outside of a loop.");
+                return Ok(());
+            }
+        };
+
+        self.discard_locals_deeper_than(scope_depth)?;
+        let line = self.prev()?.0.line;
+        self.pop_handlers_deeper_than(try_depth, line);
+        self.writer.write_loop(start, line as i32)?;
+        self.exits_block = true;
+
+        Ok(())
+    }
+
+    /// Emits the pops for locals that a jump out of their scopes leaves behind. The locals
+    /// themselves stay declared since the code after the jump is still inside those scopes.
+    fn discard_locals_deeper_than(&mut self, scope_depth: i32) -> Result<()> {
+        let line = self.prev()?.0.line;
+        let op_codes: Vec<OpCode> = self.locals.iter()
+            .rev()
+            .take_while(|local| local.depth > scope_depth)
+            .map(|local| if local.is_captured { OpCode::CloseUpvalue } else { OpCode::Pop })
+            .collect();
+
+        for op_code in op_codes {
+            self.writer.write_op_code(op_code, line as i32);
+        }
+
+        Ok(())
+    }
+
+    fn return_statement(&mut self) -> Result<()> {
+        if self.function_type == FunctionType::Script && !self.eval_mode {
+            self.push_prev_parse_error(error_code::RETURN_FROM_TOP_LEVEL, "Can't return from top-level code.");
+        }
+
+        if self.matches(&TokenType::Semicolon) {
+            let line = self.prev()?.0.line;
+            self.write_return(line);
+        } else {
+            if self.function_type == FunctionType::Initializer {
+                self.push_prev_parse_error(error_code::RETURN_FROM_INITIALIZER, "Can't return a value from an initializer.");
+            }
+
+            self.expression()?;
+            self.consume(&TokenType::Semicolon, "Expected ';' after return value.");
+
+            let line = self.prev()?.0.line;
+            self.writer.write_op_code(OpCode::Return, line as i32);
+        }
+        self.exits_block = true;
+
+        Ok(())
+    }
+
+    fn print_statement(&mut self, op_code: OpCode) -> Result<()> {
+        self.expression()?;
+        self.consume(&TokenType::Semicolon, "Expected ';' after value.");
+
+        let line = self.prev()?.0.line;
+        self.writer.write_op_code(op_code, line as i32);
+
+        Ok(())
+    }
+
+    fn block(&mut self) -> Result<()> {
+        loop {
+            if self.check(&TokenType::RightBrace) || self.check(&TokenType::Eof) || self.has_too_many_errors() {
+                break
+            }
+            // An error stays within its statement instead of abandoning the rest of the block
+            self.recovering_declaration();
+        }
+
+        self.consume(&TokenType::RightBrace, "Expected '}' after block");
+
+        Ok(())
+    }
+
+    
+    fn expression_statement(&mut self) -> Result<()> {
+        let start = self.writer.len();
+        let start_line = self.current()?.0.line;
+        self.expression()?;
+        self.consume(&TokenType::Semicolon, "Expected ';' after expression.");
+
+        // The value of the script's final statement becomes its result, but not that of
+        // a statement nested in another, like the body of a trailing `while`
+        let is_script_result = self.function_type == FunctionType::Script && self.scope_depth == 0
+            && self.statement_depth == 1 && self.check(&TokenType::Eof);
+        if !is_script_result && self.only_computes_value(start)? {
+            self.warnings.push(CompileWarning::UnusedValue { line: start_line });
+        }
+
+        let line = self.prev()?.0.line;
+        let op_code = if is_script_result { OpCode::Return } else { OpCode::Pop };
+        self.writer.write_op_code(op_code, line as i32);
+
+        Ok(())
+    }
+
+    /// Whether the code from `start` on does nothing besides working out a value, so that
+    /// discarding the value makes it pointless
+    fn only_computes_value(&self, start: usize) -> Result<bool> {
+        let mut reader = InstructionReader::new(self.writer.chunk());
+        reader.set_ip(start)?;
+        while let Some((instruction, _, _)) = reader.read_next()? {
+            let computes = matches!(instruction.op_code, OpCode::Constant | OpCode::ConstantLong | OpCode::Nil | OpCode::True | OpCode::False
+                | OpCode::GetLocal | OpCode::GetLocalLong | OpCode::GetUpvalue | OpCode::GetGlobal | OpCode::GetGlobalLong | OpCode::Negate | OpCode::Not
+                | OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide | OpCode::Modulo | OpCode::Equal
+                | OpCode::Greater | OpCode::Less | OpCode::Jump | OpCode::JumpIfFalse | OpCode::Pop | OpCode::BuildList);
+            if !computes {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn expression(&mut self) -> Result<()> {
+        self.parse_precedence(&Precedence::Assignment)
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> Result<()> {
+        self.expression()?;
+        self.consume(&TokenType::RightParen, "Expected ')'");
+        Ok(())
+    }
+
+    fn block_expression(&mut self, _can_assign: bool) -> Result<()> {
+        if !self.dialect.expression_blocks {
+            bail!(CodedError::new(error_code::EXPECTED_EXPRESSION, "Expected expression"));
+        }
+
+        self.with_result_slot(|c, slot| {
+            c.begin_scope();
+            while !c.check(&TokenType::RightBrace) && !c.check(&TokenType::Eof) && !c.has_too_many_errors() {
+                if c.at_statement_keyword() {
+                    c.recovering_declaration();
+                } else {
+                    c.recovering(|c| {
+                        let enclosing_start = c.begin_statement()?;
+                        let result = c.block_expression_item(slot);
+                        c.statement_start = enclosing_start;
+                        result
+                    });
+                }
+            }
+            c.consume(&TokenType::RightBrace, "Expected '}' after block");
+            c.end_scope()
+        })
+    }
+
+    /// Compiles an expression in a block expression, which gives the block its value if it's
+    /// the last thing in the block and not followed by a semicolon
+    fn block_expression_item(&mut self, slot: usize) -> Result<()> {
+        self.expression()?;
+        let line = self.prev()?.0.line as i32;
+
+        // A semicolon implied by the end of the block doesn't count
+        let is_value = self.check(&TokenType::RightBrace)
+            || (self.matches(&TokenType::Semicolon) && self.prev()?.1.is_empty() && self.check(&TokenType::RightBrace));
+        if is_value {
+            self.writer.write_op_code_with_index(OpCode::SetLocal, slot, line)?;
+        } else if !self.check_prev(&TokenType::Semicolon) && !self.check_prev(&TokenType::RightBrace) {
+            // Like statements, a block or `if` used as an expression needs no semicolon after it
+            self.consume(&TokenType::Semicolon, "Expected ';' after expression.");
+        }
+        self.writer.write_op_code(OpCode::Pop, line);
+
         Ok(())
     }
 
-    fn print_statement(&mut self) -> Result<()> {
+    /// `if` as an expression in the expression blocks dialect, whose value is that of the arm
+    /// that runs. An arm that's a statement other than a block has the value nil.
+    fn if_expression(&mut self, _can_assign: bool) -> Result<()> {
+        if !self.dialect.expression_blocks {
+            bail!(CodedError::new(error_code::EXPECTED_EXPRESSION, "Expected expression"));
+        }
+
+        self.consume(&TokenType::LeftParen, "Expected '(' after 'if'.");
         self.expression()?;
-        self.consume(&TokenType::Semicolon, "Expected ';' after value.");
+        self.consume(&TokenType::RightParen, "Expected ')' after condition");
 
-        let line = self.prev()?.0.line;
-        self.writer.write_op_code(OpCode::Print, line as i32);
+        let line = self.prev()?.0.line as i32;
+        let if_jump_addr = self.writer.write_jump_if_false(line);
+        self.writer.write_op_code(OpCode::Pop, line); // Pops if expression result
 
-        Ok(())
-    }
+        self.if_expression_arm()?;
 
-    fn block(&mut self) -> Result<()> {
-        loop {
-            if self.check(&TokenType::RightBrace) || self.check(&TokenType::Eof) {
-                break
-            }
-            self.declaration()?;
+        let else_jump_addr = self.writer.write_jump(line);
+
+        self.writer.patch_jump_to_chunk_end(if_jump_addr)?;
+        self.writer.write_op_code(OpCode::Pop, line); // Pops if expression result
+
+        if self.matches(&TokenType::Else) {
+            self.if_expression_arm()?;
+        } else {
+            self.writer.write_op_code(OpCode::Nil, line);
         }
 
-        self.consume(&TokenType::RightBrace, "Expected '}' after block");
+        self.writer.patch_jump_to_chunk_end(else_jump_addr)?;
 
         Ok(())
     }
 
-    
-    fn expression_statement(&mut self) -> Result<()> {
-        self.expression()?;
-        self.consume(&TokenType::Semicolon, "Expected ';' after expression.");
+    fn if_expression_arm(&mut self) -> Result<()> {
+        if self.matches(&TokenType::LeftBrace) {
+            self.block_expression(false)
+        } else if self.matches(&TokenType::If) {
+            self.if_expression(false)
+        } else {
+            self.with_result_slot(|c, _| c.statement())
+        }
+    }
 
+    /// Compiles code that leaves its value in a slot it starts by pushing nil into, so that
+    /// the code can declare locals and run statements even in the middle of an expression
+    fn with_result_slot(&mut self, compile: impl FnOnce(&mut Self, usize) -> Result<()>) -> Result<()> {
+        let slot = self.stack_height()?;
+        if slot > MAX_LONG_OPERAND {
+            bail!(CodedError::new(error_code::TOO_MANY_LOCALS, "Too many local variables in function."));
+        }
         let line = self.prev()?.0.line;
-        self.writer.write_op_code(OpCode::Pop, line as i32);
+        self.writer.write_op_code(OpCode::Nil, line as i32);
+
+        // The values below the slot that aren't locals, and the slot itself, take up local
+        // slots while compiling so that the locals declared in the code get the right ones.
+        // A local being initialized by the expression already has the result's slot.
+        let local_count = self.locals.len();
+        let placeholder = self.interner.intern("");
+        while self.locals.len() <= slot {
+            self.locals.push(Local { name: placeholder, depth: self.scope_depth, initialized: true, is_captured: false, is_const: false, is_read: true, start: 0, line: 0 });
+        }
+        let result = compile(self, slot);
+        self.locals.truncate(local_count);
 
-        Ok(())
+        result
     }
 
-    fn expression(&mut self) -> Result<()> {
-        self.parse_precedence(&Precedence::Assignment)
+    /// How many values the code so far leaves on the stack of the current call, counting
+    /// locals, worked out from the locals there were when the statement started and the stack
+    /// effect of the statement's code since
+    fn stack_height(&self) -> Result<usize> {
+        let (start, local_count) = self.statement_start;
+        let mut reader = InstructionReader::new(self.writer.chunk());
+        reader.set_ip(start)?;
+        let mut height = local_count as i32;
+        while let Some((instruction, _, _)) = reader.read_next()? {
+            height += instruction.stack_effect();
+        }
+
+        Ok(height as usize)
     }
 
-    fn grouping(&mut self, _can_assign: bool) -> Result<()> {
-        self.expression()?;
-        self.consume(&TokenType::RightParen, "Expected ')'");
-        Ok(())
+    /// Whether the next token starts a declaration or a statement other than an expression
+    /// statement. In a block expression, a block or `if` is an expression instead.
+    fn at_statement_keyword(&self) -> bool {
+        [TokenType::Export, TokenType::Class, TokenType::Fun, TokenType::Var, TokenType::Const,
+            TokenType::Print, TokenType::PrintErr, TokenType::While, TokenType::For, TokenType::Switch,
+            TokenType::Try, TokenType::Throw, TokenType::Assert, TokenType::Import, TokenType::Return,
+            TokenType::Break, TokenType::Continue].iter().any(|token_type| self.check(token_type))
     }
 
     fn and(&mut self, _can_assign: bool) -> Result<()> { 
@@ -221,6 +1179,93 @@ impl Compiler {
         Ok(())
     }
 
+    fn call(&mut self, _can_assign: bool) -> Result<()> {
+        let line = self.prev()?.0.line;
+        let arg_count = self.argument_list()?;
+        self.writer.write_op_code_with_operand(OpCode::Call, arg_count, line as i32);
+
+        Ok(())
+    }
+
+    fn argument_list(&mut self) -> Result<u8> {
+        let mut arg_count: u8 = 0;
+
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                self.expression()?;
+
+                if arg_count == u8::MAX {
+                    self.push_current_parse_error(error_code::TOO_MANY_ARGUMENTS, "Can't have more than 255 arguments.");
+                } else {
+                    arg_count += 1;
+                }
+
+                if !self.matches(&TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(&TokenType::RightParen, "Expected ')' after arguments.");
+
+        Ok(arg_count)
+    }
+
+    fn dot(&mut self, can_assign: bool) -> Result<()> {
+        let line = self.prev()?.0.line;
+        self.consume(&TokenType::Identifier, "Expected property name after '.'.");
+        let name = self.prev_symbol()?;
+        let name_constant = self.identifier_constant(name);
+
+        if can_assign && self.matches(&TokenType::Equal) {
+            self.expression()?;
+            self.writer.write_op_code_with_index(OpCode::SetProperty, name_constant, line as i32)?;
+        } else if self.matches(&TokenType::LeftParen) {
+            let arg_count = self.argument_list()?;
+            self.writer.write_op_code_with_index_and_count(OpCode::Invoke, name_constant, arg_count, line as i32)?;
+        } else {
+            self.writer.write_op_code_with_index(OpCode::GetProperty, name_constant, line as i32)?;
+        }
+
+        Ok(())
+    }
+
+    fn this(&mut self, _can_assign: bool) -> Result<()> {
+        if self.classes.is_empty() {
+            self.push_prev_parse_error(error_code::THIS_OUTSIDE_CLASS, "Can't use 'this' outside of a class.");
+            return Ok(());
+        }
+
+        let this = self.interner.intern("this");
+        self.named_variable(this, false)
+    }
+
+    fn super_(&mut self, _can_assign: bool) -> Result<()> {
+        match self.classes.last() {
+            None => self.push_prev_parse_error(error_code::SUPER_OUTSIDE_CLASS, "Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => self.push_prev_parse_error(error_code::SUPER_WITHOUT_SUPERCLASS, "Can't use 'super' in a class with no superclass."),
+            _ => {}
+        }
+
+        let line = self.prev()?.0.line;
+        self.consume(&TokenType::Dot, "Expected '.' after 'super'.");
+        self.consume(&TokenType::Identifier, "Expected superclass method name.");
+        let name = self.prev_symbol()?;
+        let name_constant = self.identifier_constant(name);
+
+        let (this, super_name) = (self.interner.intern("this"), self.interner.intern("super"));
+        self.named_variable(this, false)?;
+        if self.matches(&TokenType::LeftParen) {
+            let arg_count = self.argument_list()?;
+            self.named_variable(super_name, false)?;
+            self.writer.write_op_code_with_index_and_count(OpCode::SuperInvoke, name_constant, arg_count, line as i32)?;
+        } else {
+            self.named_variable(super_name, false)?;
+            self.writer.write_op_code_with_index(OpCode::GetSuper, name_constant, line as i32)?;
+        }
+
+        Ok(())
+    }
+
     fn unary(&mut self, _can_assign: bool) -> Result<()> {
         let (prev_token, _) = self.prev()?;
         let operator_type = prev_token.token_type.clone();
@@ -240,10 +1285,10 @@ impl Compiler {
     fn binary(&mut self, _can_assign: bool) -> Result<()> {
         let (prev_token, _) = self.prev()?;
         let operator_type = prev_token.token_type.clone();
-        let parse_rule = self.get_rule(&operator_type);
+        let parse_rule = self.get_rule(&operator_type)?;
         let line = prev_token.line;
 
-        let higher_precedence = parse_rule.precedence.higher();
+        let higher_precedence = parse_rule.precedence.higher()?;
         self.parse_precedence(&higher_precedence)?;
 
         match operator_type {
@@ -251,6 +1296,7 @@ impl Compiler {
             TokenType::Minus => { self.writer.write_op_code(OpCode::Subtract, line as i32); },
             TokenType::Star => { self.writer.write_op_code(OpCode::Multiply, line as i32); },
             TokenType::Slash => { self.writer.write_op_code(OpCode::Divide, line as i32); },
+            TokenType::Percent => { self.writer.write_op_code(OpCode::Modulo, line as i32); },
             TokenType::BangEqual => {
                 self.writer.write_op_code(OpCode::Equal, line as i32);
                 self.writer.write_op_code(OpCode::Not, line as i32);
@@ -274,29 +1320,26 @@ impl Compiler {
 
     fn begin_scope(&mut self) {
         self.scope_depth += 1;
+        // The block starts after whatever left the one around it, like a catch block after a throw
+        self.exits_block = false;
     }
 
     fn end_scope(&mut self) -> Result<()> {
         self.scope_depth -= 1;
 
-        if self.locals.len() > 0 {
-            let mut i = self.locals.len() - 1;
-            loop  {
-                if self.locals[i].depth < self.scope_depth {
-                    break;
-                } 
-
-                let line = self.prev()?.0.line;
-                self.writer.write_op_code(OpCode::Pop, line as i32);
-
-                self.locals.pop();
+        while let Some(local) = self.locals.last() {
+            if local.depth <= self.scope_depth {
+                break;
+            }
 
-                if i == 0 {
-                    break;
-                }
+            // Captured locals are moved off the stack so closures can outlive the scope
+            let op_code = if local.is_captured { OpCode::CloseUpvalue } else { OpCode::Pop };
+            let line = self.prev()?.0.line;
+            self.writer.write_op_code(op_code, line as i32);
 
-                i -= 1;
-            }
+            self.record_local_var(self.locals.len() - 1);
+            self.check_local_read(self.locals.len() - 1);
+            self.locals.pop();
         }
 
         Ok(())
@@ -304,10 +1347,11 @@ impl Compiler {
 
 
     fn variable(&mut self, can_assign: bool) -> Result<()> {
-        self.named_variable(self.prev_lexeme_str()?.to_string(), can_assign)
+        let name = self.prev_symbol()?;
+        self.named_variable(name, can_assign)
     }
 
-    fn parse_variable(&mut self, msg: &str) -> Result<u8> {
+    fn parse_variable(&mut self, msg: &str) -> Result<usize> {
         self.consume(&TokenType::Identifier, msg);
 
         self.declare_variable()?;
@@ -315,8 +1359,11 @@ impl Compiler {
             return Ok(0);
         }
 
-        let c = self.prev_lexeme_str()?.to_string();
-        self.identifier_constant(c)
+        let name = self.prev_symbol()?;
+        if !self.declared_globals.contains(&name) {
+            self.declared_globals.push(name);
+        }
+        Ok(self.identifier_constant(name))
     }
 
     fn declare_variable(&mut self) -> Result<()> {
@@ -324,26 +1371,68 @@ impl Compiler {
             return Ok(());
         }
 
-        let name = self.prev_lexeme_str()?.to_string();
+        let name = self.prev_symbol()?;
+        let redeclared = self.locals.iter().rev()
+            .take_while(|local| local.depth >= self.scope_depth)
+            .any(|local| local.name == name);
+        if redeclared {
+            let msg = format!("Already a variable named '{}' in this scope.", self.interner.resolve(name));
+            self.push_prev_parse_error(error_code::REDECLARED_LOCAL, msg);
+        }
 
         self.add_local(name);
 
         Ok(())
     }
 
-    fn add_local(&mut self, name: String) {
-        if self.locals.len() >= u8::MAX as usize {
-            panic!("Too many locals");
+    fn add_local(&mut self, name: Symbol) {
+        // Locals are addressed by a slot of at most three bytes
+        if self.locals.len() > MAX_LONG_OPERAND {
+            self.push_prev_parse_error(error_code::TOO_MANY_LOCALS, "Too many local variables in function.");
+            return;
+        }
+        let line = self.prev_token.as_ref().map_or(0, |t| t.line);
+        self.locals.push(Local { name, depth: self.scope_depth, initialized: false, is_captured: false, is_const: false, is_read: false, start: 0, line });
+    }
+
+    /// Notes that the innermost variable of the name, in this function or an enclosing one,
+    /// is read
+    fn mark_read(&mut self, name: Symbol) {
+        for level in (0..=self.enclosing.len()).rev() {
+            if let Ok(Some(pos)) = self.resolve_local_at(level, name) {
+                let locals = if level == self.enclosing.len() { &mut self.locals } else { &mut self.enclosing[level].locals };
+                locals[pos as usize].is_read = true;
+                return;
+            }
+        }
+    }
+
+    /// Warns about the local in `slot` if it's going out of scope without ever having been
+    /// read. Hidden locals, and those whose names start with an underscore, are left out.
+    fn check_local_read(&mut self, slot: usize) {
+        let local = &self.locals[slot];
+        let name = self.interner.resolve(local.name);
+        if local.is_read || name.is_empty() || name.starts_with(' ') || name.starts_with('_') {
+            return;
         }
-        self.locals.push(Local { name, depth: self.scope_depth, initialized: false });
+        self.warnings.push(CompileWarning::UnusedVariable { name: name.to_string(), line: local.line });
+    }
+
+
+    fn resolve_local(&self, name: Symbol) -> Result<Option<i32>> {
+        self.resolve_local_at(self.enclosing.len(), name)
     }
 
+    /// Resolves a local of the function at `level` in the nesting of functions being compiled,
+    /// where the current function is at the level `self.enclosing.len()`
+    fn resolve_local_at(&self, level: usize, name: Symbol) -> Result<Option<i32>> {
+        let locals = if level == self.enclosing.len() { &self.locals } else { &self.enclosing[level].locals };
 
-    fn resolve_local(&self, name: &str) -> Result<Option<i32>> {
-        for (i, l) in self.locals.iter().enumerate() {
+        // The latest local of a name is the innermost, which shadows any others
+        for (i, l) in locals.iter().enumerate().rev() {
             if l.name == name {
                 if !l.initialized {
-                    bail!("Use of uninitialized local variable {}", name);
+                    bail!(CodedError::new(error_code::UNINITIALIZED_LOCAL, format!("Use of uninitialized local variable {}", self.interner.resolve(name))));
                 }
 
                 return Ok(Some(i as i32));
@@ -353,45 +1442,229 @@ impl Compiler {
         Ok(None)
     }
 
-    fn define_variable(&mut self, index: u8) -> Result<()> {
+    fn resolve_upvalue(&mut self, name: Symbol) -> Result<Option<u8>> {
+        self.resolve_upvalue_at(self.enclosing.len(), name)
+    }
+
+    fn resolve_upvalue_at(&mut self, level: usize, name: Symbol) -> Result<Option<u8>> {
+        if level == 0 {
+            return Ok(None);
+        }
+
+        let enclosing_level = level - 1;
+
+        if let Some(local_pos) = self.resolve_local_at(enclosing_level, name)? {
+            self.enclosing[enclosing_level].locals[local_pos as usize].is_captured = true;
+            return self.add_upvalue(level, UpvalueDescriptor { is_local: true, index: local_pos as usize }, name).map(Some);
+        }
+
+        if let Some(upvalue_pos) = self.resolve_upvalue_at(enclosing_level, name)? {
+            return self.add_upvalue(level, UpvalueDescriptor { is_local: false, index: upvalue_pos as usize }, name).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, level: usize, descriptor: UpvalueDescriptor, name: Symbol) -> Result<u8> {
+        let (upvalues, writer) = if level == self.enclosing.len() {
+            (&mut self.upvalues, &mut self.writer)
+        } else {
+            let state = &mut self.enclosing[level];
+            (&mut state.upvalues, &mut state.writer)
+        };
+
+        if let Some(pos) = upvalues.iter().position(|u| *u == descriptor) {
+            return Ok(pos as u8);
+        }
+
+        if upvalues.len() >= u8::MAX as usize {
+            bail!(CodedError::new(error_code::TOO_MANY_UPVALUES, "Too many closure variables in function."));
+        }
+
+        upvalues.push(descriptor);
+        writer.add_upvalue_name(self.interner.resolve(name).to_string());
+        Ok((upvalues.len() - 1) as u8)
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.scope_depth == 0 {
+            return;
+        }
+
+        let start = self.writer.len();
+        if let Some(local) = self.locals.last_mut() {
+            local.initialized = true;
+            local.start = start;
+        }
+    }
+
+    /// Notes in the chunk where the local in `slot` lived, which ends at the code written so far.
+    /// Hidden locals, whose names can't be written in source, are left out.
+    fn record_local_var(&mut self, slot: usize) {
+        let local = &self.locals[slot];
+        let name = self.interner.resolve(local.name);
+        if name.is_empty() || name.starts_with(' ') {
+            return;
+        }
+        self.writer.add_local_var(LocalVar { name: name.to_string(), slot, start: local.start, end: self.writer.len() });
+    }
+
+    fn define_variable(&mut self, index: usize) -> Result<()> {
         if self.scope_depth > 0 {
-            self.locals.last_mut().unwrap().initialized = true;
+            self.mark_initialized();
             return Ok(());
         }
         let line = self.prev()?.0.line;
-        self.writer.write_op_code_with_operand(OpCode::DefineGlobal, index, line as i32);
+        self.writer.write_op_code_with_index(OpCode::DefineGlobal, index, line as i32)?;
         Ok(())
     }
 
-    fn identifier_constant(&mut self, s: String) -> Result<u8> {
-        Ok(self.writer.add_constant(Value::String(s)))
+    fn identifier_constant(&mut self, name: Symbol) -> usize {
+        if let Some(index) = self.identifier_constants.get(&name) {
+            return *index;
+        }
+
+        let index = self.make_constant(Value::String(self.interner.resolve(name).to_string()));
+        self.identifier_constants.insert(name, index);
+        index
     }
 
-    fn named_variable(&mut self, name: String, can_assign: bool) -> Result<()> {
-        let line = self.prev()?.0.line;
+    /// Adds a constant for an instruction to refer to by its index
+    fn make_constant(&mut self, value: Value) -> usize {
+        match self.writer.add_constant(value) {
+            Ok(index) => index,
+            Err(_) => {
+                self.push_prev_parse_error(error_code::TOO_MANY_CONSTANTS, "Too many constants in one chunk.");
+                0
+            }
+        }
+    }
 
-        let (get_op, set_op, operand) = if let Some(local_pos) = self.resolve_local(&name)? {
-            (OpCode::GetLocal, OpCode::SetLocal, local_pos as u8)
-        } else {
-            let index = self.identifier_constant(name)?;
-            (OpCode::GetGlobal, OpCode::SetGlobal, index)
-        };
+    fn named_variable(&mut self, name: Symbol, can_assign: bool) -> Result<()> {
+        let line = self.prev()?.0.line as i32;
+        let (get_op, set_op, operand) = self.variable_ops(name)?;
 
         if can_assign && self.matches(&TokenType::Equal) {
+            self.check_assignable(name);
             self.expression()?;
-            self.writer.write_op_code_with_operand(set_op, operand, line as i32);
+            self.writer.write_op_code_with_index(set_op, operand, line)?;
+        } else if self.matches(&TokenType::PlusPlus) || self.matches(&TokenType::MinusMinus) {
+            self.check_assignable(name);
+            self.mark_read(name);
+            // Postfix: the old value is left on the stack underneath the updated one, which is stored and popped
+            let step_op = if self.check_prev(&TokenType::PlusPlus) { OpCode::Increment } else { OpCode::Decrement };
+            self.writer.write_op_code_with_index(get_op.clone(), operand, line)?;
+            self.writer.write_op_code_with_index(get_op, operand, line)?;
+            self.writer.write_op_code(step_op, line);
+            self.writer.write_op_code_with_index(set_op, operand, line)?;
+            self.writer.write_op_code(OpCode::Pop, line);
         } else {
-            self.writer.write_op_code_with_operand(get_op, operand, line as i32);
+            self.mark_read(name);
+            self.writer.write_op_code_with_index(get_op, operand, line)?;
         }
 
         Ok(())
     }
 
+    /// `++x` and `--x`
+    fn prefix_step(&mut self, _can_assign: bool) -> Result<()> {
+        let step_op = if self.check_prev(&TokenType::PlusPlus) { OpCode::Increment } else { OpCode::Decrement };
+        self.consume(&TokenType::Identifier, "Expected variable name after prefix operator.");
+
+        let line = self.prev()?.0.line as i32;
+        let name = self.prev_symbol()?;
+        self.check_assignable(name);
+        self.mark_read(name);
+        let (get_op, set_op, operand) = self.variable_ops(name)?;
+
+        self.writer.write_op_code_with_index(get_op, operand, line)?;
+        self.writer.write_op_code(step_op, line);
+        self.writer.write_op_code_with_index(set_op, operand, line)?;
+
+        Ok(())
+    }
+
+    /// Reports assigning to a local, of this function or an enclosing one, declared with `const`.
+    /// Constant globals are only known at runtime.
+    fn check_assignable(&mut self, name: Symbol) {
+        let is_const = (0..=self.enclosing.len()).rev().find_map(|level| {
+            let locals = if level == self.enclosing.len() { &self.locals } else { &self.enclosing[level].locals };
+            self.resolve_local_at(level, name).ok().flatten().map(|pos| locals[pos as usize].is_const)
+        });
+
+        if is_const == Some(true) {
+            self.push_prev_parse_error(error_code::ASSIGNMENT_TO_CONSTANT, format!("Can't assign to constant '{}'.", self.interner.resolve(name)));
+        }
+    }
+
+    /// The get and set opcodes for a variable and the operand both take, depending on where it lives
+    fn variable_ops(&mut self, name: Symbol) -> Result<(OpCode, OpCode, usize)> {
+        let ops = if let Some(local_pos) = self.resolve_local(name)? {
+            (OpCode::GetLocal, OpCode::SetLocal, local_pos as usize)
+        } else if let Some(upvalue_pos) = self.resolve_upvalue(name)? {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, upvalue_pos as usize)
+        } else {
+            if let Some(capability) = self.gated_natives.get(self.interner.resolve(name)) {
+                self.required_capabilities.insert(*capability);
+            }
+            self.record_global_use(name);
+            let index = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, index)
+        };
+
+        Ok(ops)
+    }
+
+    /// Notes a use of a global, with the local in scope whose name is closest to it, in case
+    /// it turns out to be a misspelling
+    fn record_global_use(&mut self, name: Symbol) {
+        let line = self.prev_token.as_ref().map_or(0, |t| t.line);
+        // The innermost locals come first, to be suggested over others just as close
+        let in_scope = self.locals.iter().rev()
+            .chain(self.enclosing.iter().rev().flat_map(|state| state.locals.iter().rev()))
+            .map(|local| self.interner.resolve(local.name))
+            .filter(|local_name| !local_name.is_empty() && !local_name.starts_with(' '));
+        let closest_local = suggestion::closest(self.interner.resolve(name), in_scope).map(str::to_string);
+        self.global_uses.push(GlobalUse { name, line, closest_local });
+    }
+
+    /// Warns about globals the script uses but never declares whose names are close to a
+    /// local in scope where they're used or to a global it does declare. Natives and globals
+    /// declared elsewhere aren't known here, so any others are left for the VM to report if
+    /// they turn out to be undefined.
+    fn warn_about_misspelt_globals(&mut self) {
+        let declared: Vec<&str> = self.declared_globals.iter().map(|name| self.interner.resolve(*name)).collect();
+        for global_use in mem::take(&mut self.global_uses) {
+            if self.declared_globals.contains(&global_use.name) {
+                continue;
+            }
+            let name = self.interner.resolve(global_use.name);
+            let candidates = global_use.closest_local.as_deref().into_iter().chain(declared.iter().copied());
+            if let Some(suggestion) = suggestion::closest(name, candidates) {
+                self.warnings.push(CompileWarning::MisspeltVariable { name: name.to_string(), suggestion: suggestion.to_string(), line: global_use.line });
+            }
+        }
+    }
+
     fn number(&mut self, _can_assign: bool) -> Result<()> {
         let (token, lexeme) = self.prev()?;
-        let num = lexeme.parse::<f64>()
-                .context(format!("Failed to parse '{}' as number", lexeme))?;
-        let num = Value::Number(num);
+        let digits = lexeme.replace('_', "");
+        let radix = match digits.get(..2) {
+            Some("0x" | "0X") => Some((16, "Hex")),
+            Some("0b" | "0B") => Some((2, "Binary")),
+            _ => None
+        };
+        let num = match radix {
+            // The scanner only lets through digits of the radix, so they can only fail to parse by overflowing
+            Some((radix, kind)) => Value::Int(i64::from_str_radix(&digits[2..], radix)
+                .map_err(|_| CodedError::new(error_code::NUMBER_TOO_LARGE, format!("{} literal is too large.", kind)))?),
+            // Without a decimal point or exponent it's an int, unless too large for one
+            None => match digits.parse::<i64>() {
+                Ok(int) => Value::Int(int),
+                _ => Value::Number(digits.parse::<f64>()
+                    .context(format!("Failed to parse '{}' as number", lexeme))?)
+            }
+        };
         self.writer.write_const(num, token.line as i32)?;
 
         Ok(())
@@ -407,6 +1680,58 @@ impl Compiler {
         Ok(())
     }
 
+    fn bytes(&mut self, _can_assign: bool) -> Result<()> {
+        let (token, lexeme) = self.prev()?;
+        // Strip the b" prefix and the closing quote
+        match bytes::parse_literal(&lexeme[2..lexeme.len()-1]) {
+            Ok(b) => { self.writer.write_const(Value::Bytes(Rc::new(b)), token.line as i32)?; },
+            Err(e) => self.push_prev_parse_error(error_code::MALFORMED_BYTES, e.to_string())
+        }
+
+        Ok(())
+    }
+
+    fn index(&mut self, can_assign: bool) -> Result<()> {
+        let line = self.prev()?.0.line;
+        self.expression()?;
+        self.consume(&TokenType::RightBracket, "Expected ']' after index.");
+
+        if can_assign && self.matches(&TokenType::Equal) {
+            self.expression()?;
+            self.writer.write_op_code(OpCode::SetIndex, line as i32);
+        } else {
+            self.writer.write_op_code(OpCode::GetIndex, line as i32);
+        }
+
+        Ok(())
+    }
+
+    fn list(&mut self, _can_assign: bool) -> Result<()> {
+        let line = self.prev()?.0.line;
+        let mut item_count: u8 = 0;
+
+        if !self.check(&TokenType::RightBracket) {
+            loop {
+                self.expression()?;
+
+                if item_count == u8::MAX {
+                    self.push_current_parse_error(error_code::TOO_MANY_LIST_ITEMS, "Can't have more than 255 items in a list literal.");
+                } else {
+                    item_count += 1;
+                }
+
+                if !self.matches(&TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(&TokenType::RightBracket, "Expected ']' after list items.");
+
+        self.writer.write_op_code_with_operand(OpCode::BuildList, item_count, line as i32);
+
+        Ok(())
+    }
+
     fn literal(&mut self, _can_assign: bool) -> Result<()> {
         let (token, _) = self.prev()?;
         match token.token_type {
@@ -439,7 +1764,7 @@ impl Compiler {
 
         if can_assign && self.matches(&TokenType::Equal) {
             let (token, lexeme) = self.prev()?;
-            bail!(CompileError::parse_error("Invalid assignment target", lexeme, token.line))
+            bail!(CompileError::parse_error(error_code::INVALID_ASSIGNMENT_TARGET, "Invalid assignment target", lexeme, token.line, token.lexeme.start))
         }
 
         Ok(())
@@ -456,8 +1781,14 @@ impl Compiler {
                     break Some(token)
                 },
                 Err(e) => {
-                    let scan_err = e.downcast_ref::<ScanError>().unwrap();
-                    self.push_scan_error(scan_err);
+                    let scan_err = match e.downcast::<ScanError>() {
+                        Ok(scan_err) => scan_err,
+                        Err(e) => match &self.prev_token {
+                            Some(t) => ScanError { line: t.line, offset: t.lexeme.start + t.lexeme.len, message: e.to_string(), code: error_code::SYNTAX_ERROR },
+                            None => ScanError { line: 0, offset: 0, message: e.to_string(), code: error_code::SYNTAX_ERROR }
+                        }
+                    };
+                    self.push_scan_error(&scan_err);
                 }
             }
         };
@@ -469,9 +1800,9 @@ impl Compiler {
                 return self.advance();
             }
 
-            self.push_current_parse_error(message)
+            self.push_current_parse_error(error_code::EXPECTED_TOKEN, message)
         } else {
-            self.push_current_parse_error(format!("Expected {:?} but no current token", token_type))
+            self.push_current_parse_error(error_code::EXPECTED_TOKEN, format!("Expected {:?} but no current token", token_type))
         }
         
     }
@@ -502,7 +1833,7 @@ impl Compiler {
 
     fn current_rule(&self) -> Result<Rc<ParseRule>> {
         let (current_token, _) = self.current()?;
-        Ok(self.get_token_rule(current_token))
+        self.get_token_rule(current_token)
     }
  
     fn prev_call_prefix(&mut self, precedence: &Precedence, msg: &str) -> Result<()> {
@@ -511,7 +1842,7 @@ impl Compiler {
         rule.call_prefix(self, can_assign, msg) 
             .with_context(|| {
                 match self.prev() {
-                    Ok((token, lexeme)) => anyhow!(CompileError::parse_error(msg, lexeme, token.line)),
+                    Ok((token, lexeme)) => anyhow!(CompileError::parse_error(error_code::EXPECTED_EXPRESSION, msg, lexeme, token.line, token.lexeme.start)),
                     Err(e) => e,
                 }
             })
@@ -523,7 +1854,7 @@ impl Compiler {
         rule.call_infix(self, can_assign, msg) 
             .with_context(|| {
                 match self.prev() {
-                    Ok((token, lexeme)) => anyhow!(CompileError::parse_error(msg, lexeme, token.line)),
+                    Ok((token, lexeme)) => anyhow!(CompileError::parse_error(error_code::EXPECTED_EXPRESSION, msg, lexeme, token.line, token.lexeme.start)),
                     Err(e) => e,
                 }
             })
@@ -531,22 +1862,29 @@ impl Compiler {
 
     fn prev_rule(&self) -> Result<Rc<ParseRule>> {
         let (prev_token, _) = self.prev()?;
-        Ok(self.get_token_rule(prev_token))
+        self.get_token_rule(prev_token)
     }
 
-    fn get_rule(&self, operator_type: &TokenType) -> Rc<ParseRule> {
+    fn get_rule(&self, operator_type: &TokenType) -> Result<Rc<ParseRule>> {
         self.parse_rules.get(operator_type)
-            .expect(format!("No parse rule found for operator {:?}", operator_type).as_str())
+            .with_context(|| format!("No parse rule found for operator {:?}", operator_type))
+    }
+
+    /// The identifier the previous token spells
+    fn prev_symbol(&mut self) -> Result<Symbol> {
+        let prev_token = self.prev_token.as_ref().context("prev token is null")?;
+        let lexeme = self.scanner.get_lexeme_str(&prev_token.lexeme)?;
+        Ok(self.interner.intern(lexeme))
     }
 
     fn prev_lexeme_str(&self) -> Result<&str> {
         match &self.prev_token {
-            Some(t) => Ok(self.lexeme_str(&t)),
+            Some(t) => self.lexeme_str(t),
             None => bail!("No prev token. Can't get prev lexeme"),
         }
     }
 
-    fn get_token_rule(&self, token: &Token) -> Rc<ParseRule> {
+    fn get_token_rule(&self, token: &Token) -> Result<Rc<ParseRule>> {
         let operator_type = token.token_type.clone();
         self.get_rule(&operator_type)
     }
@@ -554,31 +1892,41 @@ impl Compiler {
     fn current(&self) -> Result<(&Token, &str)> {
         let current_token = self.current_token.as_ref()
             .context("current token is null")?;
-        let lexeme_str = self.lexeme_str(current_token);
-        Ok((&current_token, lexeme_str))
+        let lexeme_str = self.lexeme_str(current_token)?;
+        Ok((current_token, lexeme_str))
     }
 
     fn prev(&self) -> Result<(&Token, &str)> {
         let prev_token = self.prev_token.as_ref()
             .context("prev token is null")?;
-        let lexeme_str = self.lexeme_str(prev_token);
-        Ok((&prev_token, lexeme_str))
+        let lexeme_str = self.lexeme_str(prev_token)?;
+        Ok((prev_token, lexeme_str))
     }
 
-    fn lexeme_str(&self, token: &Token) -> &str {
-        self.scanner.get_lexeme_str(&token.lexeme).expect("Current lexeme out of source boundary")
+    fn lexeme_str(&self, token: &Token) -> Result<&str> {
+        self.scanner.get_lexeme_str(&token.lexeme)
     }
 
 
-    fn push_current_parse_error<M: Into<String>>(&mut self, msg: M) {
-        let current_token = self.current_token.as_ref().expect("No current token by trying to push parse error");
-        self.push_parse_error(msg, current_token.clone())
+    /// Reports an error at the current token, or at the previous one if there's none yet
+    fn push_current_parse_error<M: Into<String>>(&mut self, code: &'static str, msg: M) {
+        let token = self.current_token.as_ref().or(self.prev_token.as_ref()).cloned();
+        self.push_parse_error(code, msg, token)
+    }
+
+    /// Reports an error at the previous token, or at the current one if there's none yet
+    fn push_prev_parse_error<M: Into<String>>(&mut self, code: &'static str, msg: M) {
+        let token = self.prev_token.as_ref().or(self.current_token.as_ref()).cloned();
+        self.push_parse_error(code, msg, token)
     }
 
-    fn push_parse_error<M: Into<String>>(&mut self, msg: M, token: Token) {
-        let lexeme = self.scanner.get_lexeme_str(&token.lexeme)
-            .expect("Lexeme outside of source boundary");
-        self.push_error(CompileError::parse_error(msg, lexeme, token.line))
+    /// Reports an error at the token, which without one is at no lexeme on line 0
+    fn push_parse_error<M: Into<String>>(&mut self, code: &'static str, msg: M, token: Option<Token>) {
+        let (lexeme, line, offset) = match &token {
+            Some(token) => (self.lexeme_str(token).unwrap_or_default().to_string(), token.line, token.lexeme.start),
+            None => (String::new(), 0, 0)
+        };
+        self.push_error(CompileError::parse_error(code, msg, lexeme, line, offset))
     }
 
     fn push_scan_error(&mut self, scan_err: &ScanError) {
@@ -586,12 +1934,17 @@ impl Compiler {
     }
 
     fn push_error(&mut self, error: CompileError) {
-        if !self.panic_mode {
+        // Errors past the cap are dropped; one extra is kept so we know to report the cut
+        if !self.panic_mode && !self.has_too_many_errors() {
             self.errors.push(error);
             self.panic_mode = true;
         }
     }
 
+    fn has_too_many_errors(&self) -> bool {
+        self.errors.len() > self.max_errors
+    }
+
     fn synchronize(&mut self) {
         self.panic_mode = false;
 
@@ -604,15 +1957,16 @@ impl Compiler {
                 return;
             }
 
-            match &self.current_token {
-                Some(t) => {
-                    match t.token_type {
-                        TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::For
-                        | TokenType::If | TokenType::While | TokenType::Print | TokenType::Return => return,
-                        _ => {}
-                    };
-                },
-                _ => {}
+            if let Some(t) = &self.current_token {
+                match t.token_type {
+                    // The end of the enclosing block, which `block` carries on from
+                    TokenType::RightBrace if self.scope_depth > 0 => return,
+                    TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::Const | TokenType::For
+                    | TokenType::If | TokenType::While | TokenType::Print | TokenType::PrintErr | TokenType::Return
+                    | TokenType::Break | TokenType::Continue | TokenType::Switch | TokenType::Try | TokenType::Throw
+                    | TokenType::Assert | TokenType::Export | TokenType::Import => return,
+                    _ => {}
+                };
             }
 
             self.advance();
@@ -622,16 +1976,23 @@ impl Compiler {
     fn set_up_parse_rules() -> ParseRuleTable {
         let mut table = ParseRuleTable::new();
 
-        table.add(&TokenType::LeftParen, Some(Self::grouping), None, Precedence::None);
+        table.add(&TokenType::LeftParen, Some(Self::grouping), Some(Self::call), Precedence::Call);
         table.add_null(&TokenType::RightParen);
-        table.add_null(&TokenType::LeftBrace);
+        table.add(&TokenType::LeftBrace, Some(Self::block_expression), None, Precedence::None);
         table.add_null(&TokenType::RightBrace);
+        table.add(&TokenType::LeftBracket, Some(Self::list), Some(Self::index), Precedence::Call);
+        table.add_null(&TokenType::RightBracket);
         table.add_null(&TokenType::Comma);
-        table.add_null(&TokenType::Dot);
+        table.add(&TokenType::Dot, None, Some(Self::dot), Precedence::Call);
+        table.add_null(&TokenType::DotDotDot);
         table.add(&TokenType::Minus, Some(Self::unary), Some(Self::binary), Precedence::Term);
+        table.add(&TokenType::PlusPlus, Some(Self::prefix_step), None, Precedence::None);
+        table.add(&TokenType::MinusMinus, Some(Self::prefix_step), None, Precedence::None);
         table.add(&TokenType::Plus, None, Some(Self::binary), Precedence::Term);
         table.add_null(&TokenType::Semicolon);
+        table.add_null(&TokenType::Colon);
         table.add(&TokenType::Slash, None, Some(Self::binary), Precedence::Factor);
+        table.add(&TokenType::Percent, None, Some(Self::binary), Precedence::Factor);
         table.add(&TokenType::Star, None, Some(Self::binary), Precedence::Factor);
 
         table.add(&TokenType::Bang, Some(Self::unary), None, Precedence::Factor);
@@ -645,22 +2006,36 @@ impl Compiler {
 
         table.add(&TokenType::Identifier, Some(Self::variable), None, Precedence::None);
         table.add(&TokenType::String, Some(Self::string), None, Precedence::None);
+        table.add(&TokenType::Bytes, Some(Self::bytes), None, Precedence::None);
         table.add(&TokenType::Number, Some(Self::number), None, Precedence::None);
 
 
         table.add(&TokenType::And, None, Some(Self::and), Precedence::And);
+        table.add_null(&TokenType::Assert);
+        table.add_null(&TokenType::Break);
+        table.add_null(&TokenType::Case);
         table.add_null(&TokenType::Class);
+        table.add_null(&TokenType::Continue);
+        table.add_null(&TokenType::Default);
         table.add_null(&TokenType::Else);
         table.add(&TokenType::False, Some(Self::literal), None, Precedence::None);
         table.add_null(&TokenType::Fun);
         table.add_null(&TokenType::For);
-        table.add_null(&TokenType::If);
+        table.add(&TokenType::If, Some(Self::if_expression), None, Precedence::None);
         table.add(&TokenType::Nil, Some(Self::literal), None, Precedence::None);
         table.add(&TokenType::Or, None, Some(Self::or), Precedence::And);
         table.add_null(&TokenType::Print);
+        table.add_null(&TokenType::PrintErr);
         table.add_null(&TokenType::Return);
-        table.add_null(&TokenType::Super);
-        table.add_null(&TokenType::This);
+        table.add(&TokenType::Super, Some(Self::super_), None, Precedence::None);
+        table.add_null(&TokenType::Switch);
+        table.add_null(&TokenType::Try);
+        table.add_null(&TokenType::Catch);
+        table.add_null(&TokenType::Throw);
+        table.add_null(&TokenType::Export);
+        table.add_null(&TokenType::Import);
+        table.add_null(&TokenType::Const);
+        table.add(&TokenType::This, Some(Self::this), None, Precedence::None);
         table.add(&TokenType::True, Some(Self::literal), None, Precedence::None);
         table.add_null(&TokenType::Var);
         table.add_null(&TokenType::While);
@@ -689,7 +2064,7 @@ impl ParseRuleTable {
     }
 
     pub fn get(&self, token_type: &TokenType) -> Option<Rc<ParseRule>> {
-       self.lookup.get(token_type).map(|p| p.clone())
+       self.lookup.get(token_type).cloned()
     }
 }
 
@@ -717,7 +2092,7 @@ impl ParseRule {
     fn call<M: Into<String>>(callback: &Option<ParseFn>, c: &mut Compiler, can_assign: bool, msg: M) -> Result<()> {
         match callback {
             Some(f) => f(c, can_assign),
-            None => bail!(msg.into())
+            None => bail!(CodedError::new(error_code::EXPECTED_EXPRESSION, msg))
         }
     }
 }
@@ -741,9 +2116,9 @@ enum Precedence {
 }
 
 impl Precedence {
-    pub fn higher(&self) -> Precedence {
+    pub fn higher(&self) -> Result<Precedence> {
         let clone = self.clone();
-        (clone as i32 + 1).into()
+        (clone as i32 + 1).try_into()
     }
 
     pub fn is_greater_than(&self, other: &Precedence) -> bool {
@@ -755,20 +2130,77 @@ impl Precedence {
     }
 }
 
-impl From<i32> for Precedence {
-    fn from(i: i32) -> Self {
-        if i > Precedence::Primary as i32 {
-            panic!("Failed to convert {} to Precedence", i);
+impl TryFrom<i32> for Precedence {
+    type Error = anyhow::Error;
+
+    fn try_from(i: i32) -> Result<Self> {
+        if !(Precedence::None as i32..=Precedence::Primary as i32).contains(&i) {
+            bail!("Failed to convert {} to Precedence", i);
         }
-        unsafe { std::mem::transmute(i) }
+        Ok(unsafe { std::mem::transmute::<i32, Precedence>(i) })
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FunctionType {
+    Script,
+    Function,
+    Method,
+    Getter,
+    Initializer
+}
+
+/// Compilation state of a function whose compilation is suspended while a nested function is compiled
+struct FunctionState {
+    writer: InstructionWriter,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueDescriptor>,
+    scope_depth: i32,
+    function_type: FunctionType,
+    function_name: Option<String>,
+    arity: u8,
+    variadic: bool,
+    loops: Vec<LoopState>,
+    try_depth: usize,
+    identifier_constants: SymbolMap<usize>
+}
+
+struct LoopState {
+    /// Where `continue` jumps back to
+    start: usize,
+    /// Scope depth outside the loop body. Locals deeper than this are popped on `break` and `continue`
+    scope_depth: i32,
+    /// Number of enclosing `try` blocks outside the loop. Handlers of deeper ones are removed on `break` and `continue`
+    try_depth: usize,
+    /// `break` jumps to patch once the end of the loop is known
+    break_jumps: Vec<usize>
+}
+
+struct ClassState {
+    has_superclass: bool
+}
+
 #[derive(Clone, Debug)]
 struct Local {
-    name: String,
+    name: Symbol,
     depth: i32,
-    initialized: bool
+    initialized: bool,
+    is_captured: bool,
+    is_const: bool,
+    /// Whether the local's value is ever read, by this function or a closure
+    is_read: bool,
+    /// Offset of the code from which the local is initialized
+    start: usize,
+    /// Line the local is declared on
+    line: usize
+}
+
+/// A name that resolved to a global, where it's used
+struct GlobalUse {
+    name: Symbol,
+    line: usize,
+    /// Of the locals in scope, the one with a name close enough to be what was meant, if any
+    closest_local: Option<String>
 }
 
 #[derive(Error, Clone, Debug)]
@@ -790,17 +2222,113 @@ impl Display for CompileErrorCollection {
 pub enum CompileError {
     #[error("[line {line}] Compile error: '{lexeme}' - {msg}")]
     Parse {
+        /// Stable code of the kind of error, from `error_code`
+        code: &'static str,
         msg: String,
         lexeme: String,
-        line: usize 
+        line: usize,
+        /// Where the lexeme starts in the source, in bytes
+        offset: usize
     },
     #[error("{0}")]
-    Scan(ScanError)
+    Scan(ScanError),
+    #[error("Too many errors (more than {0}), stopping")]
+    TooMany(usize)
+}
+
+/// Something in the source that compiles but likely isn't what was meant
+#[derive(Error, Clone, Debug, PartialEq)]
+pub enum CompileWarning {
+    #[error("[line {line}] Warning: Unreachable code")]
+    UnreachableCode {
+        line: usize
+    },
+    #[error("[line {line}] Warning: Local variable '{name}' is never read")]
+    UnusedVariable {
+        name: String,
+        line: usize
+    },
+    #[error("[line {line}] Warning: Value of expression statement is never used")]
+    UnusedValue {
+        line: usize
+    },
+    #[error("[line {line}] Warning: Variable '{name}' is never declared; did you mean '{suggestion}'?")]
+    MisspeltVariable {
+        name: String,
+        /// The declared name it's closest to
+        suggestion: String,
+        line: usize
+    }
+}
+
+impl CompileWarning {
+    /// The stable code of the kind of warning, such as `L0902` for an unused variable
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnreachableCode { .. } => error_code::UNREACHABLE_CODE,
+            Self::UnusedVariable { .. } => error_code::UNUSED_VARIABLE,
+            Self::UnusedValue { .. } => error_code::UNUSED_VALUE,
+            Self::MisspeltVariable { .. } => error_code::MISSPELT_VARIABLE
+        }
+    }
+
+    pub fn line(&self) -> usize {
+        match self {
+            Self::UnreachableCode { line } | Self::UnusedVariable { line, .. } | Self::UnusedValue { line } | Self::MisspeltVariable { line, .. } => *line
+        }
+    }
+
+    /// What's likely wrong, without where
+    pub fn message(&self) -> String {
+        match self {
+            Self::UnreachableCode { .. } => "Unreachable code".to_string(),
+            Self::UnusedVariable { name, .. } => format!("Local variable '{}' is never read", name),
+            Self::UnusedValue { .. } => "Value of expression statement is never used".to_string(),
+            Self::MisspeltVariable { name, suggestion, .. } => format!("Variable '{}' is never declared; did you mean '{}'?", name, suggestion)
+        }
+    }
 }
 
 impl CompileError {
-    pub fn parse_error<M: Into<String>, N: Into<String>>(msg: M, lexeme: N, line:usize) -> Self { 
-        Self::Parse { msg: msg.into(), lexeme: lexeme.into(), line }
+    pub fn parse_error<M: Into<String>, N: Into<String>>(code: &'static str, msg: M, lexeme: N, line: usize, offset: usize) -> Self {
+        Self::Parse { code, msg: msg.into(), lexeme: lexeme.into(), line, offset }
+    }
+
+    /// The stable code of the kind of error, such as `L0201` for a missing token
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Parse { code, .. } => code,
+            Self::Scan(scan_err) => scan_err.code,
+            Self::TooMany(_) => error_code::TOO_MANY_ERRORS
+        }
+    }
+
+    /// The line the error is on, or None if it's about the source as a whole
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Self::Parse { line, .. } => Some(*line),
+            Self::Scan(scan_err) => Some(scan_err.line),
+            Self::TooMany(_) => None
+        }
+    }
+
+    /// What went wrong, without where
+    pub fn message(&self) -> String {
+        match self {
+            Self::Parse { msg, .. } => msg.clone(),
+            Self::Scan(scan_err) => scan_err.message.clone(),
+            Self::TooMany(_) => self.to_string()
+        }
+    }
+
+    /// Where in the source the error is, in bytes, and how many bytes long. At least one byte
+    /// long, so that there's something to point at even for the end of the source.
+    pub fn span(&self) -> Option<(usize, usize)> {
+        match self {
+            Self::Parse { offset, lexeme, .. } => Some((*offset, lexeme.len().max(1))),
+            Self::Scan(scan_err) => Some((scan_err.offset, 1)),
+            Self::TooMany(_) => None
+        }
     }
 }   
 
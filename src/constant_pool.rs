@@ -0,0 +1,66 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::value::Value;
+
+/// A constant pool shared by every chunk compiled against it, so that a string or number
+/// used by many small compiled units (REPL lines, modules) is stored once.
+pub type SharedConstantPool = Rc<RefCell<ConstantPool>>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Int(i64),
+    String(String)
+}
+
+#[derive(Debug, Default)]
+pub struct ConstantPool {
+    values: Vec<Value>,
+    /// Where already interned strings and numbers live in `values`
+    lookup: HashMap<ConstantKey, u32>
+}
+
+impl ConstantPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shared() -> SharedConstantPool {
+        Rc::new(RefCell::new(Self::new()))
+    }
+
+    /// Adds a constant and returns its index. Strings and numbers already in the pool are
+    /// reused; other values such as functions are always added.
+    pub fn intern(&mut self, value: Value) -> u32 {
+        let key = match &value {
+            Value::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Value::Int(n) => Some(ConstantKey::Int(*n)),
+            Value::String(s) => Some(ConstantKey::String(s.clone())),
+            _ => None
+        };
+
+        if let Some(index) = key.as_ref().and_then(|k| self.lookup.get(k)) {
+            return *index;
+        }
+
+        self.values.push(value);
+        let index = (self.values.len() - 1) as u32;
+        if let Some(key) = key {
+            self.lookup.insert(key, index);
+        }
+
+        index
+    }
+
+    pub fn get(&self, index: u32) -> Option<&Value> {
+        self.values.get(index as usize)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
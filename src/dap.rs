@@ -0,0 +1,415 @@
+//! A minimal server for the Debug Adapter Protocol, so editors such as VS Code can debug
+//! scripts: breakpoints, stepping, and inspecting call frames and variables.
+//!
+//! There's a single thread, the script, and requests arriving while it runs are only
+//! handled once it next stops.
+
+use std::{cell::RefCell, fs::read_to_string, io::{BufRead, Write}, path::{Path, PathBuf}, rc::Rc};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::{compiler::Compiler, debugger::{Debugger, Location, Resume, StopReason, Stepper}, json::Json, value::Value, vm::{Vm, VmOptions}};
+
+/// Id of the only thread
+const THREAD_ID: i64 = 1;
+/// Variables reference of the globals. Those of each frame's locals follow it.
+const GLOBALS_REFERENCE: i64 = 1;
+
+/// Runs a debugging session over `reader` and `writer` until the client disconnects
+pub fn serve_debug_adapter<R: BufRead + 'static, W: Write + 'static>(reader: R, writer: W, options: VmOptions) -> Result<()> {
+    let session = Rc::new(RefCell::new(Session::new(Connection::new(reader, writer))));
+
+    // The client sends breakpoints and other settings before saying it's done configuring
+    if !session.borrow_mut().serve_until(|action| matches!(action, Action::Launch), None)? {
+        return Ok(());
+    }
+
+    let program = session.borrow().program.clone().ok_or_else(|| anyhow!("Launched without a program"))?;
+    let exit_code = run_program(&program, options, &session)?;
+
+    let mut session = session.borrow_mut();
+    if session.disconnected {
+        return Ok(());
+    }
+    session.connection.event("exited", Json::object([("exitCode", Json::from(exit_code))]))?;
+    session.connection.event("terminated", Json::Object(Vec::new()))?;
+    session.serve_until(|_| false, None)?;
+    Ok(())
+}
+
+/// Runs the script with the session as its debugger, returning its exit code
+fn run_program<R: BufRead + 'static, W: Write + 'static>(program: &Path, options: VmOptions, session: &Rc<RefCell<Session<R, W>>>) -> Result<i64> {
+    let source = read_to_string(program).with_context(|| format!("Failed to read {}", program.display()))?;
+    let chunk = match Compiler::new(source).with_dialect(options.dialect).compile() {
+        Ok(chunk) => chunk,
+        Err(e) => {
+            session.borrow_mut().connection.output("stderr", &format!("{}\n", e))?;
+            return Ok(1);
+        }
+    };
+
+    let mut vm = Vm::new(options);
+    vm.set_script_path(program);
+    vm.set_debugger(Box::new(SessionDebugger(session.clone())));
+    match vm.run(&chunk) {
+        Ok(_) => Ok(0),
+        Err(_) if session.borrow().disconnected => Ok(1),
+        Err(e) => {
+            session.borrow_mut().connection.output("stderr", &format!("{}\n", e))?;
+            Ok(1)
+        }
+    }
+}
+
+/// What a request asks the server to do next
+enum Action {
+    Wait,
+    /// Start running the script, now that it's known and the client has finished configuring
+    Launch,
+    Resume(Resume),
+    Disconnect
+}
+
+struct Session<R, W> {
+    connection: Connection<R, W>,
+    stepper: Stepper,
+    program: Option<PathBuf>,
+    configured: bool,
+    disconnected: bool
+}
+
+impl<R: BufRead, W: Write> Session<R, W> {
+    fn new(connection: Connection<R, W>) -> Self {
+        Self { connection, stepper: Stepper::new(), program: None, configured: false, disconnected: false }
+    }
+
+    /// Handles requests until one asks for an action matching `done`, returning false if the
+    /// client disconnected first. `vm` is the VM stopped in the debugger, if any.
+    fn serve_until<D: Fn(&Action) -> bool>(&mut self, done: D, vm: Option<(&Vm, &Location)>) -> Result<bool> {
+        loop {
+            let request = match self.connection.read()? {
+                Some(request) => request,
+                None => {
+                    self.disconnected = true;
+                    return Ok(false);
+                }
+            };
+
+            match self.handle(&request, vm) {
+                Ok(Action::Disconnect) => {
+                    self.disconnected = true;
+                    return Ok(false);
+                },
+                Ok(action) if done(&action) => {
+                    if let (Action::Resume(how), Some((_, location))) = (action, vm) {
+                        self.stepper.resume(how, location);
+                    }
+                    return Ok(true);
+                },
+                Ok(_) => {},
+                Err(e) => self.connection.respond_error(&request, &e.to_string())?
+            }
+        }
+    }
+
+    fn handle(&mut self, request: &Json, vm: Option<(&Vm, &Location)>) -> Result<Action> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or_default();
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Null);
+        let vm = vm.map(|(vm, _)| vm);
+
+        let (body, action) = match command {
+            "initialize" => {
+                self.connection.respond(request, Json::object([("supportsConfigurationDoneRequest", Json::from(true))]))?;
+                self.connection.event("initialized", Json::Object(Vec::new()))?;
+                return Ok(Action::Wait);
+            },
+            "launch" => {
+                let program = arguments.get("program").and_then(Json::as_str)
+                    .ok_or_else(|| anyhow!("Launch needs the path of the program"))?;
+                let program = PathBuf::from(program).canonicalize().with_context(|| format!("Failed to find {}", program))?;
+                self.program = Some(program);
+                if arguments.get("stopOnEntry").and_then(Json::as_bool) == Some(true) {
+                    self.stepper = std::mem::take(&mut self.stepper).with_stop_on_entry();
+                }
+                (Json::Null, self.launch_action())
+            },
+            "setBreakpoints" => (self.set_breakpoints(&arguments)?, Action::Wait),
+            "configurationDone" => {
+                self.configured = true;
+                (Json::Null, self.launch_action())
+            },
+            "threads" => {
+                let thread = Json::object([("id", Json::from(THREAD_ID)), ("name", Json::from("main"))]);
+                (Json::object([("threads", Json::from(vec![thread]))]), Action::Wait)
+            },
+            "stackTrace" => (stack_trace(vm), Action::Wait),
+            "scopes" => {
+                let frame_id = arguments.get("frameId").and_then(Json::as_f64).ok_or_else(|| anyhow!("Scopes need a frame id"))? as i64;
+                let scope = |name: &str, reference: i64| Json::object([
+                    ("name", Json::from(name)), ("variablesReference", Json::from(reference)), ("expensive", Json::from(false))
+                ]);
+                let scopes = vec![scope("Locals", GLOBALS_REFERENCE + frame_id), scope("Globals", GLOBALS_REFERENCE)];
+                (Json::object([("scopes", Json::from(scopes))]), Action::Wait)
+            },
+            "variables" => {
+                let reference = arguments.get("variablesReference").and_then(Json::as_f64).ok_or_else(|| anyhow!("Variables need a reference"))? as i64;
+                (variables(vm, reference), Action::Wait)
+            },
+            "continue" => (Json::object([("allThreadsContinued", Json::from(true))]), Action::Resume(Resume::Continue)),
+            "next" => (Json::Null, Action::Resume(Resume::StepOver)),
+            "stepIn" => (Json::Null, Action::Resume(Resume::StepIn)),
+            "stepOut" => (Json::Null, Action::Resume(Resume::StepOut)),
+            "disconnect" | "terminate" => (Json::Null, Action::Disconnect),
+            other => bail!("Unsupported request '{}'", other)
+        };
+
+        self.connection.respond(request, body)?;
+        Ok(action)
+    }
+
+    fn launch_action(&self) -> Action {
+        if self.program.is_some() && self.configured { Action::Launch } else { Action::Wait }
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json> {
+        let path = arguments.get("source").and_then(|s| s.get("path")).and_then(Json::as_str)
+            .ok_or_else(|| anyhow!("Breakpoints need the path of their source"))?;
+        let lines: Vec<i64> = arguments.get("breakpoints").and_then(Json::as_array).unwrap_or_default().iter()
+            .filter_map(|b| b.get("line").and_then(Json::as_f64))
+            .map(|line| line as i64)
+            .collect();
+
+        self.stepper.set_breakpoints(Path::new(path), lines.iter().map(|line| *line as i32));
+        let breakpoints = lines.into_iter()
+            .map(|line| Json::object([("verified", Json::from(true)), ("line", Json::from(line))]))
+            .collect();
+        Ok(Json::object([("breakpoints", Json::Array(breakpoints))]))
+    }
+}
+
+/// Frames are numbered from one, innermost first
+fn stack_trace(vm: Option<&Vm>) -> Json {
+    let frames: Vec<Json> = vm.map(Vm::debug_frames).unwrap_or_default().into_iter().enumerate()
+        .map(|(i, frame)| {
+            let mut members = vec![
+                ("id", Json::from(i as i64 + 1)), ("name", Json::from(frame.name)),
+                ("line", Json::from(frame.line as i64)), ("column", Json::from(1))
+            ];
+            if let Some(path) = frame.source {
+                let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                members.push(("source", Json::object([("name", Json::from(name)), ("path", Json::from(path.display().to_string()))])));
+            }
+            Json::object(members)
+        })
+        .collect();
+    let total = frames.len() as i64;
+    Json::object([("stackFrames", Json::from(frames)), ("totalFrames", Json::from(total))])
+}
+
+fn variables(vm: Option<&Vm>, reference: i64) -> Json {
+    let named: Vec<(String, Value)> = match vm {
+        Some(vm) if reference == GLOBALS_REFERENCE => {
+            // Natives are globals too, but they'd bury the script's own
+            let mut globals: Vec<(String, Value)> = vm.globals()
+                .filter(|(_, value)| !matches!(value, Value::Native(_)))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            globals.sort_by(|a, b| a.0.cmp(&b.0));
+            globals
+        },
+        Some(vm) => vm.debug_frames().into_iter()
+            .nth((reference - GLOBALS_REFERENCE - 1) as usize)
+            .map(|frame| frame.locals)
+            .unwrap_or_default(),
+        None => Vec::new()
+    };
+
+    let variables = named.into_iter()
+        .map(|(name, value)| {
+            let shown = match &value {
+                Value::String(s) => format!("{:?}", s),
+                other => other.to_string()
+            };
+            Json::object([("name", Json::from(name)), ("value", Json::from(shown)), ("variablesReference", Json::from(0))])
+        })
+        .collect();
+    Json::object([("variables", Json::Array(variables))])
+}
+
+/// The VM's side of a session, stopping to serve requests wherever the stepper says
+struct SessionDebugger<R, W>(Rc<RefCell<Session<R, W>>>);
+
+impl<R: BufRead, W: Write> Debugger for SessionDebugger<R, W> {
+    fn on_line(&mut self, vm: &Vm, location: &Location) -> Result<()> {
+        let mut session = self.0.borrow_mut();
+        let reason = match session.stepper.stop_reason(location) {
+            Some(reason) => reason,
+            None => return Ok(())
+        };
+
+        let reason = match reason {
+            StopReason::Entry => "entry",
+            StopReason::Breakpoint => "breakpoint",
+            StopReason::Step => "step"
+        };
+        session.connection.event("stopped", Json::object([
+            ("reason", Json::from(reason)), ("threadId", Json::from(THREAD_ID)), ("allThreadsStopped", Json::from(true))
+        ]))?;
+
+        if !session.serve_until(|action| matches!(action, Action::Resume(_)), Some((vm, location)))? {
+            bail!("Debugging session ended");
+        }
+        Ok(())
+    }
+}
+
+/// Messages framed with a `Content-Length` header, as the protocol sends them
+struct Connection<R, W> {
+    reader: R,
+    writer: W,
+    seq: i64
+}
+
+impl<R: BufRead, W: Write> Connection<R, W> {
+    fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer, seq: 0 }
+    }
+
+    /// The next message, or `None` once the client has closed the connection
+    fn read(&mut self) -> Result<Option<Json>> {
+        let mut content_length = None;
+        loop {
+            let mut header = String::new();
+            if self.reader.read_line(&mut header).context("Failed to read message header")? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(length) = header.strip_prefix("Content-Length:") {
+                content_length = Some(length.trim().parse::<usize>().context("Invalid Content-Length")?);
+            }
+        }
+
+        let mut content = vec![0; content_length.ok_or_else(|| anyhow!("Message without a Content-Length"))?];
+        self.reader.read_exact(&mut content).context("Failed to read message")?;
+        let content = String::from_utf8(content).context("Message isn't UTF-8")?;
+        Json::parse(&content).map(Some)
+    }
+
+    fn send(&mut self, message_type: &str, members: Vec<(&str, Json)>) -> Result<()> {
+        self.seq += 1;
+        let mut message = vec![("seq", Json::from(self.seq)), ("type", Json::from(message_type))];
+        message.extend(members);
+        let content = Json::object(message).to_string();
+
+        write!(self.writer, "Content-Length: {}\r\n\r\n{}", content.len(), content).context("Failed to send message")?;
+        self.writer.flush().context("Failed to send message")
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> Result<()> {
+        let mut members = self.response_members(request, true);
+        if body != Json::Null {
+            members.push(("body", body));
+        }
+        self.send("response", members)
+    }
+
+    fn respond_error(&mut self, request: &Json, message: &str) -> Result<()> {
+        let mut members = self.response_members(request, false);
+        members.push(("message", Json::from(message)));
+        self.send("response", members)
+    }
+
+    fn response_members(&self, request: &Json, success: bool) -> Vec<(&'static str, Json)> {
+        vec![
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::from(success)),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null))
+        ]
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Result<()> {
+        self.send("event", vec![("event", Json::from(event)), ("body", body)])
+    }
+
+    fn output(&mut self, category: &str, text: &str) -> Result<()> {
+        self.event("output", Json::object([("category", Json::from(category)), ("output", Json::from(text))]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A writer the test can still read after handing it to the server
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(messages: &[String]) -> Vec<u8> {
+        messages.iter()
+            .map(|m| format!("Content-Length: {}\r\n\r\n{}", m.len(), m))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn replies(output: &[u8]) -> Vec<Json> {
+        let mut connection = Connection::new(Cursor::new(output.to_vec()), Vec::new());
+        std::iter::from_fn(|| connection.read().unwrap()).collect()
+    }
+
+    #[test]
+    fn stops_at_breakpoint_and_shows_frames_and_locals() {
+        let dir = std::env::temp_dir().join(format!("lox-dap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("main.lox");
+        std::fs::write(&program, "var factor = 2;\nfun scale(n) {\n  var scaled = n * factor;\n  return scaled;\n}\nvar result = scale(21);\n").unwrap();
+        let path = program.display().to_string().replace('\\', "\\\\");
+
+        let requests = [
+            r#"{"seq":1,"type":"request","command":"initialize","arguments":{"adapterID":"lox"}}"#.to_string(),
+            format!(r#"{{"seq":2,"type":"request","command":"launch","arguments":{{"program":"{}"}}}}"#, path),
+            format!(r#"{{"seq":3,"type":"request","command":"setBreakpoints","arguments":{{"source":{{"path":"{}"}},"breakpoints":[{{"line":3}}]}}}}"#, path),
+            r#"{"seq":4,"type":"request","command":"configurationDone"}"#.to_string(),
+            r#"{"seq":5,"type":"request","command":"stackTrace","arguments":{"threadId":1}}"#.to_string(),
+            r#"{"seq":6,"type":"request","command":"variables","arguments":{"variablesReference":2}}"#.to_string(),
+            r#"{"seq":7,"type":"request","command":"next","arguments":{"threadId":1}}"#.to_string(),
+            r#"{"seq":8,"type":"request","command":"variables","arguments":{"variablesReference":2}}"#.to_string(),
+            r#"{"seq":9,"type":"request","command":"continue","arguments":{"threadId":1}}"#.to_string(),
+            r#"{"seq":10,"type":"request","command":"disconnect"}"#.to_string()
+        ];
+        let output = SharedBuffer::default();
+        serve_debug_adapter(Cursor::new(frame(&requests)), output.clone(), VmOptions::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let replies = replies(&output.0.borrow());
+        let events: Vec<&str> = replies.iter().filter_map(|r| r.get("event").and_then(Json::as_str)).collect();
+        assert_eq!(events, ["initialized", "stopped", "stopped", "exited", "terminated"]);
+        assert!(replies.iter().filter(|r| r.get("type").and_then(Json::as_str) == Some("response"))
+            .all(|r| r.get("success") == Some(&Json::Bool(true))));
+
+        let body = |seq: i64| replies.iter()
+            .find(|r| r.get("request_seq").and_then(Json::as_f64) == Some(seq as f64))
+            .and_then(|r| r.get("body"))
+            .unwrap()
+            .to_string();
+        assert!(body(5).contains(r#"{"id":1,"name":"scale","line":3,"column":1,"source":{"name":"main.lox""#));
+        assert!(body(5).contains(r#"{"id":2,"name":"script","line":6"#));
+        assert_eq!(body(6), r#"{"variables":[{"name":"n","value":"21","variablesReference":0}]}"#);
+        assert_eq!(body(8), r#"{"variables":[{"name":"n","value":"21","variablesReference":0},{"name":"scaled","value":"42","variablesReference":0}]}"#);
+    }
+}
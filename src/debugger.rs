@@ -0,0 +1,157 @@
+//! Hooks for debuggers. The VM tells a [`Debugger`] each time execution reaches a line,
+//! and the debugger may inspect the VM and block until it has decided how to go on.
+
+use std::{collections::{HashMap, HashSet}, fmt::Debug, path::{Path, PathBuf}};
+
+use anyhow::Result;
+
+use crate::{value::Value, vm::Vm};
+
+pub trait Debugger {
+    /// Called before the first instruction run on a line, and again when a loop jumps back
+    /// or a call returns to it. An error ends execution without running `catch` blocks.
+    fn on_line(&mut self, vm: &Vm, location: &Location) -> Result<()>;
+}
+
+impl Debug for dyn Debugger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<debugger>")
+    }
+}
+
+/// Where execution is about to carry on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// File of the script or module running, if known
+    pub source: Option<PathBuf>,
+    pub line: i32,
+    /// Number of active call frames
+    pub depth: usize
+}
+
+/// A call frame as seen by a debugger
+#[derive(Debug, Clone)]
+pub struct DebugFrame {
+    pub name: String,
+    pub source: Option<PathBuf>,
+    pub line: i32,
+    /// Locals in scope at the current instruction, in order of slot
+    pub locals: Vec<(String, Value)>
+}
+
+/// How to carry on after stopping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    /// Stop at the next line run, even in a called function
+    StepIn,
+    /// Stop at the next line of this function or the one it returns to
+    StepOver,
+    /// Stop once this function has returned
+    StepOut
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Entry,
+    Breakpoint,
+    Step
+}
+
+/// Decides where to stop, from breakpoints and the last step asked for
+#[derive(Debug, Default)]
+pub struct Stepper {
+    /// Lines with breakpoints, by canonical path of their file
+    breakpoints: HashMap<PathBuf, HashSet<i32>>,
+    /// How execution was resumed and from where, unless it's to run to the next breakpoint
+    step: Option<(Resume, Location)>,
+    stop_on_entry: bool,
+    /// Depth of the last location seen, to tell returning to a line from arriving at it
+    last_depth: usize
+}
+
+impl Stepper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop at the first line run
+    pub fn with_stop_on_entry(self) -> Self {
+        Self { stop_on_entry: true, ..self }
+    }
+
+    /// Replaces the breakpoints in `source` with ones on `lines`
+    pub fn set_breakpoints<I: IntoIterator<Item = i32>>(&mut self, source: &Path, lines: I) {
+        self.breakpoints.insert(canonical(source), lines.into_iter().collect());
+    }
+
+    /// Why execution should stop at `location`, if it should
+    pub fn stop_reason(&mut self, location: &Location) -> Option<StopReason> {
+        let returned = location.depth < std::mem::replace(&mut self.last_depth, location.depth);
+        if std::mem::take(&mut self.stop_on_entry) {
+            return Some(StopReason::Entry);
+        }
+
+        // A breakpoint was already passed on the way into a call made from its line
+        let on_breakpoint = !returned && location.source.as_ref()
+            .and_then(|source| self.breakpoints.get(source))
+            .is_some_and(|lines| lines.contains(&location.line));
+        if on_breakpoint {
+            return Some(StopReason::Breakpoint);
+        }
+
+        let step_done = match &self.step {
+            Some((Resume::StepIn, _)) => true,
+            Some((Resume::StepOver, from)) => location.depth < from.depth || (location.depth == from.depth && location.line != from.line),
+            Some((Resume::StepOut, from)) => location.depth < from.depth,
+            Some((Resume::Continue, _)) | None => false
+        };
+        step_done.then_some(StopReason::Step)
+    }
+
+    /// Carries on from `location`, where execution stopped
+    pub fn resume(&mut self, how: Resume, location: &Location) {
+        self.step = match how {
+            Resume::Continue => None,
+            step => Some((step, location.clone()))
+        };
+    }
+}
+
+/// The path the VM knows a file by, so breakpoints match however the editor names it
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(line: i32, depth: usize) -> Location {
+        Location { source: Some(PathBuf::from("/no/such/script.lox")), line, depth }
+    }
+
+    #[test]
+    fn stops_on_breakpoints_and_after_steps() {
+        let mut stepper = Stepper::new().with_stop_on_entry();
+        stepper.set_breakpoints(Path::new("/no/such/script.lox"), [5]);
+        assert_eq!(stepper.stop_reason(&at(1, 1)), Some(StopReason::Entry));
+        assert_eq!(stepper.stop_reason(&at(2, 1)), None);
+        assert_eq!(stepper.stop_reason(&at(5, 1)), Some(StopReason::Breakpoint));
+
+        stepper.resume(Resume::StepOver, &at(5, 1));
+        assert_eq!(stepper.stop_reason(&at(9, 2)), None);
+        assert_eq!(stepper.stop_reason(&at(5, 1)), None);
+        assert_eq!(stepper.stop_reason(&at(6, 1)), Some(StopReason::Step));
+
+        stepper.resume(Resume::StepIn, &at(6, 1));
+        assert_eq!(stepper.stop_reason(&at(9, 2)), Some(StopReason::Step));
+
+        stepper.resume(Resume::StepOut, &at(9, 2));
+        assert_eq!(stepper.stop_reason(&at(10, 2)), None);
+        assert_eq!(stepper.stop_reason(&at(6, 1)), Some(StopReason::Step));
+
+        stepper.resume(Resume::Continue, &at(6, 1));
+        assert_eq!(stepper.stop_reason(&at(7, 1)), None);
+    }
+}
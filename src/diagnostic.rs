@@ -0,0 +1,141 @@
+//! Errors and warnings in a form for tools such as editors to read, each with its stable code
+//! and where in the source it is
+
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+
+use crate::{compiler::{CompileError, CompileWarning}, error_code, json::Json, vm::LastError};
+
+/// How errors and warnings are reported
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiagnosticsFormat {
+    /// Messages for people, with compile errors shown under the source line they're on
+    #[default]
+    Human,
+    /// One JSON object per line for each diagnostic
+    Json
+}
+
+impl FromStr for DiagnosticsFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "human" => Ok(DiagnosticsFormat::Human),
+            "json" => Ok(DiagnosticsFormat::Json),
+            _ => bail!("Unknown diagnostics format '{}', expected human or json", s)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+/// An error or warning with where it is. Lines and columns count from 1, columns in
+/// characters; the span is the bytes of the source it's about, from `start` up to `end`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructuredDiagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub span: Option<(usize, usize)>
+}
+
+impl StructuredDiagnostic {
+    /// `source` is what was compiled, for working out the column
+    pub fn from_compile_error(error: &CompileError, source: &str, file: Option<&str>) -> Self {
+        let (line, column, span) = match error.span() {
+            Some((offset, len)) => {
+                let offset = offset.min(source.len());
+                let (line, column) = line_and_column(source, offset);
+                (Some(line), Some(column), Some((offset, (offset + len).min(source.len()))))
+            },
+            None => (error.line(), None, None)
+        };
+        Self { severity: Severity::Error, code: error.code(), message: error.message(), file: file.map(str::to_string), line, column, span }
+    }
+
+    pub fn from_warning(warning: &CompileWarning, file: Option<&str>) -> Self {
+        Self { severity: Severity::Warning, code: warning.code(), message: warning.message(), file: file.map(str::to_string), line: Some(warning.line()), column: None, span: None }
+    }
+
+    pub fn from_runtime_error(error: &LastError, file: Option<&str>) -> Self {
+        let line = error.line.and_then(|line| usize::try_from(line).ok());
+        Self { severity: Severity::Error, code: error.code, message: error.message.clone(), file: file.map(str::to_string), line, column: None, span: None }
+    }
+
+    /// A failure that isn't the source's fault, such as a bug in the compiler
+    pub fn internal<M: Into<String>>(message: M) -> Self {
+        Self { severity: Severity::Error, code: error_code::INTERNAL_ERROR, message: message.into(), file: None, line: None, column: None, span: None }
+    }
+
+    /// The diagnostic as a JSON object on one line, with null for what isn't known
+    pub fn to_json(&self) -> String {
+        let optional = |n: Option<usize>| n.map_or(Json::Null, |n| Json::from(n as i64));
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning"
+        };
+        let span = match self.span {
+            Some((start, end)) => Json::object([("start", Json::from(start as i64)), ("end", Json::from(end as i64))]),
+            None => Json::Null
+        };
+        Json::object([
+            ("severity", Json::from(severity)),
+            ("code", Json::from(self.code)),
+            ("message", Json::from(self.message.as_str())),
+            ("file", self.file.as_deref().map_or(Json::Null, Json::from)),
+            ("line", optional(self.line)),
+            ("column", optional(self.column)),
+            ("span", span)
+        ]).to_string()
+    }
+}
+
+/// The line and the column in characters, both from 1, of the byte `offset` into `source`
+pub fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = source[..line_start].matches('\n').count() + 1;
+    (line, source[line_start..offset].chars().count() + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{Compiler, CompileErrorCollection};
+
+    #[test]
+    fn compile_errors_are_located_by_line_column_and_span() {
+        let source = "var a = 1;\nprint a +;\n";
+        let err = Compiler::new(source.to_string()).compile().unwrap_err();
+        let errors = &err.downcast_ref::<CompileErrorCollection>().unwrap().errors;
+        let json = Json::parse(&StructuredDiagnostic::from_compile_error(&errors[0], source, Some("a.lox")).to_json()).unwrap();
+
+        assert_eq!(json.get("severity").and_then(Json::as_str), Some("error"));
+        assert_eq!(json.get("code").and_then(Json::as_str), Some("L0202"));
+        assert_eq!(json.get("file").and_then(Json::as_str), Some("a.lox"));
+        assert_eq!(json.get("line").and_then(Json::as_f64), Some(2.0));
+        assert_eq!(json.get("column").and_then(Json::as_f64), Some(10.0));
+        assert_eq!(json.get("span").and_then(|span| span.get("start")).and_then(Json::as_f64), Some(20.0));
+        assert_eq!(json.get("span").and_then(|span| span.get("end")).and_then(Json::as_f64), Some(21.0));
+    }
+
+    #[test]
+    fn what_isnt_known_is_null() {
+        let warning = CompileWarning::UnusedValue { line: 3 };
+        let json = Json::parse(&StructuredDiagnostic::from_warning(&warning, None).to_json()).unwrap();
+
+        assert_eq!(json.get("severity").and_then(Json::as_str), Some("warning"));
+        assert_eq!(json.get("code").and_then(Json::as_str), Some("L0903"));
+        assert_eq!(json.get("line").and_then(Json::as_f64), Some(3.0));
+        assert_eq!(json.get("file"), Some(&Json::Null));
+        assert_eq!(json.get("span"), Some(&Json::Null));
+    }
+}
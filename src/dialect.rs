@@ -0,0 +1,22 @@
+//! Optional changes to the syntax, for users more at home with that of another language.
+//! Everything is off by default, which is plain Lox.
+
+/// Syntax options a source is compiled with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Dialect {
+    /// `not x` for `!x` and `a mod b` for `a % b`, as in Lua and Python. `not` and `mod` are
+    /// then reserved words instead of identifiers.
+    pub word_operators: bool,
+    /// A line break ends a statement, as if there were a semicolon before it, if the line's
+    /// last token can end one, which is to say it's a name, a literal, `this`, `)`, `]`, `++`,
+    /// `--`, `return`, `break` or `continue`. Even then a line break doesn't end a statement
+    /// inside parentheses or brackets, or when the next line starts with `.` or a binary
+    /// operator other than `-`. The end of the source and a `}` end a statement the same way.
+    pub implicit_semicolons: bool,
+    /// A block, and an `if` whose arms are blocks, can be used as an expression, as in
+    /// `var x = if (c) { 1 } else { 2 };`. Its value is that of the block's last expression if
+    /// that isn't followed by a semicolon, and nil otherwise, as it is for an `if` without an
+    /// `else` whose condition is false. A block or `if` starting a statement is still a
+    /// statement. A line break after one doesn't imply a semicolon.
+    pub expression_blocks: bool
+}
@@ -1,19 +1,84 @@
+use std::{collections::HashSet, io::{self, Write}, str::FromStr};
+
 use anyhow::{Result, Context, bail};
 
-use crate::{instruction::{InstructionReader, Instruction, OpCode}, chunk::Chunk};
+use crate::{instruction::{InstructionReader, Instruction, OpCode}, chunk::Chunk, value::Value};
+
+/// How much to show of each instruction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisassemblyFormat {
+    /// The instruction, its operands and the constants they refer to
+    #[default]
+    Compact,
+    /// Also the names of locals, where jumps go with a label at each place jumped to, and
+    /// how many values each instruction adds to or removes from the stack
+    Verbose
+}
+
+impl FromStr for DisassemblyFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "compact" => Ok(DisassemblyFormat::Compact),
+            "verbose" => Ok(DisassemblyFormat::Verbose),
+            _ => bail!("Unknown disassembly format '{}', expected compact or verbose", s)
+        }
+    }
+}
+
+/// The disassembly of one function, for telling what changed between compiles
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionListing {
+    pub name: String,
+    /// Each instruction with its offset, without the source line so that code moving to
+    /// another line doesn't count as a change
+    pub lines: Vec<String>
+}
 
 pub struct Disassembler {
-    prev_src_line_number: Option<i32>
+    /// Where the disassembly is written, stdout unless set with `with_output`
+    out: Box<dyn Write>,
+    prev_src_line_number: Option<i32>,
+    /// Lines of the source the code was compiled from, shown above the instructions of each line
+    source_lines: Option<Vec<String>>,
+    format: DisassemblyFormat,
+    /// Offsets jumped to in the chunk being disassembled, labelled in the verbose format
+    jump_targets: HashSet<usize>
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Disassembler {
     pub fn new() -> Self {
-        Self { prev_src_line_number: None }
+        Self { out: Box::new(io::stdout()), prev_src_line_number: None, source_lines: None, format: DisassemblyFormat::default(), jump_targets: HashSet::new() }
+    }
+
+    /// Writes the disassembly to `out` instead of stdout
+    pub fn with_output(self, out: Box<dyn Write>) -> Self {
+        Self { out, ..self }
+    }
+
+    /// Interleaves the text of each source line with the instructions generated from it
+    pub fn with_source(self, source: &str) -> Self {
+        Self { source_lines: Some(source.lines().map(str::to_string).collect()), ..self }
+    }
+
+    pub fn with_format(self, format: DisassemblyFormat) -> Self {
+        Self { format, ..self }
     }
 
     pub fn disassemble(&mut self, chunk: &Chunk, name: &str) -> Result<()> {
-        println!("== {} ==", name);
+        writeln!(self.out, "== {} ==", name)?;
 
+        self.jump_targets = match self.format {
+            DisassemblyFormat::Compact => HashSet::new(),
+            DisassemblyFormat::Verbose => Self::jump_targets(chunk)?
+        };
         let mut reader = InstructionReader::new(chunk);
 
         loop {
@@ -26,54 +91,299 @@ impl Disassembler {
             }
         }
 
+        // Functions declared in this chunk carry their own chunks
+        for constant in chunk.constants() {
+            if let Value::Function(function) = constant {
+                writeln!(self.out)?;
+                self.prev_src_line_number = None;
+                self.disassemble(&function.chunk, &function.to_string())?;
+            }
+        }
+
         Ok(())
     }
 
     pub fn disassemble_instruction<'a>(&mut self, reader: &mut InstructionReader<'a>, instruction: &Instruction, offset: usize, src_line_number: i32) -> Result<()> {
-        print!("{:04} ", offset);
-
         let same_src_line_no_as_prev = self.prev_src_line_number.is_some() && src_line_number == self.prev_src_line_number.unwrap();
+        if !same_src_line_no_as_prev {
+            if let Some(text) = self.source_line(src_line_number).map(|text| text.trim().to_string()) {
+                writeln!(self.out, "          // {}", text)?;
+            }
+        }
+        if self.jump_targets.contains(&offset) {
+            writeln!(self.out, "L{:04}:", offset)?;
+        }
+
+        write!(self.out, "{:04} ", offset)?;
+
         if same_src_line_no_as_prev {
-            print!("   | ");
+            write!(self.out, "   | ")?;
         } else {
-            print!("{:4} ", src_line_number);
+            write!(self.out, "{:4} ", src_line_number)?;
         }
 
         self.prev_src_line_number = Some(src_line_number);
 
-        match &instruction.op_code {
-            OpCode::Constant | OpCode::DefineGlobal 
+        let (text, captures) = self.instruction_text(reader, instruction, offset)?;
+        if self.format == DisassemblyFormat::Verbose {
+            writeln!(self.out, "{:<40} ; stack {:+}", text, instruction.stack_effect())?;
+        } else {
+            writeln!(self.out, "{}", text)?;
+        }
+        for line in captures {
+            writeln!(self.out, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Listings of the chunk's code, named `name`, and of every function declared in it
+    pub fn listings(&self, chunk: &Chunk, name: &str) -> Result<Vec<FunctionListing>> {
+        let mut lines = Vec::new();
+        let mut reader = InstructionReader::new(chunk);
+        while let Some((instruction, offset, _)) = reader.read_next().context("Failed to disassemble instruction")? {
+            let (text, captures) = self.instruction_text(&reader, &instruction, offset)?;
+            lines.push(format!("{:04} {}", offset, text));
+            lines.extend(captures);
+        }
+
+        let mut listings = vec![FunctionListing { name: name.to_string(), lines }];
+        for constant in chunk.constants() {
+            if let Value::Function(function) = constant {
+                listings.extend(self.listings(&function.chunk, &function.to_string())?);
+            }
+        }
+        Ok(listings)
+    }
+
+    /// The instruction and its operands as shown after its offset and line, and the lines to
+    /// show under it about the upvalues a closure captures
+    pub fn instruction_text(&self, reader: &InstructionReader, instruction: &Instruction, offset: usize) -> Result<(String, Vec<String>)> {
+        let verbose = self.format == DisassemblyFormat::Verbose;
+        let mut captures = Vec::new();
+        let text = match &instruction.op_code {
+            OpCode::Constant | OpCode::DefineGlobal | OpCode::DefineConstGlobal | OpCode::Import
             | OpCode::GetGlobal | OpCode::SetGlobal
-            | OpCode::GetLocal | OpCode::SetLocal => {
+            | OpCode::GetLocal | OpCode::SetLocal | OpCode::GetLocalAdd | OpCode::Closure
+            | OpCode::Class | OpCode::GetProperty | OpCode::SetProperty | OpCode::Method | OpCode::Getter
+            | OpCode::GetSuper => {
                 match instruction.operand1 {
                     Some(operand1) => {
-                        print!("{} {:04}", instruction.op_code, operand1);
-
-                        match &instruction.op_code {
-                            OpCode::GetLocal | OpCode::SetLocal => {
-                                let stack_offset = format!("Stack[{}]", operand1);
-                                println!(" '{}'", stack_offset)
+                        let described = match &instruction.op_code {
+                            OpCode::GetLocal | OpCode::SetLocal | OpCode::GetLocalAdd => {
+                                match reader.chunk().local_name(operand1 as usize, offset).filter(|_| verbose) {
+                                    Some(name) => format!("'{}'", name),
+                                    None => format!("'Stack[{}]'", operand1)
+                                }
                             }
-                            _ => {
-                                let value = reader.get_const(operand1 as usize)?;
-                                println!(" '{}'", value)
-                            }
-                        }
+                            _ => Self::constant_text(reader, operand1 as usize, offset, &mut captures)?
+                        };
+                        format!("{} {:04} {}", instruction.op_code, operand1, described)
                     }
                     _ => bail!("Opcode {} has no operand", instruction.op_code),
                 }
             },
-            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            OpCode::ConstantLong | OpCode::DefineGlobalLong | OpCode::DefineConstGlobalLong | OpCode::ImportLong
+            | OpCode::GetGlobalLong | OpCode::SetGlobalLong | OpCode::ClosureLong
+            | OpCode::ClassLong | OpCode::GetPropertyLong | OpCode::SetPropertyLong | OpCode::MethodLong | OpCode::GetterLong
+            | OpCode::GetSuperLong => {
+                match instruction.long_operand() {
+                    Some(index) => format!("{} {:04} {}", instruction.op_code, index, Self::constant_text(reader, index, offset, &mut captures)?),
+                    _ => bail!("Opcode {} has missing operands", instruction.op_code),
+                }
+            },
+            OpCode::GetLocalLong | OpCode::SetLocalLong => {
+                match instruction.local_slot() {
+                    Some(slot) => match reader.chunk().local_name(slot, offset).filter(|_| verbose) {
+                        Some(name) => format!("{} {:04} '{}'", instruction.op_code, slot, name),
+                        None => format!("{} {:04} 'Stack[{}]'", instruction.op_code, slot, slot)
+                    },
+                    _ => bail!("Opcode {} has missing operands", instruction.op_code),
+                }
+            },
+            OpCode::LoopLong | OpCode::JumpLong => {
+                match (instruction.long_operand(), instruction.jump_target(offset)) {
+                    (Some(distance), Some(target)) if verbose => format!("{} {:04} -> L{:04}", instruction.op_code, distance, target),
+                    (Some(distance), _) => format!("{} {:04}", instruction.op_code, distance),
+                    _ => bail!("Opcode {} has missing operands", instruction.op_code),
+                }
+            },
+            OpCode::Call if verbose => {
+                match instruction.operand1 {
+                    Some(operand1) => format!("{} {:04} ({} args)", instruction.op_code, operand1, operand1),
+                    _ => bail!("Opcode {} has no operand", instruction.op_code),
+                }
+            },
+            OpCode::Call | OpCode::GetUpvalue | OpCode::SetUpvalue | OpCode::BuildList => {
+                match instruction.operand1 {
+                    Some(operand1) => format!("{} {:04}", instruction.op_code, operand1),
+                    _ => bail!("Opcode {} has no operand", instruction.op_code),
+                }
+            },
+            OpCode::Invoke | OpCode::SuperInvoke | OpCode::ConstantCall => {
                 match (instruction.operand1, instruction.operand2) {
-                    (Some(operand1), Some(operand2)) => {
-                        println!("{} {:04} {:04}", instruction.op_code, operand1, operand2);
+                    // The method name for an invoke, and the last argument for a constant call
+                    (Some(const_index), Some(arg_count)) => {
+                        let value = reader.get_const(const_index as usize)?;
+                        format!("{} ({} args) {:04} '{}'", instruction.op_code, arg_count, const_index, value)
+                    }
+                    _ => bail!("Opcode {} has one or both operands missing", instruction.op_code),
+                }
+            },
+            OpCode::InvokeLong | OpCode::SuperInvokeLong => {
+                match (instruction.long_operand(), instruction.operand4) {
+                    (Some(const_index), Some(arg_count)) => {
+                        let value = reader.get_const(const_index)?;
+                        format!("{} ({} args) {:04} '{}'", instruction.op_code, arg_count, const_index, value)
                     }
+                    _ => bail!("Opcode {} has missing operands", instruction.op_code),
+                }
+            },
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::PushHandler | OpCode::LessJumpIfFalse => {
+                match (instruction.operand1, instruction.operand2, instruction.jump_target(offset)) {
+                    (Some(operand1), Some(operand2), Some(target)) if verbose => {
+                        format!("{} {:04} {:04} -> L{:04}", instruction.op_code, operand1, operand2, target)
+                    }
+                    (Some(operand1), Some(operand2), _) => format!("{} {:04} {:04}", instruction.op_code, operand1, operand2),
                     _ => bail!("Opcode {} has one or both operands missing", instruction.op_code),
                 }
             },
-            op_code => println!("{}", op_code)
+            op_code => op_code.to_string()
         };
 
-        Ok(())
+        Ok((text, captures))
     }
-}
\ No newline at end of file
+
+    /// The constant at `index` as shown after the instruction at `offset` referring to it,
+    /// adding the lines about the upvalues a function captures to `captures`
+    fn constant_text(reader: &InstructionReader, index: usize, offset: usize, captures: &mut Vec<String>) -> Result<String> {
+        let value = reader.get_const(index)?;
+        if let Value::Function(function) = &value {
+            for upvalue in &function.upvalues {
+                let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                captures.push(format!("{:04}    |   captures {} {}", offset, kind, upvalue.index));
+            }
+        }
+        Ok(format!("'{}'", value))
+    }
+
+    /// Every offset of the chunk that a jump, loop or handler goes to
+    fn jump_targets(chunk: &Chunk) -> Result<HashSet<usize>> {
+        let mut targets = HashSet::new();
+        let mut reader = InstructionReader::new(chunk);
+        while let Some((instruction, offset, _)) = reader.read_next().context("Failed to disassemble instruction")? {
+            targets.extend(instruction.jump_target(offset));
+        }
+        Ok(targets)
+    }
+
+    fn source_line(&self, src_line_number: i32) -> Option<&str> {
+        let index = usize::try_from(src_line_number).ok()?.checked_sub(1)?;
+        self.source_lines.as_ref()?.get(index).map(String::as_str)
+    }
+}
+/// What changed from one compile's listings to the next: the lines removed and added in each
+/// function whose code differs, every line of new functions, and the names of those gone.
+/// Functions are matched up by name, and by order among those of the same name.
+pub fn listing_diff(old: &[FunctionListing], new: &[FunctionListing]) -> String {
+    let keyed = |listings: &[FunctionListing]| -> Vec<(String, usize)> {
+        listings.iter().enumerate()
+            .map(|(i, listing)| (listing.name.clone(), listings[..i].iter().filter(|other| other.name == listing.name).count()))
+            .collect()
+    };
+    let (old_keys, new_keys) = (keyed(old), keyed(new));
+
+    let mut diff = String::new();
+    for (listing, key) in new.iter().zip(&new_keys) {
+        let old_lines = old_keys.iter().position(|old_key| old_key == key).map_or(&[][..], |i| &old[i].lines[..]);
+        if old_lines != listing.lines.as_slice() {
+            diff.push_str(&format!("== {} ==\n", listing.name));
+            for line in line_diff(old_lines, &listing.lines) {
+                diff.push_str(&line);
+                diff.push('\n');
+            }
+        }
+    }
+    for (listing, key) in old.iter().zip(&old_keys) {
+        if !new_keys.contains(key) {
+            diff.push_str(&format!("== {} == removed\n", listing.name));
+        }
+    }
+    diff
+}
+
+/// The lines only in `old` marked with '-' and those only in `new` with '+', in order,
+/// leaving out those in the longest sequence common to both
+fn line_diff(old: &[String], new: &[String]) -> Vec<String> {
+    // common[i][j] is the length of the longest common sequence of old[i..] and new[j..]
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] { common[i + 1][j + 1] + 1 } else { common[i + 1][j].max(common[i][j + 1]) };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::output::OutputBuffer;
+
+    #[test]
+    fn formats_parse_by_name() {
+        assert_eq!("verbose".parse::<DisassemblyFormat>().unwrap(), DisassemblyFormat::Verbose);
+        assert_eq!("compact".parse::<DisassemblyFormat>().unwrap(), DisassemblyFormat::Compact);
+        assert!("wide".parse::<DisassemblyFormat>().is_err());
+    }
+
+    #[test]
+    fn disassembly_goes_to_the_output_given() {
+        let out = OutputBuffer::new();
+        let chunk = Compiler::new("print 1;".to_string()).compile().unwrap();
+        Disassembler::new().with_output(out.writer()).disassemble(&chunk, "Chunk").unwrap();
+        assert_eq!(out.text(), "== Chunk ==\n0000    1 Constant 0000 '1'\n0002    | Print\n0003    | Nil\n0004    | Return\n");
+    }
+
+    #[test]
+    fn jump_targets_include_loop_starts_and_exits() {
+        let chunk = Compiler::new("var i = 0; while (i < 2) i = i + 1;".to_string()).compile().unwrap();
+        let targets = Disassembler::jump_targets(&chunk).unwrap();
+        assert_eq!(targets.len(), 2);
+        assert!(targets.iter().all(|target| *target < chunk.len()));
+    }
+
+    fn listings(source: &str) -> Vec<FunctionListing> {
+        let chunk = Compiler::new(source.to_string()).compile().unwrap();
+        Disassembler::new().listings(&chunk, "Chunk").unwrap()
+    }
+
+    #[test]
+    fn listing_diff_shows_only_functions_whose_code_changed() {
+        let old = listings("fun f() { return 1 + 2; }\nfun g() { return 3; }\nfun h() {}");
+        // g moves down a line without changing, f's addition becomes a subtraction and h goes
+        let new = listings("fun f() { return 1 - 2; }\n\nfun g() { return 3; }");
+
+        let diff = listing_diff(&old, &new);
+        assert!(diff.contains("== <fn f> ==\n- 0004 Add\n+ 0004 Subtract\n"), "{}", diff);
+        assert!(!diff.contains("<fn g>"), "{}", diff);
+        assert!(diff.contains("== <fn h> == removed"), "{}", diff);
+        assert_eq!(listing_diff(&new, &new), "");
+    }
+}
@@ -0,0 +1,176 @@
+//! Checks that every instruction comes back unchanged from each form it's kept in: written
+//! to a chunk and read back, disassembled and assembled again, and serialized. Any opcode
+//! added without being taught to all of them fails here rather than in some later run.
+
+use std::rc::Rc;
+
+use anyhow::{Result, bail, anyhow};
+
+use crate::{chunk::Chunk, disassembler::Disassembler, function::Function, instruction::{Instruction, InstructionReader, InstructionWriter, OpCode}, serialize::{ValueReader, ValueWriter}, value::Value};
+
+/// Every opcode, checking that they're numbered without gaps and named uniquely
+fn all_op_codes() -> Vec<OpCode> {
+    let op_codes: Vec<OpCode> = (0..=u8::MAX).map_while(|byte| OpCode::try_from(byte).ok()).collect();
+    assert!((op_codes.len()..=u8::MAX as usize).all(|byte| OpCode::try_from(byte as u8).is_err()), "Opcodes aren't numbered contiguously");
+    for (i, op_code) in op_codes.iter().enumerate() {
+        assert!(op_codes[..i].iter().all(|other| other.to_string() != op_code.to_string()), "Opcode name {} is used twice", op_code);
+    }
+    op_codes
+}
+
+fn encoding(instruction: &Instruction) -> (u8, Option<u8>, Option<u8>, Option<u8>, Option<u8>) {
+    (instruction.op_code.clone() as u8, instruction.operand1, instruction.operand2, instruction.operand3, instruction.operand4)
+}
+
+/// The instruction shown by the compact disassembly `text`, such as `GetLocal 0003 'Stack[3]'`,
+/// `Invoke (2 args) 0001 'm'`, `ConstantLong 1000 '3'` or `InvokeLong (2 args) 1000 '3'`
+fn assemble(text: &str) -> Result<Instruction> {
+    let (name, rest) = text.split_once(' ').unwrap_or((text, ""));
+    let op_code = all_op_codes().into_iter().find(|op_code| op_code.to_string() == name)
+        .ok_or_else(|| anyhow!("Unknown opcode {}", name))?;
+
+    // The argument count of an invoke comes first but is its second operand
+    let (arg_count, rest) = match rest.strip_prefix('(').and_then(|rest| rest.split_once(" args) ")) {
+        Some((arg_count, rest)) => (Some(arg_count.parse::<u8>()?), rest),
+        None => (None, rest)
+    };
+    let numbers: Vec<u32> = rest.split(' ')
+        .take_while(|word| !word.is_empty() && word.chars().all(|c| c.is_ascii_digit()))
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+    // A three byte operand is shown as one number
+    let mut operands: Vec<u8> = match (op_code.operand_count(), numbers.as_slice()) {
+        (3, [long_operand]) | (4, [long_operand]) => long_operand.to_be_bytes()[1..].to_vec(),
+        _ => numbers.into_iter().map(u8::try_from).collect::<Result<_, _>>()?
+    };
+    operands.extend(arg_count);
+
+    if operands.len() != op_code.operand_count() {
+        bail!("{} takes {} operands, got {} in '{}'", op_code, op_code.operand_count(), operands.len(), text);
+    }
+    Ok(Instruction::with_operands(op_code, &operands))
+}
+
+fn write(writer: &mut InstructionWriter, instruction: &Instruction) {
+    match (instruction.long_operand(), instruction.operand4) {
+        (Some(long_operand), Some(count)) => {
+            writer.write_op_code_with_long_operand_and_count(instruction.op_code.clone(), long_operand, count, 1);
+            return;
+        },
+        (Some(long_operand), None) => {
+            writer.write_op_code_with_long_operand(instruction.op_code.clone(), long_operand, 1);
+            return;
+        },
+        _ => {}
+    }
+    match (instruction.operand1, instruction.operand2) {
+        (Some(operand1), Some(operand2)) => writer.write_op_code_with_operands(instruction.op_code.clone(), operand1, operand2, 1),
+        (Some(operand1), None) => writer.write_op_code_with_operand(instruction.op_code.clone(), operand1, 1),
+        _ => writer.write_op_code(instruction.op_code.clone(), 1)
+    };
+}
+
+/// A chunk of the instructions with a constant for every index a sampled operand can hold
+fn chunk_of(instructions: &[Instruction]) -> Chunk {
+    let mut chunk = Chunk::new();
+    for i in 0..=u16::MAX {
+        chunk.add_constant(Value::Number(i as f64));
+    }
+    let mut writer = InstructionWriter::new(chunk);
+    for instruction in instructions {
+        write(&mut writer, instruction);
+    }
+    writer.into_chunk()
+}
+
+/// Each opcode with random operands, plus the smallest and largest they can be
+fn sample_instructions() -> Vec<Instruction> {
+    // xorshift, seeded so that a failure can be reproduced
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut random_byte = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 56) as u8
+    };
+
+    let mut instructions = Vec::new();
+    for op_code in all_op_codes() {
+        let mut operand_sets = vec![(0, 0), (u8::MAX, u8::MAX)];
+        operand_sets.extend((0..16).map(|_| (random_byte(), random_byte())));
+        for (operand1, operand2) in operand_sets {
+            instructions.push(match op_code.operand_count() {
+                0 => Instruction::simple(op_code.clone()),
+                1 => Instruction::unary(op_code.clone(), operand1),
+                2 => Instruction::binary(op_code.clone(), operand1, operand2),
+                // Kept to indexes of constants the chunk has
+                3 => Instruction::ternary(op_code.clone(), 0, operand1, operand2),
+                _ => Instruction::with_operands(op_code.clone(), &[0, operand1, operand2, operand1 ^ operand2])
+            });
+        }
+    }
+    instructions
+}
+
+#[test]
+fn instructions_read_back_as_written() {
+    let instructions = sample_instructions();
+    let chunk = chunk_of(&instructions);
+
+    let mut reader = InstructionReader::new(&chunk);
+    let mut expected_offset = 0;
+    for expected in &instructions {
+        let (instruction, offset, _) = reader.read_next().unwrap().unwrap();
+        assert_eq!(encoding(&instruction), encoding(expected));
+        assert_eq!(offset, expected_offset, "{} starts at the wrong offset", instruction.op_code);
+        expected_offset = instruction.next_offset(offset);
+        // Every instruction has a stack effect, and only jumps have targets
+        instruction.stack_effect();
+        let is_jump = matches!(instruction.op_code, OpCode::Jump | OpCode::JumpIfFalse | OpCode::PushHandler | OpCode::JumpLong | OpCode::LessJumpIfFalse);
+        if is_jump {
+            assert!(instruction.jump_target(offset).is_some());
+        } else if !matches!(instruction.op_code, OpCode::Loop | OpCode::LoopLong) {
+            assert!(instruction.jump_target(offset).is_none());
+        }
+    }
+    assert!(reader.read_next().unwrap().is_none());
+    assert_eq!(expected_offset, chunk.len());
+}
+
+#[test]
+fn disassembly_assembles_to_the_same_code() {
+    let instructions = sample_instructions();
+    let chunk = chunk_of(&instructions);
+    let disassembler = Disassembler::new();
+
+    let mut reader = InstructionReader::new(&chunk);
+    let mut assembled = Vec::new();
+    while let Some((instruction, offset, _)) = reader.read_next().unwrap() {
+        let (text, _) = disassembler.instruction_text(&reader, &instruction, offset).unwrap();
+        let instruction_again = assemble(&text).unwrap_or_else(|e| panic!("Can't assemble '{}': {}", text, e));
+        assert_eq!(encoding(&instruction_again), encoding(&instruction), "'{}' assembles to something else", text);
+        assembled.push(instruction_again);
+    }
+
+    assert_eq!(chunk_of(&assembled).code(), chunk.code());
+}
+
+#[test]
+fn serialized_code_is_unchanged() {
+    let chunk = chunk_of(&sample_instructions());
+    let function = Value::Function(Rc::new(Function::script(chunk.clone())));
+
+    let mut writer = ValueWriter::new();
+    let mut roots = Vec::new();
+    writer.write_value(&mut roots, &function).unwrap();
+    let data = writer.finish(&roots).unwrap();
+    let no_natives = |_: &str| None;
+    let copy = match ValueReader::new(&data, &no_natives).unwrap().read_value().unwrap() {
+        Value::Function(function) => function,
+        other => panic!("Expected a function, got {}", other)
+    };
+
+    assert_eq!(copy.chunk.code(), chunk.code());
+    assert_eq!(copy.chunk.src_line_numbers(), chunk.src_line_numbers());
+    assert_eq!(copy.chunk.constants(), chunk.constants());
+}
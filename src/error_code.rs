@@ -0,0 +1,93 @@
+//! Stable codes for compile errors, runtime errors and warnings, for tools to tell them apart
+//! by instead of by their messages, whose wording may change. A code is never given to a
+//! different kind of error once released.
+//!
+//! Codes from L01 are for scanning, L02 syntax, L03 declarations, L04 limits of the bytecode,
+//! L05 statements used where they can't be, L06 compilation as a whole, L07 runtime errors,
+//! L08 runs ended by something other than the script, and L09 warnings.
+
+use thiserror::Error;
+
+pub const UNEXPECTED_CHARACTER: &str = "L0101";
+pub const UNTERMINATED_STRING: &str = "L0102";
+pub const UNTERMINATED_BYTES: &str = "L0103";
+pub const MALFORMED_NUMBER: &str = "L0104";
+
+pub const SYNTAX_ERROR: &str = "L0200";
+pub const EXPECTED_TOKEN: &str = "L0201";
+pub const EXPECTED_EXPRESSION: &str = "L0202";
+pub const INVALID_ASSIGNMENT_TARGET: &str = "L0203";
+pub const MISPLACED_CASE: &str = "L0204";
+pub const MALFORMED_BYTES: &str = "L0205";
+
+pub const DUPLICATE_PARAMETER: &str = "L0301";
+pub const REDECLARED_LOCAL: &str = "L0302";
+pub const ASSIGNMENT_TO_CONSTANT: &str = "L0303";
+pub const UNINITIALIZED_LOCAL: &str = "L0304";
+pub const DUPLICATE_EXPORT: &str = "L0305";
+pub const SELF_INHERITANCE: &str = "L0306";
+pub const GETTER_INITIALIZER: &str = "L0307";
+pub const MISPLACED_REST_PARAMETER: &str = "L0308";
+pub const MISPLACED_EXPORT: &str = "L0309";
+
+pub const TOO_MANY_PARAMETERS: &str = "L0401";
+pub const TOO_MANY_ARGUMENTS: &str = "L0402";
+pub const TOO_MANY_LIST_ITEMS: &str = "L0403";
+pub const TOO_MANY_CONSTANTS: &str = "L0404";
+pub const TOO_MANY_LOCALS: &str = "L0405";
+pub const TOO_MANY_UPVALUES: &str = "L0406";
+pub const JUMP_TOO_FAR: &str = "L0407";
+pub const NUMBER_TOO_LARGE: &str = "L0408";
+
+pub const RETURN_FROM_TOP_LEVEL: &str = "L0501";
+pub const RETURN_FROM_INITIALIZER: &str = "L0502";
+pub const BREAK_OUTSIDE_LOOP: &str = "L0503";
+pub const CONTINUE_OUTSIDE_LOOP: &str = "L0504";
+pub const THIS_OUTSIDE_CLASS: &str = "L0505";
+pub const SUPER_OUTSIDE_CLASS: &str = "L0506";
+pub const SUPER_WITHOUT_SUPERCLASS: &str = "L0507";
+
+pub const TOO_MANY_ERRORS: &str = "L0601";
+
+pub const RUNTIME_ERROR: &str = "L0700";
+pub const INVALID_OPERAND: &str = "L0701";
+pub const DIVISION_BY_ZERO: &str = "L0702";
+pub const UNDEFINED_VARIABLE: &str = "L0703";
+pub const UNDEFINED_PROPERTY: &str = "L0704";
+pub const WRONG_ARGUMENT_COUNT: &str = "L0705";
+pub const NOT_CALLABLE: &str = "L0706";
+pub const STACK_OVERFLOW: &str = "L0707";
+pub const CONSTANT_REASSIGNED: &str = "L0708";
+pub const NOT_AN_INSTANCE: &str = "L0709";
+pub const INVALID_SUPERCLASS: &str = "L0710";
+pub const SIZE_LIMIT_EXCEEDED: &str = "L0711";
+pub const IMPORT_FAILED: &str = "L0712";
+pub const ASSERTION_FAILED: &str = "L0713";
+
+pub const UNCAUGHT_EXCEPTION: &str = "L0800";
+pub const WATCHPOINT_HIT: &str = "L0801";
+pub const STOPPED: &str = "L0802";
+pub const BUDGET_EXCEEDED: &str = "L0803";
+pub const CANCELLED: &str = "L0804";
+pub const TIMED_OUT: &str = "L0805";
+pub const INTERNAL_ERROR: &str = "L0899";
+
+pub const UNREACHABLE_CODE: &str = "L0901";
+pub const UNUSED_VARIABLE: &str = "L0902";
+pub const UNUSED_VALUE: &str = "L0903";
+pub const MISSPELT_VARIABLE: &str = "L0904";
+
+/// An error raised where it isn't known which part of the source it's about, carrying its code
+/// to wherever that's worked out
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct CodedError {
+    pub code: &'static str,
+    pub message: String
+}
+
+impl CodedError {
+    pub fn new<M: Into<String>>(code: &'static str, message: M) -> Self {
+        Self { code, message: message.into() }
+    }
+}
@@ -0,0 +1,32 @@
+//! `compile(source)` and `run(script)`, for scripts that build and run code at runtime.
+//! Only defined when the host enables `VmOptions::allow_dynamic_code`.
+
+use std::rc::Rc;
+
+use anyhow::{Result, bail};
+
+use crate::{compiler::Compiler, function::Function, native::Args, value::Value, vm::Vm};
+
+pub fn define_natives(vm: &mut Vm) {
+    vm.define_native("compile", 1, Rc::new(compile));
+    vm.define_native("run", 1, Rc::new(run));
+}
+
+/// `compile(source)`: compiles source into a script that `run` executes. Compile errors are runtime errors.
+fn compile(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let source = Args::new("compile", args).get_string(0)?.to_string();
+
+    match Compiler::new(source).with_dialect(vm.dialect()).with_constant_pool(vm.constant_pool()).compile() {
+        Ok(chunk) => Ok(Value::Function(Rc::new(Function::script(chunk)))),
+        Err(e) => bail!("Failed to compile: {}", e.to_string().trim_end())
+    }
+}
+
+/// `run(script)`: runs a script from `compile` with the same globals as the caller, returning
+/// the value of its final expression statement
+fn run(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::Function(script)] if script.name.is_none() => vm.run_nested(script.clone()),
+        _ => bail!("run expects a script returned by compile")
+    }
+}
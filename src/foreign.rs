@@ -0,0 +1,149 @@
+use std::{any::Any, fmt::{Debug, Display}, hash::{Hash, Hasher}, rc::Rc};
+
+/// A host object exposed to Lox. Equality, hashing and display default to the object's
+/// identity; override them so values that mean the same thing compare equal in `==` and
+/// as map or set keys.
+pub trait ForeignObject: Any + Debug {
+    /// Name used when displaying the object and in error messages
+    fn type_name(&self) -> &str;
+
+    fn as_any(&self) -> &dyn Any;
+
+    /// Whether this equals another foreign object, or None to compare by identity
+    fn equals(&self, _other: &dyn ForeignObject) -> Option<bool> {
+        None
+    }
+
+    /// Hash consistent with `equals`, or None to hash by identity.
+    /// Must be overridden whenever `equals` is.
+    fn hash_code(&self) -> Option<u64> {
+        None
+    }
+
+    /// How `print` shows the object
+    fn fmt_display(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{}>", self.type_name())
+    }
+}
+
+/// A foreign object as held by a `Value`
+#[derive(Clone)]
+pub struct Foreign {
+    object: Rc<dyn ForeignObject>
+}
+
+impl Foreign {
+    pub fn new<T: ForeignObject>(object: T) -> Self {
+        Self { object: Rc::new(object) }
+    }
+
+    pub fn object(&self) -> &dyn ForeignObject {
+        self.object.as_ref()
+    }
+
+    /// The wrapped object if it's a `T`
+    pub fn downcast_ref<T: ForeignObject>(&self) -> Option<&T> {
+        self.object.as_any().downcast_ref::<T>()
+    }
+
+    fn address(&self) -> usize {
+        Rc::as_ptr(&self.object) as *const () as usize
+    }
+}
+
+impl PartialEq for Foreign {
+    fn eq(&self, other: &Self) -> bool {
+        self.object.equals(other.object.as_ref()).unwrap_or_else(|| self.address() == other.address())
+    }
+}
+
+impl PartialOrd for Foreign {
+    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
+        None
+    }
+}
+
+impl Hash for Foreign {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.object.hash_code() {
+            Some(code) => code.hash(state),
+            None => self.address().hash(state)
+        }
+    }
+}
+
+impl Debug for Foreign {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.object.fmt(f)
+    }
+}
+
+impl Display for Foreign {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.object.fmt_display(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Point { x: i64, y: i64 }
+
+    impl ForeignObject for Point {
+        fn type_name(&self) -> &str { "Point" }
+        fn as_any(&self) -> &dyn Any { self }
+
+        fn equals(&self, other: &dyn ForeignObject) -> Option<bool> {
+            Some(other.as_any().downcast_ref::<Point>().is_some_and(|p| p.x == self.x && p.y == self.y))
+        }
+
+        fn hash_code(&self) -> Option<u64> {
+            Some((self.x as u64) << 32 ^ self.y as u64)
+        }
+
+        fn fmt_display(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Point({}, {})", self.x, self.y)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Handle;
+
+    impl ForeignObject for Handle {
+        fn type_name(&self) -> &str { "Handle" }
+        fn as_any(&self) -> &dyn Any { self }
+    }
+
+    fn hash(foreign: &Foreign) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        foreign.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn hooks_decide_equality_hash_and_display() {
+        let a = Foreign::new(Point { x: 1, y: 2 });
+        let b = Foreign::new(Point { x: 1, y: 2 });
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        assert_ne!(a, Foreign::new(Point { x: 2, y: 1 }));
+        assert_ne!(a, Foreign::new(Handle));
+        assert_eq!(a.to_string(), "Point(1, 2)");
+        assert_eq!(a.downcast_ref::<Point>().map(|p| p.y), Some(2));
+    }
+
+    #[test]
+    fn defaults_use_identity() {
+        let a = Foreign::new(Handle);
+        let b = Foreign::new(Handle);
+        assert_eq!(a, a.clone());
+        assert_eq!(hash(&a), hash(&a.clone()));
+        assert_ne!(a, b);
+        assert_eq!(a.to_string(), "<Handle>");
+        assert!(a.downcast_ref::<Point>().is_none());
+    }
+}
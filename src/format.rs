@@ -0,0 +1,169 @@
+//! `format` and `printf`, which put values into a string at `{}` placeholders. A placeholder
+//! can give a width and precision after a colon, as Rust's do: `{:8}`, `{:.2}`, `{:>8.2}`,
+//! `{:*^10}` or `{:08.3}`. Numbers are aligned right and other values left unless an
+//! alignment of `<`, `^` or `>` says otherwise. The precision is the number of decimals a
+//! number is shown with, and the most characters shown of anything else. `{{` and `}}` stand
+//! for `{` and `}`.
+
+use std::{iter::Peekable, rc::Rc, str::Chars};
+
+use anyhow::{Result, bail};
+
+use crate::{native::Args, value::Value, vm::Vm};
+
+pub fn define_natives(vm: &mut Vm) {
+    vm.define_variadic_native("format", 1, Rc::new(|_, args| Ok(Value::String(format("format", args)?))));
+    vm.define_variadic_native("printf", 1, Rc::new(printf));
+}
+
+/// `printf(fmt, ...)`: prints the formatted values as `print` would
+fn printf(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let text = format("printf", args)?;
+    vm.print(&text)?;
+    Ok(Value::Nil)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Center,
+    Right
+}
+
+#[derive(Debug, Default)]
+struct Spec {
+    fill: Option<char>,
+    align: Option<Align>,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>
+}
+
+/// The format string in the first argument with the rest of the arguments put into its placeholders
+fn format(native: &str, args: &[Value]) -> Result<String> {
+    let format = Args::new(native, args).get_string(0)?;
+    let values = &args[1..];
+    let mut out = String::new();
+    let mut used = 0;
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            },
+            '{' => {
+                let spec = placeholder(native, &mut chars)?;
+                let value = match values.get(used) {
+                    Some(value) => value,
+                    None => bail!("{} has more placeholders than the {} values given", native, values.len())
+                };
+                used += 1;
+                out.push_str(&render(native, value, &spec)?);
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            },
+            '}' => bail!("{} found a '}}' outside a placeholder, which is written '}}}}'", native),
+            c => out.push(c)
+        }
+    }
+
+    if used < values.len() {
+        bail!("{} was given {} values for {} placeholders", native, values.len(), used);
+    }
+    Ok(out)
+}
+
+/// Reads what's in a placeholder after the `{`, up to and including the `}`
+fn placeholder(native: &str, chars: &mut Peekable<Chars>) -> Result<Spec> {
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(c) => text.push(c),
+            None => bail!("{} has an unclosed placeholder '{{{}'", native, text)
+        }
+    }
+
+    let spec = match text.strip_prefix(':') {
+        Some(spec) => spec,
+        None if text.is_empty() => return Ok(Spec::default()),
+        None => bail!("{} has an invalid placeholder '{{{}}}'", native, text)
+    };
+    parse_spec(spec).ok_or_else(|| anyhow::anyhow!("{} has an invalid placeholder '{{{}}}'", native, text))
+}
+
+fn parse_spec(spec: &str) -> Option<Spec> {
+    let align_of = |c| match c {
+        '<' => Some(Align::Left),
+        '^' => Some(Align::Center),
+        '>' => Some(Align::Right),
+        _ => None
+    };
+
+    let mut result = Spec::default();
+    let mut rest = spec;
+    let mut chars = spec.chars();
+    match (chars.next(), chars.next().and_then(align_of)) {
+        (Some(fill), Some(align)) => {
+            result.fill = Some(fill);
+            result.align = Some(align);
+            rest = &rest[fill.len_utf8() + 1..];
+        },
+        (Some(c), _) if align_of(c).is_some() => {
+            result.align = align_of(c);
+            rest = &rest[1..];
+        },
+        _ => {}
+    }
+
+    if let Some(after_zero) = rest.strip_prefix('0') {
+        result.zero = true;
+        rest = after_zero;
+    }
+    let (width, precision) = match rest.split_once('.') {
+        Some((width, precision)) => (width, Some(precision)),
+        None => (rest, None)
+    };
+    if !width.is_empty() {
+        result.width = width.parse().ok()?;
+    }
+    if let Some(precision) = precision {
+        result.precision = Some(precision.parse().ok()?);
+    }
+    Some(result)
+}
+
+fn render(native: &str, value: &Value, spec: &Spec) -> Result<String> {
+    let number = match value {
+        Value::Number(n) => Some(*n),
+        Value::Int(n) => Some(*n as f64),
+        _ => None
+    };
+    let text = match (number, spec.precision) {
+        (Some(n), Some(precision)) => format!("{:.*}", precision, n),
+        (None, Some(precision)) => value.to_string().chars().take(precision).collect(),
+        _ => value.to_string()
+    };
+
+    let padding = spec.width.saturating_sub(text.chars().count());
+    if spec.zero {
+        if number.is_none() {
+            bail!("{} can only pad numbers with zeros, got {}", native, value.type_name());
+        }
+        // The zeros go after the sign, as in -007
+        let (sign, digits) = text.split_at(if text.starts_with('-') { 1 } else { 0 });
+        return Ok(format!("{}{}{}", sign, "0".repeat(padding), digits));
+    }
+
+    let default_align = if number.is_some() { Align::Right } else { Align::Left };
+    let (before, after) = match spec.align.unwrap_or(default_align) {
+        Align::Left => (0, padding),
+        Align::Center => (padding / 2, padding - padding / 2),
+        Align::Right => (padding, 0)
+    };
+    let fill = spec.fill.unwrap_or(' ').to_string();
+    Ok(format!("{}{}{}", fill.repeat(before), text, fill.repeat(after)))
+}
@@ -0,0 +1,113 @@
+use std::{cell::RefCell, fmt::Display, rc::Rc};
+
+use crate::{chunk::Chunk, value::Value};
+
+/// Where a closure finds a captured variable when it's created: either a local of the
+/// immediately enclosing function or one of that function's own upvalues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueDescriptor {
+    pub is_local: bool,
+    pub index: usize
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub name: Option<String>,
+    pub arity: u8,
+    /// Whether a last parameter after the `arity` others collects any further arguments into a list
+    pub variadic: bool,
+    pub chunk: Chunk,
+    pub upvalues: Vec<UpvalueDescriptor>,
+    /// The imported module whose globals the function uses, the main script's if none
+    pub module: Option<usize>
+}
+
+impl Function {
+    pub fn new<N: Into<String>>(name: N, arity: u8, chunk: Chunk) -> Self {
+        Self { name: Some(name.into()), arity, variadic: false, chunk, upvalues: Vec::new(), module: None }
+    }
+
+    pub fn with_upvalues(self, upvalues: Vec<UpvalueDescriptor>) -> Self {
+        Self { upvalues, ..self }
+    }
+
+    pub fn with_module(self, module: Option<usize>) -> Self {
+        Self { module, ..self }
+    }
+
+    pub fn with_variadic(self, variadic: bool) -> Self {
+        Self { variadic, ..self }
+    }
+
+    pub fn script(chunk: Chunk) -> Self {
+        Self { name: None, arity: 0, variadic: false, chunk, upvalues: Vec::new(), module: None }
+    }
+
+    pub fn display_name(&self) -> &str {
+        match &self.name {
+            Some(name) => name,
+            None => "script",
+        }
+    }
+}
+
+// Functions are compared by identity, not by their code
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Function {
+    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
+        None
+    }
+}
+
+/// A captured variable. It points into the stack while the variable is in scope
+/// and holds the value itself once the variable goes out of scope.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value)
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>
+}
+
+impl Closure {
+    pub fn new(function: Rc<Function>, upvalues: Vec<Rc<RefCell<Upvalue>>>) -> Self {
+        Self { function, upvalues }
+    }
+}
+
+// Closures are compared by identity, not by their function or captures
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Closure {
+    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
+        None
+    }
+}
+
+impl Display for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.function)
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
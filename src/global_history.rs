@@ -0,0 +1,82 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::value::Value;
+
+/// Number of most recent writes kept per variable
+const MAX_WRITES_PER_VARIABLE: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct GlobalWrite {
+    pub src_line_number: i32,
+    pub value: Value,
+    pub is_definition: bool
+}
+
+#[derive(Debug)]
+struct VariableHistory {
+    writes: Vec<GlobalWrite>,
+    total_writes: usize
+}
+
+/// Log of every definition and assignment of global variables during a run
+#[derive(Debug, Default)]
+pub struct GlobalHistory {
+    variables: HashMap<String, VariableHistory>,
+    names_in_definition_order: Vec<String>
+}
+
+impl GlobalHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_define(&mut self, name: &str, value: &Value, src_line_number: i32) {
+        self.record(name, GlobalWrite { src_line_number, value: value.clone(), is_definition: true })
+    }
+
+    pub fn record_set(&mut self, name: &str, value: &Value, src_line_number: i32) {
+        self.record(name, GlobalWrite { src_line_number, value: value.clone(), is_definition: false })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.variables.is_empty()
+    }
+
+    fn record(&mut self, name: &str, write: GlobalWrite) {
+        if !self.variables.contains_key(name) {
+            self.names_in_definition_order.push(name.to_string());
+        }
+
+        let history = self.variables.entry(name.to_string())
+            .or_insert_with(|| VariableHistory { writes: Vec::new(), total_writes: 0 });
+
+        if history.writes.len() == MAX_WRITES_PER_VARIABLE {
+            history.writes.remove(0);
+        }
+        history.writes.push(write);
+        history.total_writes += 1;
+    }
+}
+
+impl Display for GlobalHistory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Global variable history:")?;
+
+        for name in &self.names_in_definition_order {
+            let history = &self.variables[name];
+            writeln!(f, "  {}:", name)?;
+
+            let dropped = history.total_writes - history.writes.len();
+            if dropped > 0 {
+                writeln!(f, "    ... {} earlier writes ...", dropped)?;
+            }
+
+            for write in &history.writes {
+                let action = if write.is_definition { "var" } else { "set" };
+                writeln!(f, "    [line {}] {} {}", write.src_line_number, action, write.value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
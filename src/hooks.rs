@@ -0,0 +1,41 @@
+//! Callbacks the VM makes around each instruction it runs, for tools such as profilers and
+//! visualizers that watch execution without changing it.
+
+use std::fmt::Debug;
+
+use crate::{instruction::Instruction, value::Value};
+
+/// What an instruction hook can see of the VM
+#[derive(Debug, Clone, Copy)]
+pub struct VmState<'a> {
+    /// The value stack, bottom first
+    pub stack: &'a [Value],
+    /// Number of active call frames
+    pub depth: usize,
+    /// Name of the function the instruction belongs to
+    pub function: &'a str,
+    /// Where the instruction is in its function's code
+    pub offset: usize,
+    pub line: i32
+}
+
+type HookFn = dyn FnMut(&VmState, &Instruction);
+
+/// A callback given to `Vm::set_pre_instruction_hook` or `Vm::set_post_instruction_hook`
+pub struct InstructionHook(Box<HookFn>);
+
+impl InstructionHook {
+    pub fn new(hook: impl FnMut(&VmState, &Instruction) + 'static) -> Self {
+        Self(Box::new(hook))
+    }
+
+    pub fn call(&mut self, state: &VmState, instruction: &Instruction) {
+        (self.0)(state, instruction)
+    }
+}
+
+impl Debug for InstructionHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<instruction hook>")
+    }
+}
@@ -0,0 +1,57 @@
+//! The `[]` operator on lists, maps, strings and bytes
+
+use anyhow::{Result, bail};
+
+use crate::{bytes, map::MapKey, value::Value};
+
+pub fn get(target: &Value, index: &Value) -> Result<Value> {
+    match target {
+        Value::List(items) => {
+            let items = items.borrow();
+            let i = to_index(index, items.len(), "List")?;
+            Ok(items[i].clone())
+        },
+        Value::Map(map) => {
+            let key = MapKey::new(index.clone())?;
+            match map.borrow().get(&key) {
+                Some(value) => Ok(value.clone()),
+                None => bail!("Key {} not found in map", index)
+            }
+        },
+        Value::String(s) => {
+            let len = s.chars().count();
+            let i = to_index(index, len, "String")?;
+            Ok(Value::String(s.chars().nth(i).map(String::from).unwrap_or_default()))
+        },
+        Value::Bytes(b) => bytes::get(b, index),
+        other => bail!("Can't index into {}", other)
+    }
+}
+
+pub fn set(target: &Value, index: &Value, value: Value) -> Result<()> {
+    match target {
+        Value::List(items) => {
+            let mut items = items.borrow_mut();
+            let i = to_index(index, items.len(), "List")?;
+            items[i] = value;
+        },
+        Value::Map(map) => {
+            let key = MapKey::new(index.clone())?;
+            map.borrow_mut().insert(key, value);
+        },
+        Value::String(_) | Value::Bytes(_) => bail!("Can't assign to an index of {} since it is immutable", target),
+        other => bail!("Can't index into {}", other)
+    }
+
+    Ok(())
+}
+
+/// Checks that `index` is a whole number within a sequence of length `len`
+fn to_index(index: &Value, len: usize, what: &str) -> Result<usize> {
+    match index {
+        Value::Int(n) if *n >= 0 && (*n as u64) < len as u64 => Ok(*n as usize),
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && *n < len as f64 => Ok(*n as usize),
+        Value::Int(_) | Value::Number(_) => bail!("{} index {} out of range for length {}", what, index, len),
+        other => bail!("{} index must be a number, got {}", what, other)
+    }
+}
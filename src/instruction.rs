@@ -1,18 +1,24 @@
-use std::fmt::Display;
+use std::{collections::HashMap, fmt::Display};
 
-use crate::{chunk::Chunk, value::Value};
+use crate::{chunk::{Chunk, LocalVar}, error_code::{self, CodedError}, value::Value};
 use anyhow::{Result, bail};
 
+/// The largest value a three byte operand can hold
+pub const MAX_LONG_OPERAND: usize = 0xff_ffff;
+
 #[derive(Debug, Clone)]
 pub struct Instruction {
     pub op_code: OpCode,
     pub operand1: Option<u8>,
-    pub operand2: Option<u8>
+    pub operand2: Option<u8>,
+    pub operand3: Option<u8>,
+    /// The argument count of a long invoke, which follows its three byte name
+    pub operand4: Option<u8>
 }
 
 impl Instruction {
     pub fn new(op_code: OpCode, operand1: Option<u8>, operand2: Option<u8>) -> Self {
-        Self { op_code, operand1, operand2 }
+        Self { op_code, operand1, operand2, operand3: None, operand4: None }
     }
 
     pub fn simple(op_code: OpCode) -> Self {
@@ -26,37 +32,157 @@ impl Instruction {
     pub fn binary(op_code: OpCode, operand1: u8, operand2: u8) -> Self {
         Self::new(op_code, Some(operand1), Some(operand2))
     }
+
+    pub fn ternary(op_code: OpCode, operand1: u8, operand2: u8, operand3: u8) -> Self {
+        Self { op_code, operand1: Some(operand1), operand2: Some(operand2), operand3: Some(operand3), operand4: None }
+    }
+
+    /// The instruction with the operand bytes that follow its opcode in a chunk
+    pub fn with_operands(op_code: OpCode, operands: &[u8]) -> Self {
+        Self {
+            op_code, operand1: operands.first().copied(), operand2: operands.get(1).copied(), operand3: operands.get(2).copied(),
+            operand4: operands.get(3).copied()
+        }
+    }
+
+    /// The 24 bit operand of an instruction with three or more operands, high byte first
+    pub fn long_operand(&self) -> Option<usize> {
+        match (self.operand1, self.operand2, self.operand3) {
+            (Some(op1), Some(op2), Some(op3)) => Some((op1 as usize) << 16 | (op2 as usize) << 8 | op3 as usize),
+            _ => None
+        }
+    }
+
+    /// Net number of values the instruction leaves on the stack, negative if it consumes more than it produces.
+    /// `Return` is treated as consuming its return value.
+    pub fn stack_effect(&self) -> i32 {
+        match self.op_code {
+            OpCode::Constant | OpCode::ConstantLong | OpCode::Nil | OpCode::True | OpCode::False
+            | OpCode::GetGlobal | OpCode::GetGlobalLong | OpCode::GetLocal | OpCode::GetLocalLong | OpCode::GetUpvalue
+            | OpCode::Closure | OpCode::ClosureLong | OpCode::Class | OpCode::ClassLong => 1,
+            OpCode::Negate | OpCode::GetLocalAdd | OpCode::Increment | OpCode::Decrement | OpCode::Not | OpCode::SetGlobal | OpCode::SetGlobalLong
+            | OpCode::SetLocal | OpCode::SetLocalLong | OpCode::SetUpvalue
+            | OpCode::GetProperty | OpCode::GetPropertyLong | OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::LoopLong | OpCode::JumpLong
+            | OpCode::PushHandler | OpCode::PopHandler | OpCode::Import | OpCode::ImportLong => 0,
+            OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide | OpCode::Modulo
+            | OpCode::Equal | OpCode::Greater | OpCode::Less
+            | OpCode::Print | OpCode::PrintErr | OpCode::Pop | OpCode::DefineGlobal | OpCode::DefineGlobalLong
+            | OpCode::DefineConstGlobal | OpCode::DefineConstGlobalLong | OpCode::CloseUpvalue
+            | OpCode::SetProperty | OpCode::SetPropertyLong | OpCode::Method | OpCode::MethodLong | OpCode::Getter | OpCode::GetterLong
+            | OpCode::Inherit | OpCode::GetSuper | OpCode::GetSuperLong
+            | OpCode::GetIndex | OpCode::Throw | OpCode::Return | OpCode::LessJumpIfFalse => -1,
+            // The callee and arguments are replaced by the result
+            OpCode::Call | OpCode::Invoke | OpCode::InvokeLong => -(self.arg_count() as i32),
+            // The last argument is pushed before the call
+            OpCode::ConstantCall => 1 - self.arg_count() as i32,
+            // The superclass is popped as well
+            OpCode::SuperInvoke | OpCode::SuperInvokeLong => -(self.arg_count() as i32) - 1,
+            // The target and index are popped, leaving the assigned value
+            OpCode::SetIndex => -2,
+            // The condition and message are popped
+            OpCode::Assert => -2,
+            // The items are replaced by the list
+            OpCode::BuildList => 1 - self.operand1.unwrap_or(0) as i32,
+        }
+    }
+
+    /// The number of arguments a call passes
+    pub fn arg_count(&self) -> u8 {
+        match self.op_code {
+            OpCode::Invoke | OpCode::SuperInvoke | OpCode::ConstantCall => self.operand2.unwrap_or(0),
+            OpCode::InvokeLong | OpCode::SuperInvokeLong => self.operand4.unwrap_or(0),
+            _ => self.operand1.unwrap_or(0)
+        }
+    }
+
+    /// The index among the chunk's constants of the name an instruction refers to, such as a
+    /// global's or a property's
+    pub fn name_index(&self) -> Option<usize> {
+        match self.op_code {
+            OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal | OpCode::DefineConstGlobal | OpCode::Class
+            | OpCode::GetProperty | OpCode::SetProperty | OpCode::Method | OpCode::Getter | OpCode::Invoke
+            | OpCode::GetSuper | OpCode::SuperInvoke => self.operand1.map(usize::from),
+            OpCode::DefineGlobalLong | OpCode::GetGlobalLong | OpCode::SetGlobalLong | OpCode::DefineConstGlobalLong | OpCode::ClassLong
+            | OpCode::GetPropertyLong | OpCode::SetPropertyLong | OpCode::MethodLong | OpCode::GetterLong | OpCode::InvokeLong
+            | OpCode::GetSuperLong | OpCode::SuperInvokeLong => self.long_operand(),
+            _ => None
+        }
+    }
+
+    /// The stack slot, relative to the frame, of a local variable instruction
+    pub fn local_slot(&self) -> Option<usize> {
+        match self.op_code {
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::GetLocalAdd => self.operand1.map(usize::from),
+            OpCode::GetLocalLong | OpCode::SetLocalLong => self.long_operand(),
+            _ => None
+        }
+    }
+
+    /// Offset of the instruction following this one in the chunk, given where this one starts
+    pub fn next_offset(&self, offset: usize) -> usize {
+        offset + 1 + [self.operand1, self.operand2, self.operand3, self.operand4].iter().flatten().count()
+    }
+
+    /// Where a jump or loop instruction at `offset` transfers control to
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        let distance = match (self.operand1, self.operand2, self.operand3) {
+            (Some(op1), Some(op2), None) => (op1 as usize) << 8 | op2 as usize,
+            (Some(_), Some(_), Some(_)) => self.long_operand()?,
+            _ => return None
+        };
+
+        match self.op_code {
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::PushHandler | OpCode::JumpLong | OpCode::LessJumpIfFalse => Some(self.next_offset(offset) + distance),
+            OpCode::Loop | OpCode::LoopLong => self.next_offset(offset).checked_sub(distance),
+            _ => None
+        }
+    }
 }
 
 impl Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.op_code)?;
-        match self.operand1 {
-            Some(o) => write!(f, " {}", o)?,
-            None => {},
-        };
+        if let Some(o) = self.operand1 {
+            write!(f, " {}", o)?;
+        }
+
+        if let Some(o) = self.operand2 {
+            write!(f, " {}", o)?;
+        }
+
+        if let Some(o) = self.operand3 {
+            write!(f, " {}", o)?;
+        }
 
-        match self.operand2 {
+        match self.operand4 {
             Some(o) => write!(f, " {}", o),
             None => Ok(()),
         }
     }
 }
 
+/// How far past a forward jump the code can get before the jump is sent on through an island,
+/// leaving the rest of its two byte reach for the statement being compiled
+const ISLAND_DISTANCE: usize = u16::MAX as usize / 2;
+
 pub struct InstructionWriter {
-    chunk: Chunk
+    chunk: Chunk,
+    /// Where the forward jumps not yet patched were written
+    pending_jumps: Vec<usize>,
+    /// The `JumpLong` in an island that a forward jump now goes through, by where the jump was written
+    far_jumps: HashMap<usize, usize>
 }
 
 impl InstructionWriter {
     pub fn new(chunk: Chunk) -> Self {
-        Self { chunk }
+        Self { chunk, pending_jumps: Vec::new(), far_jumps: HashMap::new() }
     }
 
     pub fn with_new_chunk() -> Self {
-        Self { chunk: Chunk::new() }
+        Self::new(Chunk::new())
     }
 
-    pub fn to_chunk(self) -> Chunk {
+    pub fn into_chunk(self) -> Chunk {
         self.chunk
     }
 
@@ -64,15 +190,39 @@ impl InstructionWriter {
         self.chunk.len()
     }
 
+    /// The chunk as written so far
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    /// Writes a `Constant` if the constant's index fits in its operand, and a `ConstantLong` if not
     pub fn write_const(&mut self, value: Value, src_line_number: i32) -> Result<usize> {
-        let const_index = self.chunk.add_constant(value);
-        if const_index > u8::MAX {
-            bail!("Too many costants in chunk")
+        let const_index = self.add_constant(value)?;
+        self.write_op_code_with_index(OpCode::Constant, const_index, src_line_number)
+    }
+
+    /// Writes the opcode with an index as its operand, or the opcode's long form if the index
+    /// doesn't fit in one byte
+    pub fn write_op_code_with_index(&mut self, op_code: OpCode, index: usize, src_line_number: i32) -> Result<usize> {
+        if let Ok(operand) = u8::try_from(index) {
+            return Ok(self.write_op_code_with_operand(op_code, operand, src_line_number));
         }
-        let start = self.chunk.write(OpCode::Constant, src_line_number);
-        self.chunk.write(const_index, src_line_number);
+        match op_code.long_form() {
+            Some(long_op_code) if index <= MAX_LONG_OPERAND => Ok(self.write_op_code_with_long_operand(long_op_code, index, src_line_number)),
+            _ => bail!("Index {} is too large for {}", index, op_code)
+        }
+    }
 
-        Ok(start)
+    /// Writes the opcode with an index and a one byte count as its operands, or the opcode's
+    /// long form if the index doesn't fit in one byte
+    pub fn write_op_code_with_index_and_count(&mut self, op_code: OpCode, index: usize, count: u8, src_line_number: i32) -> Result<usize> {
+        if let Ok(operand) = u8::try_from(index) {
+            return Ok(self.write_op_code_with_operands(op_code, operand, count, src_line_number));
+        }
+        match op_code.long_form() {
+            Some(long_op_code) if index <= MAX_LONG_OPERAND => Ok(self.write_op_code_with_long_operand_and_count(long_op_code, index, count, src_line_number)),
+            _ => bail!("Index {} is too large for {}", index, op_code)
+        }
     }
 
     pub fn write_op_code_with_operand(&mut self, op_code: OpCode, operand: u8, src_line_number: i32) -> usize {
@@ -81,6 +231,14 @@ impl InstructionWriter {
         start
     }
 
+    pub fn add_local_var(&mut self, local_var: LocalVar) {
+        self.chunk.add_local_var(local_var);
+    }
+
+    pub fn add_upvalue_name(&mut self, name: String) {
+        self.chunk.add_upvalue_name(name);
+    }
+
     pub fn write_op_code_with_operands(&mut self, op_code: OpCode, operand1: u8, operand2: u8, src_line_number: i32) -> usize {
         let start = self.chunk.write(op_code, src_line_number);
         self.chunk.write(operand1, src_line_number);
@@ -88,23 +246,77 @@ impl InstructionWriter {
         start
     }
 
+    /// Writes the opcode followed by `operand` as three bytes, high byte first
+    pub fn write_op_code_with_long_operand(&mut self, op_code: OpCode, operand: usize, src_line_number: i32) -> usize {
+        let start = self.chunk.write(op_code, src_line_number);
+        for shift in [16, 8, 0] {
+            self.chunk.write(((operand >> shift) & 0xff) as u8, src_line_number);
+        }
+        start
+    }
+
+    /// Writes the opcode followed by `operand` as three bytes, high byte first, and then `count`
+    pub fn write_op_code_with_long_operand_and_count(&mut self, op_code: OpCode, operand: usize, count: u8, src_line_number: i32) -> usize {
+        let start = self.write_op_code_with_long_operand(op_code, operand, src_line_number);
+        self.chunk.write(count, src_line_number);
+        start
+    }
+
     pub fn write_op_code<I: Into<i32>>(&mut self, op_code: OpCode, src_line_number: I) -> usize  {
         self.chunk.write(op_code, src_line_number.into())
     }
 
     pub fn write_jump_if_false(&mut self, src_line_number: i32) -> usize {
-        self.write_op_code_with_operands(OpCode::JumpIfFalse, 0xff,0xff, src_line_number)
+        self.write_forward_jump(OpCode::JumpIfFalse, src_line_number)
     }
 
     pub fn write_jump(&mut self, src_line_number: i32) -> usize {
-        self.write_op_code_with_operands(OpCode::Jump, 0xff,0xff, src_line_number)
+        self.write_forward_jump(OpCode::Jump, src_line_number)
     }
 
-    pub fn write_loop(&mut self, loop_start_loc: usize, src_line_number: i32) -> Result<usize> {
-        let offset = self.chunk.len() - (loop_start_loc - 3);
+    /// Installs an exception handler whose location is patched in like a jump's
+    pub fn write_push_handler(&mut self, src_line_number: i32) -> usize {
+        self.write_forward_jump(OpCode::PushHandler, src_line_number)
+    }
+
+    fn write_forward_jump(&mut self, op_code: OpCode, src_line_number: i32) -> usize {
+        let start = self.write_op_code_with_operands(op_code, 0xff,0xff, src_line_number);
+        self.pending_jumps.push(start);
+        start
+    }
 
-        if offset > usize::MAX {
-            bail!("Loop body too big ({})", offset);
+    /// Sends forward jumps getting too far from where they were written on through an island of
+    /// `JumpLong`s, which the code before it jumps over. Called between statements, so that no
+    /// statement is cut in two.
+    pub fn write_jump_islands(&mut self) -> Result<()> {
+        let len = self.chunk.len();
+        let far: Vec<usize> = self.pending_jumps.iter().copied()
+            .filter(|jump| (ISLAND_DISTANCE..=u16::MAX as usize).contains(&(len - (jump + 3))))
+            .collect();
+        if far.is_empty() {
+            return Ok(());
+        }
+
+        let line = self.chunk.src_line_numbers().last().copied().unwrap_or(0);
+        let over_island = self.write_op_code_with_operands(OpCode::Jump, 0xff, 0xff, line);
+        for jump in far {
+            let island = self.write_op_code_with_long_operand(OpCode::JumpLong, 0, line);
+            self.patch_short_jump(jump, island)?;
+            self.pending_jumps.retain(|pending| *pending != jump);
+            self.far_jumps.insert(jump, island);
+        }
+        self.patch_short_jump(over_island, self.chunk.len())
+    }
+
+    /// Writes a `Loop` back to `loop_start_loc`, or a `LoopLong` if that's too far back for it
+    pub fn write_loop(&mut self, loop_start_loc: usize, src_line_number: i32) -> Result<usize> {
+        let offset = self.chunk.len() + 3 - loop_start_loc;
+        if offset > u16::MAX as usize {
+            let long_offset = offset + 1;
+            if long_offset > MAX_LONG_OPERAND {
+                bail!(CodedError::new(error_code::JUMP_TOO_FAR, "Loop body too large."));
+            }
+            return Ok(self.write_op_code_with_long_operand(OpCode::LoopLong, long_offset, src_line_number));
         }
 
         let op1 = ((offset >> 8) & 0xff) as u8;
@@ -131,22 +343,43 @@ impl InstructionWriter {
     }
 
     pub fn patch_jump_to_chunk_end(&mut self, jmp_op_code_loc: usize) -> Result<()> {
-        let relative_offset_to_current_chunk_end = self.chunk.len() - (jmp_op_code_loc + 3);
+        self.pending_jumps.retain(|pending| *pending != jmp_op_code_loc);
+        let island = match self.far_jumps.remove(&jmp_op_code_loc) {
+            Some(island) => island,
+            None => return self.patch_short_jump(jmp_op_code_loc, self.chunk.len())
+        };
 
-        if relative_offset_to_current_chunk_end > usize::MAX {
-            bail!("Jump too long ({})", relative_offset_to_current_chunk_end);
+        let relative_offset_to_current_chunk_end = self.chunk.len() - (island + 4);
+        if relative_offset_to_current_chunk_end > MAX_LONG_OPERAND {
+            bail!(CodedError::new(error_code::JUMP_TOO_FAR, "Too much code to jump over."));
+        }
+        for (i, shift) in [16, 8, 0].into_iter().enumerate() {
+            self.set_byte(island + 1 + i, ((relative_offset_to_current_chunk_end >> shift) & 0xff) as u8)?;
         }
 
-        let operand1 = (relative_offset_to_current_chunk_end >> 8) & 0xff;
-        let operand2 = relative_offset_to_current_chunk_end & 0xff;
+        Ok(())
+    }
 
-        self.patch_operands(jmp_op_code_loc, Some(operand1 as u8), Some(operand2 as u8))?;
+    fn patch_short_jump(&mut self, jmp_op_code_loc: usize, target: usize) -> Result<()> {
+        let relative_offset = target - (jmp_op_code_loc + 3);
+        if relative_offset > u16::MAX as usize {
+            bail!(CodedError::new(error_code::JUMP_TOO_FAR, "Too much code to jump over."));
+        }
 
-        Ok(())
+        let operand1 = (relative_offset >> 8) & 0xff;
+        let operand2 = relative_offset & 0xff;
+
+        self.patch_operands(jmp_op_code_loc, Some(operand1 as u8), Some(operand2 as u8))
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 { 
-        self.chunk.add_constant(value)
+    /// Adds a constant for an instruction to refer to, by an index no larger than the long form
+    /// of the instruction takes
+    pub fn add_constant(&mut self, value: Value) -> Result<usize> {
+        let index = self.chunk.add_constant(value);
+        if index > MAX_LONG_OPERAND {
+            bail!(CodedError::new(error_code::TOO_MANY_CONSTANTS, "Too many constants in one chunk."));
+        }
+        Ok(index)
     }
 }
 
@@ -174,31 +407,28 @@ impl<'a> InstructionReader<'a> {
 
         let op_code: OpCode = code_byte.try_into()?;
 
-        let instruction = match op_code {
-            OpCode::Constant | OpCode::DefineGlobal
-            | OpCode::GetGlobal | OpCode::SetGlobal 
-            | OpCode::GetLocal | OpCode::SetLocal => {
-                let operand1 = self.chunk.read(self.ip)?;
-                self.ip += 1;
-                Instruction::unary(op_code, operand1)
-            },
-            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop  => {
-                let operand1 = self.chunk.read(self.ip)?;
-                self.ip += 1;
-                let operand2 = self.chunk.read(self.ip)?;
-                self.ip += 1;
-                Instruction::binary(op_code, operand1, operand2)
-            },
-            op_code => Instruction::simple(op_code)
+        let operands = match self.chunk.code().get(self.ip..self.ip + op_code.operand_count()) {
+            Some(operands) => operands,
+            None => bail!("Operands missing on instruction {} at {}", op_code, instruction_offset)
         };
+        self.ip += operands.len();
+        let instruction = Instruction::with_operands(op_code, operands);
         Ok(Some((instruction, instruction_offset, src_line_number)))
     }
 
 
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
     pub fn get_const(&self, index: usize) -> Result<Value> {
         self.chunk.get_constant(index)
     }
 
+    pub fn chunk(&self) -> &'a Chunk {
+        self.chunk
+    }
+
     pub fn set_ip(&mut self, new_ip: usize) -> Result<()> {
         if new_ip > self.chunk.len() {
             bail!("Attempt to set ip beyond chunk ({})", new_ip);
@@ -224,10 +454,13 @@ pub enum OpCode {
     Constant,
     Return,
     Negate,
+    Increment,
+    Decrement,
     Add,
     Subtract,
     Multiply,
     Divide,
+    Modulo,
     Nil,
     True,
     False,
@@ -236,6 +469,7 @@ pub enum OpCode {
     Greater,
     Less,
     Print,
+    PrintErr,
     Pop,
     DefineGlobal,
     GetGlobal,
@@ -244,12 +478,104 @@ pub enum OpCode {
     SetLocal,
     Jump,
     JumpIfFalse,
-    Loop
+    Loop,
+    Call,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    Invoke,
+    Inherit,
+    GetSuper,
+    SuperInvoke,
+    GetIndex,
+    SetIndex,
+    BuildList,
+    PushHandler,
+    PopHandler,
+    Throw,
+    Import,
+    DefineConstGlobal,
+    Getter,
+    Assert,
+    ConstantLong,
+    GetLocalLong,
+    SetLocalLong,
+    LoopLong,
+    JumpLong,
+    GetLocalAdd,
+    ConstantCall,
+    LessJumpIfFalse,
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    DefineConstGlobalLong,
+    ClassLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    MethodLong,
+    GetterLong,
+    InvokeLong,
+    GetSuperLong,
+    SuperInvokeLong,
+    ClosureLong,
+    ImportLong
+}
+
+impl OpCode {
+    /// How many one byte operands follow the opcode in the chunk
+    pub fn operand_count(&self) -> usize {
+        match self {
+            OpCode::Constant | OpCode::DefineGlobal | OpCode::DefineConstGlobal
+            | OpCode::GetGlobal | OpCode::SetGlobal
+            | OpCode::GetLocal | OpCode::SetLocal | OpCode::Call | OpCode::BuildList | OpCode::Import
+            | OpCode::Closure | OpCode::GetUpvalue | OpCode::SetUpvalue
+            | OpCode::Class | OpCode::GetProperty | OpCode::SetProperty | OpCode::Method | OpCode::Getter
+            | OpCode::GetSuper | OpCode::GetLocalAdd => 1,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::Invoke | OpCode::SuperInvoke | OpCode::PushHandler
+            | OpCode::ConstantCall | OpCode::LessJumpIfFalse => 2,
+            OpCode::ConstantLong | OpCode::GetLocalLong | OpCode::SetLocalLong | OpCode::LoopLong | OpCode::JumpLong
+            | OpCode::DefineGlobalLong | OpCode::GetGlobalLong | OpCode::SetGlobalLong | OpCode::DefineConstGlobalLong
+            | OpCode::ClassLong | OpCode::GetPropertyLong | OpCode::SetPropertyLong | OpCode::MethodLong | OpCode::GetterLong
+            | OpCode::GetSuperLong | OpCode::ClosureLong | OpCode::ImportLong => 3,
+            // A three byte name followed by the argument count
+            OpCode::InvokeLong | OpCode::SuperInvokeLong => 4,
+            _ => 0
+        }
+    }
+
+    /// The opcode that does the same with a three byte operand, for operands too large for one
+    pub fn long_form(&self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::GetLocal => Some(OpCode::GetLocalLong),
+            OpCode::SetLocal => Some(OpCode::SetLocalLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::DefineConstGlobal => Some(OpCode::DefineConstGlobalLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            OpCode::Getter => Some(OpCode::GetterLong),
+            OpCode::Invoke => Some(OpCode::InvokeLong),
+            OpCode::GetSuper => Some(OpCode::GetSuperLong),
+            OpCode::SuperInvoke => Some(OpCode::SuperInvokeLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            OpCode::Import => Some(OpCode::ImportLong),
+            _ => None
+        }
+    }
 }
 
-impl Into<u8> for OpCode {
-    fn into(self) -> u8 {
-        self as u8
+impl From<OpCode> for u8 {
+    fn from(op_code: OpCode) -> u8 {
+        op_code as u8
     }
 }
 
@@ -257,11 +583,11 @@ impl TryFrom<u8> for OpCode {
     type Error = anyhow::Error;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        if value > OpCode::Loop as u8 {
+        if value > OpCode::ImportLong as u8 {
             bail!("Unknown opcode {}", value);
         }
 
-        Ok(unsafe { std::mem::transmute(value) })
+        Ok(unsafe { std::mem::transmute::<u8, OpCode>(value) })
     }
 }
 
@@ -0,0 +1,77 @@
+//! Identifiers seen while compiling, each stored once. Locals are resolved by comparing
+//! symbols instead of strings, and a name is only copied out when it becomes a constant.
+
+use std::{collections::HashMap, hash::{BuildHasherDefault, Hasher}, rc::Rc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+/// A map keyed by symbols
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<FnvHasher>>;
+
+#[derive(Debug, Default)]
+pub struct Interner {
+    names: Vec<Rc<str>>,
+    symbols: HashMap<Rc<str>, Symbol, BuildHasherDefault<FnvHasher>>
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(symbol) = self.symbols.get(name) {
+            return *symbol;
+        }
+
+        let symbol = Symbol(self.names.len() as u32);
+        let name: Rc<str> = Rc::from(name);
+        self.names.push(name.clone());
+        self.symbols.insert(name, symbol);
+        symbol
+    }
+
+    pub fn resolve(&self, symbol: Symbol) -> &str {
+        &self.names[symbol.0 as usize]
+    }
+}
+
+/// FNV-1a, much quicker than the default hasher on the short keys hashed here. Its weaker
+/// protection against crafted collisions doesn't matter for names in a script being compiled.
+#[derive(Debug)]
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_name_gives_same_symbol() {
+        let mut interner = Interner::new();
+        let a = interner.intern("count");
+        let b = interner.intern("total");
+        assert_eq!(interner.intern("count"), a);
+        assert_ne!(a, b);
+        assert_eq!(interner.resolve(b), "total");
+    }
+}
@@ -0,0 +1,312 @@
+//! Just enough JSON for the messages of the debug adapter protocol
+
+use std::{fmt::Display, iter::Peekable, str::Chars};
+
+use anyhow::{Result, bail, anyhow};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Members in the order they were written
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(c) => bail!("Unexpected '{}' after JSON value", c),
+            None => Ok(value)
+        }
+    }
+
+    pub fn object<K: Into<String>, I: IntoIterator<Item = (K, Json)>>(members: I) -> Json {
+        Json::Object(members.into_iter().map(|(key, value)| (key.into(), value)).collect())
+    }
+
+    /// The member `key` of an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            // JSON has no infinities or NaN
+            Json::Number(n) if !n.is_finite() => write!(f, "null"),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?
+        }
+    }
+    write!(f, "\"")
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+        chars.next();
+    }
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Json> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('{') => parse_object(chars),
+        Some('[') => parse_array(chars),
+        Some('"') => Ok(Json::String(parse_string(chars)?)),
+        Some('t') => parse_word(chars, "true", Json::Bool(true)),
+        Some('f') => parse_word(chars, "false", Json::Bool(false)),
+        Some('n') => parse_word(chars, "null", Json::Null),
+        Some(c) if *c == '-' || c.is_ascii_digit() => parse_number(chars),
+        Some(c) => bail!("Unexpected '{}' in JSON", c),
+        None => bail!("Unexpected end of JSON")
+    }
+}
+
+fn parse_word(chars: &mut Peekable<Chars>, word: &str, value: Json) -> Result<Json> {
+    for expected in word.chars() {
+        if chars.next() != Some(expected) {
+            bail!("Expected '{}' in JSON", word);
+        }
+    }
+    Ok(value)
+}
+
+fn parse_number(chars: &mut Peekable<Chars>) -> Result<Json> {
+    let mut text = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+        text.push(*c);
+        chars.next();
+    }
+    text.parse::<f64>().map(Json::Number).map_err(|_| anyhow!("Invalid number '{}' in JSON", text))
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String> {
+    // The opening "
+    chars.next();
+
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                Some('/') => s.push('/'),
+                Some('b') => s.push('\u{8}'),
+                Some('f') => s.push('\u{c}'),
+                Some('n') => s.push('\n'),
+                Some('r') => s.push('\r'),
+                Some('t') => s.push('\t'),
+                Some('u') => s.push(parse_unicode_escape(chars)?),
+                _ => bail!("Invalid escape in JSON string")
+            },
+            Some(c) => s.push(c),
+            None => bail!("Unterminated JSON string")
+        }
+    }
+}
+
+/// The character of a `\u` escape, combining the two halves of a surrogate pair
+fn parse_unicode_escape(chars: &mut Peekable<Chars>) -> Result<char> {
+    let high = parse_hex4(chars)?;
+    if !(0xD800..0xDC00).contains(&high) {
+        return char::from_u32(high).ok_or_else(|| anyhow!("Invalid \\u escape in JSON string"));
+    }
+
+    if chars.next() != Some('\\') || chars.next() != Some('u') {
+        bail!("Unpaired surrogate in JSON string");
+    }
+    let low = parse_hex4(chars)?;
+    if !(0xDC00..0xE000).contains(&low) {
+        bail!("Unpaired surrogate in JSON string");
+    }
+    char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)).ok_or_else(|| anyhow!("Invalid \\u escape in JSON string"))
+}
+
+fn parse_hex4(chars: &mut Peekable<Chars>) -> Result<u32> {
+    let digits: String = chars.take(4).collect();
+    match u32::from_str_radix(&digits, 16) {
+        Ok(n) if digits.len() == 4 => Ok(n),
+        _ => bail!("Invalid \\u escape in JSON string")
+    }
+}
+
+fn parse_array(chars: &mut Peekable<Chars>) -> Result<Json> {
+    // The [
+    chars.next();
+
+    let mut items = Vec::new();
+    skip_whitespace(chars);
+    if chars.peek() == Some(&']') {
+        chars.next();
+        return Ok(Json::Array(items));
+    }
+
+    loop {
+        items.push(parse_value(chars)?);
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some(']') => return Ok(Json::Array(items)),
+            _ => bail!("Expected ',' or ']' in JSON array")
+        }
+    }
+}
+
+fn parse_object(chars: &mut Peekable<Chars>) -> Result<Json> {
+    // The {
+    chars.next();
+
+    let mut members = Vec::new();
+    skip_whitespace(chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+        return Ok(Json::Object(members));
+    }
+
+    loop {
+        skip_whitespace(chars);
+        if chars.peek() != Some(&'"') {
+            bail!("Expected a member name in JSON object");
+        }
+        let key = parse_string(chars)?;
+        skip_whitespace(chars);
+        if chars.next() != Some(':') {
+            bail!("Expected ':' after member name in JSON object");
+        }
+        members.push((key, parse_value(chars)?));
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some('}') => return Ok(Json::Object(members)),
+            _ => bail!("Expected ',' or '}}' in JSON object")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_writes_back() {
+        let text = r#"{"seq":1,"ok":true,"args":[null,-2.5e1,"a\"b\n\u00e9\ud83d\ude00"],"empty":{}}"#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.get("seq").and_then(Json::as_f64), Some(1.0));
+        assert_eq!(json.get("args").and_then(Json::as_array).map(|a| a[1].clone()), Some(Json::Number(-25.0)));
+        assert_eq!(json.to_string(), "{\"seq\":1,\"ok\":true,\"args\":[null,-25,\"a\\\"b\\né😀\"],\"empty\":{}}");
+    }
+
+    #[test]
+    fn malformed_json_is_rejected() {
+        for text in ["", "{", "[1,]", "{\"a\" 1}", "\"open", "tru", "1 2", "\"\\ud800\""] {
+            assert!(Json::parse(text).is_err(), "{} should not parse", text);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! A bytecode virtual machine for the Lox language.
+//!
+//! Embedders should depend on the items re-exported from [`prelude`]; the modules
+//! behind it are internal and may change between releases, as may what [`tooling`]
+//! re-exports for the binary.
+
+mod vm;
+mod chunk;
+mod chunk_cache;
+mod capability;
+mod constant_pool;
+mod disassembler;
+mod instruction;
+mod stack;
+mod scanner;
+mod compiler;
+mod error_code;
+mod peephole;
+mod dialect;
+mod debugger;
+mod diagnostic;
+mod hooks;
+mod dap;
+mod json;
+mod interner;
+mod value;
+mod function;
+mod class;
+mod native;
+mod foreign;
+mod format;
+mod bytes;
+mod map;
+mod ordered_map;
+mod operand_source;
+mod output;
+mod index;
+mod eval;
+mod module;
+mod serialize;
+mod checkpoint;
+mod global_history;
+mod allocations;
+mod stats;
+mod suggestion;
+mod profile;
+mod repl;
+#[cfg(test)]
+mod encoding_audit;
+#[cfg(feature = "stdlib")]
+mod stdlib;
+#[cfg(feature = "soa-stack")]
+mod soa_stack;
+#[cfg(feature = "stack-check")]
+mod stack_check;
+
+pub mod prelude;
+pub mod tooling;
+
+/// Alternative value representations under evaluation. Not covered by any stability guarantee.
+#[cfg(feature = "soa-stack")]
+pub mod experimental {
+    pub use crate::stack::Stack;
+    pub use crate::soa_stack::SoaStack;
+}
@@ -1,100 +1,602 @@
-use std::{path::{PathBuf, Path}, fs::read_to_string, io::{self, Write, BufRead}};
+use std::{env, path::{PathBuf, Path}, fs::{metadata, read, read_to_string, write}, io::{self, Write, BufRead, BufReader}, net::TcpListener, thread, time::{Duration, SystemTime}};
 
 use anyhow::{Context, Result};
-use compiler::{Compiler, CompileErrorCollection};
-use disassembler::Disassembler;
+use lox::prelude::*;
+use lox::tooling::*;
 use structopt::StructOpt;
-use vm::{Vm, VmError};
-
-mod vm;
-mod chunk;
-mod disassembler;
-mod instruction;
-mod stack;
-mod scanner;
-mod compiler;
-mod value;
 
 
 #[derive(Debug, StructOpt)]
-#[structopt()]
+#[structopt(setting = structopt::clap::AppSettings::TrailingVarArg, setting = structopt::clap::AppSettings::ArgsNegateSubcommands)]
 struct Options {
     /// Output file, stdout if not present
     #[structopt(parse(from_os_str))]
     source_file_path: Option<PathBuf>,
 
+    /// Arguments for the script, which it gets from `args()`
+    script_args: Vec<String>,
+
     #[structopt(short, long)]
     trace: bool,
 
     #[structopt(short="d", long="dasm")]
-    disassemble: bool
+    disassemble: bool,
+
+    /// Run the source file again whenever it changes. With --dasm, only the bytecode of
+    /// functions that changed since the last compile is shown after the first
+    #[structopt(short, long)]
+    watch: bool,
+
+    /// How much --dasm shows of each instruction: compact, or verbose to add local names,
+    /// jump labels and stack effects
+    #[structopt(long, default_value = "compact")]
+    dasm_format: DisassemblyFormat,
+
+    /// Print every frame of runtime error stack traces instead of eliding the middle ones
+    #[structopt(long)]
+    full_trace: bool,
+
+    /// Record writes to global variables and print their history on runtime errors
+    #[structopt(long)]
+    global_history: bool,
+
+    /// Treat numbers differing by no more than this as equal in `==`
+    #[structopt(long)]
+    equality_epsilon: Option<f64>,
+
+    /// Keep compiled scripts in ~/.cache/lox and reuse them while the script and compiler
+    /// are unchanged
+    #[structopt(long)]
+    cache: bool,
+
+    /// Work out operators on constants, such as `2 * 3`, when compiling instead of when running,
+    /// and leave out code that can't be reached
+    #[structopt(short = "O", long = "optimize")]
+    optimize: bool,
+
+    /// Report code that compiles but likely isn't what was meant, such as statements after a
+    /// `return`, local variables that are never read, values that are never used, and
+    /// variables never declared whose names are close to one that is
+    #[structopt(long)]
+    warnings: bool,
+
+    /// How compile errors, warnings and runtime errors are printed: human, or json for one
+    /// object per line with the code, message, file, line, column and span of each
+    #[structopt(long, default_value = "human")]
+    diagnostics_format: DiagnosticsFormat,
+
+    /// Print static metrics of each compiled function before running
+    #[structopt(long)]
+    dump_stats: bool,
+
+    /// After running, print the size of the compiled code, how often each opcode appears in
+    /// it, and the most values the stack held
+    #[structopt(long)]
+    stats: bool,
+
+    /// Don't end the output of `print` and `printErr` with a newline
+    #[structopt(long)]
+    no_newline: bool,
+
+    /// Stop compiling after this many errors, 20 if not present
+    #[structopt(long)]
+    max_errors: Option<usize>,
+
+    /// Evaluate this source and print its result. A trailing ';' may be left out
+    #[structopt(short = "e", long = "eval")]
+    eval_source: Option<String>,
+
+    /// Run this program once per line of stdin with `line` and `lineNo` bound as globals
+    #[structopt(short = "n", long = "lines")]
+    line_program: Option<String>,
+
+    /// Save checkpoints of the execution state to this file
+    #[structopt(long = "checkpoint", parse(from_os_str))]
+    checkpoint_path: Option<PathBuf>,
+
+    /// Save a checkpoint after every this many instructions
+    #[structopt(long)]
+    checkpoint_every: Option<u64>,
+
+    /// Fail scripts that build a string longer than this many bytes
+    #[structopt(long)]
+    max_string_length: Option<usize>,
+
+    /// Fail scripts that put more than this many items in a list or map
+    #[structopt(long)]
+    max_collection_size: Option<usize>,
+
+    /// Cut off what scripts print after this many bytes
+    #[structopt(long)]
+    max_output_bytes: Option<usize>,
+
+    /// Report a stack overflow once the stack holds this many values
+    #[structopt(long)]
+    stack_capacity: Option<usize>,
+
+    /// Let scripts compile and run code at runtime with `compile` and `run`
+    #[structopt(long)]
+    allow_dynamic_code: bool,
+
+    /// Accept `not` and `mod` as operators meaning `!` and `%`
+    #[structopt(long)]
+    word_operators: bool,
+
+    /// End statements at line breaks where a semicolon could go, so semicolons can be left out
+    #[structopt(long)]
+    implicit_semicolons: bool,
+
+    /// Let blocks and `if` be used as expressions, taking the value of the block's last expression
+    #[structopt(long)]
+    expression_blocks: bool,
+
+    /// Also look for imported modules in this directory, before those listed in LOX_PATH
+    #[structopt(long = "include", parse(from_os_str), number_of_values = 1)]
+    include_dirs: Vec<PathBuf>,
+
+    /// Make `+` on a string and a value of another type an error instead of concatenating the value's printed form
+    #[structopt(long)]
+    strict_concat: bool,
+
+    /// Stop with an error on division or modulo by zero instead of giving infinity or NaN
+    #[structopt(long)]
+    error_on_division_by_zero: bool,
+
+    /// Print how many objects of each kind the script created, and roughly how much memory they took
+    #[structopt(long)]
+    alloc_report: bool,
+
+    /// Print how many times each opcode and source line was run, and how long they took
+    #[structopt(long)]
+    profile: bool,
+
+    /// Also write the profile to this file as JSON. Implies --profile
+    #[structopt(long, parse(from_os_str))]
+    profile_json: Option<PathBuf>,
+
+    /// Stop with a stack trace when a variable of this name is assigned
+    #[structopt(long = "watchpoint", number_of_values = 1)]
+    watchpoints: Vec<String>,
+
+    /// Carry on from a checkpoint saved with --checkpoint instead of running a source file
+    #[structopt(long, parse(from_os_str))]
+    resume: Option<PathBuf>,
+
+    /// Load the standard library modules, run the source file if one is given, and save the
+    /// resulting globals and modules to this file for starting later runs with --snapshot
+    #[structopt(long, parse(from_os_str))]
+    build_snapshot: Option<PathBuf>,
+
+    /// Start from the globals and modules saved with --build-snapshot
+    #[structopt(long = "snapshot", parse(from_os_str))]
+    snapshot_path: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Serve the Debug Adapter Protocol, for debugging scripts from an editor. The options
+    /// given before `dap` apply to the scripts it launches
+    Dap {
+        /// Local port to listen on
+        port: u16
+    }
 }
 
 fn main() -> Result<()> {
-    let Options { source_file_path, trace , disassemble} = Options::from_args();
-    match source_file_path {
-        Some(path) => run_file(&path, trace, disassemble),
-        None => run_prompt(trace, disassemble)
+    let options = Options::from_args();
+    if let Some(Command::Dap { port }) = options.command {
+        return run_debug_adapter(port, &options);
+    }
+
+    if let Some(path) = &options.resume {
+        return run_resume(path, &options);
+    }
+
+    if let Some(path) = &options.build_snapshot {
+        return build_snapshot(path, &options);
+    }
+
+    if let Some(program) = &options.line_program {
+        return run_lines(program.clone(), &options);
+    }
+
+    if let Some(source) = &options.eval_source {
+        return run_eval(source, &options);
+    }
+
+    match &options.source_file_path {
+        Some(path) if options.watch => run_watch(path, &options),
+        Some(path) => run_file(path, &options),
+        None => run_prompt(&options)
+    }
+}
+
+/// How often watch mode checks whether the source file has changed
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+fn run_watch(source_file_path: &Path, options: &Options) -> Result<()> {
+    let mut last_modified: Option<SystemTime> = None;
+    let mut last_listings: Option<Vec<FunctionListing>> = None;
+    loop {
+        let modified = metadata(source_file_path).and_then(|m| m.modified()).context("Failed to read source file")?;
+        if last_modified != Some(modified) {
+            last_modified = Some(modified);
+            let source = read_to_string(source_file_path).context("Failed to read source file")?;
+            if let Compiled::Chunk(chunk) = compile(&source, options) {
+                if options.disassemble {
+                    last_listings = Some(show_dasm_changes(&chunk, &source, last_listings.as_deref(), options)?);
+                }
+                run_chunk(*chunk, &source, Some(source_file_path), options)?;
+            }
+            println!("-- Waiting for {} to change --", source_file_path.display());
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Disassembles the whole chunk the first time, and after that just the changes, returning
+/// the listings to compare the next compile with
+fn show_dasm_changes(chunk: &Chunk, source: &str, last_listings: Option<&[FunctionListing]>, options: &Options) -> Result<Vec<FunctionListing>> {
+    let mut disassembler = Disassembler::new().with_source(source).with_format(options.dasm_format);
+    let listings = disassembler.listings(chunk, "Chunk")?;
+    match last_listings {
+        None => {
+            disassembler.disassemble(chunk, "Chunk")?;
+            println!();
+        },
+        Some(last_listings) => match listing_diff(last_listings, &listings).as_str() {
+            "" => println!("-- Bytecode unchanged --"),
+            diff => println!("{}", diff)
+        }
     }
+    Ok(listings)
 }
 
-fn run_file(source_file_path: &Path, trace: bool, disassemble: bool) -> Result<()> {
+fn run_file(source_file_path: &Path, options: &Options) -> Result<()> {
     let source = read_to_string(source_file_path).context("Failed to read source file")?;
-    run(source, trace, disassemble);
+    run(source, Some(source_file_path), options)
+}
+
+fn run_resume(checkpoint_path: &Path, options: &Options) -> Result<()> {
+    let checkpoint = read(checkpoint_path).context("Failed to read checkpoint file")?;
+    let mut vm = new_vm(options, None)?;
+    if let Err(e) = vm.resume(&checkpoint) {
+        report_runtime_error(&vm, e, options);
+    }
     Ok(())
 }
 
-fn run_prompt(trace: bool, disassemble: bool) -> Result<()> {
+/// Waits for an editor to connect, then debugs the script it launches
+fn run_debug_adapter(port: u16, options: &Options) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).context("Failed to listen for the debugger")?;
+    eprintln!("Waiting for a debugger on port {}", listener.local_addr().context("Failed to listen for the debugger")?.port());
+    let (stream, _) = listener.accept().context("Failed to accept the debugger's connection")?;
+    let reader = BufReader::new(stream.try_clone().context("Failed to accept the debugger's connection")?);
+    serve_debug_adapter(reader, stream, vm_options(options))
+}
+
+fn build_snapshot(snapshot_path: &Path, options: &Options) -> Result<()> {
+    let source = match &options.source_file_path {
+        Some(path) => Some(read_to_string(path).context("Failed to read source file")?),
+        None => None
+    };
+
+    let mut vm = new_vm(options, source.as_deref())?;
+    vm.load_stdlib_modules().context("Failed to load the standard library")?;
+
+    if let (Some(source), Some(path)) = (&source, &options.source_file_path) {
+        match compile(source, options) {
+            Compiled::Chunk(chunk) => {
+                vm.set_script_path(path);
+                if let Err(e) = vm.run(&chunk) {
+                    report_runtime_error(&vm, e, options);
+                    return Ok(());
+                }
+            },
+            Compiled::Empty => {},
+            Compiled::Failed => return Ok(())
+        }
+    }
+
+    let snapshot = vm.snapshot()?;
+    write(snapshot_path, snapshot).context("Failed to write snapshot file")
+}
+
+fn run_prompt(options: &Options) -> Result<()> {
     loop {
         print!("> ");
         io::stdout().flush().context("Failed to flush stdout")?;
         let mut line = String::new();
         let stdin = io::stdin();
-        stdin.lock().read_line(&mut line).context("stdin failed")?;
-        run(line, trace, disassemble);
-        println!("");
+        // Nothing read at all, not even a newline, means stdin was closed
+        if stdin.lock().read_line(&mut line).context("stdin failed")? == 0 {
+            println!();
+            return Ok(());
+        }
+        run(line, None, options)?;
+        println!();
+    }
+}
+
+fn run_lines(program: String, options: &Options) -> Result<()> {
+    let chunk = match compile(&program, options) {
+        Compiled::Chunk(c) => c,
+        Compiled::Empty | Compiled::Failed => return Ok(())
+    };
+
+    // Globals persist between lines so the program can accumulate results
+    let mut vm = new_vm(options, Some(&program))?;
+    let stdin = io::stdin();
+    for (index, line) in stdin.lock().lines().enumerate() {
+        let line = line.context("stdin failed")?;
+        vm.set_global("line", Value::String(line));
+        vm.set_global("lineNo", Value::Int((index + 1) as i64));
+
+        if let Err(e) = vm.run(&chunk) {
+            report_runtime_error(&vm, e, options);
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn run_eval(source: &str, options: &Options) -> Result<()> {
+    let mut source = source.trim_end().to_string();
+    if !source.is_empty() && !source.ends_with(';') && !source.ends_with('}') {
+        source.push(';');
+    }
+
+    let chunk = match compile_with_mode(&source, options, true) {
+        Compiled::Chunk(c) => c,
+        Compiled::Empty | Compiled::Failed => return Ok(())
+    };
+
+    let mut vm = new_vm(options, Some(&source))?;
+    match vm.run(&chunk) {
+        Ok(Value::Nil) => {},
+        Ok(value) => println!("{}", value),
+        Err(e) => report_runtime_error(&vm, e, options)
+    }
+    Ok(())
+}
+
+fn run(source: String, script_path: Option<&Path>, options: &Options) -> Result<()> {
+    match compile(&source, options) {
+        Compiled::Chunk(chunk) => run_chunk(*chunk, &source, script_path, options),
+        Compiled::Empty | Compiled::Failed => Ok(())
+    }
+}
+
+fn run_chunk(chunk: Chunk, source: &str, script_path: Option<&Path>, options: &Options) -> Result<()> {
+    let mut vm = new_vm(options, Some(source))?;
+    if let Some(path) = script_path {
+        vm.set_script_path(path);
+    }
+    let profiler = (options.profile || options.profile_json.is_some()).then(|| {
+        let profiler = Profiler::new();
+        profiler.attach(&mut vm);
+        profiler
+    });
+    if let Err(e) = vm.run(&chunk) {
+        report_runtime_error(&vm, e, options);
     }
+    if let Some(allocations) = vm.allocations() {
+        print!("{}", allocations);
+    }
+    if options.stats {
+        print!("{}", chunk_stats(&chunk)?);
+        println!("Max stack depth: {}", vm.max_stack_depth());
+    }
+    if let Some(profiler) = profiler {
+        print!("{}", profiler.profile());
+        if let Some(path) = &options.profile_json {
+            write(path, profiler.profile().to_json()).context("Failed to write profile file")?;
+        }
+    }
+    Ok(())
 }
 
-fn run(source: String, trace: bool, disassemble: bool) {
-    let compiler = Compiler::new(source);
-    let mut chunk = match compiler.compile() {
-        Ok(c) => c,
+/// What compiling a source gave, any errors in it having been reported already
+enum Compiled {
+    Chunk(Box<Chunk>),
+    /// The source has nothing but whitespace and comments, so there's nothing to run
+    Empty,
+    Failed
+}
+
+fn compile(source: &str, options: &Options) -> Compiled {
+    compile_with_mode(source, options, false)
+}
+
+fn compile_with_mode(source: &str, options: &Options, eval_mode: bool) -> Compiled {
+    // Eval mode and optimizing compile the same source differently, so they aren't cached, and
+    // warnings are only found by compiling
+    let cache = if options.cache && !eval_mode && !options.optimize && !options.warnings { ChunkCache::default_dir().map(ChunkCache::new) } else { None };
+    let compiled = match cache.as_ref().and_then(|cache| cache.get(source, dialect(options))) {
+        Some(chunk) => Ok(Some(chunk)),
+        None => {
+            let (compiled, warnings) = Compiler::new(source.to_string())
+                .with_max_errors(options.max_errors.unwrap_or(DEFAULT_MAX_ERRORS))
+                .with_eval_mode(eval_mode)
+                .with_dialect(dialect(options))
+                .with_optimizations(options.optimize)
+                .compile_nonempty_with_warnings();
+            if options.warnings {
+                for warning in warnings {
+                    match options.diagnostics_format {
+                        DiagnosticsFormat::Human => eprintln!("{}", warning),
+                        DiagnosticsFormat::Json => eprintln!("{}", StructuredDiagnostic::from_warning(&warning, source_name(options).as_deref()).to_json())
+                    }
+                }
+            }
+            if let (Some(cache), Ok(Some(chunk))) = (&cache, &compiled) {
+                if let Err(e) = cache.put(source, dialect(options), chunk) {
+                    eprintln!("Caching the compiled script failed: {:#}", e);
+                }
+            }
+            compiled
+        }
+    };
+    let chunk = match compiled {
+        Ok(Some(c)) => c,
+        Ok(None) => return Compiled::Empty,
         Err(e) => {
            match &e.downcast_ref::<CompileErrorCollection>() {
-                Some(ce) => {
-                    for e in &ce.errors {
-                        println!("{}", e);
-                    }
+                Some(ce) => for error in &ce.errors {
+                    print_compile_error(error, source, options);
                 },
                 None => {
                     println!("Compilation failed: {}", e);
                 }
             };
 
-            return;
+            return Compiled::Failed;
         }
     };
 
-    if disassemble {
-        let mut disassembler = Disassembler::new();
+    // Watch mode shows the disassembly itself, so it can show what changed
+    if options.disassemble && !options.watch {
+        let mut disassembler = Disassembler::new().with_source(source).with_format(options.dasm_format);
         match disassembler.disassemble(&chunk, "Chunk") {
             Ok(_) => println!(),
             Err(e) => {
                 println!("Disassembly failed: {}", e);
-                return;
+                return Compiled::Failed;
             }
         }
     } 
 
-    let mut vm = Vm::new(trace);
-    match vm.run(&mut chunk) {
-        Err(e) => {
-            match &e.downcast_ref::<VmError>() {
-                Some(e) => print!("{}", e),
-                None => println!("Execution error: {}", e),
+    if options.dump_stats {
+        match function_stats(&chunk) {
+            Ok(stats) => println!("{}", StatsTable(&stats)),
+            Err(e) => {
+                println!("Collecting stats failed: {}", e);
+                return Compiled::Failed;
             }
-        },
-        _ => {}
+        }
+    }
+
+    Compiled::Chunk(Box::new(chunk))
+}
+
+/// `source` is what the VM will run, if known, for showing in traces
+fn new_vm(options: &Options, source: Option<&str>) -> Result<Vm> {
+    let mut vm = Vm::new(vm_options(options));
+
+    if let (true, Some(source)) = (options.trace, source) {
+        vm.set_trace_source(source);
+    }
+
+    if let Some(path) = &options.snapshot_path {
+        let snapshot = read(path).context("Failed to read snapshot file")?;
+        vm.restore_snapshot(&snapshot)?;
+    }
+
+    Ok(vm)
+}
+
+fn vm_options(options: &Options) -> VmOptions {
+    VmOptions {
+        trace: options.trace,
+        record_global_history: options.global_history,
+        record_allocations: options.alloc_report,
+        equality_epsilon: options.equality_epsilon,
+        print_terminator: if options.no_newline { Some(String::new()) } else { None },
+        checkpoint_path: options.checkpoint_path.clone(),
+        checkpoint_every: options.checkpoint_every,
+        max_string_length: options.max_string_length,
+        max_collection_size: options.max_collection_size,
+        allow_dynamic_code: options.allow_dynamic_code,
+        module_search_path: module_search_path(options),
+        dialect: dialect(options),
+        watch: options.watchpoints.clone(),
+        strict_concatenation: options.strict_concat,
+        error_on_division_by_zero: options.error_on_division_by_zero,
+        script_args: options.script_args.clone(),
+        max_output_bytes: options.max_output_bytes,
+        stack_capacity: options.stack_capacity
+    }
+}
+
+fn dialect(options: &Options) -> Dialect {
+    Dialect { word_operators: options.word_operators, implicit_semicolons: options.implicit_semicolons,
+        expression_blocks: options.expression_blocks }
+}
+
+/// `--include` directories followed by those in the LOX_PATH environment variable
+fn module_search_path(options: &Options) -> Vec<PathBuf> {
+    let mut search_path = options.include_dirs.clone();
+    if let Some(lox_path) = env::var_os("LOX_PATH") {
+        search_path.extend(env::split_paths(&lox_path).filter(|dir| !dir.as_os_str().is_empty()));
+    }
+    search_path
+}
+
+/// Prints a compile error under the line of source it's in, with the lexeme it's about
+/// underlined, as in the following, or as JSON if that's the diagnostics format
+///
+/// ```text
+/// error[L0201]: script.lox:2:12
+///   |
+/// 2 | print 1 + 2 3;
+///   |             ^ Expected ';' after value.
+/// ```
+fn print_compile_error(error: &CompileError, source: &str, options: &Options) {
+    if options.diagnostics_format == DiagnosticsFormat::Json {
+        println!("{}", StructuredDiagnostic::from_compile_error(error, source, source_name(options).as_deref()).to_json());
+        return;
+    }
+    let Some((offset, len)) = error.span() else {
+        println!("error[{}]: {}", error.code(), error);
+        return;
     };
+    let offset = offset.min(source.len());
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[offset..].find('\n').map_or(source.len(), |i| offset + i);
+    let line_number = source[..line_start].matches('\n').count() + 1;
+    let column = source[line_start..offset].chars().count() + 1;
+    let underlined = source[offset..line_end].chars().take(len).count().max(1);
+
+    let name = source_name(options).unwrap_or_else(|| "<input>".to_string());
+    // Tabs are kept so the underline lines up with what's above it however wide they show
+    let indent: String = source[line_start..offset].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    let gutter = " ".repeat(line_number.to_string().len());
+    println!("error[{}]: {}:{}:{}", error.code(), name, line_number, column);
+    println!("{} |", gutter);
+    println!("{} | {}", line_number, &source[line_start..line_end]);
+    println!("{} | {}{} {}", gutter, indent, "^".repeat(underlined), error.message());
+    println!();
+}
+
+fn report_runtime_error(vm: &Vm, e: anyhow::Error, options: &Options) {
+    if let (DiagnosticsFormat::Json, Some(last_error)) = (options.diagnostics_format, vm.last_error()) {
+        println!("{}", StructuredDiagnostic::from_runtime_error(last_error, source_name(options).as_deref()).to_json());
+        return;
+    }
+
+    match &e.downcast_ref::<VmError>() {
+        Some(e) => {
+            println!("error[{}]: {}", vm.last_error().map_or(e.code(), |last_error| last_error.code), e);
+            if let Some(stack_trace) = e.trace() {
+                let max_frames = if options.full_trace { None } else { Some(DEFAULT_TRACE_FRAME_LIMIT) };
+                print!("{}", stack_trace.format(max_frames));
+            }
+        },
+        None => println!("Execution error: {}", e),
+    }
+
+    if let Some(history) = vm.global_history() {
+        if !history.is_empty() {
+            print!("{}", history);
+        }
+    }
+}
+
+/// The script file's name as diagnostics give it, None when the source isn't from a file
+fn source_name(options: &Options) -> Option<String> {
+    options.source_file_path.as_ref().map(|path| path.display().to_string())
 }
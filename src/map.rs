@@ -0,0 +1,144 @@
+use std::{fmt::Display, hash::{Hash, Hasher}, rc::Rc};
+
+use anyhow::{Result, bail};
+
+use crate::{ordered_map::OrderedMap, value::{Value, int_equals_float, printing}, vm::Vm};
+
+pub fn define_natives(vm: &mut Vm) {
+    vm.define_native("map", 0, Rc::new(|_, _| Ok(Value::map(Map::new()))));
+}
+
+/// A value usable as a map key. Lists and maps can change after being inserted so they
+/// aren't allowed, and neither is NaN, which isn't equal to itself.
+#[derive(Debug, Clone)]
+pub struct MapKey(Value);
+
+impl MapKey {
+    pub fn new(value: Value) -> Result<Self> {
+        match &value {
+            Value::List(_) | Value::Map(_) => bail!("Can't use {} as a map key", value),
+            Value::Number(n) if n.is_nan() => bail!("Can't use NaN as a map key"),
+            _ => Ok(Self(value))
+        }
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // An int and a number holding the same value are equal so they must hash alike
+        if !matches!(self.0, Value::Number(_) | Value::Int(_)) {
+            std::mem::discriminant(&self.0).hash(state);
+        }
+        match &self.0 {
+            Value::Int(n) => n.hash(state),
+            // This also hashes -0 like 0, which it equals
+            Value::Number(n) if int_equals_float(*n as i64, *n) => (*n as i64).hash(state),
+            Value::Number(n) => n.to_bits().hash(state),
+            Value::Nil => {},
+            Value::Boolean(b) => b.hash(state),
+            Value::String(s) => s.hash(state),
+            Value::Bytes(b) => b.hash(state),
+            Value::Foreign(foreign) => foreign.hash(state),
+            // Everything else is compared by identity
+            Value::Function(f) => Rc::as_ptr(f).hash(state),
+            Value::Closure(c) => Rc::as_ptr(c).hash(state),
+            Value::Class(c) => Rc::as_ptr(c).hash(state),
+            Value::Instance(i) => Rc::as_ptr(i).hash(state),
+            Value::BoundMethod(b) => Rc::as_ptr(b).hash(state),
+            Value::Native(n) => Rc::as_ptr(n).hash(state),
+            Value::List(_) | Value::Map(_) => unreachable!("Lists and maps are not map keys")
+        }
+    }
+}
+
+/// Entries iterate in the order their keys were first inserted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Map {
+    entries: OrderedMap<MapKey, Value>
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&Value> {
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: MapKey, value: Value) {
+        self.entries.insert(key, value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&MapKey, &Value)> {
+        self.entries.iter()
+    }
+}
+
+impl PartialOrd for Map {
+    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
+        None
+    }
+}
+
+impl Display for Map {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        printing((self as *const Map).cast(), || {
+            write!(f, "{{")?;
+            for (i, (key, value)) in self.entries.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: {}", key.0, value)?;
+            }
+            write!(f, "}}")
+        }).unwrap_or_else(|| write!(f, "{{...}}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_keys_are_the_same_key() {
+        let mut map = Map::new();
+        map.insert(MapKey::new(Value::Number(0.0)).unwrap(), Value::Boolean(true));
+        assert_eq!(map.get(&MapKey::new(Value::Number(-0.0)).unwrap()), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn int_and_number_holding_the_same_value_are_the_same_key() {
+        let mut map = Map::new();
+        map.insert(MapKey::new(Value::Int(2)).unwrap(), Value::Boolean(true));
+        assert_eq!(map.get(&MapKey::new(Value::Number(2.0)).unwrap()), Some(&Value::Boolean(true)));
+        assert_eq!(map.get(&MapKey::new(Value::Number(2.5)).unwrap()), None);
+    }
+
+    #[test]
+    fn mutable_and_nan_keys_are_rejected() {
+        assert!(MapKey::new(Value::list(Vec::new())).is_err());
+        assert!(MapKey::new(Value::map(Map::new())).is_err());
+        assert!(MapKey::new(Value::Number(f64::NAN)).is_err());
+    }
+}
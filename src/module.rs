@@ -0,0 +1,84 @@
+//! Scripts loaded with `import`. Each runs once, with globals of its own, and only the
+//! names it exports are bound in the scripts importing it.
+
+use std::{collections::HashSet, path::{Path, PathBuf}, rc::Rc};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{chunk::Chunk, function::Function, ordered_map::OrderedMap, value::Value};
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    /// Canonical path of the file, so the same file imported by different paths is only run once
+    pub path: PathBuf,
+    pub globals: OrderedMap<String, Value>,
+    /// Globals declared with `const`
+    pub const_globals: HashSet<String>,
+    /// Names the module exports, set once it has finished running
+    pub exports: Option<Vec<String>>
+}
+
+/// Finds the file an import refers to. A relative path is looked up first in the directory
+/// of the importing script, or the current directory if its path isn't known, then in each
+/// directory of `search_path` in turn.
+pub fn resolve(path: &str, importer: Option<&Path>, search_path: &[PathBuf]) -> Result<PathBuf> {
+    let path = Path::new(path);
+    let candidates: Vec<PathBuf> = if path.is_absolute() {
+        vec![path.to_path_buf()]
+    } else {
+        let importer_dir = importer.and_then(Path::parent).unwrap_or(Path::new(""));
+        std::iter::once(importer_dir).chain(search_path.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(path))
+            .collect()
+    };
+
+    match candidates.iter().find(|candidate| candidate.is_file()) {
+        Some(found) => found.canonicalize().with_context(|| format!("Failed to import {}", found.display())),
+        None => bail!("Module {} not found", path.display())
+    }
+}
+
+/// Places a precompiled script, and every function declared in it, in the module at `index`
+pub fn link(function: &Function, index: usize) -> Function {
+    let constants = function.chunk.constants().iter()
+        .map(|constant| match constant {
+            Value::Function(declared) => Value::Function(Rc::new(link(declared, index))),
+            other => other.clone()
+        })
+        .collect();
+    let mut chunk = Chunk::from_parts(function.chunk.code().to_vec(), function.chunk.src_line_numbers().to_vec(), constants);
+    chunk.set_exports(function.chunk.exports().to_vec());
+    chunk.set_required_capabilities(function.chunk.required_capabilities().to_vec());
+    chunk.set_local_vars(function.chunk.local_vars().to_vec());
+    chunk.set_upvalue_names(function.chunk.upvalue_names().to_vec());
+
+    Function { name: function.name.clone(), arity: function.arity, variadic: function.variadic, chunk, upvalues: function.upvalues.clone(), module: Some(index) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, file: &str) -> PathBuf {
+        let path = dir.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "").unwrap();
+        path.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn importing_directory_comes_before_the_search_path() {
+        let dir = std::env::temp_dir().join(format!("lox-resolve-{}", std::process::id()));
+        let local = write(&dir, "app/util.lox");
+        let shared = write(&dir, "shared/util.lox");
+        let only_shared = write(&dir, "shared/extra.lox");
+        let importer = dir.join("app/main.lox");
+        let search_path = [dir.join("shared")];
+
+        assert_eq!(resolve("util.lox", Some(&importer), &search_path).unwrap(), local);
+        assert_eq!(resolve("../shared/util.lox", Some(&importer), &[]).unwrap(), shared);
+        assert_eq!(resolve("extra.lox", Some(&importer), &search_path).unwrap(), only_shared);
+        assert!(resolve("extra.lox", Some(&importer), &[]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
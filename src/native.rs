@@ -0,0 +1,198 @@
+use std::{cell::Ref, fmt::{Debug, Display}, rc::Rc};
+
+use anyhow::{Result, bail};
+
+use crate::{map::Map, value::Value, vm::Vm};
+
+pub type NativeFn = dyn Fn(&mut Vm, &[Value]) -> Result<Value>;
+
+/// A function implemented in Rust and callable from Lox
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: u8,
+    /// Whether it takes any number of arguments after the `arity` it must have
+    pub variadic: bool,
+    pub function: Rc<NativeFn>
+}
+
+impl NativeFunction {
+    pub fn new<N: Into<String>>(name: N, arity: u8, function: Rc<NativeFn>) -> Self {
+        Self { name: name.into(), arity, variadic: false, function }
+    }
+
+    pub fn with_variadic(self, variadic: bool) -> Self {
+        Self { variadic, ..self }
+    }
+}
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeFunction").field("name", &self.name).field("arity", &self.arity).field("variadic", &self.variadic).finish()
+    }
+}
+
+// Natives are compared by identity
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for NativeFunction {
+    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
+        None
+    }
+}
+
+impl Display for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+/// The arguments a native was called with, for getting them as the types it expects. Errors
+/// name the native and the argument, e.g. "scan expects a string as argument 2, got nil".
+#[derive(Debug, Clone, Copy)]
+pub struct Args<'a> {
+    native: &'a str,
+    values: &'a [Value]
+}
+
+impl<'a> Args<'a> {
+    pub fn new(native: &'a str, values: &'a [Value]) -> Self {
+        Self { native, values }
+    }
+
+    /// Fails unless there are exactly `count` arguments
+    pub fn expect_count(&self, count: usize) -> Result<()> {
+        if self.values.len() != count {
+            bail!("{} expects {} arguments but got {}", self.native, count, self.values.len());
+        }
+        Ok(())
+    }
+
+    /// The argument at `index`, of any type
+    pub fn get(&self, index: usize) -> Result<&'a Value> {
+        match self.values.get(index) {
+            Some(value) => Ok(value),
+            None => bail!("{} expects at least {} arguments but got {}", self.native, index + 1, self.values.len())
+        }
+    }
+
+    pub fn get_number(&self, index: usize) -> Result<f64> {
+        let value = self.get(index)?;
+        value.as_f64().ok_or_else(|| self.type_error(index, "a number", value))
+    }
+
+    /// A number without a fractional part
+    pub fn get_int(&self, index: usize) -> Result<i64> {
+        match self.get(index)? {
+            Value::Int(n) => Ok(*n),
+            Value::Number(n) if n.fract() == 0.0 && *n >= i64::MIN as f64 && *n < i64::MAX as f64 => Ok(*n as i64),
+            value => Err(self.type_error(index, "an integer", value))
+        }
+    }
+
+    pub fn get_bool(&self, index: usize) -> Result<bool> {
+        match self.get(index)? {
+            Value::Boolean(b) => Ok(*b),
+            value => Err(self.type_error(index, "a bool", value))
+        }
+    }
+
+    pub fn get_string(&self, index: usize) -> Result<&'a str> {
+        match self.get(index)? {
+            Value::String(s) => Ok(s),
+            value => Err(self.type_error(index, "a string", value))
+        }
+    }
+
+    pub fn get_list(&self, index: usize) -> Result<Ref<'a, Vec<Value>>> {
+        match self.get(index)? {
+            Value::List(items) => Ok(items.borrow()),
+            value => Err(self.type_error(index, "a list", value))
+        }
+    }
+
+    pub fn get_map(&self, index: usize) -> Result<Ref<'a, Map>> {
+        match self.get(index)? {
+            Value::Map(map) => Ok(map.borrow()),
+            value => Err(self.type_error(index, "a map", value))
+        }
+    }
+
+    pub fn get_bytes(&self, index: usize) -> Result<&'a Rc<Vec<u8>>> {
+        match self.get(index)? {
+            Value::Bytes(bytes) => Ok(bytes),
+            value => Err(self.type_error(index, "bytes", value))
+        }
+    }
+
+    fn type_error(&self, index: usize, expected: &str, value: &Value) -> anyhow::Error {
+        anyhow::anyhow!("{} expects {} as argument {}, got {}", self.native, expected, index + 1, value.type_name())
+    }
+}
+
+/// `clock()`: seconds since the VM started, for timing scripts like the benchmarks in
+/// Crafting Interpreters
+pub fn clock(vm: &mut Vm, _args: &[Value]) -> Result<Value> {
+    Ok(Value::Number(vm.uptime().as_secs_f64()))
+}
+
+/// `time_ms()`: milliseconds since the VM started, with the fraction of a millisecond
+pub fn time_ms(vm: &mut Vm, _args: &[Value]) -> Result<Value> {
+    Ok(Value::Number(vm.uptime().as_secs_f64() * 1000.0))
+}
+
+/// `args()`: the arguments given to the script, as a list of strings
+pub fn args(vm: &mut Vm, _args: &[Value]) -> Result<Value> {
+    Ok(Value::list(vm.script_args().iter().cloned().map(Value::String).collect()))
+}
+
+/// `env(name)`: the value of an environment variable, or nil if it isn't set or isn't valid unicode
+pub fn env(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let name = Args::new("env", args).get_string(0)?;
+    Ok(std::env::var(name).map_or(Value::Nil, Value::String))
+}
+
+/// `input(prompt)`: prints the prompt, without a newline, and reads a line of input,
+/// giving nil once there's nothing left to read
+pub fn input(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let prompt = Args::new("input", args).get_string(0)?;
+    vm.write_output(prompt)?;
+    Ok(vm.read_line()?.map_or(Value::Nil, Value::String))
+}
+
+/// `type(v)`: the name of the value's type, such as "number", "string", "bool" or "nil"
+pub fn type_of(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::String(Args::new("type", args).get(0)?.type_name().to_string()))
+}
+
+/// `num(v)`: a number as it is, or the number written in a string, ignoring whitespace around
+/// it, or nil if the string isn't a finite number. Values of other types are an error.
+pub fn num(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    match Args::new("num", args).get(0)? {
+        number @ (Value::Int(_) | Value::Number(_)) => Ok(number.clone()),
+        Value::String(s) => {
+            let s = s.trim();
+            Ok(match (s.parse::<i64>(), s.parse::<f64>()) {
+                (Ok(n), _) => Value::Int(n),
+                (_, Ok(n)) if n.is_finite() => Value::Number(n),
+                _ => Value::Nil
+            })
+        },
+        value => bail!("num expects a number or string as argument 1, got {}", value.type_name())
+    }
+}
+
+/// `str(v)`: the value as `print` shows it
+pub fn str(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::String(Args::new("str", args).get(0)?.to_string()))
+}
+
+/// `approxEquals(a, b, epsilon)`: whether two numbers differ by no more than epsilon
+pub fn approx_equals(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let args = Args::new("approxEquals", args);
+    let (a, b, epsilon) = (args.get_number(0)?, args.get_number(1)?, args.get_number(2)?);
+    Ok(Value::Boolean((a - b).abs() <= epsilon))
+}
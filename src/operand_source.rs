@@ -0,0 +1,71 @@
+//! Working out from the bytecode what produced an operand, so errors about a bad operand
+//! can name the variable it came from
+
+use crate::{chunk::Chunk, instruction::{Instruction, InstructionReader, OpCode}};
+
+/// The source of the expression that pushed the operand `depth` values below the top of the
+/// stack when the instruction at `offset` runs, such as `total` or `cart.total()`. `None` if
+/// it's not a chain of variables, properties and calls, or can't be told for sure, as when
+/// control flow joins in between.
+pub fn describe_operand(chunk: &Chunk, offset: usize, depth: usize) -> Option<String> {
+    let mut reader = InstructionReader::new(chunk);
+    let mut before = Vec::new();
+    let mut jump_targets = Vec::new();
+    while let Ok(Some((instruction, instruction_offset, _))) = reader.read_next() {
+        if let Some(target) = instruction.jump_target(instruction_offset) {
+            jump_targets.push(target);
+        }
+        if instruction_offset < offset {
+            before.push((instruction, instruction_offset));
+        }
+    }
+
+    describe_pushed(chunk, &before, &jump_targets, offset, depth)
+}
+
+/// Like `describe_operand`, with `before` the instructions preceding the one at `offset`
+fn describe_pushed(chunk: &Chunk, before: &[(Instruction, usize)], jump_targets: &[usize], offset: usize, depth: usize) -> Option<String> {
+    // Going back from the instruction, each one of an expression consumed some of the values
+    // after it and pushed one. The operand is the value pushed when none are left to skip.
+    let mut skip = depth as i32;
+    for (i, (instruction, instruction_offset)) in before.iter().enumerate().rev() {
+        if !pushes_one_value(instruction) {
+            return None;
+        }
+        if skip == 0 {
+            if jump_targets.iter().any(|target| *target > *instruction_offset && *target <= offset) {
+                return None;
+            }
+            return describe(chunk, &before[..i], jump_targets, instruction, *instruction_offset);
+        }
+        skip += -instruction.stack_effect();
+    }
+
+    None
+}
+
+/// Whether the instruction is one of an expression's, which all leave exactly one value
+fn pushes_one_value(instruction: &Instruction) -> bool {
+    !matches!(instruction.op_code,
+        OpCode::Return | OpCode::Print | OpCode::PrintErr | OpCode::Pop | OpCode::DefineGlobal | OpCode::DefineGlobalLong
+        | OpCode::DefineConstGlobal | OpCode::DefineConstGlobalLong
+        | OpCode::Jump | OpCode::JumpLong | OpCode::JumpIfFalse | OpCode::Loop | OpCode::LoopLong | OpCode::CloseUpvalue
+        | OpCode::Method | OpCode::MethodLong | OpCode::Getter | OpCode::GetterLong
+        | OpCode::Inherit | OpCode::PushHandler | OpCode::PopHandler | OpCode::Throw | OpCode::Import | OpCode::ImportLong | OpCode::Assert | OpCode::LessJumpIfFalse)
+}
+
+fn describe(chunk: &Chunk, before: &[(Instruction, usize)], jump_targets: &[usize], instruction: &Instruction, offset: usize) -> Option<String> {
+    let constant_name = || instruction.name_index().and_then(|index| chunk.get_constant(index).ok()).map(|name| name.to_string());
+    // What the instruction at `offset` itself took from `depth` below the top of the stack
+    let operand = |depth: usize| describe_pushed(chunk, before, jump_targets, offset, depth);
+    match instruction.op_code {
+        OpCode::GetGlobal | OpCode::SetGlobal | OpCode::GetGlobalLong | OpCode::SetGlobalLong => constant_name(),
+        OpCode::GetLocal | OpCode::SetLocal | OpCode::GetLocalLong | OpCode::SetLocalLong => instruction.local_slot().and_then(|slot| chunk.local_name(slot, offset)).map(str::to_string),
+        OpCode::GetProperty | OpCode::GetPropertyLong => Some(format!("{}.{}", operand(0)?, constant_name()?)),
+        OpCode::Call => Some(format!("{}()", operand(instruction.operand1? as usize)?)),
+        // The constant is the last argument, pushed by the instruction itself
+        OpCode::ConstantCall => Some(format!("{}()", operand(instruction.operand2?.checked_sub(1)? as usize)?)),
+        OpCode::Invoke | OpCode::InvokeLong => Some(format!("{}.{}()", operand(instruction.arg_count() as usize)?, constant_name()?)),
+        _ => None
+    }
+}
@@ -0,0 +1,122 @@
+//! A hash map that iterates in the order keys were first inserted, so anything listing
+//! globals or map entries gives the same output from run to run
+
+use std::{borrow::Borrow, collections::HashMap, fmt::Debug, hash::Hash};
+
+#[derive(Clone)]
+pub struct OrderedMap<K, V> {
+    entries: Vec<(K, V)>,
+    /// Position of each key in `entries`
+    index: HashMap<K, usize>
+}
+
+impl<K: Clone + Eq + Hash, V> OrderedMap<K, V> {
+    pub fn new() -> Self {
+        Self { entries: Vec::new(), index: HashMap::new() }
+    }
+
+    pub fn get<Q: Eq + Hash + ?Sized>(&self, key: &Q) -> Option<&V> where K: Borrow<Q> {
+        self.index.get(key).map(|i| &self.entries[*i].1)
+    }
+
+    /// Sets the value of `key`, returning the old one. A key already present keeps its place.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.insert_full(key, value).1
+    }
+
+    /// Like `insert`, also giving the slot of the key's entry
+    pub fn insert_full(&mut self, key: K, value: V) -> (usize, Option<V>) {
+        match self.index.get(&key) {
+            Some(i) => (*i, Some(std::mem::replace(&mut self.entries[*i].1, value))),
+            None => {
+                let slot = self.entries.len();
+                self.index.insert(key.clone(), slot);
+                self.entries.push((key, value));
+                (slot, None)
+            }
+        }
+    }
+
+    /// Where the key's entry is, which stays the same for as long as the map exists
+    pub fn slot<Q: Eq + Hash + ?Sized>(&self, key: &Q) -> Option<usize> where K: Borrow<Q> {
+        self.index.get(key).copied()
+    }
+
+    pub fn get_slot(&self, slot: usize) -> Option<(&K, &V)> {
+        self.entries.get(slot).map(|(k, v)| (k, v))
+    }
+
+    pub fn get_slot_mut(&mut self, slot: usize) -> Option<&mut V> {
+        self.entries.get_mut(slot).map(|(_, v)| v)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+}
+
+impl<K: Clone + Eq + Hash, V> Default for OrderedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Debug, V: Debug> Debug for OrderedMap<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.entries.iter().map(|(k, v)| (k, v))).finish()
+    }
+}
+
+// Maps holding the same entries are equal whatever order they were inserted in
+impl<K: Clone + Eq + Hash, V: PartialEq> PartialEq for OrderedMap<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> FromIterator<(K, V)> for OrderedMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iterates_in_insertion_order() {
+        let mut map: OrderedMap<String, i32> = ["c", "a", "b"].iter().enumerate().map(|(i, k)| (k.to_string(), i as i32)).collect();
+        assert_eq!(map.insert("a".to_string(), 10), Some(1));
+        map.insert("d".to_string(), 3);
+        assert_eq!(map.iter().map(|(k, v)| (k.as_str(), *v)).collect::<Vec<_>>(), [("c", 0), ("a", 10), ("b", 2), ("d", 3)]);
+        assert_eq!(map.get("d"), Some(&3));
+        assert!(map.slot("e").is_none());
+
+        let slot = map.slot("a").unwrap();
+        *map.get_slot_mut(slot).unwrap() = 11;
+        map.insert("e".to_string(), 4);
+        assert_eq!(map.get_slot(slot), Some((&"a".to_string(), &11)));
+        assert_eq!(map.get_slot(map.len()), None);
+    }
+
+    #[test]
+    fn equality_ignores_order() {
+        let a: OrderedMap<&str, i32> = [("x", 1), ("y", 2)].into_iter().collect();
+        let b: OrderedMap<&str, i32> = [("y", 2), ("x", 1)].into_iter().collect();
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,75 @@
+//! Where the VM and the disassembler write what they print: stdout and stderr unless a host
+//! gives them somewhere else, such as an `OutputBuffer` to read it back from.
+
+use std::{cell::RefCell, fmt::Debug, io::{self, Write}, rc::Rc};
+
+/// A writer that clones write to, so that the disassembler tracing a run can write to the
+/// same place as the VM
+#[derive(Clone)]
+pub struct SharedOutput(Rc<RefCell<Box<dyn Write>>>);
+
+impl SharedOutput {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self(Rc::new(RefCell::new(out)))
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(Box::new(io::stdout()))
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(Box::new(io::stderr()))
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+impl Debug for SharedOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<output>")
+    }
+}
+
+/// Keeps what's written to it in memory. Clones share the same buffer, so one can be given
+/// to a `Vm` or `Disassembler` and another kept to read what they wrote.
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A writer into this buffer, for `Vm::set_output` or `Disassembler::with_output`
+    pub fn writer(&self) -> Box<dyn Write> {
+        Box::new(self.clone())
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    /// What was written, with anything that isn't UTF-8 replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
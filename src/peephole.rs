@@ -0,0 +1,354 @@
+//! Rewrites runs of instructions into fewer that do the same: pairs that often come one after
+//! the other are fused into a single instruction doing the work of both, so that loops go
+//! through the dispatch loop fewer times, operators on constants are folded into the constant
+//! they give, and code that can't be reached is dropped. It runs over each chunk once it's
+//! compiled, since only then are all the jumps into it known.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::{chunk::{Chunk, LocalVar}, instruction::{Instruction, InstructionReader, MAX_LONG_OPERAND, OpCode}, value::Value};
+
+/// An instruction as decoded, with its offset and line
+type Decoded = (Instruction, usize, i32);
+
+/// The instruction doing what `first` and then `second` do, if there's one
+fn fused(first: &Instruction, second: &Instruction) -> Option<Instruction> {
+    match (&first.op_code, &second.op_code) {
+        (OpCode::GetLocal, OpCode::Add) => Some(Instruction::unary(OpCode::GetLocalAdd, first.operand1?)),
+        (OpCode::Constant, OpCode::Call) => Some(Instruction::binary(OpCode::ConstantCall, first.operand1?, second.operand1?)),
+        (OpCode::Less, OpCode::JumpIfFalse) => Some(Instruction::binary(OpCode::LessJumpIfFalse, second.operand1?, second.operand2?)),
+        _ => None
+    }
+}
+
+/// Rewrites a compiled chunk into fewer instructions doing the same. Folding constants and
+/// dropping dead code are left to `optimizing`, since they change which lines the code has.
+pub fn optimize(chunk: &mut Chunk, optimizing: bool) -> Result<()> {
+    if optimizing {
+        fold_constants(chunk)?;
+        eliminate_dead_code(chunk)?;
+    }
+    fuse_instructions(chunk)
+}
+
+/// Replaces each pair of instructions that `fused` has an instruction for
+fn fuse_instructions(chunk: &mut Chunk) -> Result<()> {
+    rewrite(chunk, |_, window| match window {
+        [(first, _, _), (second, _, _), ..] => Ok(fused(first, second).map(|fused| (Some(fused), 2))),
+        _ => Ok(None)
+    })?;
+    Ok(())
+}
+
+/// Replaces each operator applied to constants with the constant it gives, as in `2 * 3 + 1`
+/// or `!true`
+fn fold_constants(chunk: &mut Chunk) -> Result<()> {
+    // Each pass folds the innermost operators, whose results are operands of those around them
+    while rewrite(chunk, |chunk, window| Ok(folded_window(chunk, window).map(|(folded, count)| (Some(folded), count))))? {}
+    Ok(())
+}
+
+/// The constant that the operator after the constants starting `window` gives, loaded by a
+/// single instruction, and how many instructions that replaces
+fn folded_window(chunk: &mut Chunk, window: &[Decoded]) -> Option<(Instruction, usize)> {
+    let a = constant(chunk, &window.first()?.0)?;
+    let second = &window.get(1)?.0;
+    if let Some(value) = folded(&second.op_code, std::slice::from_ref(&a)) {
+        return Some((load(chunk, value)?, 2));
+    }
+
+    let b = constant(chunk, second)?;
+    let value = folded(&window.get(2)?.0.op_code, &[a, b])?;
+    Some((load(chunk, value)?, 3))
+}
+
+/// Drops the instructions that no path through the code reaches, and the tests of conditions
+/// that are always true, which leave nothing behind
+fn eliminate_dead_code(chunk: &mut Chunk) -> Result<()> {
+    let reachable = reachable_offsets(chunk)?;
+    rewrite(chunk, |chunk, window| {
+        let dead = window.iter().take_while(|(_, offset, _)| !reachable.contains(offset)).count();
+        if dead > 0 {
+            return Ok(Some((None, dead)));
+        }
+        match window {
+            [(condition, _, _), (jump, _, _), (pop, _, _), ..] if matches!((&jump.op_code, &pop.op_code), (OpCode::JumpIfFalse, OpCode::Pop))
+                && constant(chunk, condition).is_some_and(|value| value.is_truthy()) => Ok(Some((None, 3))),
+            _ => Ok(None)
+        }
+    })?;
+    Ok(())
+}
+
+/// The offsets of the instructions that some path through the code from its start reaches.
+/// A conditional jump right after a constant only goes the one way the constant sends it.
+fn reachable_offsets(chunk: &Chunk) -> Result<HashSet<usize>> {
+    let mut instructions = Vec::new();
+    let mut jump_targets = HashSet::new();
+    let mut reader = InstructionReader::new(chunk);
+    while let Some((instruction, offset, _)) = reader.read_next()? {
+        jump_targets.extend(instruction.jump_target(offset));
+        instructions.push((instruction, offset));
+    }
+    let indices: HashMap<usize, usize> = instructions.iter().enumerate().map(|(i, (_, offset))| (*offset, i)).collect();
+
+    let mut reachable = HashSet::new();
+    let mut pending = vec![0];
+    while let Some(i) = pending.pop() {
+        let Some((instruction, offset)) = instructions.get(i) else { continue };
+        if !reachable.insert(*offset) {
+            continue;
+        }
+        let target = match instruction.jump_target(*offset) {
+            Some(target) => Some(*indices.get(&target).ok_or_else(|| anyhow!("No instruction starts at offset {}", target))?),
+            None => None
+        };
+        match instruction.op_code {
+            OpCode::Return | OpCode::Throw => {},
+            OpCode::Jump | OpCode::JumpLong | OpCode::Loop | OpCode::LoopLong => pending.extend(target),
+            OpCode::JumpIfFalse => {
+                let condition = i.checked_sub(1)
+                    .filter(|_| !jump_targets.contains(offset))
+                    .and_then(|previous| constant(chunk, &instructions[previous].0));
+                match condition {
+                    Some(value) if value.is_truthy() => pending.push(i + 1),
+                    Some(_) => pending.extend(target),
+                    None => pending.extend(target.into_iter().chain([i + 1]))
+                }
+            },
+            _ => pending.extend(target.into_iter().chain([i + 1]))
+        }
+    }
+    Ok(reachable)
+}
+
+/// The value an instruction pushes, if it's always the same
+fn constant(chunk: &Chunk, instruction: &Instruction) -> Option<Value> {
+    match instruction.op_code {
+        OpCode::Nil => Some(Value::Nil),
+        OpCode::True => Some(Value::Boolean(true)),
+        OpCode::False => Some(Value::Boolean(false)),
+        OpCode::Constant => chunk.get_constant(instruction.operand1? as usize).ok(),
+        OpCode::ConstantLong => chunk.get_constant(instruction.long_operand()?).ok(),
+        _ => None
+    }
+}
+
+/// An instruction pushing `value`, adding it to the chunk's constants if it needs to be there
+fn load(chunk: &mut Chunk, value: Value) -> Option<Instruction> {
+    Some(match value {
+        Value::Nil => Instruction::simple(OpCode::Nil),
+        Value::Boolean(true) => Instruction::simple(OpCode::True),
+        Value::Boolean(false) => Instruction::simple(OpCode::False),
+        value => match chunk.add_constant(value) {
+            index if index <= u8::MAX as usize => Instruction::unary(OpCode::Constant, index as u8),
+            index if index <= MAX_LONG_OPERAND => Instruction::with_operands(OpCode::ConstantLong, &(index as u32).to_be_bytes()[1..]),
+            _ => return None
+        }
+    })
+}
+
+/// What the operator gives applied to the operands, worked out as the VM would at runtime.
+/// None if it can fail, or gives something that depends on how the VM is set up, such as
+/// division by zero or comparing numbers for equality.
+fn folded(op_code: &OpCode, operands: &[Value]) -> Option<Value> {
+    let is_number = |value: &Value| value.as_f64().is_some();
+    match (op_code, operands) {
+        (OpCode::Negate, [Value::Int(n)]) => Some(n.checked_neg().map_or(Value::Number(-(*n as f64)), Value::Int)),
+        (OpCode::Negate, [Value::Number(n)]) => Some(Value::Number(-n)),
+        (OpCode::Not, [value]) => Some(Value::Boolean(!value.is_truthy())),
+        (OpCode::Add, [a, b]) => arithmetic(a, b, i64::checked_add, |a, b| a + b),
+        (OpCode::Subtract, [a, b]) => arithmetic(a, b, i64::checked_sub, |a, b| a - b),
+        (OpCode::Multiply, [a, b]) => arithmetic(a, b, i64::checked_mul, |a, b| a * b),
+        (OpCode::Divide | OpCode::Modulo, [_, b]) if b.as_f64() == Some(0.0) => None,
+        (OpCode::Divide, [a, b]) => arithmetic(a, b, |a, b| a.checked_rem(b).filter(|r| *r == 0).and(a.checked_div(b)), |a, b| a / b),
+        (OpCode::Modulo, [a, b]) => arithmetic(a, b, i64::checked_rem, |a, b| a % b),
+        (OpCode::Greater, [a, b]) if is_number(a) && is_number(b) => Some(Value::Boolean(a > b)),
+        (OpCode::Less, [a, b]) if is_number(a) && is_number(b) => Some(Value::Boolean(a < b)),
+        (OpCode::Equal, [a, b]) if !is_number(a) && !is_number(b) => Some(Value::Boolean(a == b)),
+        _ => None
+    }
+}
+
+/// `int_op` on two ints, unless it can't represent the result, and `float_op` on any other
+/// numbers
+fn arithmetic(a: &Value, b: &Value, int_op: impl FnOnce(i64, i64) -> Option<i64>, float_op: impl FnOnce(f64, f64) -> f64) -> Option<Value> {
+    if let (Value::Int(a), Value::Int(b)) = (a, b) {
+        if let Some(result) = int_op(*a, *b) {
+            return Some(Value::Int(result));
+        }
+    }
+    Some(Value::Number(float_op(a.as_f64()?, b.as_f64()?)))
+}
+
+/// Replaces instructions as `replacement` says, returning whether it replaced any. It's given
+/// the instructions from each one on, up to the next one the code jumps to, and gives what
+/// replaces how many of them, or nothing if they're dropped. Jumps are given their new
+/// distances, which only get shorter, and the ranges of local variables are moved with the code.
+fn rewrite(chunk: &mut Chunk, mut replacement: impl FnMut(&mut Chunk, &[Decoded]) -> Result<Option<(Option<Instruction>, usize)>>) -> Result<bool> {
+    let mut instructions = Vec::new();
+    let mut jump_targets = HashSet::new();
+    let mut reader = InstructionReader::new(chunk);
+    while let Some((instruction, offset, line)) = reader.read_next()? {
+        jump_targets.extend(instruction.jump_target(offset));
+        instructions.push((instruction, offset, line));
+    }
+    // Where the window from each instruction ends, which is at the next one the code jumps to
+    let mut window_ends = vec![instructions.len(); instructions.len()];
+    for j in (0..instructions.len().saturating_sub(1)).rev() {
+        window_ends[j] = if jump_targets.contains(&instructions[j + 1].1) { j + 1 } else { window_ends[j + 1] };
+    }
+
+    // The instructions kept, each with where it jumps to in the code as it was
+    let mut kept = Vec::with_capacity(instructions.len());
+    let mut new_offsets = HashMap::new();
+    let mut len = 0;
+    let mut i = 0;
+    while i < instructions.len() {
+        let (instruction, offset, line) = &instructions[i];
+        new_offsets.insert(*offset, len);
+        // Jumps keep the width they had, which is only safe while the code gets no longer
+        let replaced = replacement(chunk, &instructions[i..window_ends[i]])?
+            .filter(|(replacement, count)| {
+                let (last, last_offset, _) = &instructions[i + count - 1];
+                replacement.as_ref().map_or(*offset, |replacement| replacement.next_offset(*offset)) <= last.next_offset(*last_offset)
+            });
+        let (instruction, target, line) = match replaced {
+            // Errors are reported at the last instruction's line, since it's the one whose work
+            // can fail
+            Some((replacement, count)) => {
+                let (last, last_offset, last_line) = &instructions[i + count - 1];
+                for (_, replaced_offset, _) in &instructions[i + 1..i + count] {
+                    new_offsets.insert(*replaced_offset, len);
+                }
+                i += count;
+                match replacement {
+                    Some(replacement) => (replacement, last.jump_target(*last_offset), *last_line),
+                    None => continue
+                }
+            },
+            None => {
+                i += 1;
+                (instruction.clone(), instruction.jump_target(*offset), *line)
+            }
+        };
+        len = instruction.next_offset(len);
+        kept.push((instruction, target, line));
+    }
+    new_offsets.insert(chunk.len(), len);
+    if kept.len() == instructions.len() {
+        return Ok(false);
+    }
+
+    let new_offset = |offset: usize| new_offsets.get(&offset).copied().ok_or_else(|| anyhow!("No instruction starts at offset {}", offset));
+    let mut code = Vec::with_capacity(len);
+    let mut src_line_numbers = Vec::with_capacity(len);
+    for (instruction, target, line) in kept {
+        let offset = code.len();
+        let instruction = match target {
+            Some(target) => with_jump_target(instruction, offset, new_offset(target)?)?,
+            None => instruction
+        };
+        code.push(instruction.op_code.clone().into());
+        code.extend([instruction.operand1, instruction.operand2, instruction.operand3, instruction.operand4].into_iter().flatten());
+        src_line_numbers.resize(code.len(), line);
+    }
+
+    let local_vars = chunk.local_vars().iter()
+        .map(|local| Ok(LocalVar { start: new_offset(local.start)?, end: new_offset(local.end)?, ..local.clone() }))
+        .collect::<Result<_>>()?;
+    chunk.set_code(code, src_line_numbers);
+    chunk.set_local_vars(local_vars);
+    Ok(true)
+}
+
+/// The jump at `offset` with its operands changed to go to `target`, in as many bytes as before
+fn with_jump_target(instruction: Instruction, offset: usize, target: usize) -> Result<Instruction> {
+    let next_offset = instruction.next_offset(offset);
+    let distance = match instruction.op_code {
+        OpCode::Loop | OpCode::LoopLong => next_offset.checked_sub(target),
+        _ => target.checked_sub(next_offset)
+    };
+    let Some(distance) = distance else {
+        bail!("{} at offset {} can't reach offset {}", instruction.op_code, offset, target)
+    };
+
+    let bytes = distance.to_be_bytes();
+    let width = instruction.op_code.operand_count();
+    if bytes[..bytes.len() - width].iter().any(|byte| *byte != 0) {
+        bail!("{} at offset {} can't reach offset {}", instruction.op_code, offset, target)
+    }
+    Ok(Instruction::with_operands(instruction.op_code, &bytes[bytes.len() - width..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, vm::{Vm, VmOptions}};
+
+    /// Names of the opcodes the script compiles to, leaving out its functions
+    fn op_codes(source: &str, optimize: bool) -> Vec<String> {
+        let chunk = Compiler::new(source.to_string()).with_optimizations(optimize).compile().unwrap();
+        let mut reader = InstructionReader::new(&chunk);
+        let mut op_codes = Vec::new();
+        while let Some((instruction, _, _)) = reader.read_next().unwrap() {
+            op_codes.push(instruction.op_code.to_string());
+        }
+        op_codes
+    }
+
+    #[test]
+    fn pairs_are_fused_unless_a_jump_lands_between_them() {
+        let fused = op_codes("{ var i = 0; while (i < 10) { i = i + 1; } print i; }", false);
+        assert!(fused.contains(&"LessJumpIfFalse".to_string()) && !fused.contains(&"Less".to_string()), "{:?}", fused);
+
+        // The `or` jumps to the Add, which is left as it is
+        let unfused = op_codes("{ var a = 1; var b = 2; print a + (b or a); }", false);
+        assert!(unfused.contains(&"Add".to_string()) && !unfused.contains(&"GetLocalAdd".to_string()), "{:?}", unfused);
+    }
+
+    #[test]
+    fn operators_on_constants_are_folded() {
+        assert_eq!(op_codes("print 2 * 3 + 1;", true), ["Constant", "Print", "Nil", "Return"]);
+        assert_eq!(op_codes("print !true;", true), ["False", "Print", "Nil", "Return"]);
+        assert_eq!(op_codes("print 2 * 3 + 1;", false).len(), 8);
+    }
+
+    #[test]
+    fn folded_constants_are_what_the_vm_works_out() {
+        let sources = ["2 * 3 + 1", "1 + 2 * 3", "-(1 + 2)", "!nil", "7 / 2", "8 / 2", "7 % 3", "1.5 * 2",
+            "9223372036854775807 + 1", "-(-9223372036854775807 - 1)", "1 < 2.5", "3 > 3", "nil == false", "\"a\" == \"a\"",
+            "1 == 1.0", "\"a\" + 1", "(nil or 1) + 2", "1 / 0", "-nil"];
+        for source in sources {
+            let run = |fold: bool| {
+                let chunk = Compiler::new(format!("{};", source)).with_eval_mode(true).with_optimizations(fold).compile().unwrap();
+                Vm::new(VmOptions::default()).run(&chunk).ok()
+            };
+            assert_eq!(run(true), run(false), "{}", source);
+        }
+    }
+
+    #[test]
+    fn unreachable_code_is_dropped() {
+        assert_eq!(op_codes("if (true) print 1; else print 2;", true), ["Constant", "Print", "Jump", "Nil", "Return"]);
+        assert_eq!(op_codes("while (1 < 2) print 1;", true), ["Constant", "Print", "Loop"]);
+        assert_eq!(op_codes("print false and 1;", true), ["False", "JumpIfFalse", "Print", "Nil", "Return"]);
+    }
+
+    #[test]
+    fn dropping_unreachable_code_leaves_what_the_script_does() {
+        let sources = ["fun f() { return 1; print 2; } var r = f();", "var r = 0; if (false) r = 1; else r = 2;",
+            "var r = 0; while (true) { r = r + 1; if (r > 2) break; r = r + 10; }", "var r = true or 1;",
+            "var r = 0; try { throw 1; r = 2; } catch (e) { r = e; }",
+            "var r = 0; for (var i = 0; i < 3; i = i + 1) { continue; r = 5; }"];
+        for source in sources {
+            let run = |optimize: bool| {
+                let chunk = Compiler::new(format!("{} r;", source)).with_eval_mode(true).with_optimizations(optimize).compile().unwrap();
+                Vm::new(VmOptions::default()).run(&chunk).ok()
+            };
+            assert_eq!(run(true), run(false), "{}", source);
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! The stable public surface of the crate.
+//!
+//! ```no_run
+//! use lox::prelude::*;
+//!
+//! let chunk = Compiler::new("print 1 + 2;".to_string()).compile().unwrap();
+//! let mut vm = Vm::new(VmOptions::default());
+//! vm.run(&chunk).unwrap();
+//! ```
+
+pub use crate::chunk::Chunk;
+pub use crate::compiler::{Compiler, CompileError, CompileErrorCollection};
+pub use crate::dialect::Dialect;
+pub use crate::scanner::ScanError;
+pub use crate::value::Value;
+pub use crate::vm::{Vm, VmOptions, VmError, ErrorKind, LastError};
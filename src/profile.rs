@@ -0,0 +1,172 @@
+//! Counts how often each opcode and each source line is run, and how long they take, using the
+//! VM's instruction hooks.
+
+use std::{cell::{Ref, RefCell}, collections::HashMap, fmt::Display, rc::Rc, time::{Duration, Instant}};
+
+use crate::{instruction::OpCode, json::Json, vm::Vm};
+
+/// Most lines shown in the report, hottest first. The JSON has all of them.
+const REPORT_LINES: usize = 20;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+    count: u64,
+    time: Duration
+}
+
+impl Totals {
+    fn to_json(self) -> Json {
+        Json::object([("count", Json::from(self.count as i64)), ("timeNs", Json::from(self.time.as_nanos() as i64))])
+    }
+}
+
+/// What a profiled run executed. Counts include instructions that failed; times only those
+/// that finished.
+#[derive(Debug, Default)]
+pub struct Profile {
+    /// By opcode number
+    op_codes: Vec<Totals>,
+    lines: HashMap<i32, Totals>,
+    /// When the instruction running now started
+    started: Option<Instant>
+}
+
+impl Profile {
+    fn before(&mut self, op_code: &OpCode, line: i32) {
+        let index = op_code.clone() as usize;
+        if index >= self.op_codes.len() {
+            self.op_codes.resize(index + 1, Totals::default());
+        }
+        self.op_codes[index].count += 1;
+        self.lines.entry(line).or_default().count += 1;
+        self.started = Some(Instant::now());
+    }
+
+    fn after(&mut self, op_code: &OpCode, line: i32) {
+        let Some(started) = self.started.take() else { return };
+        let time = started.elapsed();
+        if let Some(totals) = self.op_codes.get_mut(op_code.clone() as usize) {
+            totals.time += time;
+        }
+        if let Some(totals) = self.lines.get_mut(&line) {
+            totals.time += time;
+        }
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.op_codes.iter().map(|t| t.count).sum()
+    }
+
+    pub fn total_time(&self) -> Duration {
+        self.op_codes.iter().map(|t| t.time).sum()
+    }
+
+    /// Times each opcode was run
+    pub fn op_code_count(&self, op_code: OpCode) -> u64 {
+        self.op_codes.get(op_code as usize).map_or(0, |t| t.count)
+    }
+
+    /// Times an instruction on the line was run
+    pub fn line_count(&self, line: i32) -> u64 {
+        self.lines.get(&line).map_or(0, |t| t.count)
+    }
+
+    /// Opcodes run, most time taken first
+    fn op_codes_by_time(&self) -> Vec<(OpCode, Totals)> {
+        let mut op_codes: Vec<_> = self.op_codes.iter().enumerate()
+            .filter(|(_, totals)| totals.count > 0)
+            .filter_map(|(i, totals)| Some((OpCode::try_from(i as u8).ok()?, *totals)))
+            .collect();
+        op_codes.sort_by(|(_, a), (_, b)| b.time.cmp(&a.time).then(b.count.cmp(&a.count)));
+        op_codes
+    }
+
+    /// Lines run, most time taken first
+    fn lines_by_time(&self) -> Vec<(i32, Totals)> {
+        let mut lines: Vec<_> = self.lines.iter().map(|(line, totals)| (*line, *totals)).collect();
+        lines.sort_by(|(line_a, a), (line_b, b)| b.time.cmp(&a.time).then(b.count.cmp(&a.count)).then(line_a.cmp(line_b)));
+        lines
+    }
+
+    pub fn to_json(&self) -> String {
+        let op_codes = self.op_codes_by_time().into_iter()
+            .map(|(op_code, totals)| (op_code.to_string(), totals.to_json()));
+        let lines = self.lines_by_time().into_iter()
+            .map(|(line, totals)| (line.to_string(), totals.to_json()));
+        Json::object([
+            ("instructions", Json::from(self.instruction_count() as i64)),
+            ("timeNs", Json::from(self.total_time().as_nanos() as i64)),
+            ("opcodes", Json::object(op_codes)),
+            ("lines", Json::object(lines))
+        ]).to_string()
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total_time = self.total_time();
+        let percent = |time: Duration| if total_time.is_zero() { 0.0 } else { 100.0 * time.as_secs_f64() / total_time.as_secs_f64() };
+        writeln!(f, "Profile: {} instructions in {:.3} ms", self.instruction_count(), total_time.as_secs_f64() * 1000.0)?;
+
+        writeln!(f, "  {:<18}{:>12} {:>12} {:>7}", "opcode", "count", "ms", "time")?;
+        for (op_code, totals) in self.op_codes_by_time() {
+            writeln!(f, "  {:<18}{:>12} {:>12.3} {:>6.1}%", op_code.to_string(), totals.count, totals.time.as_secs_f64() * 1000.0, percent(totals.time))?;
+        }
+
+        let lines = self.lines_by_time();
+        writeln!(f, "  {:<18}{:>12} {:>12} {:>7}", "line", "count", "ms", "time")?;
+        for (line, totals) in lines.iter().take(REPORT_LINES) {
+            writeln!(f, "  {:<18}{:>12} {:>12.3} {:>6.1}%", line, totals.count, totals.time.as_secs_f64() * 1000.0, percent(totals.time))?;
+        }
+        if lines.len() > REPORT_LINES {
+            writeln!(f, "  ({} more lines)", lines.len() - REPORT_LINES)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records a `Profile` of what a VM runs. Clones share the same profile, so one can be
+/// attached to a `Vm` and another kept to read it.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler(Rc<RefCell<Profile>>);
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Has everything `vm` runs from now on recorded, in place of its instruction hooks
+    pub fn attach(&self, vm: &mut Vm) {
+        let profile = self.0.clone();
+        vm.set_pre_instruction_hook(move |state, instruction| profile.borrow_mut().before(&instruction.op_code, state.line));
+        let profile = self.0.clone();
+        vm.set_post_instruction_hook(move |state, instruction| profile.borrow_mut().after(&instruction.op_code, state.line));
+    }
+
+    pub fn profile(&self) -> Ref<'_, Profile> {
+        self.0.borrow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, vm::VmOptions};
+
+    #[test]
+    fn counts_opcodes_and_lines_run() {
+        let mut vm = Vm::new(VmOptions::default());
+        let profiler = Profiler::new();
+        profiler.attach(&mut vm);
+        vm.run(&Compiler::new("var total = 0;\nfor (var i = 0; i < 10; i = i + 1) {\n  total = total + i;\n}".to_string()).compile().unwrap()).unwrap();
+
+        let profile = profiler.profile();
+        assert_eq!(profile.line_count(3), 10 * 4);
+        assert_eq!(profile.op_code_count(OpCode::SetGlobal), 10);
+        assert_eq!(profile.instruction_count(), profile.lines.values().map(|t| t.count).sum::<u64>());
+        assert!(profile.to_string().contains("SetGlobal"), "{}", profile);
+
+        let json = Json::parse(&profile.to_json()).unwrap();
+        assert_eq!(json.get("lines").and_then(|lines| lines.get("3")).and_then(|line| line.get("count")).and_then(Json::as_f64), Some(40.0));
+    }
+}
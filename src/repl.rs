@@ -0,0 +1,140 @@
+//! A REPL session for hosts such as notebook kernels: cells run one after another in the same
+//! VM, so each sees the globals of those before it, and what running one gave comes back as
+//! data instead of being printed.
+
+use std::time::{Duration, Instant};
+
+use crate::{compiler::{CompileErrorCollection, Compiler}, diagnostic::StructuredDiagnostic, value::Value, vm::{Vm, VmOptions}};
+
+/// What running a cell gave
+#[derive(Debug, Clone)]
+pub struct CellResult {
+    /// How many cells the session has run, this one included
+    pub execution_count: usize,
+    /// What `print` wrote
+    pub stdout: String,
+    /// What `printErr` wrote
+    pub stderr: String,
+    /// The value of the cell's final expression statement, nil if it ends with another kind of
+    /// statement. None if the cell is empty or failed.
+    pub value: Option<Value>,
+    /// What went wrong in the cell, with lines and columns counted within it
+    pub diagnostics: Vec<StructuredDiagnostic>,
+    pub compile_time: Duration,
+    pub run_time: Duration
+}
+
+impl CellResult {
+    pub fn succeeded(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct ReplSession {
+    vm: Vm,
+    execution_count: usize
+}
+
+impl ReplSession {
+    pub fn new(options: VmOptions) -> Self {
+        let mut vm = Vm::new(options);
+        vm.capture_output(true);
+        Self { vm, execution_count: 0 }
+    }
+
+    /// The VM cells run in, for defining natives or reading globals
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut Vm {
+        &mut self.vm
+    }
+
+    /// Compiles and runs a cell. A final expression can leave out its semicolon, as in `a + 1`.
+    pub fn execute(&mut self, source: &str) -> CellResult {
+        self.execution_count += 1;
+        let mut result = CellResult { execution_count: self.execution_count, stdout: String::new(), stderr: String::new(), value: None,
+            diagnostics: Vec::new(), compile_time: Duration::ZERO, run_time: Duration::ZERO };
+
+        let mut source = source.trim_end().to_string();
+        if !source.is_empty() && !source.ends_with(';') && !source.ends_with('}') {
+            source.push(';');
+        }
+
+        let started = Instant::now();
+        let compiled = Compiler::new(source.clone())
+            .with_eval_mode(true)
+            .with_dialect(self.vm.dialect())
+            .with_constant_pool(self.vm.constant_pool())
+            .compile_nonempty();
+        result.compile_time = started.elapsed();
+        let chunk = match compiled {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => return result,
+            Err(e) => {
+                result.diagnostics = match e.downcast::<CompileErrorCollection>() {
+                    Ok(collection) => collection.errors.iter().map(|error| StructuredDiagnostic::from_compile_error(error, &source, None)).collect(),
+                    Err(e) => vec![StructuredDiagnostic::internal(format!("{:#}", e))]
+                };
+                return result;
+            }
+        };
+
+        self.vm.clear_last_error();
+        self.vm.set_trace_source(&source);
+        let started = Instant::now();
+        let ran = self.vm.run(&chunk);
+        result.run_time = started.elapsed();
+        (result.stdout, result.stderr) = self.vm.take_output();
+        match ran {
+            Ok(value) => result.value = Some(value),
+            Err(e) => result.diagnostics.push(match self.vm.last_error() {
+                Some(last_error) => StructuredDiagnostic::from_runtime_error(last_error, None),
+                None => StructuredDiagnostic::internal(format!("{:#}", e))
+            })
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error_code;
+
+    #[test]
+    fn cells_share_globals_and_report_output_values_and_errors() {
+        let mut session = ReplSession::new(VmOptions::default());
+
+        let first = session.execute("var a = 20; print \"set\"; printErr \"warned\";");
+        assert!(first.succeeded());
+        assert_eq!((first.execution_count, first.stdout.as_str(), first.stderr.as_str()), (1, "set\n", "warned\n"));
+        assert_eq!(first.value, Some(Value::Nil));
+
+        let second = session.execute("a + 22");
+        assert_eq!(second.value, Some(Value::Int(42)));
+        assert_eq!(second.stdout, "");
+
+        let empty = session.execute("  // nothing\n");
+        assert!(empty.succeeded() && empty.value.is_none());
+
+        let bad_syntax = session.execute("var = 1;");
+        match bad_syntax.diagnostics.as_slice() {
+            [error] => assert_eq!((error.code, error.line, error.column), (error_code::EXPECTED_TOKEN, Some(1), Some(5))),
+            other => panic!("Expected a compile error, got {:?}", other)
+        }
+
+        let failing = session.execute("print \"before\";\nundefinedVariable;");
+        assert_eq!(failing.stdout, "before\n");
+        assert!(failing.value.is_none());
+        match failing.diagnostics.as_slice() {
+            [error] => assert_eq!((error.code, error.line), (error_code::UNDEFINED_VARIABLE, Some(2))),
+            other => panic!("Expected a runtime error, got {:?}", other)
+        }
+
+        assert_eq!(session.execute("a").value, Some(Value::Int(20)));
+        assert_eq!(session.execute("a").execution_count, 7);
+    }
+}
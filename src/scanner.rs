@@ -1,11 +1,18 @@
 use thiserror::Error;
 use anyhow::{Result, bail};
 
+use crate::dialect::Dialect;
+use crate::error_code;
+
 #[derive(Error, Clone, Debug)]
 #[error("[{line}]: {message}")]
 pub struct ScanError {
 	pub line: usize,
-    pub message: String
+    /// Where the token that couldn't be scanned starts in the source, in bytes
+    pub offset: usize,
+    pub message: String,
+    /// Stable code of the kind of error, from `error_code`
+    pub code: &'static str
 }
 
 #[derive(Debug)]
@@ -13,19 +20,81 @@ pub struct Scanner {
     source: String,
     start: usize,
     current: usize,
-    line: usize
+    line: usize,
+    dialect: Dialect,
+    /// What's needed to insert implicit semicolons: whether a line break came before the
+    /// token being scanned, the last token returned, how many parentheses and brackets
+    /// are open, and a token held back while a semicolon is returned in front of it
+    crossed_line_break: bool,
+    last_token: Option<Token>,
+    nesting: usize,
+    held_back: Option<Token>
 }
 
 impl Scanner {
     pub fn new(source: String) -> Self {
-        Self { source, start: 0, current: 0, line: 1 }
+        Self { source, start: 0, current: 0, line: 1, dialect: Dialect::default(), crossed_line_break: false, last_token: None, nesting: 0, held_back: None }
+    }
+
+    pub fn with_dialect(self, dialect: Dialect) -> Self {
+        Self { dialect, ..self }
     }
 
     pub fn scan_next(&mut self) -> Result<Token> {
+        if !self.dialect.implicit_semicolons {
+            return self.scan_explicit();
+        }
+
+        let token = match self.held_back.take() {
+            Some(token) => token,
+            None => self.scan_explicit()?
+        };
+        let token = if self.ends_statement_before(&token) {
+            let last = self.last_token.as_ref().expect("Statement ended without a token");
+            let semicolon = Token { token_type: TokenType::Semicolon, lexeme: Lexeme { start: last.lexeme.start + last.lexeme.len, len: 0 }, line: last.line };
+            self.held_back = Some(token);
+            semicolon
+        } else {
+            token
+        };
+
+        match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBracket => self.nesting += 1,
+            TokenType::RightParen | TokenType::RightBracket => self.nesting = self.nesting.saturating_sub(1),
+            _ => {}
+        }
+        self.last_token = Some(token.clone());
+        Ok(token)
+    }
+
+    /// Whether a semicolon is implied between the last token and `next`
+    fn ends_statement_before(&self, next: &Token) -> bool {
+        let can_end = self.last_token.as_ref().is_some_and(|last| matches!(last.token_type,
+            TokenType::Identifier | TokenType::String | TokenType::Bytes | TokenType::Number
+            | TokenType::True | TokenType::False | TokenType::Nil | TokenType::This
+            | TokenType::RightParen | TokenType::RightBracket | TokenType::PlusPlus | TokenType::MinusMinus
+            | TokenType::Return | TokenType::Break | TokenType::Continue));
+        if !can_end || next.token_type == TokenType::Semicolon {
+            return false;
+        }
+
+        match next.token_type {
+            TokenType::RightBrace | TokenType::Eof => true,
+            _ if self.nesting > 0 || !self.crossed_line_break => false,
+            // The next line carries on the statement
+            TokenType::Dot | TokenType::Plus | TokenType::Star | TokenType::Slash | TokenType::Percent
+            | TokenType::EqualEqual | TokenType::BangEqual | TokenType::Less | TokenType::LessEqual
+            | TokenType::Greater | TokenType::GreaterEqual | TokenType::Equal | TokenType::And | TokenType::Or => false,
+            _ => true
+        }
+    }
+
+    fn scan_explicit(&mut self) -> Result<Token> {
+        self.crossed_line_break = false;
         self.skip_whitespace();
 
         if self.is_at_end() {
-            return Ok(Token { lexeme: Lexeme { start: self.source.len() - 1, len: 0 }, line: self.line, token_type: TokenType::Eof });
+            return Ok(Token { lexeme: Lexeme { start: self.source.len(), len: 0 }, line: self.line, token_type: TokenType::Eof });
         }
 
         let token_type = self.scan_token()?;
@@ -36,12 +105,12 @@ impl Scanner {
     }
 
     pub fn get_lexeme_str(&self, lexeme: &Lexeme) -> Result<&str> {
-        let lexeme_end =  lexeme.start + lexeme.len - 1;
-        if lexeme_end > self.source.len() - 1 {
+        let lexeme_end = lexeme.start + lexeme.len;
+        if lexeme_end > self.source.len() {
             bail!("Lexeme {}-{} lies outside source boundary", lexeme.start, lexeme_end);
         }
 
-        Ok(&self.source[lexeme.start..=lexeme_end])
+        Ok(&self.source[lexeme.start..lexeme_end])
     }
 
     fn skip_whitespace(&mut self) {
@@ -49,6 +118,7 @@ impl Scanner {
             match self.peek() {
                 '\n' => {
                     self.line += 1;
+                    self.crossed_line_break = true;
                     self.advance();
                 },
                 ' ' | '\r' | '\t' => { self.advance(); },
@@ -77,12 +147,16 @@ impl Scanner {
             ')' => TokenType::RightParen,
             '{' => TokenType::LeftBrace,
             '}' => TokenType::RightBrace,
+            '[' => TokenType::LeftBracket,
+            ']' => TokenType::RightBracket,
             ',' => TokenType::Comma,
-            '.' => TokenType::Dot,
-            '-' => TokenType::Minus,
-            '+' => TokenType::Plus,
+            '.' => if self.peek() == '.' && self.peek_next() == '.' { self.current += 2; TokenType::DotDotDot } else { TokenType::Dot },
+            '-' => if self.char_matches('-') { TokenType::MinusMinus } else { TokenType::Minus },
+            '+' => if self.char_matches('+') { TokenType::PlusPlus } else { TokenType::Plus },
             ';' => TokenType::Semicolon,
+            ':' => TokenType::Colon,
             '*' => TokenType::Star,
+            '%' => TokenType::Percent,
             '!' => if self.char_matches('=') { TokenType::BangEqual } else { TokenType::Bang },
             '=' => if self.char_matches('=') { TokenType::EqualEqual } else { TokenType::Equal },
             '<' => if self.char_matches('=') { TokenType::LessEqual } else { TokenType::Less },
@@ -90,12 +164,13 @@ impl Scanner {
             '/' => TokenType::Slash,
             '0'..='9' => self.number()?,
             '"' => self.string()?,
+            'b' if self.peek() == '"' => self.bytes()?,
             c => {
                 if self.is_alpha(c) {
                     self.identifier()
                 }
                 else {
-                    bail!(ScanError { line: self.line, message: "Unexpected character.".to_string() })
+                    bail!(ScanError { line: self.line, offset: self.start, message: "Unexpected character.".to_string(), code: error_code::UNEXPECTED_CHARACTER })
                 }
             }
         };
@@ -112,7 +187,7 @@ impl Scanner {
         }
 
         if self.is_at_end() {
-            bail!(ScanError { line: self.line, message: "Unterminated string.".to_string() });
+            bail!(ScanError { line: self.line, offset: self.start, message: "Unterminated string.".to_string(), code: error_code::UNTERMINATED_STRING });
         }
 
         // The closing ".
@@ -121,24 +196,75 @@ impl Scanner {
         Ok(TokenType::String)
     }
 
+    fn bytes(&mut self) -> Result<TokenType> {
+        // The opening "
+        self.advance();
+
+        while self.peek() != '"' && !self.is_at_end() {
+            match self.advance() {
+                // Skip the escaped character so an escaped quote doesn't end the literal
+                '\\' if !self.is_at_end() => { self.advance(); },
+                '\n' => self.line += 1,
+                _ => {}
+            }
+        }
+
+        if self.is_at_end() {
+            bail!(ScanError { line: self.line, offset: self.start, message: "Unterminated bytes literal.".to_string(), code: error_code::UNTERMINATED_BYTES });
+        }
+
+        // The closing ".
+        self.advance();
+
+        Ok(TokenType::Bytes)
+    }
+
     fn number(&mut self) -> Result<TokenType> {
-        while self.is_digit(self.peek()) {
+        // A radix prefix, the "0" of which was consumed already
+        if self.current_lexeme() == "0" && matches!(self.peek(), 'x' | 'X' | 'b' | 'B') {
+            let radix = if matches!(self.peek(), 'x' | 'X') { 16 } else { 2 };
+            if !self.peek_next().is_digit(radix) {
+                bail!(ScanError { line: self.line, offset: self.start, message: format!("Expected digits after '0{}'.", self.peek()), code: error_code::MALFORMED_NUMBER });
+            }
+            // Consume the "x" or "b"
             self.advance();
+            self.digits(radix);
+            return Ok(TokenType::Number);
         }
 
+        self.digits(10);
+
         // Look for a fractional part.
         if self.peek() == '.' && self.is_digit(self.peek_next()) {
             // Consume the "."
             self.advance();
-    
-            while self.is_digit(self.peek()) {
+            self.digits(10);
+        }
+
+        // Look for an exponent
+        if matches!(self.peek(), 'e' | 'E') {
+            let sign = matches!(self.peek_next(), '+' | '-');
+            let first_digit = if sign { self.char_at(self.current + 2).unwrap_or('\0') } else { self.peek_next() };
+            if self.is_digit(first_digit) {
+                // Consume the "e" and any sign
                 self.advance();
+                if sign {
+                    self.advance();
+                }
+                self.digits(10);
             }
         }
     
         Ok(TokenType::Number)
     }
 
+    /// Consumes digits in `radix`, with single underscores allowed between them
+    fn digits(&mut self, radix: u32) {
+        while self.peek().is_digit(radix) || (self.peek() == '_' && self.peek_next().is_digit(radix)) {
+            self.advance();
+        }
+    }
+
     fn identifier(&mut self) -> TokenType {
         while self.is_alphanumeric(self.peek()) {
              self.advance();
@@ -146,19 +272,34 @@ impl Scanner {
 
         match self.current_lexeme() {
             "and" => TokenType::And,
+            "not" if self.dialect.word_operators => TokenType::Bang,
+            "mod" if self.dialect.word_operators => TokenType::Percent,
+            "assert" => TokenType::Assert,
+            "break" => TokenType::Break,
+            "case" => TokenType::Case,
+            "catch" => TokenType::Catch,
             "class" => TokenType::Class,
+            "const" => TokenType::Const,
+            "continue" => TokenType::Continue,
+            "default" => TokenType::Default,
             "else" => TokenType::Else,
+            "export" => TokenType::Export,
             "false" => TokenType::False,
             "for" => TokenType::For,
             "fun" => TokenType::Fun,
             "if" => TokenType::If,
+            "import" => TokenType::Import,
             "nil" => TokenType::Nil,
             "or" => TokenType::Or,
             "print" => TokenType::Print,
+            "printErr" => TokenType::PrintErr,
             "return" => TokenType::Return,
             "super" => TokenType::Super,
+            "switch" => TokenType::Switch,
             "this" => TokenType::This,
+            "throw" => TokenType::Throw,
             "true" => TokenType::True,
+            "try" => TokenType::Try,
             "var" => TokenType::Var,
             "while" => TokenType::While,
             _ => TokenType::Identifier,
@@ -170,12 +311,10 @@ impl Scanner {
     }
 
     fn is_digit(&self, c: char) -> bool {
-        c >= '0' && c <= '9'
+        c.is_ascii_digit()
     }
     fn is_alpha(&self, c: char) -> bool {
-        (c >= 'a' && c <= 'z') ||
-        (c >= 'A' && c <= 'Z') ||
-        c == '_'
+        c.is_ascii_alphabetic() || c == '_'
     }
     
     fn is_alphanumeric(&self, c: char) -> bool {
@@ -187,7 +326,7 @@ impl Scanner {
             return false;
         } 
 
-        if self.source.chars().nth(self.current) != Some(expected) {
+        if self.char_at(self.current) != Some(expected) {
             return false;
         }
 
@@ -206,10 +345,7 @@ impl Scanner {
     }
 
     fn peek_next(&self) -> char {
-        match self.char_at(self.current + 1) {
-            Some(c) => c,
-            None => '\0'
-        }
+        self.char_at(self.current + 1).unwrap_or('\0')
     }
 
     fn current_lexeme(&self) -> &str {
@@ -244,16 +380,20 @@ pub struct Token {
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TokenType {
-    LeftParen, RightParen, LeftBrace, RightBrace, Comma,
-    Dot, Minus, Plus, Semicolon, Slash, Star,
+    LeftParen, RightParen, LeftBrace, RightBrace, LeftBracket, RightBracket, Comma,
+    Dot, Minus, Plus, Colon, Semicolon, Slash, Star, Percent,
+
+    DotDotDot,
+
+    PlusPlus, MinusMinus,
 
     Bang, BangEqual, Equal, EqualEqual, Greater, GreaterEqual,
     Less, LessEqual,
 
-    Identifier, String, Number,
+    Identifier, String, Bytes, Number,
 
-    And, Class, Else, False, Fun, For, If, Nil, Or, Print,
-    Return, Super, This, True, Var, While,
+    And, Assert, Break, Case, Catch, Class, Const, Continue, Default, Else, Export, False, Fun, For, If, Import, Nil, Or, Print, PrintErr,
+    Return, Super, Switch, This, Throw, True, Try, Var, While,
 
     Eof
-}
\ No newline at end of file
+}
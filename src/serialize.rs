@@ -0,0 +1,714 @@
+//! Binary serialization of values, including the functions, closures and objects they
+//! reference. Shared objects are written once and keep their sharing when read back, and
+//! cycles through instance fields, list items, map values, class methods or closed upvalues are fine.
+//!
+//! The output is the object table, then the contents of objects that may take part in
+//! cycles, then whatever the caller wrote as roots. Natives are written by name and looked
+//! up again on reading; foreign objects can't be serialized.
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use anyhow::{Result, bail, anyhow, Context};
+
+use crate::{chunk::{Chunk, LocalVar}, class::{BoundMethod, Class, Instance}, function::{Closure, Function, Upvalue, UpvalueDescriptor}, map::{Map, MapKey}, native::NativeFunction, value::Value};
+
+const MAGIC: &[u8; 4] = b"LOXV";
+const FORMAT_VERSION: u32 = 13;
+
+mod value_tag {
+    pub const NIL: u8 = 0;
+    pub const FALSE: u8 = 1;
+    pub const TRUE: u8 = 2;
+    pub const NUMBER: u8 = 3;
+    pub const STRING: u8 = 4;
+    pub const BYTES: u8 = 5;
+    pub const NATIVE: u8 = 6;
+    /// Followed by an object id
+    pub const OBJECT: u8 = 7;
+    pub const INT: u8 = 8;
+}
+
+mod object_tag {
+    pub const FUNCTION: u8 = 0;
+    pub const CLOSURE: u8 = 1;
+    pub const OPEN_UPVALUE: u8 = 2;
+    pub const CLOSED_UPVALUE: u8 = 3;
+    pub const CLASS: u8 = 4;
+    pub const INSTANCE: u8 = 5;
+    pub const BOUND_METHOD: u8 = 6;
+    pub const LIST: u8 = 7;
+    pub const MAP: u8 = 8;
+}
+
+/// Objects whose contents are written after the object table, since they can refer back to themselves
+enum Fill {
+    Upvalue(u32, Rc<RefCell<Upvalue>>),
+    Class(u32, Rc<Class>),
+    Instance(u32, Rc<Instance>),
+    List(u32, Rc<RefCell<Vec<Value>>>),
+    Map(u32, Rc<RefCell<Map>>)
+}
+
+#[derive(Default)]
+pub struct ValueWriter {
+    objects: Vec<u8>,
+    object_count: u32,
+    /// Ids of objects already written, by address
+    ids: HashMap<usize, u32>,
+    fills: Vec<Fill>
+}
+
+impl ValueWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_value(&mut self, out: &mut Vec<u8>, value: &Value) -> Result<()> {
+        match value {
+            Value::Nil => put_u8(out, value_tag::NIL),
+            Value::Boolean(false) => put_u8(out, value_tag::FALSE),
+            Value::Boolean(true) => put_u8(out, value_tag::TRUE),
+            Value::Number(n) => {
+                put_u8(out, value_tag::NUMBER);
+                put_u64(out, n.to_bits());
+            },
+            Value::Int(n) => {
+                put_u8(out, value_tag::INT);
+                put_u64(out, *n as u64);
+            },
+            Value::String(s) => {
+                put_u8(out, value_tag::STRING);
+                put_str(out, s);
+            },
+            Value::Bytes(b) => {
+                put_u8(out, value_tag::BYTES);
+                put_bytes(out, b);
+            },
+            Value::Native(native) => {
+                put_u8(out, value_tag::NATIVE);
+                put_str(out, &native.name);
+            },
+            Value::Foreign(foreign) => bail!("Can't serialize foreign object {}", foreign),
+            object => {
+                let id = self.object_id(object)?;
+                put_u8(out, value_tag::OBJECT);
+                put_u32(out, id);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn closure_id(&mut self, closure: &Rc<Closure>) -> Result<u32> {
+        if let Some(id) = self.existing_id(Rc::as_ptr(closure) as usize) {
+            return Ok(id);
+        }
+
+        let function_id = self.function_id(&closure.function)?;
+        let upvalue_ids: Vec<u32> = closure.upvalues.iter().map(|u| self.upvalue_id(u)).collect();
+
+        let mut entry = vec![object_tag::CLOSURE];
+        put_u32(&mut entry, function_id);
+        put_u32(&mut entry, upvalue_ids.len() as u32);
+        for id in upvalue_ids {
+            put_u32(&mut entry, id);
+        }
+
+        Ok(self.add_object(Rc::as_ptr(closure) as usize, entry))
+    }
+
+    pub fn upvalue_id(&mut self, upvalue: &Rc<RefCell<Upvalue>>) -> u32 {
+        let address = Rc::as_ptr(upvalue) as usize;
+        if let Some(id) = self.existing_id(address) {
+            return id;
+        }
+
+        let entry = match &*upvalue.borrow() {
+            Upvalue::Open(slot) => {
+                let mut entry = vec![object_tag::OPEN_UPVALUE];
+                put_u32(&mut entry, *slot as u32);
+                entry
+            },
+            Upvalue::Closed(_) => vec![object_tag::CLOSED_UPVALUE]
+        };
+
+        let id = self.add_object(address, entry);
+        if let Upvalue::Closed(_) = &*upvalue.borrow() {
+            self.fills.push(Fill::Upvalue(id, upvalue.clone()));
+        }
+        id
+    }
+
+    /// Completes the object table and puts it in front of the roots, which must have been
+    /// written with this writer
+    pub fn finish(mut self, roots: &[u8]) -> Result<Vec<u8>> {
+        // Writing an object's contents can discover further objects, which may need filling in turn
+        let mut fills = Vec::new();
+        let mut fill_count = 0u32;
+        while let Some(fill) = self.fills.pop() {
+            self.write_fill(&mut fills, fill)?;
+            fill_count += 1;
+        }
+
+        let mut out = Vec::with_capacity(self.objects.len() + fills.len() + roots.len() + 16);
+        out.extend_from_slice(MAGIC);
+        put_u32(&mut out, FORMAT_VERSION);
+        put_u32(&mut out, self.object_count);
+        out.extend_from_slice(&self.objects);
+        put_u32(&mut out, fill_count);
+        out.extend_from_slice(&fills);
+        out.extend_from_slice(roots);
+
+        Ok(out)
+    }
+
+    fn object_id(&mut self, value: &Value) -> Result<u32> {
+        let id = match value {
+            Value::Function(function) => self.function_id(function)?,
+            Value::Closure(closure) => self.closure_id(closure)?,
+            Value::Class(class) => self.class_id(class),
+            Value::Instance(instance) => self.instance_id(instance),
+            Value::BoundMethod(bound) => {
+                let address = Rc::as_ptr(bound) as usize;
+                match self.existing_id(address) {
+                    Some(id) => id,
+                    None => {
+                        let mut entry = vec![object_tag::BOUND_METHOD];
+                        self.write_value(&mut entry, &bound.receiver)?;
+                        let method_id = self.closure_id(&bound.method)?;
+                        put_u32(&mut entry, method_id);
+                        self.add_object(address, entry)
+                    }
+                }
+            },
+            Value::List(items) => {
+                let address = Rc::as_ptr(items) as usize;
+                match self.existing_id(address) {
+                    Some(id) => id,
+                    None => {
+                        let id = self.add_object(address, vec![object_tag::LIST]);
+                        self.fills.push(Fill::List(id, items.clone()));
+                        id
+                    }
+                }
+            },
+            Value::Map(map) => {
+                let address = Rc::as_ptr(map) as usize;
+                match self.existing_id(address) {
+                    Some(id) => id,
+                    None => {
+                        let id = self.add_object(address, vec![object_tag::MAP]);
+                        self.fills.push(Fill::Map(id, map.clone()));
+                        id
+                    }
+                }
+            },
+            other => bail!("{} is not an object", other)
+        };
+
+        Ok(id)
+    }
+
+    fn function_id(&mut self, function: &Rc<Function>) -> Result<u32> {
+        let address = Rc::as_ptr(function) as usize;
+        if let Some(id) = self.existing_id(address) {
+            return Ok(id);
+        }
+
+        let mut entry = vec![object_tag::FUNCTION];
+        match &function.name {
+            Some(name) => {
+                put_u8(&mut entry, 1);
+                put_str(&mut entry, name);
+            },
+            None => put_u8(&mut entry, 0)
+        }
+        put_u8(&mut entry, function.arity);
+        put_u8(&mut entry, function.variadic as u8);
+        // Zero for the main script, otherwise one more than the module's index
+        put_u32(&mut entry, function.module.map_or(0, |m| m as u32 + 1));
+
+        put_u32(&mut entry, function.upvalues.len() as u32);
+        for upvalue in &function.upvalues {
+            put_u8(&mut entry, upvalue.is_local as u8);
+            put_u32(&mut entry, upvalue.index as u32);
+        }
+
+        let chunk = &function.chunk;
+        put_bytes(&mut entry, chunk.code());
+        for line in chunk.src_line_numbers() {
+            put_u32(&mut entry, *line as u32);
+        }
+        put_u32(&mut entry, chunk.constants().len() as u32);
+        for constant in chunk.constants() {
+            self.write_value(&mut entry, constant)?;
+        }
+        put_u32(&mut entry, chunk.exports().len() as u32);
+        for export in chunk.exports() {
+            put_str(&mut entry, export);
+        }
+        put_u32(&mut entry, chunk.required_capabilities().len() as u32);
+        for capability in chunk.required_capabilities() {
+            put_str(&mut entry, &capability.to_string());
+        }
+        put_u32(&mut entry, chunk.local_vars().len() as u32);
+        for local in chunk.local_vars() {
+            put_str(&mut entry, &local.name);
+            put_u32(&mut entry, local.slot as u32);
+            put_u32(&mut entry, local.start as u32);
+            put_u32(&mut entry, local.end as u32);
+        }
+        put_u32(&mut entry, chunk.upvalue_names().len() as u32);
+        for name in chunk.upvalue_names() {
+            put_str(&mut entry, name);
+        }
+
+        Ok(self.add_object(address, entry))
+    }
+
+    fn class_id(&mut self, class: &Rc<Class>) -> u32 {
+        let address = Rc::as_ptr(class) as usize;
+        if let Some(id) = self.existing_id(address) {
+            return id;
+        }
+
+        let mut entry = vec![object_tag::CLASS];
+        put_str(&mut entry, &class.name);
+        let id = self.add_object(address, entry);
+        self.fills.push(Fill::Class(id, class.clone()));
+        id
+    }
+
+    fn instance_id(&mut self, instance: &Rc<Instance>) -> u32 {
+        let address = Rc::as_ptr(instance) as usize;
+        if let Some(id) = self.existing_id(address) {
+            return id;
+        }
+
+        let class_id = self.class_id(&instance.class);
+        let mut entry = vec![object_tag::INSTANCE];
+        put_u32(&mut entry, class_id);
+        let id = self.add_object(address, entry);
+        self.fills.push(Fill::Instance(id, instance.clone()));
+        id
+    }
+
+    fn write_fill(&mut self, out: &mut Vec<u8>, fill: Fill) -> Result<()> {
+        match fill {
+            Fill::Upvalue(id, upvalue) => {
+                put_u32(out, id);
+                if let Upvalue::Closed(value) = &*upvalue.borrow() {
+                    self.write_value(out, value)?;
+                }
+            },
+            Fill::Class(id, class) => {
+                put_u32(out, id);
+                for methods in [&class.methods, &class.getters] {
+                    let mut methods: Vec<(String, Rc<Closure>)> = methods.borrow().iter().map(|(n, m)| (n.clone(), m.clone())).collect();
+                    methods.sort_by(|a, b| a.0.cmp(&b.0));
+                    put_u32(out, methods.len() as u32);
+                    for (name, method) in methods {
+                        put_str(out, &name);
+                        let method_id = self.closure_id(&method)?;
+                        put_u32(out, method_id);
+                    }
+                }
+            },
+            Fill::Instance(id, instance) => {
+                put_u32(out, id);
+                let mut fields: Vec<(String, Value)> = instance.fields.borrow().iter().map(|(n, v)| (n.clone(), v.clone())).collect();
+                fields.sort_by(|a, b| a.0.cmp(&b.0));
+                put_u32(out, fields.len() as u32);
+                for (name, value) in fields {
+                    put_str(out, &name);
+                    self.write_value(out, &value)?;
+                }
+            },
+            Fill::List(id, items) => {
+                put_u32(out, id);
+                let items = items.borrow().clone();
+                put_u32(out, items.len() as u32);
+                for item in &items {
+                    self.write_value(out, item)?;
+                }
+            },
+            Fill::Map(id, map) => {
+                put_u32(out, id);
+                let entries: Vec<(Value, Value)> = map.borrow().iter().map(|(k, v)| (k.value().clone(), v.clone())).collect();
+                put_u32(out, entries.len() as u32);
+                for (key, value) in &entries {
+                    self.write_value(out, key)?;
+                    self.write_value(out, value)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn existing_id(&self, address: usize) -> Option<u32> {
+        self.ids.get(&address).copied()
+    }
+
+    fn add_object(&mut self, address: usize, entry: Vec<u8>) -> u32 {
+        let id = self.object_count;
+        self.objects.extend_from_slice(&entry);
+        self.object_count += 1;
+        self.ids.insert(address, id);
+        id
+    }
+}
+
+/// Reads a compiled script serialized on its own, as precompiled modules are
+pub fn script_from_bytes(data: &[u8], natives: &NativeLookup) -> Result<Rc<Function>> {
+    let mut reader = ValueReader::new(data, natives)?;
+    let script = match reader.read_value()? {
+        Value::Function(script) => script,
+        other => bail!("Expected a compiled script, found {}", other)
+    };
+    if !reader.is_at_end() {
+        bail!("Compiled script has trailing data");
+    }
+
+    Ok(script)
+}
+
+#[derive(Clone)]
+enum Object {
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Upvalue(Rc<RefCell<Upvalue>>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map>>)
+}
+
+/// Looks natives up by name when reading them back
+pub type NativeLookup<'a> = dyn Fn(&str) -> Option<Rc<NativeFunction>> + 'a;
+
+pub struct ValueReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    objects: Vec<Object>,
+    natives: &'a NativeLookup<'a>
+}
+
+impl<'a> ValueReader<'a> {
+    /// Reads the object table, leaving the reader at the start of the roots
+    pub fn new(data: &'a [u8], natives: &'a NativeLookup<'a>) -> Result<Self> {
+        let mut reader = Self { data, pos: 0, objects: Vec::new(), natives };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            bail!("Not serialized Lox data");
+        }
+        let version = reader.u32()?;
+        if version != FORMAT_VERSION {
+            bail!("Unsupported serialization format version {}", version);
+        }
+
+        let object_count = reader.u32()?;
+        for _ in 0..object_count {
+            let object = reader.read_object()?;
+            reader.objects.push(object);
+        }
+
+        let fill_count = reader.u32()?;
+        for _ in 0..fill_count {
+            reader.read_fill()?;
+        }
+
+        Ok(reader)
+    }
+
+    pub fn read_value(&mut self) -> Result<Value> {
+        let value = match self.u8()? {
+            value_tag::NIL => Value::Nil,
+            value_tag::FALSE => Value::Boolean(false),
+            value_tag::TRUE => Value::Boolean(true),
+            value_tag::NUMBER => Value::Number(f64::from_bits(self.u64()?)),
+            value_tag::INT => Value::Int(self.u64()? as i64),
+            value_tag::STRING => Value::String(self.string()?),
+            value_tag::BYTES => Value::Bytes(Rc::new(self.bytes()?)),
+            value_tag::NATIVE => {
+                let name = self.string()?;
+                let native = (self.natives)(&name).ok_or_else(|| anyhow!("Unknown native '{}'", name))?;
+                Value::Native(native)
+            },
+            value_tag::OBJECT => match self.object_ref()? {
+                Object::Function(f) => Value::Function(f),
+                Object::Closure(c) => Value::Closure(c),
+                Object::Class(c) => Value::Class(c),
+                Object::Instance(i) => Value::Instance(i),
+                Object::BoundMethod(b) => Value::BoundMethod(b),
+                Object::List(l) => Value::List(l),
+                Object::Map(m) => Value::Map(m),
+                Object::Upvalue(_) => bail!("An upvalue is not a value")
+            },
+            tag => bail!("Unknown value tag {}", tag)
+        };
+
+        Ok(value)
+    }
+
+    pub fn read_closure(&mut self) -> Result<Rc<Closure>> {
+        match self.object_ref()? {
+            Object::Closure(closure) => Ok(closure),
+            _ => bail!("Expected a closure")
+        }
+    }
+
+    pub fn read_upvalue(&mut self) -> Result<Rc<RefCell<Upvalue>>> {
+        match self.object_ref()? {
+            Object::Upvalue(upvalue) => Ok(upvalue),
+            _ => bail!("Expected an upvalue")
+        }
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn read_object(&mut self) -> Result<Object> {
+        let object = match self.u8()? {
+            object_tag::FUNCTION => {
+                let name = if self.u8()? == 1 { Some(self.string()?) } else { None };
+                let arity = self.u8()?;
+                let variadic = self.u8()? == 1;
+                let module = self.u32()?.checked_sub(1).map(|m| m as usize);
+
+                let upvalue_count = self.u32()?;
+                let mut upvalues = Vec::new();
+                for _ in 0..upvalue_count {
+                    upvalues.push(UpvalueDescriptor { is_local: self.u8()? == 1, index: self.u32()? as usize });
+                }
+
+                let code = self.bytes()?;
+                let mut lines = Vec::with_capacity(code.len());
+                for _ in 0..code.len() {
+                    lines.push(self.u32()? as i32);
+                }
+                let constant_count = self.u32()?;
+                let mut constants = Vec::new();
+                for _ in 0..constant_count {
+                    constants.push(self.read_value()?);
+                }
+
+                let mut exports = Vec::new();
+                for _ in 0..self.u32()? {
+                    exports.push(self.string()?);
+                }
+
+                let mut required_capabilities = Vec::new();
+                for _ in 0..self.u32()? {
+                    required_capabilities.push(self.string()?.parse()?);
+                }
+
+                let mut local_vars = Vec::new();
+                for _ in 0..self.u32()? {
+                    local_vars.push(LocalVar { name: self.string()?, slot: self.u32()? as usize, start: self.u32()? as usize, end: self.u32()? as usize });
+                }
+
+                let mut upvalue_names = Vec::new();
+                for _ in 0..self.u32()? {
+                    upvalue_names.push(self.string()?);
+                }
+
+                let mut chunk = Chunk::from_parts(code, lines, constants);
+                chunk.set_exports(exports);
+                chunk.set_required_capabilities(required_capabilities);
+                chunk.set_local_vars(local_vars);
+                chunk.set_upvalue_names(upvalue_names);
+                Object::Function(Rc::new(Function { name, arity, variadic, chunk, upvalues, module }))
+            },
+            object_tag::CLOSURE => {
+                let function = match self.object_ref()? {
+                    Object::Function(function) => function,
+                    _ => bail!("Closure doesn't refer to a function")
+                };
+                let upvalue_count = self.u32()?;
+                let mut upvalues = Vec::new();
+                for _ in 0..upvalue_count {
+                    upvalues.push(self.read_upvalue()?);
+                }
+                Object::Closure(Rc::new(Closure::new(function, upvalues)))
+            },
+            object_tag::OPEN_UPVALUE => Object::Upvalue(Rc::new(RefCell::new(Upvalue::Open(self.u32()? as usize)))),
+            object_tag::CLOSED_UPVALUE => Object::Upvalue(Rc::new(RefCell::new(Upvalue::Closed(Value::Nil)))),
+            object_tag::CLASS => Object::Class(Rc::new(Class::new(self.string()?))),
+            object_tag::INSTANCE => {
+                let class = match self.object_ref()? {
+                    Object::Class(class) => class,
+                    _ => bail!("Instance doesn't refer to a class")
+                };
+                Object::Instance(Rc::new(Instance::new(class)))
+            },
+            object_tag::BOUND_METHOD => {
+                let receiver = self.read_value()?;
+                let method = self.read_closure()?;
+                Object::BoundMethod(Rc::new(BoundMethod::new(receiver, method)))
+            },
+            object_tag::LIST => Object::List(Rc::new(RefCell::new(Vec::new()))),
+            object_tag::MAP => Object::Map(Rc::new(RefCell::new(Map::new()))),
+            tag => bail!("Unknown object tag {}", tag)
+        };
+
+        Ok(object)
+    }
+
+    fn read_fill(&mut self) -> Result<()> {
+        match self.object_ref()? {
+            Object::Upvalue(upvalue) => {
+                let value = self.read_value()?;
+                *upvalue.borrow_mut() = Upvalue::Closed(value);
+            },
+            Object::Class(class) => {
+                for methods in [&class.methods, &class.getters] {
+                    for _ in 0..self.u32()? {
+                        let name = self.string()?;
+                        let method = self.read_closure()?;
+                        methods.borrow_mut().insert(name, method);
+                    }
+                }
+            },
+            Object::Instance(instance) => {
+                for _ in 0..self.u32()? {
+                    let name = self.string()?;
+                    let value = self.read_value()?;
+                    instance.set_field(name, value);
+                }
+            },
+            Object::List(items) => {
+                for _ in 0..self.u32()? {
+                    let item = self.read_value()?;
+                    items.borrow_mut().push(item);
+                }
+            },
+            Object::Map(map) => {
+                for _ in 0..self.u32()? {
+                    let key = MapKey::new(self.read_value()?)?;
+                    let value = self.read_value()?;
+                    map.borrow_mut().insert(key, value);
+                }
+            },
+            _ => bail!("Object has no contents to fill")
+        }
+
+        Ok(())
+    }
+
+    /// Reads an object id and returns the object it refers to
+    fn object_ref(&mut self) -> Result<Object> {
+        let id = self.u32()?;
+        self.objects.get(id as usize).cloned().ok_or_else(|| anyhow!("Reference to unknown object {}", id))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len())
+            .context("Serialized data ends unexpectedly")?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?).context("Serialized string is not valid utf8")
+    }
+}
+
+pub fn put_u8(out: &mut Vec<u8>, value: u8) {
+    out.push(value);
+}
+
+pub fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+pub fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_bytes(out, s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: &Value) -> Value {
+        let mut writer = ValueWriter::new();
+        let mut roots = Vec::new();
+        writer.write_value(&mut roots, value).unwrap();
+        let data = writer.finish(&roots).unwrap();
+
+        let no_natives = |_: &str| None;
+        let mut reader = ValueReader::new(&data, &no_natives).unwrap();
+        let value = reader.read_value().unwrap();
+        assert!(reader.is_at_end());
+        value
+    }
+
+    #[test]
+    fn scalars_and_strings() {
+        for value in [Value::Nil, Value::Boolean(true), Value::Number(-0.5), Value::String("hé".to_string()), Value::Bytes(Rc::new(vec![0, 255]))] {
+            assert_eq!(round_trip(&value), value);
+        }
+        // Compared by variant since an int equals the number holding the same value
+        assert!(matches!(round_trip(&Value::Int(i64::MIN)), Value::Int(i64::MIN)));
+    }
+
+    #[test]
+    fn cycles_and_sharing_survive() {
+        let class = Rc::new(Class::new("Node"));
+        let instance = Rc::new(Instance::new(class));
+        let list = Value::list(vec![Value::Instance(instance.clone()), Value::Instance(instance.clone())]);
+        instance.set_field("self", Value::Instance(instance.clone()));
+        instance.set_field("list", list.clone());
+
+        let copy = match round_trip(&list) {
+            Value::List(items) => items,
+            other => panic!("Expected a list, got {}", other)
+        };
+
+        let items = copy.borrow();
+        let (first, second) = match (&items[0], &items[1]) {
+            (Value::Instance(a), Value::Instance(b)) => (a.clone(), b.clone()),
+            _ => panic!("Expected instances")
+        };
+        assert!(Rc::ptr_eq(&first, &second));
+        assert_eq!(first.class.name, "Node");
+        assert!(matches!(first.get_field("self"), Some(Value::Instance(i)) if Rc::ptr_eq(&i, &first)));
+        assert!(matches!(first.get_field("list"), Some(Value::List(l)) if Rc::ptr_eq(&l, &copy)));
+    }
+
+    #[test]
+    fn rejects_bad_data() {
+        let no_natives = |_: &str| None;
+        assert!(ValueReader::new(b"nope", &no_natives).is_err());
+        assert!(ValueReader::new(b"LOXV\x02\x00\x00\x00\x05\x00\x00\x00", &no_natives).is_err());
+    }
+}
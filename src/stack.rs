@@ -1,52 +1,108 @@
-
 use anyhow::{Result, bail};
+use thiserror::Error;
+
+/// What `Stack::push` fails with when the stack is full
+#[derive(Debug, Error)]
+#[error("Stack overflow")]
+pub struct StackOverflow;
+
+/// A stack holding at most a fixed number of items, all allocated up front so that it never
+/// grows while in use
 #[derive(Debug)]
-pub struct Stack<T>(Vec<T>);
+pub struct Stack<T> {
+    items: Vec<T>,
+    capacity: usize,
+    /// Most items the stack has held at once
+    peak: usize
+}
 
 impl<T> Stack<T> {
-    pub fn new() -> Self {
-        Self(Vec::new())
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { items: Vec::with_capacity(capacity), capacity, peak: 0 }
+    }
+
+    /// A stack of the items, bottom first, that can hold up to `capacity`
+    pub fn from_vec(mut items: Vec<T>, capacity: usize) -> Result<Self> {
+        if items.len() > capacity {
+            bail!(StackOverflow);
+        }
+
+        items.reserve_exact(capacity - items.len());
+        let peak = items.len();
+        Ok(Self { items, capacity, peak })
+    }
+
+    /// The items from the bottom of the stack to the top
+    pub fn as_slice(&self) -> &[T] {
+        &self.items
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 
-    pub fn push(&mut self, item :T) {
-        self.0.push(item)
+    /// Most items the stack has held at once since it was made
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+
+    pub fn push(&mut self, item :T) -> Result<()> {
+        if self.items.len() == self.capacity {
+            bail!(StackOverflow);
+        }
+
+        self.items.push(item);
+        self.peak = self.peak.max(self.items.len());
+        Ok(())
     }
 
     pub fn pop(&mut self) -> Result<T> {
-        if self.0.is_empty() {
+        if self.is_empty() {
             bail!("Stack underflow");
         }
 
-        Ok(self.0.pop().unwrap())
+        Ok(self.items.pop().unwrap())
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.items.truncate(len)
     }
 
-    pub fn peek(&self, pos: usize) -> Result<&T> 
+    pub fn peek(&self, pos: usize) -> Result<&T>
     {
-        if (pos + 1) > self.0.len() {
+        if (pos + 1) > self.items.len() {
             bail!("Stack underflow");
         }
 
-        let index = self.0.len() - (pos + 1);
+        let index = self.items.len() - (pos + 1);
 
-        Ok(&self.0[index])
+        Ok(&self.items[index])
     }
 
 
     pub fn peek_front(&self, pos: usize) -> Result<&T> {
-        if pos  >= self.0.len() {
+        if pos  >= self.items.len() {
             bail!("Stack overflow");
         }
 
-        Ok(&self.0[pos])
+        Ok(&self.items[pos])
     }
 
     pub fn set_front(&mut self, pos: usize, value: T) -> Result<()> {
-        if pos  >= self.0.len() {
+        if pos  >= self.items.len() {
             bail!("Stack overflow");
         }
 
-        self.0[pos] = value;
+        self.items[pos] = value;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
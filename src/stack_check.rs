@@ -0,0 +1,88 @@
+//! Checks, after every instruction, that the stack grew or shrank by exactly the
+//! instruction's declared stack effect. A mismatch means the compiler emitted unbalanced
+//! code or an opcode's implementation disagrees with `Instruction::stack_effect`, so it
+//! panics right there instead of letting a wrong value surface much later.
+//!
+//! Enabled by the `stack-check` feature, meant for `cargo test --features stack-check`.
+
+use crate::instruction::Instruction;
+
+#[derive(Debug, Default)]
+pub struct StackCheck {
+    /// For each call still in progress, the number of frames it made and the caller's stack
+    /// depth expected once it returns
+    pending_returns: Vec<(usize, usize)>
+}
+
+/// Stack and call depth at some point of execution
+#[derive(Debug, Clone, Copy)]
+pub struct Depths {
+    pub stack: usize,
+    pub frames: usize
+}
+
+impl StackCheck {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn after(&mut self, instruction: &Instruction, offset: usize, src_line_number: i32, before: Depths, after: Depths) {
+        let expected_after_call = before.stack as i64 + instruction.stack_effect() as i64;
+
+        let expected = if after.frames > before.frames {
+            // A call into Lox code: its effect shows once the callee returns
+            self.pending_returns.push((after.frames, expected_after_call as usize));
+            return;
+        } else if after.frames < before.frames {
+            // Calls made before a resumed checkpoint weren't seen, so can't be checked
+            match self.pending_returns.pop() {
+                Some((_, expected)) => expected as i64,
+                None => return
+            }
+        } else {
+            expected_after_call
+        };
+
+        if after.stack as i64 != expected {
+            panic!("Stack discipline violated by {} at offset {} (line {}): expected depth {} but found {}",
+                instruction, offset, src_line_number, expected, after.stack);
+        }
+    }
+
+    /// Forgets calls whose frames were unwound by a thrown exception
+    pub fn unwind_to(&mut self, frames: usize) {
+        self.pending_returns.retain(|(call_frames, _)| *call_frames <= frames);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::OpCode;
+
+    fn depths(stack: usize, frames: usize) -> Depths {
+        Depths { stack, frames }
+    }
+
+    #[test]
+    fn balanced_instructions_pass() {
+        let mut check = StackCheck::new();
+        check.after(&Instruction::simple(OpCode::Nil), 0, 1, depths(0, 1), depths(1, 1));
+        check.after(&Instruction::unary(OpCode::Call, 1), 1, 1, depths(3, 1), depths(3, 2));
+        check.after(&Instruction::simple(OpCode::Return), 0, 1, depths(4, 2), depths(2, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Stack discipline violated by Pop")]
+    fn unbalanced_instruction_panics() {
+        StackCheck::new().after(&Instruction::simple(OpCode::Pop), 0, 1, depths(2, 1), depths(2, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Stack discipline violated by Return")]
+    fn unbalanced_call_panics_on_return() {
+        let mut check = StackCheck::new();
+        check.after(&Instruction::unary(OpCode::Call, 0), 0, 1, depths(1, 1), depths(1, 2));
+        check.after(&Instruction::simple(OpCode::Return), 0, 1, depths(3, 2), depths(2, 1));
+    }
+}
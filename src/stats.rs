@@ -0,0 +1,224 @@
+use std::{collections::HashMap, fmt::Display};
+
+use anyhow::{Result, Context};
+
+use crate::{chunk::Chunk, instruction::{InstructionReader, Instruction, OpCode}, value::Value};
+
+/// Static metrics of one compiled function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub name: String,
+    pub instructions: usize,
+    pub constants: usize,
+    /// Number of local slots the code addresses, including parameters and the callee slot
+    pub max_locals: usize,
+    /// Deepest the function's part of the stack can get
+    pub max_stack: usize
+}
+
+/// Collects stats for the top-level chunk and every function declared in it
+pub fn function_stats(chunk: &Chunk) -> Result<Vec<FunctionStats>> {
+    let mut stats = Vec::new();
+    collect(chunk, "<script>".to_string(), 0, &mut stats)?;
+    Ok(stats)
+}
+
+fn collect(chunk: &Chunk, name: String, initial_slots: usize, stats: &mut Vec<FunctionStats>) -> Result<()> {
+    let instructions = decode(chunk).with_context(|| format!("Failed to decode {}", name))?;
+
+    let max_locals = instructions.iter()
+        .filter_map(|(_, instruction)| match instruction.op_code {
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::GetLocalLong | OpCode::SetLocalLong | OpCode::GetLocalAdd => instruction.local_slot().map(|slot| slot + 1),
+            _ => None
+        })
+        .chain(std::iter::once(initial_slots))
+        .max()
+        .unwrap_or(0);
+
+    stats.push(FunctionStats {
+        name,
+        instructions: instructions.len(),
+        constants: chunk.constants().len(),
+        max_locals,
+        max_stack: max_stack_depth(&instructions, initial_slots)
+    });
+
+    for constant in chunk.constants() {
+        if let Value::Function(function) = constant {
+            // The callee and the arguments, with any beyond the arity in a list, are on the stack when the function starts
+            collect(&function.chunk, function.to_string(), function.arity as usize + function.variadic as usize + 1, stats)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn decode(chunk: &Chunk) -> Result<Vec<(usize, Instruction)>> {
+    let mut reader = InstructionReader::new(chunk);
+    let mut instructions = Vec::new();
+
+    while let Some((instruction, offset, _)) = reader.read_next()? {
+        instructions.push((offset, instruction));
+    }
+
+    Ok(instructions)
+}
+
+/// Follows every path through the code, tracking the stack depth at each instruction
+fn max_stack_depth(instructions: &[(usize, Instruction)], initial_depth: usize) -> usize {
+    let index_by_offset: HashMap<usize, usize> = instructions.iter()
+        .enumerate()
+        .map(|(i, (offset, _))| (*offset, i))
+        .collect();
+
+    let mut depth_at: Vec<Option<i64>> = vec![None; instructions.len()];
+    let mut pending = vec![(0, initial_depth as i64)];
+    let mut max_depth = initial_depth as i64;
+
+    while let Some((index, depth)) = pending.pop() {
+        let Some((offset, instruction)) = instructions.get(index) else { continue };
+
+        match depth_at[index] {
+            Some(seen) if seen >= depth => continue,
+            _ => depth_at[index] = Some(depth)
+        }
+
+        let depth_after = depth + instruction.stack_effect() as i64;
+        max_depth = max_depth.max(depth).max(depth_after);
+
+        if let OpCode::Return = instruction.op_code {
+            continue;
+        }
+
+        if let Some(target) = instruction.jump_target(*offset).and_then(|t| index_by_offset.get(&t)) {
+            // A handler starts with the thrown value pushed
+            let target_depth = if let OpCode::PushHandler = instruction.op_code { depth_after + 1 } else { depth_after };
+            pending.push((*target, target_depth));
+        }
+
+        if !matches!(instruction.op_code, OpCode::Jump | OpCode::JumpLong | OpCode::Loop | OpCode::LoopLong) {
+            pending.push((index + 1, depth_after));
+        }
+    }
+
+    max_depth.max(0) as usize
+}
+
+/// Size of a compiled script and the functions declared in it, and how often each opcode appears
+#[derive(Debug, Clone, Default)]
+pub struct ChunkStats {
+    pub functions: usize,
+    pub code_bytes: usize,
+    pub instructions: usize,
+    pub constants: usize,
+    /// Number of instructions with each opcode, most common first
+    pub op_codes: Vec<(OpCode, usize)>
+}
+
+pub fn chunk_stats(chunk: &Chunk) -> Result<ChunkStats> {
+    let mut stats = ChunkStats::default();
+    let mut counts = HashMap::new();
+    add_chunk(chunk, &mut stats, &mut counts)?;
+
+    stats.op_codes = counts.into_iter()
+        .map(|(byte, count)| Ok((OpCode::try_from(byte)?, count)))
+        .collect::<Result<_>>()?;
+    stats.op_codes.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then((a.clone() as u8).cmp(&(b.clone() as u8))));
+    Ok(stats)
+}
+
+fn add_chunk(chunk: &Chunk, stats: &mut ChunkStats, counts: &mut HashMap<u8, usize>) -> Result<()> {
+    let instructions = decode(chunk)?;
+    stats.functions += 1;
+    stats.code_bytes += chunk.len();
+    stats.instructions += instructions.len();
+    stats.constants += chunk.constants().len();
+    for (_, instruction) in instructions {
+        *counts.entry(instruction.op_code as u8).or_default() += 1;
+    }
+
+    for constant in chunk.constants() {
+        if let Value::Function(function) = constant {
+            add_chunk(&function.chunk, stats, counts)?;
+        }
+    }
+
+    Ok(())
+}
+
+impl Display for ChunkStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Code: {} bytes, {} instructions in {} functions", self.code_bytes, self.instructions, self.functions)?;
+        writeln!(f, "Constants: {}", self.constants)?;
+        writeln!(f, "Opcodes:")?;
+        for (op_code, count) in &self.op_codes {
+            let percent = 100.0 * *count as f64 / self.instructions as f64;
+            writeln!(f, "  {:<18}{:>8} {:>6.1}%", op_code.to_string(), count, percent)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct StatsTable<'a>(pub &'a [FunctionStats]);
+
+impl Display for StatsTable<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name_width = self.0.iter().map(|s| s.name.len()).max().unwrap_or(0).max("function".len());
+
+        writeln!(f, "{:<name_width$}  {:>12}  {:>9}  {:>10}  {:>9}", "function", "instructions", "constants", "max locals", "max stack")?;
+        for stats in self.0 {
+            writeln!(f, "{:<name_width$}  {:>12}  {:>9}  {:>10}  {:>9}",
+                stats.name, stats.instructions, stats.constants, stats.max_locals, stats.max_stack)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    fn stats_for(source: &str) -> Vec<FunctionStats> {
+        let chunk = Compiler::new(source.to_string()).compile().unwrap();
+        function_stats(&chunk).unwrap()
+    }
+
+    #[test]
+    fn script_stats() {
+        let stats = stats_for("var a = 1 + 2 * 3; print a;");
+        assert_eq!(stats, vec![FunctionStats { name: "<script>".to_string(), instructions: 10, constants: 4, max_locals: 0, max_stack: 3 }]);
+    }
+
+    #[test]
+    fn function_stats_include_params_and_locals() {
+        let stats = stats_for("fun f(a, b) { var c = a; { var d = b; print d; } return c; }");
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[1].name, "<fn f>");
+        assert_eq!(stats[1].max_locals, 5);
+        assert_eq!(stats[1].max_stack, 6);
+    }
+
+    #[test]
+    fn stack_depth_follows_branches_and_loops() {
+        let stats = stats_for("var i = 0; while (i < 3) { if (i == 1) print i; i = i + 1; }");
+        assert_eq!(stats[0].max_stack, 2);
+    }
+
+    #[test]
+    fn chunk_stats_count_every_function() {
+        let chunk = Compiler::new("fun f(a) { return a; } print f(1) + f(2);".to_string()).compile().unwrap();
+        let stats = chunk_stats(&chunk).unwrap();
+        let functions = function_stats(&chunk).unwrap();
+        assert_eq!(stats.functions, 2);
+        assert_eq!(stats.instructions, functions.iter().map(|f| f.instructions).sum::<usize>());
+        assert_eq!(stats.constants, functions.iter().map(|f| f.constants).sum::<usize>());
+        assert_eq!(stats.op_codes.iter().map(|(_, count)| count).sum::<usize>(), stats.instructions);
+        let count_of = |name: &str| stats.op_codes.iter().find(|(op_code, _)| op_code.to_string() == name).map(|(_, count)| *count);
+        assert_eq!(count_of("ConstantCall"), Some(2));
+        // `f` also ends with the return of nil every function has
+        assert_eq!(count_of("Return"), Some(3));
+    }
+}
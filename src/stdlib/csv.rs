@@ -0,0 +1,131 @@
+//! Reading and writing comma and tab separated text
+
+use std::rc::Rc;
+
+use anyhow::{Result, bail};
+
+use crate::{native::Args, value::Value, vm::Vm};
+
+pub fn define_natives(vm: &mut Vm) {
+    vm.define_native("csvParse", 1, Rc::new(|_, args| parse_delimited(args, ',', "csvParse")));
+    vm.define_native("csvStringify", 1, Rc::new(|_, args| stringify_delimited(args, ',', "csvStringify")));
+    vm.define_native("tsvParse", 1, Rc::new(|_, args| parse_delimited(args, '\t', "tsvParse")));
+    vm.define_native("tsvStringify", 1, Rc::new(|_, args| stringify_delimited(args, '\t', "tsvStringify")));
+}
+
+fn parse_delimited(args: &[Value], delimiter: char, native_name: &str) -> Result<Value> {
+    let text = Args::new(native_name, args).get_string(0)?;
+
+    let rows = parse_rows(text, delimiter).map_err(|e| anyhow::anyhow!("{}: {}", native_name, e))?;
+    let rows = rows.into_iter()
+        .map(|row| Value::list(row.into_iter().map(Value::String).collect()))
+        .collect();
+
+    Ok(Value::list(rows))
+}
+
+/// Splits text into rows of fields. Fields may be quoted to contain delimiters, newlines
+/// or quotes, the latter written twice. A trailing newline doesn't start another row.
+fn parse_rows(text: &str, delimiter: char) -> Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                },
+                '"' => in_quotes = false,
+                c => field.push(c)
+            }
+            continue;
+        }
+
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            c if c == delimiter => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {},
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            },
+            c => field.push(c)
+        }
+    }
+
+    if in_quotes {
+        bail!("Unterminated quoted field");
+    }
+
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+fn stringify_delimited(args: &[Value], delimiter: char, native_name: &str) -> Result<Value> {
+    let rows = Args::new(native_name, args).get_list(0)?;
+
+    let mut text = String::new();
+    for row in rows.iter() {
+        let fields = match row {
+            Value::List(fields) => fields.borrow(),
+            _ => bail!("{} expects every row to be a list", native_name)
+        };
+
+        let line: Vec<String> = fields.iter().map(|field| quote_field(&field.to_string(), delimiter)).collect();
+        text.push_str(&line.join(&delimiter.to_string()));
+        text.push('\n');
+    }
+
+    Ok(Value::String(text))
+}
+
+fn quote_field(field: &str, delimiter: char) -> String {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(text: &str) -> Vec<Vec<String>> {
+        parse_rows(text, ',').unwrap()
+    }
+
+    #[test]
+    fn parses_plain_and_quoted_fields() {
+        assert_eq!(rows("a,b\r\n1,\"x, \"\"y\"\"\nz\"\n"), vec![
+            vec!["a".to_string(), "b".to_string()],
+            vec!["1".to_string(), "x, \"y\"\nz".to_string()]
+        ]);
+        assert_eq!(rows("a,,\n"), vec![vec!["a".to_string(), String::new(), String::new()]]);
+        assert!(rows("").is_empty());
+        assert!(parse_rows("\"open", ',').is_err());
+    }
+
+    #[test]
+    fn stringify_round_trips() {
+        let text = "name,note\nann,\"says \"\"hi\"\", twice\"\n";
+        let parsed = parse_delimited(&[Value::String(text.to_string())], ',', "csvParse").unwrap();
+        assert_eq!(stringify_delimited(&[parsed], ',', "csvStringify").unwrap(), Value::String(text.to_string()));
+    }
+
+    #[test]
+    fn tsv_uses_tabs() {
+        assert_eq!(parse_rows("a\tb,c\n", '\t').unwrap(), vec![vec!["a".to_string(), "b,c".to_string()]]);
+        let rows = Value::list(vec![Value::list(vec![Value::Number(1.0), Value::String("x\ty".to_string())])]);
+        assert_eq!(stringify_delimited(&[rows], '\t', "tsvStringify").unwrap(), Value::String("1\t\"x\ty\"\n".to_string()));
+    }
+}
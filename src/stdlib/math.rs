@@ -0,0 +1,89 @@
+//! Math functions. Those that can keep an int result do, as for the arithmetic operators.
+
+use std::rc::Rc;
+
+use anyhow::Result;
+
+use crate::{native::Args, value::{Value, int_equals_float}, vm::Vm};
+
+pub fn define_natives(vm: &mut Vm) {
+    vm.define_native("sqrt", 1, Rc::new(|_, args| float_fn("sqrt", args, f64::sqrt)));
+    vm.define_native("sin", 1, Rc::new(|_, args| float_fn("sin", args, f64::sin)));
+    vm.define_native("cos", 1, Rc::new(|_, args| float_fn("cos", args, f64::cos)));
+    vm.define_native("floor", 1, Rc::new(|_, args| rounding_fn("floor", args, f64::floor)));
+    vm.define_native("ceil", 1, Rc::new(|_, args| rounding_fn("ceil", args, f64::ceil)));
+    vm.define_native("abs", 1, Rc::new(abs));
+    vm.define_native("min", 2, Rc::new(|_, args| pick("min", args, |a, b| b < a)));
+    vm.define_native("max", 2, Rc::new(|_, args| pick("max", args, |a, b| b > a)));
+    vm.define_native("pow", 2, Rc::new(pow));
+}
+
+fn float_fn(name: &str, args: &[Value], f: fn(f64) -> f64) -> Result<Value> {
+    Ok(Value::Number(f(Args::new(name, args).get_number(0)?)))
+}
+
+/// Rounds a number to an int, or to a whole number if it's too large for an int
+fn rounding_fn(name: &str, args: &[Value], f: fn(f64) -> f64) -> Result<Value> {
+    let args = Args::new(name, args);
+    if let Value::Int(n) = args.get(0)? {
+        return Ok(Value::Int(*n));
+    }
+    let rounded = f(args.get_number(0)?);
+    Ok(if int_equals_float(rounded as i64, rounded) { Value::Int(rounded as i64) } else { Value::Number(rounded) })
+}
+
+fn abs(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let args = Args::new("abs", args);
+    Ok(match args.get(0)? {
+        Value::Int(n) => n.checked_abs().map_or(Value::Number((*n as f64).abs()), Value::Int),
+        _ => Value::Number(args.get_number(0)?.abs())
+    })
+}
+
+/// The first argument, unless `replace` prefers the second. Either keeps its type.
+fn pick(name: &str, args: &[Value], replace: fn(f64, f64) -> bool) -> Result<Value> {
+    let args = Args::new(name, args);
+    let (a, b) = (args.get_number(0)?, args.get_number(1)?);
+    Ok(if replace(a, b) { args.get(1)? } else { args.get(0)? }.clone())
+}
+
+/// `pow(base, exponent)`: an int if both are ints, the exponent isn't negative and the
+/// result fits
+fn pow(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let args = Args::new("pow", args);
+    if let (Value::Int(base), Value::Int(exponent)) = (args.get(0)?, args.get(1)?) {
+        if let Some(result) = u32::try_from(*exponent).ok().and_then(|exponent| base.checked_pow(exponent)) {
+            return Ok(Value::Int(result));
+        }
+    }
+    Ok(Value::Number(args.get_number(0)?.powf(args.get_number(1)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounding_gives_ints() {
+        assert!(matches!(rounding_fn("floor", &[Value::Number(-2.5)], f64::floor).unwrap(), Value::Int(-3)));
+        assert!(matches!(rounding_fn("ceil", &[Value::Number(2.1)], f64::ceil).unwrap(), Value::Int(3)));
+        assert!(matches!(rounding_fn("floor", &[Value::Number(1e300)], f64::floor).unwrap(), Value::Number(_)));
+        assert!(rounding_fn("floor", &[Value::Nil], f64::floor).is_err());
+    }
+
+    #[test]
+    fn pow_and_abs_keep_ints_while_they_fit() {
+        let mut vm = Vm::new(crate::vm::VmOptions::default());
+        assert!(matches!(pow(&mut vm, &[Value::Int(2), Value::Int(10)]).unwrap(), Value::Int(1024)));
+        assert!(matches!(pow(&mut vm, &[Value::Int(2), Value::Int(-1)]).unwrap(), Value::Number(n) if n == 0.5));
+        assert!(matches!(pow(&mut vm, &[Value::Int(10), Value::Int(30)]).unwrap(), Value::Number(_)));
+        assert!(matches!(abs(&mut vm, &[Value::Int(-4)]).unwrap(), Value::Int(4)));
+        assert!(matches!(abs(&mut vm, &[Value::Int(i64::MIN)]).unwrap(), Value::Number(_)));
+    }
+
+    #[test]
+    fn min_and_max_keep_the_chosen_value() {
+        assert!(matches!(pick("min", &[Value::Int(3), Value::Number(2.5)], |a, b| b < a).unwrap(), Value::Number(n) if n == 2.5));
+        assert!(matches!(pick("max", &[Value::Int(3), Value::Number(2.5)], |a, b| b > a).unwrap(), Value::Int(3)));
+    }
+}
@@ -0,0 +1,18 @@
+//! Natives that aren't part of the core language. Only compiled with the `stdlib` feature.
+
+mod csv;
+mod math;
+mod modules;
+mod strings;
+mod text;
+
+pub use modules::{module_paths, precompiled};
+
+use crate::vm::Vm;
+
+pub fn define_natives(vm: &mut Vm) {
+    csv::define_natives(vm);
+    math::define_natives(vm);
+    strings::define_natives(vm);
+    text::define_natives(vm);
+}
@@ -0,0 +1,17 @@
+//! Standard library modules written in Lox, imported as `std/<name>`. The build script
+//! precompiles them and only their bytecode is embedded.
+
+const MODULES: &[(&str, &[u8])] = &[
+    ("std/list", include_bytes!(concat!(env!("OUT_DIR"), "/list.loxc"))),
+    ("std/string", include_bytes!(concat!(env!("OUT_DIR"), "/string.loxc")))
+];
+
+/// The bytecode of the module an import path names, if it's a standard library one
+pub fn precompiled(path: &str) -> Option<&'static [u8]> {
+    MODULES.iter().find(|(name, _)| *name == path).map(|(_, bytecode)| *bytecode)
+}
+
+/// Import paths of all the standard library modules
+pub fn module_paths() -> impl Iterator<Item = &'static str> {
+    MODULES.iter().map(|(name, _)| *name)
+}
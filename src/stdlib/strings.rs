@@ -0,0 +1,98 @@
+//! Basic string handling. Positions and lengths count characters, not bytes.
+
+use std::rc::Rc;
+
+use anyhow::{Result, bail};
+
+use crate::{native::Args, value::Value, vm::Vm};
+
+pub fn define_natives(vm: &mut Vm) {
+    vm.define_native("substr", 3, Rc::new(substr));
+    vm.define_native("upper", 1, Rc::new(|_, args| Ok(Value::String(Args::new("upper", args).get_string(0)?.to_uppercase()))));
+    vm.define_native("lower", 1, Rc::new(|_, args| Ok(Value::String(Args::new("lower", args).get_string(0)?.to_lowercase()))));
+    vm.define_native("indexOf", 2, Rc::new(index_of));
+    vm.define_native("split", 2, Rc::new(split));
+}
+
+/// `substr(s, start, len)`: the `len` characters of `s` from position `start`
+fn substr(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let args = Args::new("substr", args);
+    let (s, start, len) = (args.get_string(0)?, args.get_int(1)?, args.get_int(2)?);
+    let char_count = s.chars().count() as i64;
+    if start < 0 || start > char_count {
+        bail!("substr start {} out of range for length {}", start, char_count);
+    }
+    if len < 0 || start + len > char_count {
+        bail!("substr length {} from {} runs past the end of a string of length {}", len, start, char_count);
+    }
+
+    Ok(Value::String(s.chars().skip(start as usize).take(len as usize).collect()))
+}
+
+/// `indexOf(s, part)`: the position of the first `part` in `s`, or -1 if there isn't one.
+/// Also finds the first item of a list equal to a value.
+fn index_of(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let args = Args::new("indexOf", args);
+    if let Value::List(items) = args.get(0)? {
+        let value = args.get(1)?;
+        return Ok(Value::Int(items.borrow().iter().position(|item| item == value).map_or(-1, |i| i as i64)));
+    }
+
+    let (s, part) = (args.get_string(0)?, args.get_string(1)?);
+    Ok(Value::Int(match s.find(part) {
+        Some(byte_index) => s[..byte_index].chars().count() as i64,
+        None => -1
+    }))
+}
+
+/// `split(s, separator)`: the parts of `s` between separators as a list of strings, or each
+/// character of `s` if the separator is empty
+fn split(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let args = Args::new("split", args);
+    let (s, separator) = (args.get_string(0)?, args.get_string(1)?);
+    let parts: Vec<Value> = if separator.is_empty() {
+        s.chars().map(|c| Value::String(c.to_string())).collect()
+    } else {
+        s.split(separator).map(|part| Value::String(part.to_string())).collect()
+    };
+
+    Ok(Value::list(parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VmOptions;
+
+    fn call(native: fn(&mut Vm, &[Value]) -> Result<Value>, args: &[Value]) -> Result<Value> {
+        native(&mut Vm::new(VmOptions::default()), args)
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn substr_counts_characters() {
+        assert_eq!(call(substr, &[string("héllo"), Value::Int(1), Value::Int(3)]).unwrap(), string("éll"));
+        assert_eq!(call(substr, &[string("abc"), Value::Int(3), Value::Int(0)]).unwrap(), string(""));
+        assert!(call(substr, &[string("abc"), Value::Int(2), Value::Int(2)]).is_err());
+        assert!(call(substr, &[string("abc"), Value::Int(-1), Value::Int(1)]).is_err());
+    }
+
+    #[test]
+    fn index_of_finds_the_first_match() {
+        assert_eq!(call(index_of, &[string("naïve vs naïve"), string("ve")]).unwrap(), Value::Int(3));
+        assert_eq!(call(index_of, &[string("abc"), string("x")]).unwrap(), Value::Int(-1));
+        let list = Value::list(vec![string("a"), Value::Int(2)]);
+        assert_eq!(call(index_of, &[list.clone(), Value::Number(2.0)]).unwrap(), Value::Int(1));
+        assert_eq!(call(index_of, &[list, string("b")]).unwrap(), Value::Int(-1));
+    }
+
+    #[test]
+    fn split_on_separator_or_into_characters() {
+        assert_eq!(call(split, &[string("a,,b"), string(",")]).unwrap().to_string(), "[a, , b]");
+        assert_eq!(call(split, &[string("hé"), string("")]).unwrap().to_string(), "[h, é]");
+        assert!(call(split, &[Value::Nil, string(",")]).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! Pulling numbers and words out of lines of text without regular expressions
+
+use std::{iter::Peekable, rc::Rc, str::Chars};
+
+use anyhow::{Result, bail};
+
+use crate::{native::Args, value::Value, vm::Vm};
+
+pub fn define_natives(vm: &mut Vm) {
+    vm.define_native("scan", 2, Rc::new(scan));
+    vm.define_native("splitWhitespace", 1, Rc::new(split_whitespace));
+}
+
+/// `splitWhitespace(s)`: the words of `s` as a list of strings
+fn split_whitespace(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let s = Args::new("splitWhitespace", args).get_string(0)?;
+    Ok(Value::list(s.split_whitespace().map(|w| Value::String(w.to_string())).collect()))
+}
+
+/// `scan(s, fmt)`: matches `s` against `fmt` and returns the captured values as a list, or nil
+/// if it doesn't match. In the format `%d` captures an integer, `%f` a number, `%s` a word and
+/// `%%` matches a percent sign. Whitespace matches any run of whitespace, including none, and
+/// everything else must appear literally.
+fn scan(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let args = Args::new("scan", args);
+    let (input, format) = (args.get_string(0)?, args.get_string(1)?);
+
+    Ok(match scan_captures(input, format)? {
+        Some(captures) => Value::list(captures),
+        None => Value::Nil
+    })
+}
+
+fn scan_captures(input: &str, format: &str) -> Result<Option<Vec<Value>>> {
+    let mut input = input.chars().peekable();
+    let mut format = format.chars().peekable();
+    let mut captures = Vec::new();
+
+    while let Some(f) = format.next() {
+        if f.is_whitespace() {
+            skip_whitespace(&mut input);
+            continue;
+        }
+
+        if f != '%' {
+            if input.next() != Some(f) {
+                return Ok(None);
+            }
+            continue;
+        }
+
+        let capture = match format.next() {
+            Some('%') => {
+                if input.next() != Some('%') {
+                    return Ok(None);
+                }
+                continue;
+            },
+            Some('d') => {
+                skip_whitespace(&mut input);
+                let digits = take_while(&mut input, |c, taken| c.is_ascii_digit() || (taken.is_empty() && (c == '-' || c == '+')));
+                digits.parse::<i64>().ok().map(Value::Int)
+            },
+            Some('f') => {
+                skip_whitespace(&mut input);
+                let number = take_while(&mut input, |c, taken| {
+                    c.is_ascii_digit() || c == '.' || ((c == '-' || c == '+') && (taken.is_empty() || taken.ends_with(['e', 'E'])))
+                        || ((c == 'e' || c == 'E') && !taken.is_empty())
+                });
+                number.parse::<f64>().ok().map(Value::Number)
+            },
+            Some('s') => {
+                skip_whitespace(&mut input);
+                let word = take_while(&mut input, |c, _| !c.is_whitespace());
+                if word.is_empty() { None } else { Some(Value::String(word)) }
+            },
+            Some(other) => bail!("Unknown scan directive '%{}'", other),
+            None => bail!("Scan format ends with a lone '%'")
+        };
+
+        match capture {
+            Some(value) => captures.push(value),
+            None => return Ok(None)
+        }
+    }
+
+    Ok(Some(captures))
+}
+
+fn skip_whitespace(input: &mut Peekable<Chars>) {
+    while input.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+/// Consumes characters while `accept` approves of them given what's been taken so far
+fn take_while<F: Fn(char, &str) -> bool>(input: &mut Peekable<Chars>, accept: F) -> String {
+    let mut taken = String::new();
+    while let Some(c) = input.next_if(|c| accept(*c, &taken)) {
+        taken.push(c);
+    }
+    taken
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captures(input: &str, format: &str) -> Option<Vec<Value>> {
+        scan_captures(input, format).unwrap()
+    }
+
+    #[test]
+    fn scans_numbers_and_words() {
+        assert_eq!(captures("move 10 to -20", "move %d to %d"), Some(vec![Value::Number(10.0), Value::Number(-20.0)]));
+        assert_eq!(captures("x=2.5 e=2.7e0", "x=%f e=%f"), Some(vec![Value::Number(2.5), Value::Number(2.7)]));
+        assert_eq!(captures("  alice   42%", "%s %d%%"), Some(vec![Value::String("alice".to_string()), Value::Number(42.0)]));
+    }
+
+    #[test]
+    fn mismatches_give_none() {
+        assert_eq!(captures("move ten", "move %d"), None);
+        assert_eq!(captures("jump 1", "move %d"), None);
+        assert_eq!(captures("", "%s"), None);
+        assert!(scan_captures("1", "%x").is_err());
+    }
+}
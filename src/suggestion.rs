@@ -0,0 +1,66 @@
+//! Working out which name a misspelt one was meant to be
+
+use std::mem;
+
+/// How many characters have to be inserted, deleted, replaced, or swapped with the one next
+/// to them to turn `a` into `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // Distances between prefixes of `a` and of `b`, a row per prefix of `a`, keeping the
+    // two rows before the current one for swaps
+    let mut before_prev: Vec<usize> = Vec::new();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (prev[j] + 1).min(row[j - 1] + 1).min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(before_prev[j - 2] + 1);
+            }
+        }
+        before_prev = mem::replace(&mut prev, row);
+    }
+    prev[b.len()]
+}
+
+/// The candidate closest to `name`, if one is close enough to be what was meant: no further
+/// than a third of the name's length, and at least one edit, away. Of equally close ones the
+/// first is taken.
+pub fn closest<'a, I: IntoIterator<Item = &'a str>>(name: &str, candidates: I) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates.into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_insertions_deletions_replacements_and_swaps() {
+        assert_eq!(edit_distance("counter", "counter"), 0);
+        assert_eq!(edit_distance("countr", "counter"), 1);
+        assert_eq!(edit_distance("counterr", "counter"), 1);
+        assert_eq!(edit_distance("cointer", "counter"), 1);
+        assert_eq!(edit_distance("conuter", "counter"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn only_close_enough_names_are_suggested() {
+        assert_eq!(closest("countr", ["total", "counter", "count"]), Some("counter"));
+        // `count` and `counter` are both a single edit from `countr`; the first wins
+        assert_eq!(closest("countr", ["count", "counter"]), Some("count"));
+        assert_eq!(closest("x", ["y", "xs"]), Some("y"));
+        assert_eq!(closest("total", ["counter", "print"]), None);
+        assert_eq!(closest("counter", ["counter"]), None);
+    }
+}
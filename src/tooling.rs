@@ -0,0 +1,26 @@
+//! Everything the `lox` binary builds on beyond the [`prelude`](crate::prelude): the
+//! debugger, profiler, REPL session, disassembler, stats and the like. Not covered by any
+//! stability guarantee.
+
+pub use crate::allocations::AllocationReport;
+pub use crate::capability::Capability;
+pub use crate::chunk_cache::ChunkCache;
+pub use crate::constant_pool::{ConstantPool, SharedConstantPool};
+pub use crate::class::{Class, Instance, BoundMethod};
+pub use crate::compiler::{CompileWarning, DEFAULT_MAX_ERRORS};
+pub use crate::dap::serve_debug_adapter;
+pub use crate::debugger::{Debugger, DebugFrame, Location, Resume, StopReason, Stepper};
+pub use crate::diagnostic::{DiagnosticsFormat, Severity, StructuredDiagnostic};
+pub use crate::disassembler::{Disassembler, DisassemblyFormat, FunctionListing, listing_diff};
+pub use crate::foreign::{Foreign, ForeignObject};
+pub use crate::function::{Function, Closure};
+pub use crate::map::{Map, MapKey};
+pub use crate::native::{Args, NativeFunction, NativeFn};
+pub use crate::output::OutputBuffer;
+pub use crate::profile::{Profile, Profiler};
+pub use crate::global_history::{GlobalHistory, GlobalWrite};
+pub use crate::hooks::VmState;
+pub use crate::instruction::{Instruction, OpCode};
+pub use crate::repl::{ReplSession, CellResult};
+pub use crate::stats::{ChunkStats, FunctionStats, StatsTable, chunk_stats, function_stats};
+pub use crate::vm::{VmHandle, InlineCacheStats, StackTrace, TraceFrame, DEFAULT_TRACE_FRAME_LIMIT, DEFAULT_STACK_CAPACITY};
@@ -1,20 +1,202 @@
-use std::fmt::Display;
+use std::{cell::RefCell, cmp::Ordering, fmt::Display, rc::Rc};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+use crate::{bytes, foreign::Foreign, map::Map, function::{Function, Closure}, class::{Class, Instance, BoundMethod}, native::NativeFunction};
+
+#[derive(Debug, Clone)]
 pub enum Value {
     Number(f64),
+    /// A number written without a decimal point, or computed from such numbers without losing precision
+    Int(i64),
     Nil,
     Boolean(bool),
-    String(String)
+    String(String),
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    Native(Rc<NativeFunction>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map>>),
+    Bytes(Rc<Vec<u8>>),
+    Foreign(Foreign)
+}
+
+impl Value {
+    pub fn list(items: Vec<Value>) -> Self {
+        Value::List(Rc::new(RefCell::new(items)))
+    }
+
+    pub fn map(map: Map) -> Self {
+        Value::Map(Rc::new(RefCell::new(map)))
+    }
+
+    /// The value of a number, whichever kind it is
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Int(n) => Some(*n as f64),
+            _ => None
+        }
+    }
+
+    /// Whether the value counts as true in a condition: all but `nil` and `false` do
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    /// The name of the value's type, as used in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) | Value::Int(_) => "number",
+            Value::Nil => "nil",
+            Value::Boolean(_) => "bool",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::Closure(_) | Value::BoundMethod(_) | Value::Native(_) => "function",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Bytes(_) => "bytes",
+            Value::Foreign(_) => "foreign object"
+        }
+    }
+
+    /// Position of the variant in the declaration, ordering values of different types
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Number(_) => 0,
+            Value::Int(_) => 1,
+            Value::Nil => 2,
+            Value::Boolean(_) => 3,
+            Value::String(_) => 4,
+            Value::Function(_) => 5,
+            Value::Closure(_) => 6,
+            Value::Class(_) => 7,
+            Value::Instance(_) => 8,
+            Value::BoundMethod(_) => 9,
+            Value::Native(_) => 10,
+            Value::List(_) => 11,
+            Value::Map(_) => 12,
+            Value::Bytes(_) => 13,
+            Value::Foreign(_) => 14
+        }
+    }
+}
+
+/// Whether a float holds exactly the integer `int`
+pub fn int_equals_float(int: i64, float: f64) -> bool {
+    // i64::MAX as f64 rounds up to 2^63, which is out of range
+    float.fract() == 0.0 && float >= i64::MIN as f64 && float < i64::MAX as f64 && float as i64 == int
+}
+
+/// Ints and numbers are equal when they hold the same value, so `1 == 1.0`
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => int_equals_float(*a, *b),
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::Closure(a), Value::Closure(b)) => a == b,
+            (Value::Class(a), Value::Class(b)) => a == b,
+            (Value::Instance(a), Value::Instance(b)) => a == b,
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => a == b,
+            (Value::Native(a), Value::Native(b)) => a == b,
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b) || comparing(a.as_ptr().cast(), b.as_ptr().cast(), || a == b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b) || comparing(a.as_ptr().cast(), b.as_ptr().cast(), || a == b),
+            (Value::Bytes(a), Value::Bytes(b)) => a == b,
+            (Value::Foreign(a), Value::Foreign(b)) => a == b,
+            _ => false
+        }
+    }
+}
+
+thread_local! {
+    /// Lists and maps being printed further up the stack
+    static PRINTING: RefCell<Vec<*const ()>> = const { RefCell::new(Vec::new()) };
+    /// Pairs of lists or maps being compared further up the stack
+    static COMPARING: RefCell<Vec<(*const (), *const ())>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Prints the list or map at `ptr` with `print`, or returns `None` without calling it if it's
+/// already being printed, meaning it contains itself
+pub(crate) fn printing<T>(ptr: *const (), print: impl FnOnce() -> T) -> Option<T> {
+    if PRINTING.with(|printing| printing.borrow().contains(&ptr)) {
+        return None;
+    }
+    PRINTING.with(|printing| printing.borrow_mut().push(ptr));
+    let result = print();
+    PRINTING.with(|printing| printing.borrow_mut().pop());
+    Some(result)
+}
+
+/// Compares the lists or maps at `a` and `b` with `compare`. If they're already being compared
+/// they contain themselves the same way, and are taken to be equal as far as that goes.
+fn comparing(a: *const (), b: *const (), compare: impl FnOnce() -> bool) -> bool {
+    if COMPARING.with(|comparing| comparing.borrow().contains(&(a, b))) {
+        return true;
+    }
+    COMPARING.with(|comparing| comparing.borrow_mut().push((a, b)));
+    let equal = compare();
+    COMPARING.with(|comparing| comparing.borrow_mut().pop());
+    equal
+}
+
+/// Numbers of either kind compare by value. Other values of the same type compare by
+/// content and values of different types by the order of their variants.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Number(_) | Value::Int(_), Value::Number(_) | Value::Int(_)) => self.as_f64()?.partial_cmp(&other.as_f64()?),
+            (Value::Boolean(a), Value::Boolean(b)) => a.partial_cmp(b),
+            (Value::String(a), Value::String(b)) => a.partial_cmp(b),
+            (Value::Function(a), Value::Function(b)) => a.partial_cmp(b),
+            (Value::Closure(a), Value::Closure(b)) => a.partial_cmp(b),
+            (Value::Class(a), Value::Class(b)) => a.partial_cmp(b),
+            (Value::Instance(a), Value::Instance(b)) => a.partial_cmp(b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => a.partial_cmp(b),
+            (Value::Native(a), Value::Native(b)) => a.partial_cmp(b),
+            (Value::List(a), Value::List(b)) => a.partial_cmp(b),
+            (Value::Map(a), Value::Map(b)) => a.partial_cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.partial_cmp(b),
+            (Value::Foreign(a), Value::Foreign(b)) => a.partial_cmp(b),
+            _ => self.rank().partial_cmp(&other.rank())
+        }
+    }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Value::Number(n) => write!(f, "{}", n),
-            Value::Nil => write!(f, "{}", "nil"),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Nil => write!(f, "nil"),
             Value::Boolean(b) => write!(f, "{}", b),
             Value::String(s) => write!(f, "{}", s),
+            Value::Function(func) => write!(f, "{}", func),
+            Value::Closure(closure) => write!(f, "{}", closure),
+            Value::Class(class) => write!(f, "{}", class),
+            Value::Instance(instance) => write!(f, "{}", instance),
+            Value::BoundMethod(method) => write!(f, "{}", method),
+            Value::Native(native) => write!(f, "{}", native),
+            Value::Bytes(b) => write!(f, "{}", bytes::format(b)),
+            Value::Foreign(foreign) => write!(f, "{}", foreign),
+            Value::Map(map) => write!(f, "{}", map.borrow()),
+            Value::List(items) => printing(items.as_ptr().cast(), || {
+                write!(f, "[")?;
+                for (i, item) in items.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }).unwrap_or_else(|| write!(f, "[...]")),
         }?;
 
         Ok(())
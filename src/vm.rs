@@ -1,236 +1,5126 @@
-use std::collections::HashMap;
-use std::fmt::Display;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::io::{self, BufRead, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result, bail, anyhow};
 use thiserror::Error;
 
 use crate::disassembler::Disassembler;
+use crate::error_code;
+use crate::suggestion;
+use crate::class::{Class, Instance, BoundMethod, PropertyCache};
+use crate::function::{Function, Closure, Upvalue};
+use crate::bytes;
+use crate::eval;
+use crate::format;
+use crate::index;
+use crate::map::{self, MapKey};
+use crate::module::{self, Module};
+use crate::serialize;
+use crate::checkpoint::{self, FrameState, HandlerState, NativeRegistry};
+use crate::hooks::{InstructionHook, VmState};
+use crate::native::{self, NativeFunction, NativeFn};
+use crate::allocations::AllocationReport;
+use crate::global_history::GlobalHistory;
+use crate::ordered_map::OrderedMap;
+use crate::operand_source::describe_operand;
 use crate::instruction::{InstructionReader, OpCode, Instruction};
+use crate::capability::Capability;
 use crate::chunk::Chunk;
-use crate::stack::Stack;
+use crate::constant_pool::{ConstantPool, SharedConstantPool};
+use crate::compiler::Compiler;
+use crate::debugger::{DebugFrame, Debugger, Location};
+use crate::dialect::Dialect;
+use crate::output::SharedOutput;
+use crate::stack::{Stack, StackOverflow};
+#[cfg(feature = "stack-check")]
+use crate::stack_check::{Depths, StackCheck};
 use crate::value::Value;
 
+/// Maximum depth of nested calls before a stack overflow is reported
+const MAX_FRAMES: usize = 4096;
+
+/// Number of values the stack holds when `VmOptions::stack_capacity` isn't set
+pub const DEFAULT_STACK_CAPACITY: usize = 1 << 16;
+
+/// Number of instructions run before the next backward jump or call checks whether a
+/// `VmHandle` has cancelled the run or it has run out of time
+const STOP_CHECK_INTERVAL: u64 = 1024;
+
+/// Source of `Vm::globals_id`, so that no two sets of globals share one
+static NEXT_GLOBALS_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    slot_base: usize
+}
+
+impl CallFrame {
+    fn new(closure: Rc<Closure>, slot_base: usize) -> Self {
+        Self { closure, ip: 0, slot_base }
+    }
+
+    fn current_src_line_number(&self) -> i32 {
+        let offset = if self.ip > 0 { self.ip - 1 } else { 0 };
+        self.closure.function.chunk.get_src_line_number(offset).unwrap_or(0)
+    }
+}
+
+/// An active `try` block: where its `catch` starts and what to unwind to when something is thrown
+#[derive(Debug, Clone)]
+struct Handler {
+    /// Number of frames when the handler was installed, the last being the one it belongs to
+    frame_count: usize,
+    stack_len: usize,
+    catch_ip: usize
+}
+
+/// Where `input` reads lines from, in place of stdin
+struct InputStream(Box<dyn BufRead>);
+
+impl Debug for InputStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<input>")
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VmOptions {
+    /// Print the stack and each instruction as it executes
+    pub trace: bool,
+    /// Record every definition and assignment of globals so their history can be inspected after a failure
+    pub record_global_history: bool,
+    /// Count the objects created, by kind, so they can be reported after the run
+    pub record_allocations: bool,
+    /// When set, `==` treats numbers as equal if they differ by no more than this
+    pub equality_epsilon: Option<f64>,
+    /// Written after each `print` and `printErr`, a newline if not set
+    pub print_terminator: Option<String>,
+    /// Where checkpoints of the execution state are saved. Nothing is saved if not set
+    pub checkpoint_path: Option<PathBuf>,
+    /// Also save a checkpoint after every this many instructions
+    pub checkpoint_every: Option<u64>,
+    /// Longest string, in bytes, a script may create
+    pub max_string_length: Option<usize>,
+    /// Most items a script may put in a list or map
+    pub max_collection_size: Option<usize>,
+    /// Define the `compile` and `run` natives, which let scripts run code built at runtime
+    pub allow_dynamic_code: bool,
+    /// Directories searched in turn for imports not found next to the importing script
+    pub module_search_path: Vec<PathBuf>,
+    /// Syntax options for compiling imported modules and code run with `eval`, `compile` and `run`
+    pub dialect: Dialect,
+    /// Names of variables whose assignment stops execution, globals by `SetGlobal` and locals by `SetLocal`
+    pub watch: Vec<String>,
+    /// Only concatenate strings with strings, instead of converting the other operand of `+`
+    /// to a string when one is
+    pub strict_concatenation: bool,
+    /// Make dividing a number by zero, or taking the remainder, an error instead of giving infinity or NaN
+    pub error_on_division_by_zero: bool,
+    /// What `args()` gives the script, such as the command line arguments after its path
+    pub script_args: Vec<String>,
+    /// Most bytes `print` and `printErr` may write between them in a run, after which the
+    /// output is cut off with a notice and the rest dropped
+    pub max_output_bytes: Option<usize>,
+    /// Most values the stack holds, allocated when the VM is created, past which a stack
+    /// overflow is reported. `DEFAULT_STACK_CAPACITY` if not set
+    pub stack_capacity: Option<usize>
+}
+
 #[derive(Debug)]
 pub struct Vm {
     stack: Stack<Value>,
-    globals: HashMap<String, Value>,
+    frames: Vec<CallFrame>,
+    globals: OrderedMap<String, Value>,
+    /// Identifies the current globals of the main script and the modules, changing whenever
+    /// they're replaced, for telling whether the slots chunks found globals at still hold
+    globals_id: u64,
+    /// Globals declared with `const`, which can't be assigned
+    const_globals: HashSet<String>,
+    /// Every module imported so far, indexed by `Function::module`
+    modules: Vec<Module>,
+    /// Path of the main script, which its relative imports are resolved against
+    script_path: Option<PathBuf>,
+    module_search_path: Vec<PathBuf>,
+    dialect: Dialect,
+    /// Upvalues still pointing into the stack, ordered by stack slot
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    handlers: Vec<Handler>,
+    /// The value being thrown, until a handler receives it
+    pending_exception: Option<Value>,
+    last_error: Option<LastError>,
+    global_history: Option<GlobalHistory>,
+    allocations: Option<AllocationReport>,
+    watch: HashSet<String>,
+    /// Shared by everything compiled through `eval`
+    constant_pool: SharedConstantPool,
+    equality_epsilon: Option<f64>,
+    print_terminator: String,
+    max_string_length: Option<usize>,
+    max_collection_size: Option<usize>,
+    max_output_bytes: Option<usize>,
+    /// How often the current run found globals and properties where it had before
+    inline_cache_stats: InlineCacheStats,
+    /// Bytes printed so far in the current run, and whether `max_output_bytes` cut it off
+    output_written: usize,
+    output_truncated: bool,
+    strict_concatenation: bool,
+    error_on_division_by_zero: bool,
+    /// When the VM was created, which `clock` and `time_ms` count from
+    started: Instant,
+    script_args: Vec<String>,
+    /// Natives defined with `define_gated_native`, and the capability each needs
+    gated_natives: HashMap<String, Capability>,
+    /// Every native defined so far, for finding them again when resuming a checkpoint
+    natives: NativeRegistry,
+    checkpoint_path: Option<PathBuf>,
+    checkpoint_every: Option<u64>,
+    checkpoint_requested: Arc<AtomicBool>,
+    instructions_since_checkpoint: u64,
+    /// Most instructions a run may execute before it's stopped, if limited
+    max_instructions: Option<u64>,
+    /// Instructions executed so far in the current run, counted only while they're limited
+    instructions_executed: u64,
+    /// Set through a `VmHandle` to stop the run going on
+    cancel_requested: Arc<AtomicBool>,
+    /// When the run given a timeout must end by, and the timeout
+    deadline: Option<(Instant, Duration)>,
+    instructions_since_stop_check: u64,
+    /// Number of frames when the innermost `execute` started, returning from the last of which ends it
+    entry_frames: usize,
+    /// Source of the code being run, for showing each line's text in traces
+    trace_source: Option<String>,
+    debugger: Option<Box<dyn Debugger>>,
+    /// Called before and after each instruction is run
+    pre_instruction_hook: Option<InstructionHook>,
+    post_instruction_hook: Option<InstructionHook>,
+    input: Option<InputStream>,
+    /// Where `print` and tracing write, and where `printErr` writes
+    out: SharedOutput,
+    err: SharedOutput,
+    /// What `print` and `printErr` wrote while output is captured instead of going to `out` and `err`
+    captured_output: Option<(String, String)>,
+    /// Frame count, line and offset where the debugger was last told execution had got to
+    debug_position: Option<(usize, i32, usize)>,
+    /// Offset of the instruction about to run while the debugger is told of it, by which time
+    /// the frame's ip has moved past it
+    paused_at: Option<usize>,
+    #[cfg(feature = "stack-check")]
+    stack_check: StackCheck,
     trace: bool
 }
 
 impl Vm {
-    pub fn new(trace: bool) -> Self {
-        Self { stack: Stack::new(), globals: HashMap::new(), trace }
+    pub fn new(options: VmOptions) -> Self {
+        let global_history = if options.record_global_history { Some(GlobalHistory::new()) } else { None };
+        let mut vm = Self { stack: Stack::with_capacity(options.stack_capacity.unwrap_or(DEFAULT_STACK_CAPACITY)), frames: Vec::new(), globals: OrderedMap::new(), globals_id: NEXT_GLOBALS_ID.fetch_add(1, Ordering::Relaxed), const_globals: HashSet::new(), modules: Vec::new(), script_path: None, module_search_path: options.module_search_path, dialect: options.dialect, open_upvalues: Vec::new(),
+            handlers: Vec::new(), pending_exception: None, last_error: None, global_history, allocations: options.record_allocations.then(AllocationReport::new), watch: options.watch.into_iter().collect(), constant_pool: ConstantPool::shared(), equality_epsilon: options.equality_epsilon,
+            print_terminator: options.print_terminator.unwrap_or_else(|| "\n".to_string()), natives: HashMap::new(), gated_natives: HashMap::new(),
+            max_string_length: options.max_string_length, max_collection_size: options.max_collection_size,
+            max_output_bytes: options.max_output_bytes, inline_cache_stats: InlineCacheStats::default(), output_written: 0, output_truncated: false, strict_concatenation: options.strict_concatenation,
+            error_on_division_by_zero: options.error_on_division_by_zero, started: Instant::now(), script_args: options.script_args,
+            checkpoint_path: options.checkpoint_path, checkpoint_every: options.checkpoint_every, max_instructions: None, instructions_executed: 0,
+            cancel_requested: Arc::new(AtomicBool::new(false)), deadline: None, instructions_since_stop_check: 0,
+            checkpoint_requested: Arc::new(AtomicBool::new(false)), instructions_since_checkpoint: 0, entry_frames: 1, trace_source: None, debugger: None, pre_instruction_hook: None, post_instruction_hook: None, input: None, out: SharedOutput::stdout(), err: SharedOutput::stderr(), captured_output: None, debug_position: None, paused_at: None,
+            #[cfg(feature = "stack-check")]
+            stack_check: StackCheck::new(),
+            trace: options.trace };
+
+        vm.define_native("approxEquals", 3, Rc::new(native::approx_equals));
+        vm.define_native("clock", 0, Rc::new(native::clock));
+        vm.define_native("time_ms", 0, Rc::new(native::time_ms));
+        vm.define_native("type", 1, Rc::new(native::type_of));
+        vm.define_native("num", 1, Rc::new(native::num));
+        vm.define_native("str", 1, Rc::new(native::str));
+        vm.define_native("args", 0, Rc::new(native::args));
+        vm.define_native("env", 1, Rc::new(native::env));
+        vm.define_native("input", 1, Rc::new(native::input));
+        bytes::define_natives(&mut vm);
+        map::define_natives(&mut vm);
+        format::define_natives(&mut vm);
+        #[cfg(feature = "stdlib")]
+        crate::stdlib::define_natives(&mut vm);
+        if options.allow_dynamic_code {
+            eval::define_natives(&mut vm);
+        }
+
+        vm
     }
 
-    pub fn run(&mut self, chunk: &mut Chunk) -> Result<()> {
-        let mut reader = InstructionReader::new(chunk);
-        let mut disassembler = Disassembler::new();
-        loop {
-            let read_result =  reader.read_next()
-            .context(VmError::from_msg("Failed to read code byte"))?;
+    /// Makes a Rust function callable from Lox as a global with the given name
+    pub fn define_native<N: Into<String>>(&mut self, name: N, arity: u8, function: Rc<NativeFn>) {
+        self.add_native(NativeFunction::new(name, arity, function));
+    }
 
-            match read_result {
-                Some((instruction, offset, src_line_number)) => {
-                    if self.trace {
-                        println!("{:?}", self.stack);
-                        disassembler.disassemble_instruction(&mut reader, &instruction, offset, src_line_number)
-                            .context(VmError::new("Failed to disassemble instruction", (instruction.clone(), offset, src_line_number)))?;
-                    }
-
-                    match instruction.op_code {
-                        OpCode::Constant => {
-                            match instruction.operand1 {
-                                Some(index) => {
-                                    let value = reader.get_const(index as usize)
-                                        .context(VmError::new(format!("Failed to get constant at index {}", index), (instruction.clone(), offset, src_line_number)))?;
-                                    if self.trace {
-                                        println!("--> Const: {}", value);
-                                    }
-                                    self.stack.push(value);
-                                },
-                                None => bail!("Opcode {} has no operand", instruction.op_code),
-                            }
-                        },
-                        OpCode::Return => {
-                            return Ok(())
-                        },
-                        OpCode::Negate => {
-                            let negated_value = match self.stack.pop()? {
-                                Value::Number(n) => Value::Number(-n),
-                                _ => bail!(VmError::new("Attempt to negate a non-numeric value", (instruction.clone(), offset, src_line_number)))
-                            };
-
-                            self.stack.push(negated_value)
-                        },
-                        OpCode::Add => {
-                            let a = self.stack.peek(1)?;
-                            let b = self.stack.peek(0)?;
-
-                            match (a, b) {
-                                (Value::Number(_), Value::Number(_)) => self.num_binary_op(|a, b| a + b)?,
-                                (Value::String(_), Value::String(_)) => self.binary_op(|a, b| {
-                                    match (a, b) {
-                                    (Value::String(a), Value::String(b)) => Ok(Value::String(format!("{}{}", a, b))),
-                                    _ => bail!("Attempted add or concatenate on non-numeric or non-string operands")
-                                } })?,
-                                _ => bail!("Attempted add or concatenate on non-numeric or non-string operands")
-                            };
-                        },
-                        OpCode::Subtract => self.num_binary_op(|a, b| a - b)?,
-                        OpCode::Multiply => self.num_binary_op(|a, b| a * b)?,
-                        OpCode::Divide => self.num_binary_op(|a, b| a / b)?,
-                        OpCode::Nil => self.stack.push(Value::Nil),
-                        OpCode::True => self.stack.push(Value::Boolean(true)),
-                        OpCode::False => self.stack.push(Value::Boolean(false)),
-                        OpCode::Not => {
-                            match self.stack.pop()? {
-                                Value::Boolean(v) => self.stack.push(Value::Boolean(!v)),
-                                _ => bail!(VmError::new("Attempted not on a non-bool value", (instruction.clone(), offset, src_line_number)))
-                            }
-                        },
-                        OpCode::Equal => self.binary_op(|a, b| Ok(Value::Boolean(a == b)))?,
-                        OpCode::Greater => self.binary_op(|a, b| Ok(Value::Boolean(a > b)))?,
-                        OpCode::Less => self.binary_op(|a, b| Ok(Value::Boolean(a < b)))?,
-                        OpCode::Print => println!("{}", self.stack.pop()?),
-                        OpCode::Pop => { let _ = self.stack.pop()?; },
-                        OpCode::DefineGlobal => {
-                            let global_name = self.get_global_name(&instruction, &reader)?;
-
-                            let val = self.stack.peek(0)?;
-                            self.globals.insert(global_name, val.clone());
-                            self.stack.pop()?;
-                        },
-                        OpCode::GetGlobal => {
-                            let val =  self.get_global(&instruction, &reader)?;
-                            self.stack.push(val);
-                        },
-                        OpCode::SetGlobal => {
-                            let global_name = self.get_global_name(&instruction, &reader)?;
-                            
-                            if !self.globals.contains_key(&global_name) {
-                                bail!(VmError::from_msg(format!("Undefined variable '{}'", global_name)));
-                            }
+    /// Like `define_native`, for a function that takes any number of arguments after the first `arity`
+    pub fn define_variadic_native<N: Into<String>>(&mut self, name: N, arity: u8, function: Rc<NativeFn>) {
+        self.add_native(NativeFunction::new(name, arity, function).with_variadic(true));
+    }
 
-                            let new_value = self.stack.peek(0)?.clone();
-                            self.globals.insert(global_name, new_value);
-                        },
-                        OpCode::GetLocal => {
-                            let slot = Self::get_operand1(&instruction)?;
-                            let val = self.stack.peek_front( slot as usize)?;
-                            self.stack.push(val.clone());
-                        },
-                        OpCode::SetLocal => {
-                            let slot = Self::get_operand1(&instruction)?;
-                            let val = self.stack.peek(0)?;
-                            self.stack.set_front(slot as usize, val.clone())?;
-                        },
-                        OpCode::Jump => {
-                            let jmp_offset = Self::read_operands_as_usize(instruction)?;
-                            reader.inc_ip(jmp_offset)?;
-                        }
-                        OpCode::JumpIfFalse => {
-                            let jmp_offset = Self::read_operands_as_usize(instruction)?;
-                            match self.stack.peek(0)? {
-                                Value::Boolean(v) => if !*v {
-                                    reader.inc_ip(jmp_offset)?;
-                                },
-                                _ => bail!("Can't jump. Non boolean value found on stack")
-                            };
-                        },
-                        OpCode::Loop => {
-                            let jmp_offset = Self::read_operands_as_usize(instruction)?;
-                            reader.dec_ip(jmp_offset)?;
-                        },
-                    }
-                },
-                None => break
+    fn add_native(&mut self, native: NativeFunction) {
+        let name = native.name.clone();
+        let native = Rc::new(native);
+        self.natives.insert(name.clone(), native.clone());
+        self.globals.insert(name, Value::Native(native));
+    }
+
+    /// The arguments the script was given, as returned by `args()`
+    pub fn script_args(&self) -> &[String] {
+        &self.script_args
+    }
+
+    /// How long it's been since the VM was created
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Like `define_native`, for a native needing a capability. Scripts compiled with
+    /// `Compiler::with_gated_natives(vm.gated_natives())` record it when they refer to it.
+    pub fn define_gated_native<N: Into<String>>(&mut self, name: N, arity: u8, capability: Capability, function: Rc<NativeFn>) {
+        let name = name.into();
+        self.gated_natives.insert(name.clone(), capability);
+        self.define_native(name, arity, function);
+    }
+
+    /// The natives defined with `define_gated_native`, by name
+    pub fn gated_natives(&self) -> HashMap<String, Capability> {
+        self.gated_natives.clone()
+    }
+
+    /// Defines or overwrites a global variable from the host
+    pub fn set_global<N: Into<String>>(&mut self, name: N, value: Value) {
+        self.globals.insert(name.into(), value);
+    }
+
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
+
+    /// Globals of the main script, in the order they were first defined
+    pub fn globals(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.globals.iter()
+    }
+
+    /// Syntax options that code compiled while running should be written in
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    /// The pool to compile against so chunks run by this VM share their constants
+    pub fn constant_pool(&self) -> SharedConstantPool {
+        self.constant_pool.clone()
+    }
+
+    pub fn global_history(&self) -> Option<&GlobalHistory> {
+        self.global_history.as_ref()
+    }
+
+    /// Most values the stack has held at once
+    pub fn max_stack_depth(&self) -> usize {
+        self.stack.peak()
+    }
+
+    pub fn allocations(&self) -> Option<&AllocationReport> {
+        self.allocations.as_ref()
+    }
+
+    /// Runs a compiled script and returns its result: the value of a final expression statement
+    /// or of a top-level `return` in eval mode, nil otherwise
+    pub fn run(&mut self, chunk: &Chunk) -> Result<Value> {
+        // The clone shares the chunk's code and constants rather than copying them
+        let script = Rc::new(Function::script(chunk.clone()));
+        // Whatever a failed run left on the stack would take the slots of the script's locals
+        self.stack.truncate(0);
+        self.frames.push(CallFrame::new(Rc::new(Closure::new(script, Vec::new())), 0));
+        self.execute_to_end()
+    }
+
+    /// Runs a compiled script like `run`, but stops it with an error that `catch` blocks can't
+    /// intercept if it's still running once `timeout` has passed
+    pub fn run_with_timeout(&mut self, chunk: &Chunk, timeout: Duration) -> Result<Value> {
+        self.deadline = Some((Instant::now() + timeout, timeout));
+        let result = self.run(chunk);
+        self.deadline = None;
+        result
+    }
+
+    /// Carries on executing from a checkpoint saved by this or another VM. The globals of
+    /// the checkpoint replace this VM's, and natives are matched up with this VM's by name.
+    pub fn resume(&mut self, checkpoint: &[u8]) -> Result<Value> {
+        let lookup = |name: &str| self.natives.get(name).cloned();
+        let state = checkpoint::VmState::from_bytes(checkpoint, &lookup).context("Failed to read checkpoint")?;
+
+        self.stack = Stack::from_vec(state.stack, self.stack.capacity()).context("Checkpoint doesn't fit on the stack")?;
+        self.globals = state.globals;
+        self.globals_id = NEXT_GLOBALS_ID.fetch_add(1, Ordering::Relaxed);
+        self.const_globals = state.const_globals;
+        self.modules = state.modules;
+        self.frames = state.frames.into_iter()
+            .map(|f| CallFrame { closure: f.closure, ip: f.ip, slot_base: f.slot_base })
+            .collect();
+        self.open_upvalues = state.open_upvalues;
+        self.handlers = state.handlers.into_iter()
+            .map(|h| Handler { frame_count: h.frame_count, stack_len: h.stack_len, catch_ip: h.catch_ip })
+            .collect();
+
+        self.execute_to_end()
+    }
+
+    /// Saves the state of the run going on, for `resume` to carry on from the instruction
+    /// about to run. It can only be taken between instructions, as from a `Debugger`.
+    pub fn checkpoint(&self) -> Result<Vec<u8>> {
+        let Some(offset) = self.paused_at else {
+            bail!("Can only take a checkpoint while execution is paused for a debugger");
+        };
+        // A native running a script can't be resumed mid-call
+        if self.entry_frames > 1 {
+            bail!("Can't take a checkpoint while a native is running a script");
+        }
+
+        let mut state = self.live_state();
+        if let Some(frame) = state.frames.last_mut() {
+            frame.ip = offset;
+        }
+        state.to_bytes().context("Failed to save checkpoint")
+    }
+
+    /// Everything about the run going on that's needed to carry it on
+    fn live_state(&self) -> checkpoint::VmState {
+        checkpoint::VmState {
+            stack: self.stack.as_slice().to_vec(),
+            globals: self.globals.clone(),
+            const_globals: self.const_globals.clone(),
+            modules: self.modules.clone(),
+            frames: self.frames.iter().map(|f| FrameState { closure: f.closure.clone(), ip: f.ip, slot_base: f.slot_base }).collect(),
+            open_upvalues: self.open_upvalues.clone(),
+            handlers: self.handlers.iter()
+                .map(|h| HandlerState { frame_count: h.frame_count, stack_len: h.stack_len, catch_ip: h.catch_ip })
+                .collect()
+        }
+    }
+
+    /// Saves the globals and loaded modules, so later VMs can start from them with
+    /// `restore_snapshot` instead of loading the same modules again
+    pub fn snapshot(&self) -> Result<Vec<u8>> {
+        if !self.frames.is_empty() {
+            bail!("Can't take a snapshot while running");
+        }
+
+        let state = checkpoint::VmState {
+            stack: Vec::new(),
+            globals: self.globals.clone(),
+            const_globals: self.const_globals.clone(),
+            modules: self.modules.clone(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new()
+        };
+        state.to_bytes().context("Failed to save snapshot")
+    }
+
+    /// Replaces this VM's globals and loaded modules with those of a snapshot. Natives are
+    /// matched up with this VM's by name, as when resuming a checkpoint.
+    pub fn restore_snapshot(&mut self, snapshot: &[u8]) -> Result<()> {
+        let lookup = |name: &str| self.natives.get(name).cloned();
+        let state = checkpoint::VmState::from_bytes(snapshot, &lookup).context("Failed to read snapshot")?;
+        if !state.frames.is_empty() {
+            bail!("Failed to read snapshot: it's a checkpoint saved while running, to be resumed instead");
+        }
+
+        self.globals = state.globals;
+        self.globals_id = NEXT_GLOBALS_ID.fetch_add(1, Ordering::Relaxed);
+        self.const_globals = state.const_globals;
+        self.modules = state.modules;
+
+        Ok(())
+    }
+
+    /// Loads every standard library module not loaded yet, without binding any of their
+    /// exports, so a snapshot taken afterwards has them ready for importing
+    pub fn load_stdlib_modules(&mut self) -> Result<()> {
+        #[cfg(feature = "stdlib")]
+        for path in crate::stdlib::module_paths() {
+            if self.modules.iter().any(|m| m.path == Path::new(path)) {
+                continue;
+            }
+            if let Err(e) = self.load_module(PathBuf::from(path), crate::stdlib::precompiled(path)) {
+                self.reset_execution();
+                return Err(e);
             }
         }
 
         Ok(())
     }
 
-    fn get_global(&mut self, instruction: &Instruction, reader: &InstructionReader) -> Result<Value> {
-        let global_name = self.get_global_name(&instruction, &reader)?;
+    /// Path of the script being run, so that `import` can find files relative to it
+    pub fn set_script_path(&mut self, path: &Path) {
+        self.script_path = Some(path.to_path_buf());
+    }
+
+    /// Source the next chunk run was compiled from, shown line by line above the traced instructions
+    pub fn set_trace_source(&mut self, source: &str) {
+        self.trace_source = Some(source.to_string());
+    }
+
+    /// Has `debugger` told of every line execution reaches
+    pub fn set_debugger(&mut self, debugger: Box<dyn Debugger>) {
+        self.debugger = Some(debugger);
+    }
+
+    /// Has `hook` called with each instruction just before it's run
+    pub fn set_pre_instruction_hook(&mut self, hook: impl FnMut(&VmState, &Instruction) + 'static) {
+        self.pre_instruction_hook = Some(InstructionHook::new(hook));
+    }
+
+    /// Has `hook` called with each instruction once it has run, unless it failed. The stack and
+    /// depth are as the instruction left them, so after a call or return they're the callee's
+    /// or the caller's.
+    pub fn set_post_instruction_hook(&mut self, hook: impl FnMut(&VmState, &Instruction) + 'static) {
+        self.post_instruction_hook = Some(InstructionHook::new(hook));
+    }
+
+    /// Has `input` read lines from `input` instead of stdin
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = Some(InputStream(input));
+    }
+
+    /// The next line of input without its line ending, or None at the end of the input
+    pub(crate) fn read_line(&mut self) -> Result<Option<String>> {
+        let mut line = String::new();
+        let read = match &mut self.input {
+            Some(InputStream(input)) => input.read_line(&mut line),
+            None => io::stdin().lock().read_line(&mut line)
+        }.context("Failed to read input")?;
+        if read == 0 {
+            return Ok(None);
+        }
 
-        match self.globals.get(&global_name) {
-            Some(v) => Ok(v.clone()),
-            None => bail!(VmError::from_msg(format!("Undefined variable '{}'", global_name))),
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
         }
+        Ok(Some(line))
     }
 
-    fn get_global_name(&mut self, instruction: &Instruction, reader: &InstructionReader) -> Result<String> {
-        let global_name_index = Self::get_operand1(instruction)?;
+    /// The active call frames, innermost first
+    pub fn debug_frames(&self) -> Vec<DebugFrame> {
+        self.frames.iter().rev()
+            .map(|frame| {
+                let chunk = &frame.closure.function.chunk;
+                let offset = frame.ip.saturating_sub(1);
+                let mut in_scope: Vec<_> = chunk.local_vars().iter()
+                    .filter(|local| (local.start..local.end).contains(&offset))
+                    .collect();
+                in_scope.sort_by_key(|local| local.slot);
+                let locals = in_scope.into_iter()
+                    .filter_map(|local| {
+                        let value = self.stack.peek_front(frame.slot_base + local.slot).ok()?;
+                        Some((local.name.clone(), value.clone()))
+                    })
+                    .collect();
+                DebugFrame { name: frame.closure.function.display_name().to_string(), source: self.source_path(frame), line: frame.current_src_line_number(), locals }
+            })
+            .collect()
+    }
 
-        let constant = reader.get_const(global_name_index as _)
-            .context(anyhow!("No global at index {}", global_name_index))?;
-        
-        match constant {
-            Value::String(name) => Ok(name),
-            _ => bail!(VmError::from_msg(format!("Operand 1 missing on instruction {}", instruction.op_code)))
+    /// File the code of `frame` was loaded from, if known
+    fn source_path(&self, frame: &CallFrame) -> Option<PathBuf> {
+        match frame.closure.function.module {
+            Some(module) => self.modules.get(module).map(|m| m.path.clone()),
+            None => self.script_path.clone()
         }
     }
 
-    fn get_operand1(instruction: &Instruction) -> Result<u8> {
-        instruction.operand1
-            .ok_or(anyhow!(VmError::from_msg(format!("Operand 1 missing on instruction {}", instruction.op_code))))
+    /// Tells the debugger when execution moves to another line, or back to the start of one
+    fn notify_debugger(&mut self, offset: usize, line: i32) -> Result<()> {
+        let depth = self.frames.len();
+        let moved = match self.debug_position {
+            Some((last_depth, last_line, last_offset)) => depth != last_depth || line != last_line || offset <= last_offset,
+            None => true
+        };
+        self.debug_position = Some((depth, line, offset));
+        if !moved {
+            return Ok(());
+        }
+
+        if let Some(mut debugger) = self.debugger.take() {
+            let location = Location { source: self.source_path(self.frame()?), line, depth };
+            self.paused_at = Some(offset);
+            let result = debugger.on_line(self, &location);
+            self.paused_at = None;
+            self.debugger = Some(debugger);
+            result.map_err(|e| anyhow!(VmError::stopped(e.to_string())))?;
+        }
+
+        Ok(())
     }
 
-    fn get_operand2(instruction: &Instruction) -> Result<u8> {
-        instruction.operand2
-            .ok_or(anyhow!(VmError::from_msg(format!("Operand 2 missing on instruction {}", instruction.op_code))))
+    /// Shows the hook before or after the instruction what it can see of the VM
+    fn call_instruction_hook(&mut self, post: bool, function: &Function, (instruction, offset, line): (Instruction, usize, i32)) {
+        let hook = if post { &mut self.post_instruction_hook } else { &mut self.pre_instruction_hook };
+        if let Some(hook) = hook {
+            let state = VmState { stack: self.stack.as_slice(), depth: self.frames.len(), function: function.display_name(), offset, line };
+            hook.call(&state, &instruction);
+        }
     }
 
-    fn read_operands_as_usize(instruction: Instruction) -> Result<usize, anyhow::Error> {
-        let op1 = Self::get_operand1(&instruction)? as usize;
-        let op2 = Self::get_operand2(&instruction)? as usize;
-        let jmp_offset = op1 << 8 | op2;
-        Ok(jmp_offset)
+    /// Limits each run to executing `max_instructions` instructions, after which it stops with
+    /// an error that `catch` blocks can't intercept, so that an untrusted script can't loop forever
+    pub fn with_limits(self, max_instructions: u64) -> Self {
+        Self { max_instructions: Some(max_instructions), ..self }
     }
 
-    fn binary_op<O: FnOnce(&Value, &Value) -> Result<Value>>(&mut self, op: O) -> Result<()> {
-        let b = self.stack.pop()?;
-        let a = self.stack.pop()?;
+    /// A handle for stopping this VM's runs from another thread
+    pub fn handle(&self) -> VmHandle {
+        VmHandle { cancel_requested: self.cancel_requested.clone() }
+    }
 
-        let res = op(&a, &b)?;
+    /// A flag the host can set, from any thread, to have a checkpoint saved before the next instruction
+    pub fn checkpoint_requester(&self) -> Arc<AtomicBool> {
+        self.checkpoint_requested.clone()
+    }
 
-        self.stack.push(res);
+    /// Runs a compiled script to completion from inside a native, returning its result
+    pub(crate) fn run_nested(&mut self, script: Rc<Function>) -> Result<Value> {
+        self.run_closure_nested(Rc::new(Closure::new(script, Vec::new())), self.stack.len())
+    }
 
-        Ok(())
+    /// Runs a closure whose frame starts at `slot_base` to completion, returning its result
+    fn run_closure_nested(&mut self, closure: Rc<Closure>, slot_base: usize) -> Result<Value> {
+        if self.frames.len() >= MAX_FRAMES {
+            bail!(VmError::from_msg("Stack overflow").with_code(error_code::STACK_OVERFLOW));
+        }
+
+        self.frames.push(CallFrame::new(closure, slot_base));
+        let outer_entry_frames = std::mem::replace(&mut self.entry_frames, self.frames.len());
+
+        let result = self.execute();
+        self.entry_frames = outer_entry_frames;
+
+        // After a failure the frames are left for the stack trace, and cleared by the outermost run
+        if result.is_ok() {
+            self.close_upvalues(slot_base)?;
+            self.frames.pop();
+            self.stack.truncate(slot_base);
+        }
+
+        result
     }
 
-    fn num_binary_op<O: FnOnce(f64, f64) -> f64>(&mut self, op: O) -> Result<()> {
-        self.binary_op(|a, b| {
-            match (a, b) {
-                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(op(*a, *b))),
-                _ => bail!("Numberic operation attempted on non-numbeic values")
+    fn execute_to_end(&mut self) -> Result<Value> {
+        self.entry_frames = 1;
+        self.instructions_since_checkpoint = 0;
+        self.instructions_executed = 0;
+        self.output_written = 0;
+        self.output_truncated = false;
+        self.inline_cache_stats = InlineCacheStats::default();
+        let result = self.execute().map_err(|e| {
+            match e.downcast::<VmError>() {
+                Ok(vm_error) => anyhow!(vm_error.with_trace(self.stack_trace())),
+                Err(e) => e
             }
-        })
+        });
+        if let Err(e) = &result {
+            self.last_error = Some(self.describe_error(e));
+        }
+        if self.trace {
+            let _ = writeln!(self.out, "Inline caches: {}", self.inline_cache_stats);
+        }
+
+        self.reset_execution();
+
+        result
     }
-}
 
-#[derive(Error, Debug)]
-pub struct VmError {
-    msg: String,
-    details: Option<(Instruction, usize, i32)>
-}
+    fn describe_error(&self, error: &anyhow::Error) -> LastError {
+        let vm_error = match error.downcast_ref::<VmError>() {
+            Some(vm_error) => vm_error,
+            None => return LastError { kind: ErrorKind::Internal, code: ErrorKind::Internal.code(), message: format!("{:#}", error), line: None, trace: None, thrown: None }
+        };
 
-impl VmError {
-    pub fn new<M: Into<String>>(msg: M, details: (Instruction, usize, i32)) -> Self { 
-        Self { msg: msg.into(), details: Some(details) }
+        let kind = match vm_error.kind {
+            ErrorKind::Runtime if self.pending_exception.is_some() => ErrorKind::UncaughtException,
+            kind => kind
+        };
+        let line = vm_error.details.as_ref().map(|details| details.2)
+            .or_else(|| self.frames.last().map(CallFrame::current_src_line_number));
+
+        let code = if kind == ErrorKind::Runtime { vm_error.code } else { kind.code() };
+
+        LastError { kind, code, message: vm_error.msg.clone(), line, trace: vm_error.trace.clone(), thrown: self.pending_exception.clone() }
     }
 
+    /// What went wrong in the most recent run that failed, kept until another fails or
+    /// `clear_last_error` is called
+    pub fn last_error(&self) -> Option<&LastError> {
+        self.last_error.as_ref()
+    }
 
-    pub fn from_msg<M: Into<String>>(msg: M) -> Self { 
-        Self { msg: msg.into(), details: None }
+    pub fn clear_last_error(&mut self) {
+        self.last_error = None;
     }
-}
 
-impl Display for VmError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self.details {
-            Some(details) => write!(f, "[source line {}, byte code offset {}, inst '{}'] {}", details.2, details.1, details.0, self.msg),
-            None => write!(f, "{}", self.msg),
+    /// Drops what's left of an execution, such as the frames kept for a stack trace after it failed
+    fn reset_execution(&mut self) {
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.handlers.clear();
+        self.pending_exception = None;
+        self.debug_position = None;
+    }
+
+    /// Runs until the outermost frame of this execution returns, passing errors and thrown
+    /// values to the innermost handler installed during it
+    fn execute(&mut self) -> Result<Value> {
+        loop {
+            // A full stack is a runtime error like any other, which a handler can catch
+            let result = self.execute_instructions().map_err(|e| match e.downcast::<StackOverflow>() {
+                Ok(_) => anyhow!(VmError::from_msg("Stack overflow").with_code(error_code::STACK_OVERFLOW)),
+                Err(e) => e
+            });
+            match result {
+                Err(e) if self.catch(&e)? => continue,
+                result => return result
+            }
+        }
+    }
+
+    /// Unwinds to the innermost handler, if this execution installed one, and gives it the
+    /// thrown value or the error's message
+    fn catch(&mut self, error: &anyhow::Error) -> Result<bool> {
+        // Watchpoints and debuggers stop the whole execution, whatever handlers are installed
+        if error.downcast_ref::<VmError>().is_some_and(VmError::is_uncatchable) {
+            return Ok(false);
+        }
+
+        let handler = match self.handlers.last() {
+            Some(handler) if handler.frame_count >= self.entry_frames => handler.clone(),
+            _ => return Ok(false)
+        };
+        self.handlers.pop();
+
+        let exception = match self.pending_exception.take() {
+            Some(value) => value,
+            None => match error.downcast_ref::<VmError>() {
+                Some(vm_error) => Value::String(vm_error.message().to_string()),
+                None => Value::String(error.to_string())
+            }
+        };
+
+        self.close_upvalues(handler.stack_len)?;
+        self.frames.truncate(handler.frame_count);
+        self.frame_mut()?.ip = handler.catch_ip;
+        self.stack.truncate(handler.stack_len);
+        self.stack.push(exception)?;
+
+        #[cfg(feature = "stack-check")]
+        self.stack_check.unwind_to(self.frames.len());
+
+        Ok(true)
+    }
+
+    fn save_checkpoint_if_due(&mut self) -> Result<()> {
+        let path = match &self.checkpoint_path {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        // A native running a script can't be resumed mid-call, so wait until it has finished
+        if self.entry_frames > 1 {
+            return Ok(());
+        }
+
+        self.instructions_since_checkpoint += 1;
+        let interval_elapsed = self.checkpoint_every.is_some_and(|every| self.instructions_since_checkpoint >= every);
+        if !interval_elapsed && !self.checkpoint_requested.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.instructions_since_checkpoint = 0;
+
+        let data = self.live_state().to_bytes().map_err(|e| anyhow!(VmError::from_msg(format!("Failed to save checkpoint: {}", e))))?;
+        checkpoint::write_file(path, &data).map_err(|e| anyhow!(VmError::from_msg(format!("{:#}", e))))
+    }
+
+    /// Compiles and runs source in eval mode, returning its result
+    pub fn eval(&mut self, source: &str) -> Result<Value> {
+        let chunk = Compiler::new(source.to_string())
+            .with_eval_mode(true)
+            .with_dialect(self.dialect)
+            .with_constant_pool(self.constant_pool())
+            .compile()?;
+        if self.trace {
+            self.set_trace_source(source);
+        }
+        self.run(&chunk)
+    }
+
+    fn stack_trace(&self) -> StackTrace {
+        let frames = self.frames.iter().rev()
+            .map(|f| {
+                let frame = TraceFrame::new(f.closure.function.display_name(), f.current_src_line_number());
+                match f.closure.function.module.and_then(|index| self.modules.get(index)) {
+                    Some(module) => frame.with_module(module.path.display().to_string()),
+                    None => frame
+                }
+            })
+            .collect();
+        StackTrace::new(frames)
+    }
+
+    fn frame(&self) -> Result<&CallFrame> {
+        self.frames.last().ok_or_else(|| anyhow!(VmError::from_msg("No active call frame")))
+    }
+
+    fn frame_mut(&mut self) -> Result<&mut CallFrame> {
+        self.frames.last_mut().ok_or_else(|| anyhow!(VmError::from_msg("No active call frame")))
+    }
+
+    fn execute_instructions(&mut self) -> Result<Value> {
+        let disassembler = Disassembler::new().with_output(Box::new(self.out.clone()));
+        let mut disassembler = match &self.trace_source {
+            Some(source) => disassembler.with_source(source),
+            None => disassembler
+        };
+        // Instructions run since the budget was last checked
+        let mut executed: u64 = 0;
+        loop {
+            let frame = self.frame()?;
+            let (closure, slot_base, mut ip) = (frame.closure.clone(), frame.slot_base, frame.ip);
+            let frame_index = self.frames.len() - 1;
+            let exit = self.execute_frame(&closure, slot_base, &mut ip, frame_index, &mut executed, &mut disassembler);
+
+            // Back in the frame for its caller, the callee, a handler or a stack trace to see,
+            // unless the frame returned
+            if let Some(frame) = self.frames.get_mut(frame_index) {
+                frame.ip = ip;
+            }
+            if let Some(result) = exit? {
+                return Ok(result);
+            }
         }
     }
-}
\ No newline at end of file
+
+    /// Runs the instructions of the frame at `frame_index` until it calls or returns, giving
+    /// the result if that ends the execution. The ip is kept in `ip` meanwhile, and only put
+    /// in the frame before something that might look at it.
+    fn execute_frame(&mut self, closure: &Closure, slot_base: usize, ip: &mut usize, frame_index: usize, executed: &mut u64, disassembler: &mut Disassembler) -> Result<Option<Value>> {
+        let chunk = &closure.function.chunk;
+        let code = chunk.code();
+        let src_line_numbers = chunk.src_line_numbers();
+        loop {
+            if self.checkpoint_path.is_some() {
+                self.frames[frame_index].ip = *ip;
+                self.save_checkpoint_if_due()?;
+            }
+            *executed += 1;
+
+            // Operands are read straight from the code. An `Instruction` is only put together
+            // to describe the instruction, for tracing or an error.
+            let offset = *ip;
+            let op_code = match code.get(offset) {
+                Some(byte) => OpCode::try_from(*byte).with_context(|| VmError::from_msg("Failed to read code byte"))?,
+                // Running off the end of a chunk without a return yields nil
+                None => return Ok(Some(Value::Nil))
+            };
+            let next_ip = offset + 1 + op_code.operand_count();
+            let operands = match code.get(offset + 1..next_ip) {
+                Some(operands) => operands,
+                None => bail!(VmError::from_msg(format!("Operands missing on instruction {}", op_code)))
+            };
+            let src_line_number = src_line_numbers.get(offset).copied().unwrap_or(0);
+            let at = || (Instruction::with_operands(op_code.clone(), operands), offset, src_line_number);
+            *ip = next_ip;
+
+            if self.debugger.is_some() {
+                self.frames[frame_index].ip = next_ip;
+                self.notify_debugger(offset, src_line_number)?;
+            }
+
+            if self.pre_instruction_hook.is_some() {
+                self.call_instruction_hook(false, &closure.function, at());
+            }
+
+            if self.trace {
+                writeln!(self.out, "{:?}", self.stack)?;
+                let mut reader = InstructionReader::new(chunk);
+                reader.set_ip(next_ip)?;
+                disassembler.disassemble_instruction(&mut reader, &at().0, offset, src_line_number)
+                    .with_context(|| VmError::new("Failed to disassemble instruction", at()))?;
+            }
+
+            #[cfg(feature = "stack-check")]
+            let depths_before = Depths { stack: self.stack.len(), frames: self.frames.len() };
+
+            match op_code {
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let index = Self::wide_operand(operands);
+                    let value = chunk.get_constant(index)
+                        .with_context(|| VmError::new(format!("Failed to get constant at index {}", index), at()))?;
+                    if self.trace {
+                        writeln!(self.out, "--> Const: {}", value)?;
+                    }
+                    self.stack.push(value)?;
+                },
+                OpCode::Return => {
+                    let result = self.stack.pop()?;
+
+                    // Handlers installed by the returning function end with it
+                    while self.handlers.last().is_some_and(|h| h.frame_count >= self.frames.len()) {
+                        self.handlers.pop();
+                    }
+
+                    self.close_upvalues(slot_base)?;
+
+                    // Returning from the top-level script, or from one run by a native, ends execution
+                    if self.frames.len() == self.entry_frames {
+                        self.stack.truncate(slot_base);
+                        if self.post_instruction_hook.is_some() {
+                            self.call_instruction_hook(true, &closure.function, at());
+                        }
+                        return Ok(Some(result))
+                    }
+
+                    self.frames.pop();
+                    self.stack.truncate(slot_base);
+                    self.stack.push(result)?;
+                },
+                OpCode::Negate => {
+                    let negated_value = match self.stack.peek(0)? {
+                        Value::Number(n) => Value::Number(-n),
+                        Value::Int(n) => n.checked_neg().map_or(Value::Number(-(*n as f64)), Value::Int),
+                        _ => {
+                            self.check_nil_operands(chunk, &op_code, operands, offset, src_line_number)?;
+                            bail!(VmError::new("Attempt to negate a non-numeric value", at()).with_code(error_code::INVALID_OPERAND))
+                        }
+                    };
+
+                    self.stack.pop()?;
+                    self.stack.push(negated_value)?
+                },
+                OpCode::Increment | OpCode::Decrement => {
+                    let step = if let OpCode::Increment = op_code { 1 } else { -1 };
+                    let stepped_value = match self.stack.pop()? {
+                        Value::Number(n) => Value::Number(n + step as f64),
+                        Value::Int(n) => n.checked_add(step).map_or(Value::Number(n as f64 + step as f64), Value::Int),
+                        _ => bail!(VmError::new("Attempt to increment or decrement a non-numeric value", at()).with_code(error_code::INVALID_OPERAND))
+                    };
+
+                    self.stack.push(stepped_value)?
+                },
+                OpCode::Add => self.add(at).map_err(|e| self.explain_arithmetic_error(e, chunk, &op_code, operands, offset, src_line_number))?,
+                OpCode::GetLocalAdd => {
+                    let val = self.stack.peek_front(slot_base + operands[0] as usize)?;
+                    self.stack.push(val.clone())?;
+                    self.add(at).map_err(|e| self.explain_arithmetic_error(e, chunk, &op_code, operands, offset, src_line_number))?;
+                },
+                OpCode::Subtract => self.num_binary_op(i64::checked_sub, |a, b| a - b).map_err(|e| self.explain_arithmetic_error(e, chunk, &op_code, operands, offset, src_line_number))?,
+                OpCode::Multiply => self.num_binary_op(i64::checked_mul, |a, b| a * b).map_err(|e| self.explain_arithmetic_error(e, chunk, &op_code, operands, offset, src_line_number))?,
+                // Ints divide to an int only when the division is exact
+                OpCode::Divide | OpCode::Modulo if self.error_on_division_by_zero && self.stack.peek(0)?.as_f64() == Some(0.0) => {
+                    let what = if matches!(op_code, OpCode::Divide) { "Division" } else { "Modulo" };
+                    bail!(VmError::new(format!("{} by zero", what), at()).with_code(error_code::DIVISION_BY_ZERO));
+                },
+                OpCode::Divide => self.num_binary_op(|a, b| a.checked_rem(b).filter(|r| *r == 0).and(a.checked_div(b)), |a, b| a / b).map_err(|e| self.explain_arithmetic_error(e, chunk, &op_code, operands, offset, src_line_number))?,
+                OpCode::Modulo => self.num_binary_op(i64::checked_rem, |a, b| a % b).map_err(|e| self.explain_arithmetic_error(e, chunk, &op_code, operands, offset, src_line_number))?,
+                OpCode::Nil => self.stack.push(Value::Nil)?,
+                OpCode::True => self.stack.push(Value::Boolean(true))?,
+                OpCode::False => self.stack.push(Value::Boolean(false))?,
+                OpCode::Not => {
+                    let value = self.stack.pop()?;
+                    self.stack.push(Value::Boolean(!value.is_truthy()))?;
+                },
+                OpCode::Equal => {
+                    let epsilon = self.equality_epsilon;
+                    self.binary_op(|a, b| Ok(Value::Boolean(Self::values_equal(a, b, epsilon))))?
+                },
+                OpCode::Greater => self.binary_op(|a, b| Ok(Value::Boolean(a > b)))?,
+                OpCode::Less => self.binary_op(|a, b| Ok(Value::Boolean(a < b)))?,
+                OpCode::LessJumpIfFalse => {
+                    self.binary_op(|a, b| Ok(Value::Boolean(a < b)))?;
+                    if !self.stack.peek(0)?.is_truthy() {
+                        *ip = Self::jump_target(code, Some(next_ip + Self::wide_operand(operands)))?;
+                    }
+                },
+                OpCode::Print => {
+                    let value = self.stack.pop()?;
+                    self.write_output(&format!("{}{}", value, self.print_terminator))?;
+                },
+                OpCode::PrintErr => {
+                    let value = self.stack.pop()?;
+                    self.write_error(&format!("{}{}", value, self.print_terminator))?;
+                },
+                OpCode::Pop => { let _ = self.stack.pop()?; },
+                OpCode::DefineGlobal | OpCode::DefineConstGlobal | OpCode::DefineGlobalLong | OpCode::DefineConstGlobalLong => {
+                    let global_name = Self::get_global_name(chunk, Self::index_operand(operands))?;
+
+                    let val = self.stack.peek(0)?;
+                    if let Some(history) = &mut self.global_history {
+                        history.record_define(&global_name, val, src_line_number);
+                    }
+                    let val = val.clone();
+                    // A declaration replaces whatever was there, constant or not
+                    let const_globals = self.const_globals_mut(closure.function.module)?;
+                    if let OpCode::DefineConstGlobal | OpCode::DefineConstGlobalLong = op_code {
+                        const_globals.insert(global_name.clone());
+                    } else {
+                        const_globals.remove(&global_name);
+                    }
+                    self.globals_mut(closure.function.module)?.insert(global_name, val);
+                    self.stack.pop()?;
+                },
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let val =  self.get_global(chunk, Self::index_operand(operands), closure.function.module, offset)?;
+                    self.stack.push(val)?;
+                },
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let module = closure.function.module;
+                    let name_index = Self::index_operand(operands);
+                    let slot = match self.global_slot(chunk, name_index, module)? {
+                        Some(slot) => slot,
+                        None => bail!(self.undefined_variable(chunk, name_index, module, offset)?)
+                    };
+                    let new_value = self.stack.peek(0)?.clone();
+
+                    // Only these need the name, which the slot saves looking up otherwise
+                    let mut hit = None;
+                    if !self.const_globals_mut(module)?.is_empty() || self.global_history.is_some() || !self.watch.is_empty() {
+                        let global_name = Self::get_global_name(chunk, name_index)?;
+                        if self.const_globals_mut(module)?.contains(&global_name) {
+                            bail!(VmError::from_msg(format!("Can't assign to constant '{}'", global_name)).with_code(error_code::CONSTANT_REASSIGNED));
+                        }
+                        if let Some(history) = &mut self.global_history {
+                            history.record_set(&global_name, &new_value, src_line_number);
+                        }
+                        hit = self.watch.contains(&global_name).then(|| VmError::watchpoint_hit(&global_name, &new_value, at()));
+                    }
+                    match self.globals_mut(module)?.get_slot_mut(slot) {
+                        Some(value) => *value = new_value,
+                        None => bail!(VmError::from_msg(format!("No global at slot {}", slot)))
+                    }
+                    if let Some(hit) = hit {
+                        bail!(hit);
+                    }
+                },
+                OpCode::GetLocal | OpCode::GetLocalLong => {
+                    let slot = Self::wide_operand(operands);
+                    let val = self.stack.peek_front(slot_base + slot)?;
+                    self.stack.push(val.clone())?;
+                },
+                OpCode::SetLocal | OpCode::SetLocalLong => {
+                    let slot = Self::wide_operand(operands);
+                    let val = self.stack.peek(0)?.clone();
+                    self.stack.set_front(slot_base + slot, val.clone())?;
+                    if let Some(name) = self.watched(|| closure.function.chunk.local_name(slot, offset)) {
+                        bail!(VmError::watchpoint_hit(name, &val, at()));
+                    }
+                },
+                OpCode::PushHandler => {
+                    let catch_offset = Self::wide_operand(operands);
+                    self.handlers.push(Handler { frame_count: self.frames.len(), stack_len: self.stack.len(), catch_ip: next_ip + catch_offset });
+                },
+                OpCode::PopHandler => {
+                    self.handlers.pop()
+                        .ok_or_else(|| anyhow!(VmError::new("No exception handler to remove", at())))?;
+                },
+                OpCode::Throw => {
+                    let value = self.stack.pop()?;
+                    let msg = format!("Uncaught exception: {}", value);
+                    self.pending_exception = Some(value);
+                    bail!(VmError::new(msg, at()));
+                },
+                OpCode::Assert => {
+                    let message = self.stack.pop()?;
+                    let msg = match (self.stack.pop()?.is_truthy(), message) {
+                        (true, _) => None,
+                        (false, Value::Nil) => Some("Assertion failed".to_string()),
+                        (false, message) => Some(format!("Assertion failed: {}", message)),
+                    };
+                    if let Some(msg) = msg {
+                        bail!(VmError::new(msg, at()).with_code(error_code::ASSERTION_FAILED));
+                    }
+                },
+                OpCode::Import | OpCode::ImportLong => {
+                    let path = Self::get_name(chunk, Self::index_operand(operands))?;
+                    self.frames[frame_index].ip = next_ip;
+                    self.import(&path, closure.function.module).map_err(|e| {
+                        // Errors from running the module already say where they happened
+                        if e.is::<VmError>() { e } else { anyhow!(VmError::new(format!("{:#}", e), at()).with_code(error_code::IMPORT_FAILED)) }
+                    })?;
+                },
+                OpCode::Jump | OpCode::JumpLong => *ip = Self::jump_target(code, Some(next_ip + Self::wide_operand(operands)))?,
+                OpCode::JumpIfFalse => {
+                    if !self.stack.peek(0)?.is_truthy() {
+                        *ip = Self::jump_target(code, Some(next_ip + Self::wide_operand(operands)))?;
+                    }
+                },
+                OpCode::Loop | OpCode::LoopLong => {
+                    *ip = Self::jump_target(code, next_ip.checked_sub(Self::wide_operand(operands)))?;
+                    self.check_limits(executed)?;
+                },
+                OpCode::Call => {
+                    let arg_count = operands[0];
+                    self.check_limits(executed)?;
+                    self.frames[frame_index].ip = next_ip;
+                    self.call_value(arg_count)
+                        .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+                },
+                OpCode::ConstantCall => {
+                    let value = chunk.get_constant(operands[0] as usize)
+                        .with_context(|| VmError::new(format!("Failed to get constant at index {}", operands[0]), at()))?;
+                    self.stack.push(value)?;
+                    self.check_limits(executed)?;
+                    self.frames[frame_index].ip = next_ip;
+                    self.call_value(operands[1])
+                        .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+                },
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let function = match chunk.get_constant(Self::index_operand(operands))? {
+                        Value::Function(f) => f,
+                        _ => bail!(VmError::new("Closure operand is not a function", at()))
+                    };
+
+                    let mut upvalues = Vec::with_capacity(function.upvalues.len());
+                    for descriptor in &function.upvalues {
+                        let upvalue = if descriptor.is_local {
+                            self.capture_upvalue(slot_base + descriptor.index)
+                        } else {
+                            closure.upvalues.get(descriptor.index)
+                                .ok_or_else(|| anyhow!(VmError::new(format!("No upvalue at index {}", descriptor.index), at())))?
+                                .clone()
+                        };
+                        upvalues.push(upvalue);
+                    }
+
+                    self.stack.push(Value::Closure(Rc::new(Closure::new(function, upvalues))))?;
+                    self.record_allocation_on_top()?;
+                },
+                OpCode::GetUpvalue => {
+                    let upvalue = Self::get_upvalue(closure, operands[0])?;
+                    let val = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack.peek_front(*slot)?.clone(),
+                        Upvalue::Closed(v) => v.clone(),
+                    };
+                    self.stack.push(val)?;
+                },
+                OpCode::SetUpvalue => {
+                    let upvalue = Self::get_upvalue(closure, operands[0])?;
+                    let val = self.stack.peek(0)?.clone();
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(slot) => self.stack.set_front(*slot, val.clone())?,
+                        Upvalue::Closed(v) => *v = val.clone(),
+                    };
+                    if let Some(name) = self.watched(|| closure.function.chunk.upvalue_name(operands[0] as usize)) {
+                        bail!(VmError::watchpoint_hit(name, &val, at()));
+                    }
+                },
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1)?;
+                    self.stack.pop()?;
+                },
+                OpCode::Class | OpCode::ClassLong => {
+                    let name = Self::get_name(chunk, Self::index_operand(operands))?;
+                    self.stack.push(Value::Class(Rc::new(Class::new(name))))?;
+                    self.record_allocation_on_top()?;
+                },
+                OpCode::Method | OpCode::Getter | OpCode::MethodLong | OpCode::GetterLong => {
+                    let name = Self::get_name(chunk, Self::index_operand(operands))?;
+                    let method = match self.stack.peek(0)? {
+                        Value::Closure(c) => c.clone(),
+                        _ => bail!(VmError::new("Method is not a closure", at()))
+                    };
+                    match self.stack.peek(1)? {
+                        Value::Class(class) if matches!(op_code, OpCode::Getter | OpCode::GetterLong) => class.add_getter(name, method),
+                        Value::Class(class) => class.add_method(name, method),
+                        _ => bail!(VmError::new("Methods can only be defined on classes", at()))
+                    };
+                    self.stack.pop()?;
+                },
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let name = Self::name_constant(chunk, Self::index_operand(operands))?;
+                    let instance = match self.stack.peek(0)? {
+                        Value::Instance(instance) => instance.clone(),
+                        _ => bail!(VmError::new("Only instances have properties", at()).with_code(error_code::NOT_AN_INSTANCE))
+                    };
+
+                    // Fields come first, then methods, and finally getters, which are called
+                    // with the instance already in place as their receiver. A class never has
+                    // a method and a getter of the same name.
+                    let cache = chunk.property_cache(offset);
+                    if let Some(value) = self.cached_field(cache.as_ref(), chunk, offset, &instance, name) {
+                        self.stack.pop()?;
+                        self.stack.push(value)?;
+                    } else if let Some(method) = self.cached_method(cache.as_ref(), chunk, offset, &instance.class, name) {
+                        self.stack.pop()?;
+                        self.stack.push(Value::BoundMethod(Rc::new(BoundMethod::new(Value::Instance(instance.clone()), method))))?;
+                        self.record_allocation_on_top()?;
+                    } else if let Some(getter) = instance.class.find_getter(name) {
+                        self.frames[frame_index].ip = next_ip;
+                        self.call(getter, 0)
+                            .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+                    } else {
+                        bail!(VmError::new(format!("Undefined property '{}'", name), at()).with_code(error_code::UNDEFINED_PROPERTY));
+                    }
+                },
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let name = Self::name_constant(chunk, Self::index_operand(operands))?;
+                    let instance = match self.stack.peek(1)? {
+                        Value::Instance(instance) => instance.clone(),
+                        _ => bail!(VmError::new("Only instances have fields", at()).with_code(error_code::NOT_AN_INSTANCE))
+                    };
+
+                    let value = self.stack.pop()?;
+                    let cached_slot = chunk.property_cache(offset).and_then(|cache| cache.field_slot(&instance.class));
+                    match cached_slot.map_or(Err(value.clone()), |slot| instance.set_field_at(slot, name, value.clone())) {
+                        Ok(()) => self.inline_cache_stats.hits += 1,
+                        // A field added at the cached slot is where an instance of the class
+                        // got the same field last time, so the cache stays as it is
+                        Err(value) => {
+                            let slot = instance.set_field(name, value);
+                            if cached_slot != Some(slot) {
+                                self.inline_cache_stats.misses += 1;
+                                chunk.set_property_cache(offset, PropertyCache::field(&instance.class, slot));
+                            }
+                        }
+                    }
+                    self.stack.pop()?;
+                    self.stack.push(value)?;
+                },
+                OpCode::GetIndex => {
+                    let index = self.stack.pop()?;
+                    let target = self.stack.pop()?;
+                    let value = index::get(&target, &index)
+                        .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+
+                    self.stack.push(value)?;
+                },
+                OpCode::SetIndex => {
+                    let value = self.stack.pop()?;
+                    let index = self.stack.pop()?;
+                    let target = self.stack.pop()?;
+                    if let Value::Map(map) = &target {
+                        let is_new_key = MapKey::new(index.clone()).is_ok_and(|key| map.borrow().get(&key).is_none());
+                        if is_new_key {
+                            self.check_collection_size(map.borrow().len() + 1)
+                                .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+                        }
+                    }
+                    index::set(&target, &index, value.clone())
+                        .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+
+                    self.stack.push(value)?;
+                },
+                OpCode::BuildList => {
+                    let count = operands[0] as usize;
+                    self.check_collection_size(count)
+                        .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+                    let first = self.stack.len().checked_sub(count)
+                        .ok_or_else(|| anyhow!(VmError::new("Not enough values on the stack for the list", at())))?;
+                    let items = self.stack.as_slice()[first..].to_vec();
+                    self.stack.truncate(first);
+
+                    self.stack.push(Value::list(items))?;
+                    self.record_allocation_on_top()?;
+                },
+                OpCode::Inherit => {
+                    let superclass = match self.stack.peek(1)? {
+                        Value::Class(class) => class.clone(),
+                        _ => bail!(VmError::new("Superclass must be a class", at()).with_code(error_code::INVALID_SUPERCLASS))
+                    };
+                    match self.stack.peek(0)? {
+                        Value::Class(subclass) => subclass.inherit(&superclass),
+                        _ => bail!(VmError::new("Only classes can inherit", at()).with_code(error_code::INVALID_SUPERCLASS))
+                    };
+                    self.stack.pop()?;
+                },
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let name = Self::get_name(chunk, Self::index_operand(operands))?;
+                    let superclass = match self.stack.pop()? {
+                        Value::Class(class) => class,
+                        _ => bail!(VmError::new("Superclass must be a class", at()).with_code(error_code::INVALID_SUPERCLASS))
+                    };
+
+                    if let Some(getter) = superclass.find_getter(&name) {
+                        // The receiver left on the stack becomes the getter's 'this'
+                        self.frames[frame_index].ip = next_ip;
+                        self.call(getter, 0)
+                            .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+                    } else {
+                        let receiver = self.stack.pop()?;
+                        match superclass.find_method(&name) {
+                            Some(method) => {
+                                self.stack.push(Value::BoundMethod(Rc::new(BoundMethod::new(receiver, method))))?;
+                                self.record_allocation_on_top()?;
+                            },
+                            None => bail!(VmError::new(format!("Undefined property '{}'", name), at()).with_code(error_code::UNDEFINED_PROPERTY))
+                        }
+                    }
+                },
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                    let name = Self::get_name(chunk, Self::index_operand(operands))?;
+                    let arg_count = operands[operands.len() - 1];
+                    let superclass = match self.stack.pop()? {
+                        Value::Class(class) => class,
+                        _ => bail!(VmError::new("Superclass must be a class", at()).with_code(error_code::INVALID_SUPERCLASS))
+                    };
+                    self.check_limits(executed)?;
+                    self.frames[frame_index].ip = next_ip;
+                    self.invoke_from_class(&superclass, &name, arg_count)
+                        .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+                },
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let name = Self::name_constant(chunk, Self::index_operand(operands))?;
+                    let arg_count = operands[operands.len() - 1];
+                    self.check_limits(executed)?;
+                    self.frames[frame_index].ip = next_ip;
+                    self.invoke(name, arg_count, chunk, offset)
+                        .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+                },
+            }
+
+            #[cfg(feature = "stack-check")]
+            self.stack_check.after(&at().0, offset, src_line_number, depths_before,
+                Depths { stack: self.stack.len(), frames: self.frames.len() });
+
+            if self.post_instruction_hook.is_some() {
+                self.call_instruction_hook(true, &closure.function, at());
+            }
+
+            // The instruction called a function or returned from this one
+            if self.frames.len() != frame_index + 1 {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn call_value(&mut self, arg_count: u8) -> Result<()> {
+        let callee = self.stack.peek(arg_count as usize)?.clone();
+
+        match callee {
+            Value::Closure(closure) => self.call(closure, arg_count),
+            Value::Function(function) => self.call(Rc::new(Closure::new(function, Vec::new())), arg_count),
+            Value::Class(class) => {
+                let callee_slot = self.stack.len() - arg_count as usize - 1;
+                let instance = Value::Instance(Rc::new(Instance::new(class.clone())));
+                self.record_allocation(&instance);
+                self.stack.set_front(callee_slot, instance)?;
+
+                match class.find_method("init") {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => bail!(VmError::from_msg(format!("Expected 0 arguments but got {}", arg_count)).with_code(error_code::WRONG_ARGUMENT_COUNT)),
+                    None => Ok(())
+                }
+            },
+            Value::Native(native) => {
+                if native.variadic {
+                    if arg_count < native.arity {
+                        bail!(VmError::from_msg(format!("Expected at least {} arguments but got {}", native.arity, arg_count)).with_code(error_code::WRONG_ARGUMENT_COUNT));
+                    }
+                } else if arg_count != native.arity {
+                    bail!(VmError::from_msg(format!("Expected {} arguments but got {}", native.arity, arg_count)).with_code(error_code::WRONG_ARGUMENT_COUNT));
+                }
+
+                let args = (0..arg_count as usize).rev()
+                    .map(|i| self.stack.peek(i).cloned())
+                    .collect::<Result<Vec<_>>>()?;
+                let result = (native.function)(self, &args)?;
+                self.check_size(&result)?;
+                self.record_allocation(&result);
+
+                self.stack.truncate(self.stack.len() - arg_count as usize - 1);
+                self.stack.push(result)?;
+                Ok(())
+            },
+            Value::BoundMethod(bound) => {
+                let callee_slot = self.stack.len() - arg_count as usize - 1;
+                self.stack.set_front(callee_slot, bound.receiver.clone())?;
+                self.call(bound.method.clone(), arg_count)
+            },
+            _ => bail!(VmError::from_msg("Can only call functions and classes").with_code(error_code::NOT_CALLABLE))
+        }
+    }
+
+    /// Calls the method called `name` on the instance below the arguments, using and updating
+    /// the cache of the instruction at `offset` in `chunk`
+    fn invoke(&mut self, name: &str, arg_count: u8, chunk: &Chunk, offset: usize) -> Result<()> {
+        let instance = match self.stack.peek(arg_count as usize)? {
+            Value::Instance(instance) => instance.clone(),
+            _ => bail!(VmError::from_msg("Only instances have methods").with_code(error_code::NOT_AN_INSTANCE))
+        };
+
+        // A field holding a callable shadows a method of the same name
+        if let Some(field) = instance.get_field(name) {
+            let callee_slot = self.stack.len() - arg_count as usize - 1;
+            self.stack.set_front(callee_slot, field)?;
+            return self.call_value(arg_count);
+        }
+
+        let cache = chunk.property_cache(offset);
+        match self.cached_method(cache.as_ref(), chunk, offset, &instance.class, name) {
+            Some(method) => self.call(method, arg_count),
+            None => self.invoke_from_class(&instance.class, name, arg_count)
+        }
+    }
+
+    /// The instance's field called `name`, found at the slot `cache` has for the instance's
+    /// class if it's there, and otherwise looked up by name and cached for the instruction at `offset`
+    fn cached_field(&mut self, cache: Option<&PropertyCache>, chunk: &Chunk, offset: usize, instance: &Rc<Instance>, name: &str) -> Option<Value> {
+        let cached = cache.and_then(|cache| cache.field_slot(&instance.class)).and_then(|slot| instance.field_at(slot, name));
+        if cached.is_some() {
+            self.inline_cache_stats.hits += 1;
+            return cached;
+        }
+
+        let slot = instance.field_slot(name)?;
+        self.inline_cache_stats.misses += 1;
+        chunk.set_property_cache(offset, PropertyCache::field(&instance.class, slot));
+        instance.field_at(slot, name)
+    }
+
+    /// The class's method called `name`, taken from `cache` if it was found on the class as it
+    /// is now, and otherwise looked up by name and cached for the instruction at `offset`
+    fn cached_method(&mut self, cache: Option<&PropertyCache>, chunk: &Chunk, offset: usize, class: &Rc<Class>, name: &str) -> Option<Rc<Closure>> {
+        if let Some(method) = cache.and_then(|cache| cache.method_of(class)) {
+            self.inline_cache_stats.hits += 1;
+            return Some(method);
+        }
+
+        let method = class.find_method(name)?;
+        self.inline_cache_stats.misses += 1;
+        chunk.set_property_cache(offset, PropertyCache::method(class, &method));
+        Some(method)
+    }
+
+    fn invoke_from_class(&mut self, class: &Class, name: &str, arg_count: u8) -> Result<()> {
+        if let Some(getter) = class.find_getter(name) {
+            // The getter has to finish before what it returns can be called with the arguments
+            let callee_slot = self.stack.len() - arg_count as usize - 1;
+            let receiver = self.stack.peek(arg_count as usize)?.clone();
+            self.stack.push(receiver)?;
+            let callee = self.run_closure_nested(getter, self.stack.len() - 1)?;
+            self.stack.set_front(callee_slot, callee)?;
+            return self.call_value(arg_count);
+        }
+
+        match class.find_method(name) {
+            Some(method) => self.call(method, arg_count),
+            None => bail!(VmError::from_msg(format!("Undefined property '{}'", name)).with_code(error_code::UNDEFINED_PROPERTY))
+        }
+    }
+
+    fn call(&mut self, closure: Rc<Closure>, arg_count: u8) -> Result<()> {
+        let arity = closure.function.arity;
+        if closure.function.variadic {
+            if arg_count < arity {
+                bail!(VmError::from_msg(format!("Expected at least {} arguments but got {}", arity, arg_count)).with_code(error_code::WRONG_ARGUMENT_COUNT));
+            }
+        } else if arg_count != arity {
+            bail!(VmError::from_msg(format!("Expected {} arguments but got {}", arity, arg_count)).with_code(error_code::WRONG_ARGUMENT_COUNT));
+        }
+
+        if self.frames.len() >= MAX_FRAMES {
+            bail!(VmError::from_msg("Stack overflow").with_code(error_code::STACK_OVERFLOW));
+        }
+
+        let slot_base = self.stack.len() - arg_count as usize - 1;
+        if closure.function.variadic {
+            // The arguments past the fixed parameters become the list in the rest parameter's slot
+            let rest_start = slot_base + 1 + arity as usize;
+            let rest = self.stack.as_slice()[rest_start..].to_vec();
+            self.check_collection_size(rest.len())?;
+            self.stack.truncate(rest_start);
+            self.stack.push(Value::list(rest))?;
+            self.record_allocation_on_top()?;
+        }
+        self.frames.push(CallFrame::new(closure, slot_base));
+
+        Ok(())
+    }
+
+    fn get_upvalue(closure: &Closure, index: u8) -> Result<Rc<RefCell<Upvalue>>> {
+        closure.upvalues.get(index as usize)
+            .cloned()
+            .ok_or_else(|| anyhow!(VmError::from_msg(format!("No upvalue at index {}", index))))
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let existing = self.open_upvalues.iter()
+            .find(|u| matches!(&*u.borrow(), Upvalue::Open(s) if *s == slot));
+        if let Some(upvalue) = existing {
+            return upvalue.clone();
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        let pos = self.open_upvalues.iter()
+            .position(|u| matches!(&*u.borrow(), Upvalue::Open(s) if *s > slot))
+            .unwrap_or(self.open_upvalues.len());
+        self.open_upvalues.insert(pos, upvalue.clone());
+
+        upvalue
+    }
+
+    /// Moves the values of all upvalues pointing at or above `from_slot` off the stack
+    fn close_upvalues(&mut self, from_slot: usize) -> Result<()> {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match &*upvalue.borrow() {
+                Upvalue::Open(slot) if *slot >= from_slot => *slot,
+                _ => break
+            };
+
+            let value = self.stack.peek_front(slot)?.clone();
+            *upvalue.borrow_mut() = Upvalue::Closed(value);
+            self.open_upvalues.pop();
+        }
+
+        Ok(())
+    }
+
+    /// `offset` is that of the instruction getting it, for suggesting locals in scope there
+    fn get_global(&mut self, chunk: &Chunk, name_index: usize, module: Option<usize>, offset: usize) -> Result<Value> {
+        let value = match self.global_slot(chunk, name_index, module)? {
+            Some(slot) => self.globals_mut(module)?.get_slot(slot).map(|(_, value)| value.clone()),
+            None => None
+        };
+        match value {
+            Some(value) => Ok(value),
+            None => bail!(self.undefined_variable(chunk, name_index, module, offset)?),
+        }
+    }
+
+    /// The error for there being no global named by the constant at `name_index`, suggesting
+    /// a global of `module`, or a local in scope at `offset`, with a name close to it
+    fn undefined_variable(&mut self, chunk: &Chunk, name_index: usize, module: Option<usize>, offset: usize) -> Result<VmError> {
+        let name = Self::get_global_name(chunk, name_index)?;
+        // Locals the compiler adds for itself have names a variable can't
+        let locals = chunk.local_vars().iter()
+            .filter(|local| (local.start..local.end).contains(&offset) && !local.name.is_empty() && !local.name.starts_with(' '))
+            .map(|local| local.name.as_str());
+        let globals = self.globals_mut(module)?.iter().map(|(name, _)| name.as_str());
+        let msg = match suggestion::closest(&name, locals.chain(globals)) {
+            Some(suggestion) => format!("Undefined variable '{}'; did you mean '{}'?", name, suggestion),
+            None => format!("Undefined variable '{}'", name)
+        };
+        Ok(VmError::from_msg(msg).with_code(error_code::UNDEFINED_VARIABLE))
+    }
+
+    /// The slot of the global named by the constant at `name_index` in the globals of `module`,
+    /// or None if there's no such global. The chunk remembers the slot, so the name is looked up
+    /// only the first time, and again after the globals are replaced.
+    fn global_slot(&mut self, chunk: &Chunk, name_index: usize, module: Option<usize>) -> Result<Option<usize>> {
+        let table = (self.globals_id, module);
+        if let Some(slot) = chunk.global_slot(name_index, table) {
+            self.inline_cache_stats.hits += 1;
+            return Ok(Some(slot));
+        }
+
+        let global_name = Self::get_global_name(chunk, name_index)?;
+        let slot = self.globals_mut(module)?.slot(&global_name);
+        if let Some(slot) = slot {
+            self.inline_cache_stats.misses += 1;
+            chunk.set_global_slot(name_index, table, slot);
+        }
+        Ok(slot)
+    }
+
+    /// The globals of a module, or of the main script if `module` is `None`
+    fn globals_mut(&mut self, module: Option<usize>) -> Result<&mut OrderedMap<String, Value>> {
+        match module {
+            Some(index) => match self.modules.get_mut(index) {
+                Some(module) => Ok(&mut module.globals),
+                None => bail!(VmError::from_msg(format!("No module at index {}", index)))
+            },
+            None => Ok(&mut self.globals)
+        }
+    }
+
+    /// The constant globals of a module, or of the main script if `module` is `None`
+    fn const_globals_mut(&mut self, module: Option<usize>) -> Result<&mut HashSet<String>> {
+        match module {
+            Some(index) => match self.modules.get_mut(index) {
+                Some(module) => Ok(&mut module.const_globals),
+                None => bail!(VmError::from_msg(format!("No module at index {}", index)))
+            },
+            None => Ok(&mut self.const_globals)
+        }
+    }
+
+    /// Runs the file at `path` if it hasn't been imported before, then binds the values its
+    /// exports ended up with in the importing module's globals
+    fn import(&mut self, path: &str, importer: Option<usize>) -> Result<()> {
+        #[cfg(feature = "stdlib")]
+        let precompiled = crate::stdlib::precompiled(path);
+        #[cfg(not(feature = "stdlib"))]
+        let precompiled = None;
+
+        let path = match precompiled {
+            Some(_) => PathBuf::from(path),
+            None => {
+                let importer_path = match importer {
+                    Some(index) => self.modules.get(index).map(|m| m.path.as_path()),
+                    None => self.script_path.as_deref()
+                };
+                module::resolve(path, importer_path, &self.module_search_path)?
+            }
+        };
+
+        // The main script isn't one of the modules but is running all the while, so importing
+        // it is always a cycle
+        if self.script_path.as_deref().and_then(|script| script.canonicalize().ok()).as_ref() == Some(&path) {
+            bail!("Circular import of {}", path.display());
+        }
+
+        let index = match self.modules.iter().position(|m| m.path == path) {
+            Some(index) => index,
+            None => self.load_module(path.clone(), precompiled)?
+        };
+
+        let exports = match &self.modules[index].exports {
+            Some(exports) => exports.clone(),
+            // A module still running has its script's frame on the stack
+            None if self.frames.iter().any(|f| f.closure.function.module == Some(index)) => bail!("Circular import of {}", path.display()),
+            None => bail!("Can't import {} since it failed when first imported", path.display())
+        };
+        for name in exports {
+            let value = self.modules[index].globals.get(&name).cloned().unwrap_or(Value::Nil);
+            // Exported constants stay constant where they're imported
+            if self.modules[index].const_globals.contains(&name) {
+                self.const_globals_mut(importer)?.insert(name.clone());
+            } else {
+                self.const_globals_mut(importer)?.remove(&name);
+            }
+            self.globals_mut(importer)?.insert(name, value);
+        }
+
+        Ok(())
+    }
+
+    /// Runs a module for the first time, from its source file or, for a standard library
+    /// module, its precompiled bytecode
+    fn load_module(&mut self, path: PathBuf, precompiled: Option<&[u8]>) -> Result<usize> {
+        let index = self.modules.len();
+        let script = match precompiled {
+            Some(bytecode) => {
+                let lookup = |name: &str| self.natives.get(name).cloned();
+                let script = serialize::script_from_bytes(bytecode, &lookup)
+                    .with_context(|| format!("Failed to load {}", path.display()))?;
+                module::link(&script, index)
+            },
+            None => {
+                let source = fs::read_to_string(&path).with_context(|| format!("Failed to import {}", path.display()))?;
+                let chunk = Compiler::new(source).with_dialect(self.dialect).with_constant_pool(self.constant_pool()).with_module(index).compile()
+                    .map_err(|e| anyhow!("Failed to compile {}: {}", path.display(), e.to_string().trim_end()))?;
+                Function::script(chunk).with_module(Some(index))
+            }
+        };
+
+        let globals = self.natives.iter().map(|(name, native)| (name.clone(), Value::Native(native.clone()))).collect();
+        let exports = script.chunk.exports().to_vec();
+        self.modules.push(Module { path, globals, const_globals: HashSet::new(), exports: None });
+
+        self.run_nested(Rc::new(script))?;
+        self.modules[index].exports = Some(exports);
+
+        Ok(index)
+    }
+
+    fn get_global_name(chunk: &Chunk, name_index: usize) -> Result<String> {
+        let constant = chunk.get_constant(name_index)
+            .with_context(|| anyhow!("No global at index {}", name_index))?;
+
+        match constant {
+            Value::String(name) => Ok(name),
+            _ => bail!(VmError::from_msg(format!("Constant {} is not a global name", name_index)))
+        }
+    }
+
+    /// The class, property or method name at `name_index` among the chunk's constants, without copying it
+    fn name_constant(chunk: &Chunk, name_index: usize) -> Result<&str> {
+        match chunk.constants().get(name_index) {
+            Some(Value::String(name)) => Ok(name),
+            _ => bail!(VmError::from_msg(format!("Constant {} is not a name", name_index)))
+        }
+    }
+
+    fn get_name(chunk: &Chunk, name_index: usize) -> Result<String> {
+        Self::name_constant(chunk, name_index).map(str::to_string)
+    }
+
+    /// The operand bytes as one number, high byte first: a one byte index or slot, a two byte
+    /// jump or any three byte operand
+    fn wide_operand(operands: &[u8]) -> usize {
+        operands.iter().fold(0, |value, byte| value << 8 | *byte as usize)
+    }
+
+    /// The constant index an instruction takes first: one byte, or three in the long form,
+    /// which is the only one with that many operands
+    fn index_operand(operands: &[u8]) -> usize {
+        match operands.get(..3) {
+            Some(long) => Self::wide_operand(long),
+            None => operands[0] as usize
+        }
+    }
+
+    /// Counts the `executed` instructions against the budget, and now and then sees whether
+    /// the run was cancelled or ran out of time. Only done at backward jumps and calls, since
+    /// a run can't go on for long without one.
+    fn check_limits(&mut self, executed: &mut u64) -> Result<()> {
+        let executed = std::mem::take(executed);
+        if let Some(max_instructions) = self.max_instructions {
+            self.instructions_executed += executed;
+            if self.instructions_executed > max_instructions {
+                bail!(VmError::budget_exceeded(max_instructions));
+            }
+        }
+
+        self.instructions_since_stop_check += executed;
+        if self.instructions_since_stop_check >= STOP_CHECK_INTERVAL {
+            self.instructions_since_stop_check = 0;
+            if self.cancel_requested.swap(false, Ordering::Relaxed) {
+                bail!(VmError::cancelled());
+            }
+            if let Some((deadline, timeout)) = self.deadline {
+                if Instant::now() >= deadline {
+                    bail!(VmError::timed_out(timeout));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The variable if it's being watched. Takes the name lazily, as most runs watch nothing.
+    fn watched<'a>(&self, name: impl FnOnce() -> Option<&'a str>) -> Option<&'a str> {
+        if self.watch.is_empty() {
+            return None;
+        }
+        name().filter(|name| self.watch.contains(*name))
+    }
+
+    /// `target`, the offset a jump or loop goes to, if it's inside `code`
+    fn jump_target(code: &[u8], target: Option<usize>) -> Result<usize> {
+        match target.filter(|target| *target <= code.len()) {
+            Some(target) => Ok(target),
+            None => bail!(VmError::from_msg("Attempt to jump outside the chunk"))
+        }
+    }
+
+    /// Adds the two values on top of the stack, or concatenates them if either is a string
+    fn add(&mut self, at: impl Fn() -> (Instruction, usize, i32)) -> Result<()> {
+        let a = self.stack.peek(1)?;
+        let b = self.stack.peek(0)?;
+
+        match (a, b) {
+            (Value::Number(_) | Value::Int(_), Value::Number(_) | Value::Int(_)) => self.num_binary_op(i64::checked_add, |a, b| a + b)?,
+            (Value::String(a), Value::String(b)) => {
+                // Checked up front so an oversized string is never allocated
+                self.check_string_length(a.len() + b.len())
+                    .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+                self.binary_op(|a, b| {
+                match (a, b) {
+                (Value::String(a), Value::String(b)) => Ok(Value::String(format!("{}{}", a, b))),
+                _ => bail!(VmError::from_msg("Attempted add or concatenate on non-numeric or non-string operands").with_code(error_code::INVALID_OPERAND))
+            } })?;
+                self.record_allocation_on_top()?
+            },
+            // A string and any other value concatenate as their printed forms
+            (Value::String(_), _) | (_, Value::String(_)) if !self.strict_concatenation => {
+                let (a, b) = (a.to_string(), b.to_string());
+                self.check_string_length(a.len() + b.len())
+                    .map_err(|e| anyhow!(VmError::placed(&e, at())))?;
+                self.stack.truncate(self.stack.len() - 2);
+                self.stack.push(Value::String(a + &b))?;
+                self.record_allocation_on_top()?
+            },
+            _ => bail!(VmError::from_msg("Attempted add or concatenate on non-numeric or non-string operands").with_code(error_code::INVALID_OPERAND))
+        };
+        Ok(())
+    }
+
+    /// Fails if a value a native returned is over the size limits
+    fn check_size(&self, value: &Value) -> Result<()> {
+        match value {
+            Value::String(s) => self.check_string_length(s.len()),
+            Value::List(items) => self.check_collection_size(items.borrow().len()),
+            Value::Map(map) => self.check_collection_size(map.borrow().len()),
+            _ => Ok(())
+        }
+    }
+
+    /// The error to give for an arithmetic instruction that failed with `error`, which is one
+    /// saying where a nil operand came from if there is one. The operands must still be on the stack.
+    fn explain_arithmetic_error(&self, error: anyhow::Error, chunk: &Chunk, op_code: &OpCode, operands: &[u8], offset: usize, src_line_number: i32) -> anyhow::Error {
+        match self.check_nil_operands(chunk, op_code, operands, offset, src_line_number) {
+            Err(nil_error) => nil_error,
+            Ok(()) => error
+        }
+    }
+
+    /// Fails if an operand of an arithmetic instruction is nil, saying where it came from,
+    /// most often a variable that was never assigned
+    fn check_nil_operands(&self, chunk: &Chunk, op_code: &OpCode, operands: &[u8], offset: usize, src_line_number: i32) -> Result<()> {
+        let (symbol, operand_count) = match op_code {
+            OpCode::Negate => ("-", 1),
+            OpCode::Add | OpCode::GetLocalAdd => ("+", 2),
+            OpCode::Subtract => ("-", 2),
+            OpCode::Multiply => ("*", 2),
+            OpCode::Divide => ("/", 2),
+            OpCode::Modulo => ("%", 2),
+            _ => return Ok(())
+        };
+
+        // Nil concatenates with a string like any other value
+        if operand_count == 2 && !self.strict_concatenation && matches!(op_code, OpCode::Add | OpCode::GetLocalAdd)
+            && (matches!(self.stack.peek(0)?, Value::String(_)) || matches!(self.stack.peek(1)?, Value::String(_))) {
+            return Ok(());
+        }
+
+        for depth in (0..operand_count).rev() {
+            if !matches!(self.stack.peek(depth)?, Value::Nil) {
+                continue;
+            }
+
+            let which = match (operand_count, depth) {
+                (1, _) => "Operand",
+                (_, 1) => "Left operand",
+                _ => "Right operand"
+            };
+            // The right operand of a `GetLocalAdd` is the local it pushed itself
+            let source = match (op_code, depth) {
+                (OpCode::GetLocalAdd, 0) => chunk.local_name(operands[0] as usize, offset).map(str::to_string),
+                (OpCode::GetLocalAdd, _) => describe_operand(chunk, offset, depth - 1),
+                _ => describe_operand(chunk, offset, depth)
+            };
+            let msg = match source {
+                Some(source) => format!("{} '{}' of '{}' is nil", which, source, symbol),
+                None => format!("{} of '{}' is nil", which, symbol)
+            };
+            bail!(VmError::new(msg, (Instruction::with_operands(op_code.clone(), operands), offset, src_line_number)).with_code(error_code::INVALID_OPERAND));
+        }
+
+        Ok(())
+    }
+
+    fn record_allocation(&mut self, value: &Value) {
+        if let Some(allocations) = &mut self.allocations {
+            allocations.record(value);
+        }
+    }
+
+    /// Records the object just pushed, if allocations are being counted
+    fn record_allocation_on_top(&mut self) -> Result<()> {
+        if self.allocations.is_some() {
+            let value = self.stack.peek(0)?.clone();
+            self.record_allocation(&value);
+        }
+        Ok(())
+    }
+
+    fn check_string_length(&self, len: usize) -> Result<()> {
+        match self.max_string_length {
+            Some(max) if len > max => bail!(VmError::from_msg(format!("String of length {} exceeds the limit of {}", len, max)).with_code(error_code::SIZE_LIMIT_EXCEEDED)),
+            _ => Ok(())
+        }
+    }
+
+    fn check_collection_size(&self, size: usize) -> Result<()> {
+        match self.max_collection_size {
+            Some(max) if size > max => bail!(VmError::from_msg(format!("Collection of {} items exceeds the limit of {}", size, max)).with_code(error_code::SIZE_LIMIT_EXCEEDED)),
+            _ => Ok(())
+        }
+    }
+
+    /// How often the last run found a global or property where an instruction last found it,
+    /// instead of looking it up by name
+    pub fn inline_cache_stats(&self) -> InlineCacheStats {
+        self.inline_cache_stats
+    }
+
+    /// Whether the last run printed more than `max_output_bytes` and had its output cut off
+    pub fn output_truncated(&self) -> bool {
+        self.output_truncated
+    }
+
+    /// The part of `text` that fits in what's left of `max_output_bytes`, followed by a notice
+    /// the first time something doesn't fit
+    fn limit_output<'a>(&mut self, text: &'a str) -> Cow<'a, str> {
+        let max = match self.max_output_bytes {
+            Some(max) => max,
+            None => return Cow::Borrowed(text)
+        };
+        if self.output_truncated {
+            return Cow::Borrowed("");
+        }
+
+        let allowed = max - self.output_written;
+        if text.len() <= allowed {
+            self.output_written += text.len();
+            return Cow::Borrowed(text);
+        }
+        let mut end = allowed;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.output_written += end;
+        self.output_truncated = true;
+        Cow::Owned(format!("{}\n[Output truncated after {} bytes]\n", &text[..end], max))
+    }
+
+    /// Writes the text as `print` would, followed by the print terminator
+    pub(crate) fn print(&mut self, text: &str) -> Result<()> {
+        let text = format!("{}{}", text, self.print_terminator);
+        self.write_output(&text)
+    }
+
+    /// Sends what `print` writes, and the trace, to `out` instead of stdout
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = SharedOutput::new(out);
+    }
+
+    /// Sends what `printErr` writes to `err` instead of stderr
+    pub fn set_error_output(&mut self, err: Box<dyn Write>) {
+        self.err = SharedOutput::new(err);
+    }
+
+    /// Keeps what's printed from now on for `take_output` instead of writing it out, or stops doing so
+    pub(crate) fn capture_output(&mut self, capture: bool) {
+        self.captured_output = capture.then(Default::default);
+    }
+
+    /// What `print` and `printErr` wrote since output was last taken, while it was captured
+    pub(crate) fn take_output(&mut self) -> (String, String) {
+        self.captured_output.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub(crate) fn write_output(&mut self, text: &str) -> Result<()> {
+        let text = self.limit_output(text);
+        if let Some((output, _)) = &mut self.captured_output {
+            output.push_str(&text);
+            return Ok(());
+        }
+        self.out.write_all(text.as_bytes()).context("Failed to write output")?;
+        // Without a trailing newline the text could sit in the line buffer indefinitely
+        if !text.ends_with('\n') {
+            self.out.flush().context("Failed to write output")?;
+        }
+        Ok(())
+    }
+
+    fn write_error(&mut self, text: &str) -> Result<()> {
+        let text = self.limit_output(text);
+        if let Some((_, errors)) = &mut self.captured_output {
+            errors.push_str(&text);
+            return Ok(());
+        }
+        self.err.write_all(text.as_bytes()).context("Failed to write output")
+    }
+
+    fn values_equal(a: &Value, b: &Value, epsilon: Option<f64>) -> bool {
+        match (a.as_f64(), b.as_f64(), epsilon) {
+            (Some(a), Some(b), Some(epsilon)) => (a - b).abs() <= epsilon,
+            _ => a == b
+        }
+    }
+
+    /// Replaces the two values on top of the stack with what `op` makes of them. They're
+    /// left in place if it fails, for the error to describe.
+    fn binary_op<O: FnOnce(&Value, &Value) -> Result<Value>>(&mut self, op: O) -> Result<()> {
+        let res = op(self.stack.peek(1)?, self.stack.peek(0)?)?;
+
+        self.stack.truncate(self.stack.len() - 2);
+        self.stack.push(res)?;
+
+        Ok(())
+    }
+
+    /// Applies `int_op` to two ints, and `float_op` to any other numbers or to ints whose
+    /// result `int_op` can't represent, such as on overflow
+    fn num_binary_op<I: FnOnce(i64, i64) -> Option<i64>, F: FnOnce(f64, f64) -> f64>(&mut self, int_op: I, float_op: F) -> Result<()> {
+        self.binary_op(|a, b| {
+            if let (Value::Int(a), Value::Int(b)) = (a, b) {
+                if let Some(result) = int_op(*a, *b) {
+                    return Ok(Value::Int(result));
+                }
+            }
+            match (a.as_f64(), b.as_f64()) {
+                (Some(a), Some(b)) => Ok(Value::Number(float_op(a, b))),
+                _ => bail!(VmError::from_msg("Numberic operation attempted on non-numbeic values").with_code(error_code::INVALID_OPERAND))
+            }
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub struct VmError {
+    msg: String,
+    details: Option<(Instruction, usize, i32)>,
+    trace: Option<StackTrace>,
+    /// Name of the watched variable whose assignment stopped execution
+    watchpoint: Option<String>,
+    /// `Runtime` unless the error stopped execution from outside the script, in which case
+    /// `catch` blocks don't run
+    kind: ErrorKind,
+    /// The stable code of the kind of error, from `error_code`
+    code: &'static str
+}
+
+impl VmError {
+    pub fn new<M: Into<String>>(msg: M, details: (Instruction, usize, i32)) -> Self { 
+        Self { msg: msg.into(), details: Some(details), trace: None, watchpoint: None, kind: ErrorKind::Runtime, code: error_code::RUNTIME_ERROR }
+    }
+
+    /// Execution stopped because the watched variable `name` was assigned `value`
+    pub fn watchpoint_hit(name: &str, value: &Value, details: (Instruction, usize, i32)) -> Self {
+        Self { watchpoint: Some(name.to_string()), kind: ErrorKind::Watchpoint, ..Self::new(format!("Watchpoint hit: '{}' set to {}", name, value), details) }
+    }
+
+    /// Execution stopped from outside the script, such as by a debugger, so `catch` blocks don't run
+    pub fn stopped<M: Into<String>>(msg: M) -> Self {
+        Self { kind: ErrorKind::Stopped, ..Self::from_msg(msg) }
+    }
+
+    /// Execution stopped on reaching the limit set with `Vm::with_limits`
+    pub fn budget_exceeded(max_instructions: u64) -> Self {
+        Self { kind: ErrorKind::BudgetExceeded, ..Self::from_msg(format!("Instruction budget of {} exceeded", max_instructions)) }
+    }
+
+    /// Execution stopped by `VmHandle::cancel`
+    pub fn cancelled() -> Self {
+        Self { kind: ErrorKind::Cancelled, ..Self::from_msg("Cancelled") }
+    }
+
+    /// Execution stopped on running longer than the timeout given to `Vm::run_with_timeout`
+    pub fn timed_out(timeout: Duration) -> Self {
+        Self { kind: ErrorKind::TimedOut, ..Self::from_msg(format!("Timed out after {:?}", timeout)) }
+    }
+
+    pub fn from_msg<M: Into<String>>(msg: M) -> Self { 
+        Self { msg: msg.into(), details: None, trace: None, watchpoint: None, kind: ErrorKind::Runtime, code: error_code::RUNTIME_ERROR }
+    }
+
+    /// An error raised without knowing which instruction it's about, placed at the one that
+    /// ran into it. Keeps the code it had, if it was a `VmError`.
+    pub fn placed(error: &anyhow::Error, details: (Instruction, usize, i32)) -> Self {
+        let code = error.downcast_ref::<VmError>().map_or(error_code::RUNTIME_ERROR, |e| e.code);
+        Self::new(error.to_string(), details).with_code(code)
+    }
+
+    pub fn with_code(self, code: &'static str) -> Self {
+        Self { code, ..self }
+    }
+
+    pub fn with_trace(self, trace: StackTrace) -> Self {
+        Self { trace: Some(trace), ..self }
+    }
+
+    /// The stable code of the kind of error, such as `L0703` for an undefined variable
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    pub fn trace(&self) -> Option<&StackTrace> {
+        self.trace.as_ref()
+    }
+
+    /// Name of the variable, if the error is a watchpoint being hit
+    pub fn watchpoint(&self) -> Option<&str> {
+        self.watchpoint.as_deref()
+    }
+
+    pub fn is_budget_exceeded(&self) -> bool {
+        self.kind == ErrorKind::BudgetExceeded
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.kind == ErrorKind::Cancelled
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        self.kind == ErrorKind::TimedOut
+    }
+
+    /// Whether the error ends execution without running `catch` blocks
+    fn is_uncatchable(&self) -> bool {
+        self.kind != ErrorKind::Runtime
+    }
+
+    /// The error without where it happened
+    pub fn message(&self) -> &str {
+        &self.msg
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.details {
+            Some(details) => write!(f, "[source line {}, byte code offset {}, inst '{}'] {}", details.2, details.1, details.0, self.msg),
+            None => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+/// Lets another thread stop a VM's run, which then fails with an error that `catch` blocks
+/// can't intercept. The VM only checks now and then, so it may run a few more instructions,
+/// and it can't stop a native that's blocked, such as one waiting for input.
+#[derive(Debug, Clone)]
+pub struct VmHandle {
+    cancel_requested: Arc<AtomicBool>
+}
+
+impl VmHandle {
+    /// Stops the run going on, or the next one to start if there isn't one
+    pub fn cancel(&self) {
+        self.cancel_requested.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Lookups of globals and properties, counted by whether the instruction found what it was
+/// after where it found it last time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InlineCacheStats {
+    pub hits: u64,
+    /// Lookups by name, after which the instruction remembered what it found
+    pub misses: u64
+}
+
+impl Display for InlineCacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lookups = self.hits + self.misses;
+        let hit_rate = if lookups == 0 { 0.0 } else { self.hits as f64 * 100.0 / lookups as f64 };
+        write!(f, "{} hits, {} misses ({:.1}% hit)", self.hits, self.misses, hit_rate)
+    }
+}
+
+/// What kind of failure ended a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An instruction or native failed, as on adding nil to a number or a failed `assert`
+    Runtime,
+    /// A value was thrown and nothing caught it
+    UncaughtException,
+    /// A watched variable was assigned
+    Watchpoint,
+    /// Execution was stopped from outside the script, as by a debugger
+    Stopped,
+    /// The run executed more instructions than `Vm::with_limits` allows
+    BudgetExceeded,
+    /// The run was stopped with `VmHandle::cancel`
+    Cancelled,
+    /// The run took longer than the timeout given to `Vm::run_with_timeout`
+    TimedOut,
+    /// The VM itself failed, as on reading a bad checkpoint
+    Internal
+}
+
+impl ErrorKind {
+    /// The stable code of the kind, which runtime errors narrow down to the error's own
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Runtime => error_code::RUNTIME_ERROR,
+            Self::UncaughtException => error_code::UNCAUGHT_EXCEPTION,
+            Self::Watchpoint => error_code::WATCHPOINT_HIT,
+            Self::Stopped => error_code::STOPPED,
+            Self::BudgetExceeded => error_code::BUDGET_EXCEEDED,
+            Self::Cancelled => error_code::CANCELLED,
+            Self::TimedOut => error_code::TIMED_OUT,
+            Self::Internal => error_code::INTERNAL_ERROR
+        }
+    }
+}
+
+/// The details of a failed run, for hosts to act on without parsing error messages
+#[derive(Debug, Clone)]
+pub struct LastError {
+    pub kind: ErrorKind,
+    /// Stable code of the error, such as `L0703` for an undefined variable
+    pub code: &'static str,
+    /// The error without where it happened
+    pub message: String,
+    /// Source line of the code that failed, if known
+    pub line: Option<i32>,
+    pub trace: Option<StackTrace>,
+    /// The value of an uncaught exception
+    pub thrown: Option<Value>
+}
+
+/// Number of frames printed by default before the middle of a trace is elided
+pub const DEFAULT_TRACE_FRAME_LIMIT: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    pub function_name: String,
+    pub src_line_number: i32,
+    /// Path of the imported module the function is from, None for the main script
+    pub module: Option<String>
+}
+
+impl TraceFrame {
+    pub fn new<N: Into<String>>(function_name: N, src_line_number: i32) -> Self {
+        Self { function_name: function_name.into(), src_line_number, module: None }
+    }
+
+    pub fn with_module<M: Into<String>>(self, module: M) -> Self {
+        Self { module: Some(module.into()), ..self }
+    }
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] in {}", self.src_line_number, self.function_name)?;
+        if let Some(module) = &self.module {
+            write!(f, " ({})", module)?;
+        }
+        Ok(())
+    }
+}
+
+/// Call frames active at the time of a runtime error, innermost first
+#[derive(Debug, Clone)]
+pub struct StackTrace {
+    frames: Vec<TraceFrame>
+}
+
+impl StackTrace {
+    pub fn new(frames: Vec<TraceFrame>) -> Self {
+        Self { frames }
+    }
+
+    /// Formats the trace, eliding the middle frames when there are more than `max_frames`.
+    /// Passing `None` prints every frame.
+    pub fn format(&self, max_frames: Option<usize>) -> String {
+        let mut out = String::new();
+
+        match max_frames {
+            Some(max) if self.frames.len() > max => {
+                let head = max - max / 2;
+                let tail = max / 2;
+                let omitted = self.frames.len() - head - tail;
+
+                for frame in &self.frames[..head] {
+                    out.push_str(&format!("{}\n", frame));
+                }
+                out.push_str(&format!("... {} frames omitted ...\n", omitted));
+                for frame in &self.frames[self.frames.len() - tail..] {
+                    out.push_str(&format!("{}\n", frame));
+                }
+            },
+            _ => for frame in &self.frames {
+                out.push_str(&format!("{}\n", frame));
+            }
+        }
+
+        out
+    }
+}
+
+impl Display for StackTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format(Some(DEFAULT_TRACE_FRAME_LIMIT)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{Compiler, CompileError, CompileErrorCollection, CompileWarning, DEFAULT_MAX_ERRORS};
+    use crate::instruction::InstructionWriter;
+    use crate::native::Args;
+    use crate::output::OutputBuffer;
+
+    fn run_with<F: FnOnce(&mut InstructionWriter)>(trace: bool, build: F) -> (Vm, Result<Value>) {
+        let mut writer = InstructionWriter::with_new_chunk();
+        build(&mut writer);
+        let chunk = writer.into_chunk();
+
+        let mut vm = Vm::new(VmOptions { trace, ..Default::default() });
+        let result = vm.run(&chunk);
+        (vm, result)
+    }
+
+    fn run<F: FnOnce(&mut InstructionWriter)>(build: F) -> (Vm, Result<Value>) {
+        run_with(false, build)
+    }
+
+    fn run_source(source: &str) -> (Vm, Result<Value>) {
+        run_source_with(VmOptions::default(), source)
+    }
+
+    fn run_source_with(options: VmOptions, source: &str) -> (Vm, Result<Value>) {
+        let chunk = Compiler::new(source.to_string()).compile().expect("Compilation failed");
+        let mut vm = Vm::new(options);
+        let result = vm.run(&chunk);
+        (vm, result)
+    }
+
+    fn function<F: FnOnce(&mut InstructionWriter)>(name: &str, arity: u8, build: F) -> Value {
+        let mut writer = InstructionWriter::with_new_chunk();
+        build(&mut writer);
+        Value::Function(Rc::new(Function::new(name, arity, writer.into_chunk())))
+    }
+
+    /// Drains the stack and returns its contents from bottom to top
+    fn drain_stack(vm: &mut Vm) -> Vec<Value> {
+        let mut values = Vec::new();
+        while let Ok(v) = vm.stack.pop() {
+            values.push(v);
+        }
+        values.reverse();
+        values
+    }
+
+    fn run_ok<F: FnOnce(&mut InstructionWriter)>(build: F) -> Vec<Value> {
+        let (mut vm, result) = run(build);
+        result.expect("Run failed");
+        drain_stack(&mut vm)
+    }
+
+    fn num(w: &mut InstructionWriter, n: f64) {
+        w.write_const(Value::Number(n), 1).unwrap();
+    }
+
+    fn string(w: &mut InstructionWriter, s: &str) {
+        w.write_const(Value::String(s.to_string()), 1).unwrap();
+    }
+
+    fn assert_vm_error<T: Debug>(result: Result<T>) {
+        let err = result.expect_err("Expected run to fail");
+        assert!(err.downcast_ref::<VmError>().is_some(), "Expected VmError but got: {}", err);
+    }
+
+    #[test]
+    fn constant_pushes_value() {
+        let stack = run_ok(|w| num(w, 1.5));
+        assert_eq!(stack, vec![Value::Number(1.5)]);
+    }
+
+    #[test]
+    fn constant_with_bad_index_fails() {
+        let (_, result) = run(|w| { w.write_op_code_with_operand(OpCode::Constant, 3, 1); });
+        assert_vm_error(result);
+    }
+
+    #[test]
+    fn return_stops_execution() {
+        let (mut vm, result) = run(|w| {
+            num(w, 1.0);
+            num(w, 2.0);
+            w.write_op_code(OpCode::Return, 1);
+            w.write_op_code(OpCode::True, 1);
+        });
+        assert_eq!(result.unwrap(), Value::Number(2.0));
+        assert!(drain_stack(&mut vm).is_empty());
+    }
+
+    #[test]
+    fn script_result_is_its_final_expression() {
+        assert_eq!(run_source("var a = 2; a * 3;").1.unwrap(), Value::Number(6.0));
+        assert_eq!(run_source("1; print 2;").1.unwrap(), Value::Nil);
+        assert_eq!(run_source("{ 1; }").1.unwrap(), Value::Nil);
+        assert_eq!(run_source("var i = 0; while (i < 3) i = i + 1;").1.unwrap(), Value::Nil);
+        assert_eq!(run_source("if (true) 1; else 2;").1.unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn literals_without_decimal_point_are_ints() {
+        let (vm, result) = run_source("var i = 7; var f = 7.5; var big = 99999999999999999999;");
+        result.unwrap();
+        assert!(matches!(vm.global("i"), Some(Value::Int(7))));
+        assert!(matches!(vm.global("f"), Some(Value::Number(n)) if *n == 7.5));
+        assert!(matches!(vm.global("big"), Some(Value::Number(_))));
+    }
+
+    #[test]
+    fn hex_binary_and_scientific_literals() {
+        let (vm, result) = run_source("var hex = 0xFF; var bin = 0b1010; var sep = 1_000_000; var sci = 1.5e-3; var big = 2E+3; var frac = 0.000_5;");
+        result.unwrap();
+        assert!(matches!(vm.global("hex"), Some(Value::Int(255))));
+        assert!(matches!(vm.global("bin"), Some(Value::Int(10))));
+        assert!(matches!(vm.global("sep"), Some(Value::Int(1_000_000))));
+        assert!(matches!(vm.global("sci"), Some(Value::Number(n)) if *n == 1.5e-3));
+        assert!(matches!(vm.global("big"), Some(Value::Number(n)) if *n == 2000.0));
+        assert!(matches!(vm.global("frac"), Some(Value::Number(n)) if *n == 0.0005));
+    }
+
+    #[test]
+    fn malformed_number_literals_are_compile_errors() {
+        for source in ["0x;", "0b2;", "0x1_0000_0000_0000_0000;"] {
+            assert!(Compiler::new(source.to_string()).compile().is_err(), "{} should not compile", source);
+        }
+    }
+
+    #[test]
+    fn overflowing_hex_and_binary_literals_say_so() {
+        assert_eq!(compile_error_messages("0xFFFFFFFFFFFFFFFFFF;"), vec!["Hex literal is too large.".to_string()]);
+        assert_eq!(compile_error_messages(&format!("0b{};", "1".repeat(64))), vec!["Binary literal is too large.".to_string()]);
+    }
+
+    #[test]
+    fn malformed_sources_are_compile_errors_rather_than_panics() {
+        let source = "class A < B { init(x) { this.x = x; } get { return super.get(); } } \
+            fun f(a, ...rest) { for (var i = 0; i < 3; i = i + 1) { switch (a) { case 1: break; default: continue; } } } \
+            try { throw [1, 2][0]; } catch (e) { print -e * (2 + 3) >= 1 and !nil or \"s\"; }";
+        // Every prefix of the source, most of which end in the middle of something
+        for end in 0..=source.len() {
+            let compiled = std::panic::catch_unwind(|| Compiler::new(source[..end].to_string()).compile());
+            match compiled {
+                Ok(Ok(_)) => {},
+                Ok(Err(e)) => assert!(e.downcast_ref::<CompileErrorCollection>().is_some(), "{}: {}", &source[..end], e),
+                Err(_) => panic!("Compiling {:?} panicked", &source[..end])
+            }
+        }
+    }
+
+    #[test]
+    fn int_arithmetic_stays_int_until_promoted() {
+        let source = "var sum = 2 + 3; var mixed = 2 + 0.5; var exact = 6 / 3; var inexact = 7 / 2; var rem = 7 % 3; \
+            var neg = -4; var step = 1; step++; var overflow = 9223372036854775807 + 1; var same = 1 == 1.0;";
+        let (vm, result) = run_source(source);
+        result.unwrap();
+        assert!(matches!(vm.global("sum"), Some(Value::Int(5))));
+        assert!(matches!(vm.global("mixed"), Some(Value::Number(n)) if *n == 2.5));
+        assert!(matches!(vm.global("exact"), Some(Value::Int(2))));
+        assert!(matches!(vm.global("inexact"), Some(Value::Number(n)) if *n == 3.5));
+        assert!(matches!(vm.global("rem"), Some(Value::Int(1))));
+        assert!(matches!(vm.global("neg"), Some(Value::Int(-4))));
+        assert!(matches!(vm.global("step"), Some(Value::Int(2))));
+        assert!(matches!(vm.global("overflow"), Some(Value::Number(n)) if *n == 9223372036854775808.0));
+        assert_eq!(vm.global("same"), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn ints_compare_and_display_like_numbers() {
+        let (vm, result) = run_source("var less = 1 < 1.5; var greater = 2 > 1.5; var text = [3, 2.5, -0];");
+        result.unwrap();
+        assert_eq!(vm.global("less"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.global("greater"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.global("text").unwrap().to_string(), "[3, 2.5, 0]");
+    }
+
+    #[test]
+    fn eval_allows_top_level_return() {
+        let mut vm = Vm::new(VmOptions::default());
+        assert_eq!(vm.eval("var a = 1; if (a > 0) return \"positive\"; return \"other\";").unwrap(), Value::String("positive".to_string()));
+        assert_eq!(vm.eval("{ var local = 3; return local; }").unwrap(), Value::Number(3.0));
+        assert!(vm.stack.is_empty());
+        assert!(Compiler::new("return 1;".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn negate_number() {
+        let stack = run_ok(|w| {
+            num(w, 2.0);
+            w.write_op_code(OpCode::Negate, 1);
+        });
+        assert_eq!(stack, vec![Value::Number(-2.0)]);
+    }
+
+    #[test]
+    fn negate_non_number_fails() {
+        let (_, result) = run(|w| {
+            string(w, "a");
+            w.write_op_code(OpCode::Negate, 1);
+        });
+        assert_vm_error(result);
+    }
+
+    #[test]
+    fn negate_on_empty_stack_fails() {
+        let (_, result) = run(|w| { w.write_op_code(OpCode::Negate, 1); });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn add_numbers() {
+        let stack = run_ok(|w| {
+            num(w, 1.0);
+            num(w, 2.0);
+            w.write_op_code(OpCode::Add, 1);
+        });
+        assert_eq!(stack, vec![Value::Number(3.0)]);
+    }
+
+    #[test]
+    fn add_concatenates_strings() {
+        let stack = run_ok(|w| {
+            string(w, "foo");
+            string(w, "bar");
+            w.write_op_code(OpCode::Add, 1);
+        });
+        assert_eq!(stack, vec![Value::String("foobar".to_string())]);
+    }
+
+    #[test]
+    fn add_mixed_operands_fails() {
+        let (_, result) = run(|w| {
+            w.write_op_code(OpCode::True, 1);
+            num(w, 1.0);
+            w.write_op_code(OpCode::Add, 1);
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn add_converts_the_other_operand_of_a_string() {
+        let (vm, result) = run_source("var a = \"count: \" + 3; var b = 2.5 + \"x\"; var c = \"\" + nil + true + [1, \"y\"];");
+        result.unwrap();
+        assert_eq!(vm.global("a"), Some(&Value::String("count: 3".to_string())));
+        assert_eq!(vm.global("b"), Some(&Value::String("2.5x".to_string())));
+        assert_eq!(vm.global("c"), Some(&Value::String("niltrue[1, y]".to_string())));
+    }
+
+    fn error_message(source: &str) -> String {
+        let err = run_source(source).1.unwrap_err();
+        err.downcast_ref::<VmError>().expect("Expected a VmError").msg.clone()
+    }
+
+    #[test]
+    fn nil_operands_name_where_they_came_from() {
+        assert_eq!(error_message("var total; var n = 1; print total + n;"), "Left operand 'total' of '+' is nil");
+        assert_eq!(error_message("fun f() { var count; return 2 * count; } f();"), "Right operand 'count' of '*' is nil");
+        assert_eq!(error_message("class A {} var a = A(); a.x = nil; print -a.x;"), "Operand 'a.x' of '-' is nil");
+        assert_eq!(error_message("fun g() {} print g() - 1;"), "Left operand 'g()' of '-' is nil");
+        assert_eq!(error_message("class A { m(x) {} } var a = A(); print 1 - a.m(2 + 3);"), "Right operand 'a.m()' of '-' is nil");
+        assert_eq!(error_message("var a; var b; print (a or b) / 2;"), "Left operand of '/' is nil");
+        assert_eq!(error_message("var a = 1; var b; print a + (b) % 3;"), "Left operand 'b' of '%' is nil");
+        assert_eq!(run_source("var s = \"x\" + nil;").0.global("s"), Some(&Value::String("xnil".to_string())));
+    }
+
+    #[test]
+    fn division_by_zero_gives_infinity_unless_it_is_an_error() {
+        let (vm, result) = run_source("var inf = 1 / 0; var nan = 1 % 0.0;");
+        result.unwrap();
+        assert!(matches!(vm.global("inf"), Some(Value::Number(n)) if *n == f64::INFINITY));
+        assert!(matches!(vm.global("nan"), Some(Value::Number(n)) if n.is_nan()));
+
+        let strict = VmOptions { error_on_division_by_zero: true, ..Default::default() };
+        let err = run_source_with(strict.clone(), "var x = 0;\nprint 1 / x;").1.unwrap_err();
+        assert_eq!(err.downcast_ref::<VmError>().unwrap().msg, "Division by zero");
+        let err = run_source_with(strict.clone(), "5 % 0.0;").1.unwrap_err();
+        assert_eq!(err.downcast_ref::<VmError>().unwrap().msg, "Modulo by zero");
+        assert_eq!(run_source_with(strict, "6 / 4;").1.unwrap(), Value::Number(1.5));
+    }
+
+    #[test]
+    fn strict_concatenation_only_adds_strings_to_strings() {
+        let strict = VmOptions { strict_concatenation: true, ..Default::default() };
+        assert!(run_source_with(strict.clone(), "var a = \"count: \" + 3;").1.is_err());
+        assert_eq!(run_source_with(strict, "\"a\" + \"b\";").1.unwrap(), Value::String("ab".to_string()));
+    }
+
+    #[test]
+    fn add_with_one_operand_underflows() {
+        let (_, result) = run(|w| {
+            num(w, 1.0);
+            w.write_op_code(OpCode::Add, 1);
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn subtract_multiply_divide() {
+        let stack = run_ok(|w| {
+            num(w, 5.0);
+            num(w, 3.0);
+            w.write_op_code(OpCode::Subtract, 1);
+            num(w, 4.0);
+            w.write_op_code(OpCode::Multiply, 1);
+            num(w, 2.0);
+            w.write_op_code(OpCode::Divide, 1);
+        });
+        assert_eq!(stack, vec![Value::Number(4.0)]);
+    }
+
+    #[test]
+    fn modulo() {
+        let (vm, result) = run_source("var a = 7 % 3; var b = -7 % 3; var c = 1 + 10 % 4 * 2; var d = 5.5 % 2;");
+        result.unwrap();
+        assert_eq!(vm.globals.get("a"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Number(-1.0)));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Number(5.0)));
+        assert_eq!(vm.globals.get("d"), Some(&Value::Number(1.5)));
+        assert!(run_source("var e = \"a\" % 2;").1.is_err());
+    }
+
+    #[test]
+    fn arithmetic_on_non_numbers_fails() {
+        for op_code in [OpCode::Subtract, OpCode::Multiply, OpCode::Divide, OpCode::Modulo] {
+            let (_, result) = run(|w| {
+                w.write_op_code(OpCode::True, 1);
+                num(w, 1.0);
+                w.write_op_code(op_code, 1);
+            });
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn literals() {
+        let stack = run_ok(|w| {
+            w.write_op_code(OpCode::Nil, 1);
+            w.write_op_code(OpCode::True, 1);
+            w.write_op_code(OpCode::False, 1);
+        });
+        assert_eq!(stack, vec![Value::Nil, Value::Boolean(true), Value::Boolean(false)]);
+    }
+
+    #[test]
+    fn not_bool() {
+        let stack = run_ok(|w| {
+            w.write_op_code(OpCode::True, 1);
+            w.write_op_code(OpCode::Not, 1);
+        });
+        assert_eq!(stack, vec![Value::Boolean(false)]);
+    }
+
+    #[test]
+    fn not_uses_truthiness() {
+        let stack = run_ok(|w| {
+            w.write_op_code(OpCode::Nil, 1);
+            w.write_op_code(OpCode::Not, 1);
+            num(w, 0.0);
+            w.write_op_code(OpCode::Not, 1);
+            string(w, "");
+            w.write_op_code(OpCode::Not, 1);
+        });
+        assert_eq!(stack, vec![Value::Boolean(true), Value::Boolean(false), Value::Boolean(false)]);
+    }
+
+    #[test]
+    fn comparisons() {
+        let stack = run_ok(|w| {
+            num(w, 1.0);
+            num(w, 1.0);
+            w.write_op_code(OpCode::Equal, 1);
+            num(w, 2.0);
+            num(w, 1.0);
+            w.write_op_code(OpCode::Greater, 1);
+            num(w, 2.0);
+            num(w, 1.0);
+            w.write_op_code(OpCode::Less, 1);
+        });
+        assert_eq!(stack, vec![Value::Boolean(true), Value::Boolean(true), Value::Boolean(false)]);
+    }
+
+    #[test]
+    fn equal_on_different_types_is_false() {
+        let stack = run_ok(|w| {
+            w.write_op_code(OpCode::Nil, 1);
+            w.write_op_code(OpCode::False, 1);
+            w.write_op_code(OpCode::Equal, 1);
+        });
+        assert_eq!(stack, vec![Value::Boolean(false)]);
+    }
+
+    #[test]
+    fn print_pops_value() {
+        let stack = run_ok(|w| {
+            num(w, 1.0);
+            w.write_op_code(OpCode::Print, 1);
+        });
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn print_on_empty_stack_fails() {
+        let (_, result) = run(|w| { w.write_op_code(OpCode::Print, 1); });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn pop() {
+        let stack = run_ok(|w| {
+            num(w, 1.0);
+            num(w, 2.0);
+            w.write_op_code(OpCode::Pop, 1);
+        });
+        assert_eq!(stack, vec![Value::Number(1.0)]);
+    }
+
+    #[test]
+    fn pop_on_empty_stack_fails() {
+        let (_, result) = run(|w| { w.write_op_code(OpCode::Pop, 1); });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn define_and_get_global() {
+        let (mut vm, result) = run(|w| {
+            let name = w.add_constant(Value::String("a".to_string())).unwrap();
+            num(w, 7.0);
+            w.write_op_code_with_index(OpCode::DefineGlobal, name, 1).unwrap();
+            w.write_op_code_with_index(OpCode::GetGlobal, name, 1).unwrap();
+        });
+        result.unwrap();
+        assert_eq!(vm.globals.get("a"), Some(&Value::Number(7.0)));
+        assert_eq!(drain_stack(&mut vm), vec![Value::Number(7.0)]);
+    }
+
+    #[test]
+    fn get_undefined_global_fails() {
+        let (_, result) = run(|w| {
+            let name = w.add_constant(Value::String("a".to_string())).unwrap();
+            w.write_op_code_with_index(OpCode::GetGlobal, name, 1).unwrap();
+        });
+        assert_vm_error(result);
+    }
+
+    #[test]
+    fn global_name_must_be_string() {
+        let (_, result) = run(|w| {
+            let name = w.add_constant(Value::Number(1.0)).unwrap();
+            w.write_op_code_with_index(OpCode::GetGlobal, name, 1).unwrap();
+        });
+        assert_vm_error(result);
+    }
+
+    #[test]
+    fn set_global_leaves_value_on_stack() {
+        let (mut vm, result) = run(|w| {
+            let name = w.add_constant(Value::String("a".to_string())).unwrap();
+            num(w, 1.0);
+            w.write_op_code_with_index(OpCode::DefineGlobal, name, 1).unwrap();
+            num(w, 2.0);
+            w.write_op_code_with_index(OpCode::SetGlobal, name, 1).unwrap();
+        });
+        result.unwrap();
+        assert_eq!(vm.globals.get("a"), Some(&Value::Number(2.0)));
+        assert_eq!(drain_stack(&mut vm), vec![Value::Number(2.0)]);
+    }
+
+    #[test]
+    fn set_undefined_global_fails() {
+        let (vm, result) = run(|w| {
+            let name = w.add_constant(Value::String("a".to_string())).unwrap();
+            num(w, 2.0);
+            w.write_op_code_with_index(OpCode::SetGlobal, name, 1).unwrap();
+        });
+        assert_vm_error(result);
+        assert!(vm.globals.get("a").is_none());
+    }
+
+    #[test]
+    fn get_and_set_local() {
+        let stack = run_ok(|w| {
+            num(w, 1.0);
+            num(w, 2.0);
+            w.write_op_code_with_operand(OpCode::GetLocal, 0, 1);
+            w.write_op_code_with_operand(OpCode::SetLocal, 1, 1);
+        });
+        assert_eq!(stack, vec![Value::Number(1.0), Value::Number(1.0), Value::Number(1.0)]);
+    }
+
+    #[test]
+    fn get_local_out_of_range_fails() {
+        let (_, result) = run(|w| { w.write_op_code_with_operand(OpCode::GetLocal, 0, 1); });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn set_local_out_of_range_fails() {
+        let (_, result) = run(|w| {
+            num(w, 1.0);
+            w.write_op_code_with_operand(OpCode::SetLocal, 4, 1);
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn jump_skips_code() {
+        let stack = run_ok(|w| {
+            let jump = w.write_jump(1);
+            w.write_op_code(OpCode::True, 1);
+            w.patch_jump_to_chunk_end(jump).unwrap();
+            w.write_op_code(OpCode::False, 1);
+        });
+        assert_eq!(stack, vec![Value::Boolean(false)]);
+    }
+
+    #[test]
+    fn jump_if_false_jumps_and_keeps_condition() {
+        let stack = run_ok(|w| {
+            w.write_op_code(OpCode::False, 1);
+            let jump = w.write_jump_if_false(1);
+            w.write_op_code(OpCode::Nil, 1);
+            w.patch_jump_to_chunk_end(jump).unwrap();
+        });
+        assert_eq!(stack, vec![Value::Boolean(false)]);
+    }
+
+    #[test]
+    fn jump_if_false_falls_through_on_true() {
+        let stack = run_ok(|w| {
+            w.write_op_code(OpCode::True, 1);
+            let jump = w.write_jump_if_false(1);
+            w.write_op_code(OpCode::Nil, 1);
+            w.patch_jump_to_chunk_end(jump).unwrap();
+        });
+        assert_eq!(stack, vec![Value::Boolean(true), Value::Nil]);
+    }
+
+    #[test]
+    fn jump_if_false_jumps_on_nil() {
+        let stack = run_ok(|w| {
+            w.write_op_code(OpCode::Nil, 1);
+            let jump = w.write_jump_if_false(1);
+            w.write_op_code(OpCode::True, 1);
+            w.patch_jump_to_chunk_end(jump).unwrap();
+        });
+        assert_eq!(stack, vec![Value::Nil]);
+    }
+
+    #[test]
+    fn jump_if_false_falls_through_on_other_values() {
+        let stack = run_ok(|w| {
+            num(w, 0.0);
+            let jump = w.write_jump_if_false(1);
+            w.write_op_code(OpCode::Nil, 1);
+            w.patch_jump_to_chunk_end(jump).unwrap();
+        });
+        assert_eq!(stack, vec![Value::Number(0.0), Value::Nil]);
+    }
+
+    #[test]
+    fn conditions_use_truthiness() {
+        let (vm, result) = run_source("
+            var maybe;
+            var a = \"unset\";
+            if (maybe) a = \"set\";
+            var b = nil or \"default\";
+            var c = 0 and \"zero is truthy\";
+            var d = !\"\";
+        ");
+        result.unwrap();
+        assert_eq!(vm.global("a"), Some(&Value::String("unset".to_string())));
+        assert_eq!(vm.global("b"), Some(&Value::String("default".to_string())));
+        assert_eq!(vm.global("c"), Some(&Value::String("zero is truthy".to_string())));
+        assert_eq!(vm.global("d"), Some(&Value::Boolean(false)));
+    }
+
+    #[test]
+    fn jump_past_chunk_end_fails() {
+        let (_, result) = run(|w| { w.write_jump(1); });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn loop_jumps_backwards() {
+        let (vm, result) = run(|w| {
+            let name = w.add_constant(Value::String("i".to_string())).unwrap();
+            num(w, 3.0);
+            w.write_op_code_with_index(OpCode::DefineGlobal, name, 1).unwrap();
+
+            let loop_start = w.len();
+            w.write_op_code_with_index(OpCode::GetGlobal, name, 1).unwrap();
+            num(w, 0.0);
+            w.write_op_code(OpCode::Greater, 1);
+            let exit_jump = w.write_jump_if_false(1);
+            w.write_op_code(OpCode::Pop, 1);
+            w.write_op_code_with_index(OpCode::GetGlobal, name, 1).unwrap();
+            num(w, 1.0);
+            w.write_op_code(OpCode::Subtract, 1);
+            w.write_op_code_with_index(OpCode::SetGlobal, name, 1).unwrap();
+            w.write_op_code(OpCode::Pop, 1);
+            w.write_loop(loop_start, 1).unwrap();
+            w.patch_jump_to_chunk_end(exit_jump).unwrap();
+            w.write_op_code(OpCode::Pop, 1);
+        });
+        result.unwrap();
+        assert_eq!(vm.globals.get("i"), Some(&Value::Number(0.0)));
+    }
+
+    #[test]
+    fn unknown_opcode_fails() {
+        let (_, result) = run(|w| {
+            w.write_op_code(OpCode::Nil, 1);
+            w.set_byte(0, 0xfe).unwrap();
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn call_and_return_value() {
+        let stack = run_ok(|w| {
+            let double = function("double", 1, |f| {
+                f.write_op_code_with_operand(OpCode::GetLocal, 1, 1);
+                num(f, 2.0);
+                f.write_op_code(OpCode::Multiply, 1);
+                f.write_op_code(OpCode::Return, 1);
+            });
+            num(w, 10.0);
+            w.write_const(double, 1).unwrap();
+            num(w, 21.0);
+            w.write_op_code_with_operand(OpCode::Call, 1, 1);
+        });
+        // Callee and argument are replaced by the return value
+        assert_eq!(stack, vec![Value::Number(10.0), Value::Number(42.0)]);
+    }
+
+    #[test]
+    fn call_with_wrong_arity_fails() {
+        let (_, result) = run(|w| {
+            let f = function("f", 2, |f| { f.write_op_code(OpCode::Return, 1); });
+            w.write_const(f, 1).unwrap();
+            num(w, 1.0);
+            w.write_op_code_with_operand(OpCode::Call, 1, 1);
+        });
+        assert_vm_error(result);
+    }
+
+    #[test]
+    fn call_non_function_fails() {
+        let (_, result) = run(|w| {
+            num(w, 1.0);
+            w.write_op_code_with_operand(OpCode::Call, 0, 1);
+        });
+        assert_vm_error(result);
+    }
+
+    #[test]
+    fn call_with_missing_callee_underflows() {
+        let (_, result) = run(|w| { w.write_op_code_with_operand(OpCode::Call, 0, 1); });
+        assert_vm_error(result);
+    }
+
+    #[test]
+    fn return_from_function() {
+        let (vm, result) = run_source("
+            fun add(a, b) { return a + b; }
+            var sum = add(1, 2);
+            fun early(n) { if (n) return 1; return 2; }
+            var early_true = early(true);
+            var early_false = early(false);
+            fun nothing() { return; }
+            var none = nothing();
+            fun implicit() {}
+            var implicit_none = implicit();
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.globals.get("early_true"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.globals.get("early_false"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.globals.get("none"), Some(&Value::Nil));
+        assert_eq!(vm.globals.get("implicit_none"), Some(&Value::Nil));
+    }
+
+    #[test]
+    fn return_unwinds_locals() {
+        let (mut vm, result) = run_source("
+            fun f(a) { var b = a * 2; { var c = b + 1; return c; } }
+            var r = f(1) + f(2);
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("r"), Some(&Value::Number(8.0)));
+        assert!(drain_stack(&mut vm).is_empty());
+    }
+
+    #[test]
+    fn recursion() {
+        let (vm, result) = run_source("
+            fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+            var f = fib(10);
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("f"), Some(&Value::Number(55.0)));
+    }
+
+    #[test]
+    fn return_at_top_level_is_compile_error() {
+        assert!(Compiler::new("return 1;".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn unbounded_recursion_overflows() {
+        let (_, result) = run_source("fun f() { return f(); } f();");
+        let err = result.unwrap_err();
+        let vm_error = err.downcast_ref::<VmError>().unwrap();
+        assert!(vm_error.to_string().contains("Stack overflow"));
+        let trace = vm_error.trace().unwrap().format(Some(20));
+        assert!(trace.contains(&format!("... {} frames omitted ...", MAX_FRAMES - 20)));
+        assert!(trace.ends_with("[line 1] in script\n"));
+    }
+
+    #[test]
+    fn runs_stop_once_they_exceed_the_instruction_budget() {
+        let mut vm = Vm::new(VmOptions::default()).with_limits(1000);
+        let endless = Compiler::new("var n = 0; try { while (true) { n = n + 1; } } catch (e) { n = -1; }".to_string()).compile().unwrap();
+        let err = vm.run(&endless).unwrap_err();
+        assert!(err.downcast_ref::<VmError>().expect("Expected a VmError").is_budget_exceeded());
+        assert_eq!(vm.last_error().unwrap().kind, ErrorKind::BudgetExceeded);
+        assert!(matches!(vm.global("n"), Some(Value::Int(n)) if *n > 0));
+
+        // Each run gets the whole budget, though the two together take more
+        let short = Compiler::new("var i = 0; while (i < 50) { i = i + 1; }".to_string()).compile().unwrap();
+        vm.run(&short).unwrap();
+        vm.run(&short).unwrap();
+    }
+
+    #[test]
+    fn a_handle_cancels_the_run_from_another_thread() {
+        let mut vm = Vm::new(VmOptions::default());
+        let handle = vm.handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            handle.cancel();
+        });
+        let endless = Compiler::new("var n = 0; try { while (true) { n = n + 1; } } catch (e) { n = -1; }".to_string()).compile().unwrap();
+        let err = vm.run(&endless).unwrap_err();
+        canceller.join().unwrap();
+        assert!(err.downcast_ref::<VmError>().expect("Expected a VmError").is_cancelled());
+        assert_eq!(vm.last_error().unwrap().kind, ErrorKind::Cancelled);
+        assert!(matches!(vm.global("n"), Some(Value::Int(n)) if *n > 0));
+
+        // Cancelling stops just the one run
+        assert_eq!(vm.eval("1 + 2;").unwrap(), Value::Int(3));
+    }
+
+    #[test]
+    fn runs_past_their_timeout_stop() {
+        let mut vm = Vm::new(VmOptions::default());
+        let endless = Compiler::new("var n = 0; try { while (true) { n = n + 1; } } catch (e) { n = -1; }".to_string()).compile().unwrap();
+        let err = vm.run_with_timeout(&endless, Duration::from_millis(20)).unwrap_err();
+        assert!(err.downcast_ref::<VmError>().expect("Expected a VmError").is_timed_out());
+        assert_eq!(vm.last_error().unwrap().kind, ErrorKind::TimedOut);
+        assert!(matches!(vm.global("n"), Some(Value::Int(n)) if *n > 0));
+
+        let short = Compiler::new("var i = 0; while (i < 50) { i = i + 1; }".to_string()).compile().unwrap();
+        vm.run_with_timeout(&short, Duration::from_secs(60)).unwrap();
+        // The timeout only applies to the run it was given to
+        assert!(vm.deadline.is_none());
+    }
+
+    #[test]
+    fn filling_the_stack_is_a_catchable_overflow() {
+        let small = || VmOptions { stack_capacity: Some(64), ..Default::default() };
+        let deep = "fun f(n) { var a = n; var b = n; return f(n + 1); }";
+        let (vm, result) = run_source_with(small(), deep);
+        result.unwrap();
+        assert_eq!(vm.stack.capacity(), 64);
+
+        let (vm, result) = run_source_with(small(), &format!("{} f(0);", deep));
+        let err = result.unwrap_err();
+        assert_eq!(err.downcast_ref::<VmError>().expect("Expected a VmError").msg, "Stack overflow");
+        assert_eq!(vm.last_error().unwrap().kind, ErrorKind::Runtime);
+        assert_eq!(vm.max_stack_depth(), 64);
+
+        let (vm, result) = run_source_with(small(), &format!("{} var caught; try {{ f(0); }} catch (e) {{ caught = e; }}", deep));
+        result.unwrap();
+        assert_eq!(vm.global("caught"), Some(&Value::String("Stack overflow".to_string())));
+    }
+
+    #[test]
+    fn global_history_records_writes() {
+        let chunk = Compiler::new("var a = 1;\nvar b;\na = nil;\nb = a;\nprint -a;".to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions { record_global_history: true, ..Default::default() });
+        assert!(vm.run(&chunk).is_err());
+
+        let history = vm.global_history().unwrap().to_string();
+        assert_eq!(history, "Global variable history:\n  \
+            a:\n    [line 1] var 1\n    [line 3] set nil\n  \
+            b:\n    [line 2] var nil\n    [line 4] set nil\n");
+    }
+
+    #[test]
+    fn allocations_are_counted_by_kind() {
+        let (vm, result) = run_source_with(VmOptions { record_allocations: true, ..Default::default() }, "
+            class A { m() {} }
+            var a = A();
+            var m = a.m;
+            var s = \"x\" + \"y\";
+            fun make() { fun inner() {} return inner; }
+            for (var i = 0; i < 3; i = i + 1) make();
+            var l = [1, 2];
+        ");
+        result.unwrap();
+        let allocations = vm.allocations().unwrap();
+        assert_eq!(allocations.count("classes"), 1);
+        assert_eq!(allocations.count("instances"), 1);
+        assert_eq!(allocations.count("bound methods"), 1);
+        assert_eq!(allocations.count("strings"), 1);
+        // The method, `make`, and one closure per call
+        assert_eq!(allocations.count("closures"), 5);
+        assert_eq!(allocations.count("lists"), 1);
+        assert!(allocations.to_string().starts_with("Allocations:\n"));
+    }
+
+    #[test]
+    fn allocations_are_not_counted_by_default() {
+        let (vm, result) = run_source("var s = \"x\" + \"y\";");
+        result.unwrap();
+        assert!(vm.allocations().is_none());
+    }
+
+    fn watching(names: &[&str], source: &str) -> (Vm, Result<Value>) {
+        run_source_with(VmOptions { watch: names.iter().map(|name| name.to_string()).collect(), ..Default::default() }, source)
+    }
+
+    #[test]
+    fn watchpoint_on_global_stops_at_assignment() {
+        let (vm, result) = watching(&["a"], "var a = 1;\nvar b = 2;\nb = 3;\na = b + 1;\nb = 5;");
+        let err = result.unwrap_err();
+        let vm_error = err.downcast_ref::<VmError>().unwrap();
+        assert_eq!(vm_error.watchpoint(), Some("a"));
+        assert_eq!(vm_error.message(), "Watchpoint hit: 'a' set to 4");
+        assert!(vm_error.to_string().starts_with("[source line 4,"));
+        assert_eq!(vm_error.trace().unwrap().format(None), "[line 4] in script\n");
+        assert_eq!(vm.global("a"), Some(&Value::Number(4.0)));
+        assert_eq!(vm.global("b"), Some(&Value::Number(3.0)));
+    }
+
+    #[test]
+    fn watchpoint_on_local_stops_at_assignment() {
+        let (_, result) = watching(&["count"], "fun f() {\n  var count = 0;\n  count = count + 1;\n}\nf();");
+        let err = result.unwrap_err();
+        let vm_error = err.downcast_ref::<VmError>().unwrap();
+        assert_eq!(vm_error.message(), "Watchpoint hit: 'count' set to 1");
+        assert_eq!(vm_error.trace().unwrap().format(None), "[line 3] in f\n[line 5] in script\n");
+    }
+
+    #[test]
+    fn watchpoint_on_captured_local_stops_at_assignment_in_closure() {
+        let (_, result) = watching(&["count"], "fun f() {\n  var count = 0;\n  fun inc() {\n    count = count + 1;\n  }\n  inc();\n}\nf();");
+        let err = result.unwrap_err();
+        let vm_error = err.downcast_ref::<VmError>().unwrap();
+        assert_eq!(vm_error.message(), "Watchpoint hit: 'count' set to 1");
+        assert_eq!(vm_error.trace().unwrap().format(None), "[line 4] in inc\n[line 6] in f\n[line 8] in script\n");
+    }
+
+    #[test]
+    fn last_error_describes_the_latest_failure_and_the_vm_runs_on() {
+        let mut vm = Vm::new(VmOptions::default());
+        let run = |vm: &mut Vm, source: &str| vm.run(&Compiler::new(source.to_string()).compile().unwrap());
+        assert!(vm.last_error().is_none());
+
+        assert!(run(&mut vm, "fun f() {\n  return -nil;\n}\n{ var a = 1; f(); }").is_err());
+        let error = vm.last_error().unwrap();
+        assert_eq!(error.kind, ErrorKind::Runtime);
+        assert_eq!(error.line, Some(2));
+        assert_eq!(error.trace.as_ref().unwrap().format(None), "[line 2] in f\n[line 4] in script\n");
+
+        // Locals of the next run start from an empty stack, and the error sticks around after it
+        assert_eq!(run(&mut vm, "var b; { var c = 2; b = c; }").unwrap(), Value::Nil);
+        assert_eq!(vm.global("b"), Some(&Value::Int(2)));
+        assert_eq!(vm.last_error().unwrap().kind, ErrorKind::Runtime);
+
+        assert!(run(&mut vm, "throw \"oops\";").is_err());
+        let error = vm.last_error().unwrap();
+        assert_eq!(error.kind, ErrorKind::UncaughtException);
+        assert_eq!(error.thrown, Some(Value::String("oops".to_string())));
+
+        vm.clear_last_error();
+        assert!(vm.last_error().is_none());
+        let (vm, _) = watching(&["x"], "var x = 1; x = 2;");
+        assert_eq!(vm.last_error().unwrap().kind, ErrorKind::Watchpoint);
+    }
+
+    #[test]
+    fn watchpoint_is_not_caught_by_try() {
+        let (_, result) = watching(&["x"], "var x = 1; try { x = 2; } catch (e) { print e; }");
+        let err = result.unwrap_err();
+        assert_eq!(err.downcast_ref::<VmError>().unwrap().watchpoint(), Some("x"));
+    }
+
+    #[test]
+    fn unwatched_assignments_run_to_the_end() {
+        let (vm, result) = watching(&["y"], "var x = 1; { var z = 2; z = 3; x = z; }");
+        result.unwrap();
+        assert_eq!(vm.global("x"), Some(&Value::Number(3.0)));
+    }
+
+    #[derive(Default)]
+    struct LineRecorder {
+        lines: Rc<RefCell<Vec<(i32, usize)>>>,
+        stop_at: Option<i32>
+    }
+
+    impl Debugger for LineRecorder {
+        fn on_line(&mut self, _vm: &Vm, location: &Location) -> Result<()> {
+            self.lines.borrow_mut().push((location.line, location.depth));
+            if self.stop_at == Some(location.line) {
+                bail!("Stopped");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn debugger_is_told_of_each_line_reached() {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let chunk = Compiler::new("fun f() {\n  return 1;\n}\nvar i = 0;\nwhile (i < 2) i = i + f();\nprint i;".to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions::default());
+        vm.set_debugger(Box::new(LineRecorder { lines: lines.clone(), stop_at: None }));
+        vm.run(&chunk).unwrap();
+        assert_eq!(*lines.borrow(), [(3, 1), (4, 1), (5, 1), (2, 2), (5, 1), (5, 1), (2, 2), (5, 1), (5, 1), (6, 1)]);
+    }
+
+    #[test]
+    fn debugger_stopping_execution_is_not_caught_by_try() {
+        let chunk = Compiler::new("var x = 0;\ntry {\n  x = 1;\n} catch (e) {\n  x = 2;\n}".to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions::default());
+        vm.set_debugger(Box::new(LineRecorder { stop_at: Some(3), ..Default::default() }));
+        assert_eq!(vm.run(&chunk).unwrap_err().to_string(), "Stopped");
+        assert_eq!(vm.global("x"), Some(&Value::Number(0.0)));
+    }
+
+    /// Lines reached, each with a checkpoint taken there
+    type Checkpoints = Rc<RefCell<Vec<(i32, Vec<u8>)>>>;
+
+    /// Takes a checkpoint at every line reached
+    struct Recorder(Checkpoints);
+
+    impl Debugger for Recorder {
+        fn on_line(&mut self, vm: &Vm, location: &Location) -> Result<()> {
+            self.0.borrow_mut().push((location.line, vm.checkpoint()?));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn checkpoints_taken_while_paused_resume_from_the_line_paused_at() {
+        let chunk = Compiler::new("var log = \"\";\nfor (var i = 0; i < 3; i = i + 1) {\n  log = log + str(i);\n}\nvar done = log;".to_string()).compile().unwrap();
+        let checkpoints = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::new(VmOptions::default());
+        vm.set_debugger(Box::new(Recorder(checkpoints.clone())));
+        vm.run(&chunk).unwrap();
+        assert_eq!(vm.global("done"), Some(&Value::String("012".to_string())));
+
+        // The second time round the loop, with one digit logged
+        let checkpoints = checkpoints.borrow();
+        let (_, second_pass) = checkpoints.iter().filter(|(line, _)| *line == 3).nth(1).unwrap();
+        let mut resumed = Vm::new(VmOptions::default());
+        resumed.resume(second_pass).unwrap();
+        assert_eq!(resumed.global("done"), Some(&Value::String("012".to_string())));
+
+        assert!(Vm::new(VmOptions::default()).checkpoint().is_err());
+    }
+
+    #[test]
+    fn global_slots_are_found_again_for_other_globals() {
+        let chunk = Compiler::new("
+            fun get() { return later; }
+            var later = 1;
+            var a = get();
+            later = later + 1;
+            var b = get();
+        ".to_string()).compile().unwrap();
+
+        let mut first = Vm::new(VmOptions::default());
+        first.run(&chunk).unwrap();
+        // The same chunk in a VM with more globals, where `later` has another slot
+        let mut second = Vm::new(VmOptions::default());
+        second.set_global("padding", Value::Nil);
+        second.run(&chunk).unwrap();
+        let snapshot = second.snapshot().unwrap();
+        second.restore_snapshot(&snapshot).unwrap();
+        second.run(&chunk).unwrap();
+
+        for vm in [&first, &second] {
+            assert_eq!(vm.global("a"), Some(&Value::Number(1.0)));
+            assert_eq!(vm.global("b"), Some(&Value::Number(2.0)));
+        }
+        assert_eq!(second.global("padding"), Some(&Value::Nil));
+        assert_vm_error(run_source("fun get() { return missing; } get();").1);
+        assert_vm_error(run_source("missing = 1;").1);
+    }
+
+    #[test]
+    fn inline_caches_are_used_only_while_they_still_apply() {
+        let (vm, result) = run_source("
+            class A {
+                init(x) { this.x = x; this.y = 0; }
+                get() { return this.x; }
+            }
+            var sum = 0;
+            for (var i = 0; i < 20; i = i + 1) {
+                var object = A(i);
+                sum = sum + object.x + object.get();
+            }
+        ");
+        result.unwrap();
+        assert_eq!(vm.global("sum"), Some(&Value::Number(380.0)));
+        let stats = vm.inline_cache_stats();
+        assert!(stats.hits > 10 * stats.misses, "{}", stats);
+
+        // Instances of different classes, with fields in another order, through the same instructions
+        let (vm, result) = run_source("
+            class A { init(x) { this.x = x; this.y = 0; } get() { return this.x; } }
+            class B { init(x) { this.y = 0; this.x = x * 10; } get() { return this.x + 1; } }
+            fun total(object) { return object.x + object.get(); }
+            var sum = 0;
+            for (var i = 0; i < 4; i = i + 1) {
+                sum = sum + total(A(i)) + total(B(i));
+            }
+            var shadowed = A(1);
+            fun hundred() { return 100; }
+            shadowed.get = hundred;
+            var fromField = total(shadowed);
+        ");
+        result.unwrap();
+        assert_eq!(vm.global("sum"), Some(&Value::Number(136.0)));
+        assert_eq!(vm.global("fromField"), Some(&Value::Number(101.0)));
+    }
+
+    #[test]
+    fn globals_and_map_entries_keep_insertion_order() {
+        let (vm, result) = run_source("var zeta = 1; var alpha = 2; var m = map(); m[\"z\"] = 1; m[\"a\"] = 2; m[\"z\"] = 3; var mid = 4;");
+        result.unwrap();
+        let names: Vec<&str> = vm.globals()
+            .filter(|(_, value)| !matches!(value, Value::Native(_)))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["zeta", "alpha", "m", "mid"]);
+        assert_eq!(vm.global("m").unwrap().to_string(), "{z: 3, a: 2}");
+    }
+
+    #[test]
+    fn global_history_is_off_by_default() {
+        let (vm, result) = run_source("var a = 1;");
+        result.unwrap();
+        assert!(vm.global_history().is_none());
+    }
+
+    #[test]
+    fn for_loops_run_all_clauses() {
+        let (vm, result) = run_source("
+            var sum = 0;
+            for (var i = 0; i < 5; i = i + 1) sum = sum + i;
+            var j = 0;
+            for (; j < 3;) j = j + 1;
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Number(10.0)));
+        assert_eq!(vm.globals.get("j"), Some(&Value::Number(3.0)));
+        assert!(vm.globals.get("i").is_none());
+    }
+
+    #[test]
+    fn for_loop_variable_is_scoped_to_the_loop() {
+        let (vm, result) = run_source("
+            var i = \"global\";
+            fun f() {
+                var total = 0;
+                for (var i = 1; i <= 3; i = i + 1) { var doubled = i * 2; total = total + doubled; }
+                return total;
+            }
+            var total = f();
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("total"), Some(&Value::Number(12.0)));
+        assert_eq!(vm.globals.get("i"), Some(&Value::String("global".to_string())));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn break_exits_the_innermost_loop() {
+        let (vm, result) = run_source("
+            var count = 0;
+            for (var i = 0; i < 3; i = i + 1) {
+                var j = 0;
+                while (true) {
+                    var k = j;
+                    if (k == 2) break;
+                    j = j + 1;
+                    count = count + 1;
+                }
+            }
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("count"), Some(&Value::Number(6.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn continue_skips_to_the_increment() {
+        let (vm, result) = run_source("
+            var sum = 0;
+            for (var i = 0; i < 6; i = i + 1) {
+                var odd = i == 1 or i == 3 or i == 5;
+                if (odd) continue;
+                sum = sum + i;
+            }
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Number(6.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn break_closes_captured_locals() {
+        let (vm, result) = run_source("
+            var f;
+            while (true) {
+                var captured = \"kept\";
+                fun g() { return captured; }
+                f = g;
+                break;
+            }
+            var value = f();
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("value"), Some(&Value::String("kept".to_string())));
+    }
+
+    #[test]
+    fn break_and_continue_outside_loops_fail_to_compile() {
+        assert!(Compiler::new("break;".to_string()).compile().is_err());
+        assert!(Compiler::new("fun f() { continue; }".to_string()).compile().is_err());
+        assert!(Compiler::new("while (true) { fun f() { break; } }".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn bytes_literals_index_and_slice() {
+        let (vm, result) = run_source(r#"
+            var b = b"\x00\x01AB";
+            var first = b[0];
+            var last = b[len(b) - 1];
+            var middle = slice(b, 1, 3);
+            var text = decode(encode("hé", "utf8"), "utf8");
+            var hex = decode(b, "hex");
+        "#);
+        result.unwrap();
+        assert_eq!(vm.globals.get("first"), Some(&Value::Number(0.0)));
+        assert_eq!(vm.globals.get("last"), Some(&Value::Number(66.0)));
+        assert_eq!(vm.globals.get("middle"), Some(&Value::Bytes(Rc::new(vec![1, b'A']))));
+        assert_eq!(vm.globals.get("text"), Some(&Value::String("hé".to_string())));
+        assert_eq!(vm.globals.get("hex"), Some(&Value::String("00014142".to_string())));
+    }
+
+    #[test]
+    fn bytes_errors() {
+        assert_vm_error(run_source(r#"b"ab"[2];"#).1);
+        assert_vm_error(run_source(r#"b"ab"[0.5];"#).1);
+        assert_vm_error(run_source(r#"b"ab"["0"];"#).1);
+        assert_vm_error(run_source("1[0];").1);
+        assert_vm_error(run_source(r#"encode("é", "ascii");"#).1);
+        assert_vm_error(run_source(r#"decode(b"\xff", "utf8");"#).1);
+        assert!(Compiler::new(r#"b"\xzz";"#.to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn index_get_and_set() {
+        let (vm, result) = run_source(r#"
+            var list = [1, "two", [3]];
+            list[0] = list[0] + 10;
+            list[2][0] = 4;
+            var nested = list[2][0];
+            var m = map();
+            m["a"] = list;
+            m[1] = "one";
+            var fromMap = m["a"][0];
+            var size = len(m);
+            var ch = "héllo"[1];
+            var assigned = list[1] = "deux";
+        "#);
+        result.unwrap();
+        assert_eq!(vm.globals.get("list"), Some(&Value::list(vec![
+            Value::Number(11.0), Value::String("deux".to_string()), Value::list(vec![Value::Number(4.0)])
+        ])));
+        assert_eq!(vm.globals.get("nested"), Some(&Value::Number(4.0)));
+        assert_eq!(vm.globals.get("fromMap"), Some(&Value::Number(11.0)));
+        assert_eq!(vm.globals.get("size"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.globals.get("ch"), Some(&Value::String("é".to_string())));
+        assert_eq!(vm.globals.get("assigned"), Some(&Value::String("deux".to_string())));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn index_errors() {
+        assert_vm_error(run_source("[1, 2][2];").1);
+        assert_vm_error(run_source("[1, 2][-1];").1);
+        assert_vm_error(run_source("[1, 2][nil] = 1;").1);
+        assert_vm_error(run_source(r#""ab"[2];"#).1);
+        assert_vm_error(run_source(r#""ab"[0] = "c";"#).1);
+        assert_vm_error(run_source(r#"map()["missing"];"#).1);
+        assert_vm_error(run_source("map()[[]] = 1;").1);
+        assert_vm_error(run_source("nil[0];").1);
+        assert!(Compiler::new("[1, 2;".to_string()).compile().is_err());
+    }
+
+    fn printed(source: &str) -> String {
+        let chunk = Compiler::new(source.to_string()).compile().expect("Compilation failed");
+        let mut vm = Vm::new(VmOptions::default());
+        vm.capture_output(true);
+        vm.run(&chunk).unwrap();
+        vm.take_output().0
+    }
+
+    #[test]
+    fn lists_and_maps_containing_themselves_print_and_compare() {
+        assert_eq!(printed("var l = [1]; l[0] = l; print l;"), "[[...]]\n");
+        assert_eq!(printed(r#"var m = map(); m["a"] = m; print m;"#), "{a: {...}}\n");
+        assert_eq!(printed("var a = [0]; a[0] = a; var b = [0]; b[0] = b; print a == b;"), "true\n");
+        assert_eq!(printed("var a = [0]; a[0] = a; var b = [1, 0]; b[1] = b; print a == b;"), "false\n");
+        assert_eq!(printed(r#"var l = [1]; var m = map(); m["l"] = l; m["again"] = l; print m;"#), "{l: [1], again: [1]}\n");
+    }
+
+    #[test]
+    fn size_limits() {
+        let limited = || VmOptions { max_string_length: Some(8), max_collection_size: Some(3), ..Default::default() };
+
+        let (vm, result) = run_source_with(limited(), r#"
+            var s = "abcd" + "efgh";
+            var l = [1, 2, 3];
+            var m = map();
+            m["a"] = 1; m["b"] = 2; m["c"] = 3; m["c"] = 4;
+        "#);
+        result.unwrap();
+        assert_eq!(vm.global("s"), Some(&Value::String("abcdefgh".to_string())));
+
+        assert_vm_error(run_source_with(limited(), r#"var s = "a"; while (true) s = s + s;"#).1);
+        assert_vm_error(run_source_with(limited(), "[1, 2, 3, 4];").1);
+        assert_vm_error(run_source_with(limited(), r#"var m = map(); m[1] = 1; m[2] = 2; m[3] = 3; m[4] = 4;"#).1);
+        #[cfg(feature = "stdlib")]
+        assert_vm_error(run_source_with(limited(), r#"splitWhitespace("a b c d");"#).1);
+        run_source_with(VmOptions::default(), "[1, 2, 3, 4];").1.unwrap();
+    }
+
+    #[test]
+    fn dynamic_code() {
+        let allowed = || VmOptions { allow_dynamic_code: true, ..Default::default() };
+        let (vm, result) = run_source_with(allowed(), r#"
+            var x = 1;
+            var script = compile("x = x + 1; fun double(n) { return n * 2; } double(x);");
+            fun twice() { run(script); return run(script); }
+            var result = twice();
+        "#);
+        result.unwrap();
+        assert_eq!(vm.global("x"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.global("result"), Some(&Value::Number(6.0)));
+        assert!(matches!(vm.global("double"), Some(Value::Closure(_))));
+        assert!(vm.stack.is_empty());
+
+        assert_vm_error(run_source_with(allowed(), r#"compile("1 +");"#).1);
+        assert_vm_error(run_source_with(allowed(), r#"run(compile("nil();"));"#).1);
+        assert_vm_error(run_source_with(allowed(), "fun f() {} run(f);").1);
+        assert_vm_error(run_source(r#"compile("1;");"#).1);
+    }
+
+    #[test]
+    fn try_catch_and_throw() {
+        let (vm, result) = run_source(r#"
+            fun check(n) {
+                if (n > 2) throw n * 10;
+                return n;
+            }
+            var total = 0;
+            var thrown = nil;
+            for (var i = 0; i < 5; i = i + 1) {
+                try {
+                    var doubled = check(i) * 2;
+                    total = total + doubled;
+                } catch (e) {
+                    thrown = e;
+                    if (i == 3) continue;
+                    break;
+                }
+            }
+            var runtime = nil;
+            try { nil(); } catch (e) { runtime = e; }
+            fun early() { try { return "returned"; } catch (e) {} }
+            var returned = early();
+            var rethrown = nil;
+            try { try { throw "inner"; } catch (e) { throw e + " again"; } } catch (e) { rethrown = e; }
+        "#);
+        result.unwrap();
+        assert_eq!(vm.global("total"), Some(&Value::Number(6.0)));
+        assert_eq!(vm.global("thrown"), Some(&Value::Number(40.0)));
+        assert_eq!(vm.global("runtime"), Some(&Value::String("Can only call functions and classes".to_string())));
+        assert_eq!(vm.global("returned"), Some(&Value::String("returned".to_string())));
+        assert_eq!(vm.global("rethrown"), Some(&Value::String("inner again".to_string())));
+        assert!(vm.stack.is_empty());
+        assert!(vm.handlers.is_empty());
+    }
+
+    #[test]
+    fn try_catch_errors() {
+        let (_, result) = run_source("fun f() { try { return 1; } catch (e) {} } f(); throw \"late\";");
+        assert!(result.unwrap_err().to_string().contains("Uncaught exception: late"));
+        assert!(Compiler::new("try { } ".to_string()).compile().is_err());
+        assert!(Compiler::new("try { } catch { }".to_string()).compile().is_err());
+        assert!(Compiler::new("throw;".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn errors_from_run_scripts_are_catchable() {
+        let options = VmOptions { allow_dynamic_code: true, ..Default::default() };
+        let (vm, result) = run_source_with(options, r#"
+            var caught = nil;
+            try { run(compile("throw 7;")); } catch (e) { caught = e; }
+        "#);
+        result.unwrap();
+        assert_eq!(vm.global("caught"), Some(&Value::Number(7.0)));
+    }
+
+    #[test]
+    fn compile_errors_are_capped() {
+        let source = "print ;".repeat(30);
+        let errors = |compiler: Compiler| match compiler.compile().unwrap_err().downcast::<CompileErrorCollection>() {
+            Ok(collection) => collection.errors,
+            Err(e) => panic!("Unexpected error {}", e)
+        };
+
+        let capped = errors(Compiler::new(source.clone()));
+        assert_eq!(capped.len(), DEFAULT_MAX_ERRORS + 1);
+        assert!(matches!(capped.last(), Some(CompileError::TooMany(DEFAULT_MAX_ERRORS))));
+
+        let raised = errors(Compiler::new(source.clone()).with_max_errors(50));
+        assert_eq!(raised.len(), 30);
+        assert!(raised.iter().all(|e| matches!(e, CompileError::Parse { .. })));
+
+        let exact = errors(Compiler::new("print ;".repeat(3)).with_max_errors(3));
+        assert_eq!(exact.len(), 3);
+    }
+
+    fn compile_error_messages(source: &str) -> Vec<String> {
+        match Compiler::new(source.to_string()).compile().unwrap_err().downcast::<CompileErrorCollection>() {
+            Ok(collection) => collection.errors.iter()
+                .map(|e| match e {
+                    CompileError::Parse { msg, .. } => msg.clone(),
+                    other => other.to_string()
+                })
+                .collect(),
+            Err(e) => panic!("Unexpected error {}", e)
+        }
+    }
+
+    #[test]
+    fn statements_after_leaving_a_block_are_unreachable() {
+        let warning_lines = |source: &str| Compiler::new(source.to_string()).compile_nonempty_with_warnings().1.into_iter()
+            .filter_map(|warning| match warning {
+                CompileWarning::UnreachableCode { line } => Some(line),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(warning_lines("fun f() {\n return 1;\n print 2;\n print 3;\n}"), [3]);
+        assert_eq!(warning_lines("while (true) {\n { break; }\n print 1;\n}"), [3]);
+        assert_eq!(warning_lines("fun f(x) {\n if (x) return 1;\n return 2;\n}"), []);
+        // A catch block runs after a throw, and each case after the one before returns
+        assert_eq!(warning_lines("try { throw 1; } catch (e) { print e; }"), []);
+        assert_eq!(warning_lines("fun f(x) { switch (x) { case 1: return 1; case 2: return 2; } }"), []);
+    }
+
+    #[test]
+    fn locals_never_read_and_values_never_used_are_warned_about() {
+        let warnings = |source: &str| Compiler::new(source.to_string()).compile_nonempty_with_warnings().1;
+
+        assert_eq!(warnings("{\n var a = 1;\n var b = 2;\n a = b;\n}"), [CompileWarning::UnusedVariable { name: "a".to_string(), line: 2 }]);
+        assert_eq!(warnings("fun f() {\n var count = 0;\n count = count + 1;\n}"), []);
+        // Reads by a closure count, and parameters and names starting with an underscore are left out
+        assert_eq!(warnings("fun f(unused) { var a = 1; var _b = 2; fun g() { return a; } return g; }"), []);
+        assert_eq!(warnings("class A {} class B < A { m() {} }"), []);
+
+        assert_eq!(warnings("var a = 1;\na + 2;\nprint a;"), [CompileWarning::UnusedValue { line: 2 }]);
+        assert_eq!(warnings("var a = 1; a == 1 or a; print a;").len(), 1);
+        // Calls and assignments do more than give a value, and the script's last value is its result
+        assert_eq!(warnings("fun f() {} var a; f(); a = 1; a.b; a;"), []);
+    }
+
+    #[test]
+    fn undefined_variables_suggest_a_close_name() {
+        let message = |source: &str| run_source(source).0.last_error().unwrap().message.clone();
+
+        assert_eq!(message("var counter = 0;\nprint countr;"), "Undefined variable 'countr'; did you mean 'counter'?");
+        assert_eq!(message("var counter = 0;\ncountr = 1;"), "Undefined variable 'countr'; did you mean 'counter'?");
+        assert_eq!(message("print clok();"), "Undefined variable 'clok'; did you mean 'clock'?");
+        // Locals in scope count, as when a name misspelt in a function isn't resolved to one
+        assert_eq!(message("fun f() { var total = 1; print totl; }\nf();"), "Undefined variable 'totl'; did you mean 'total'?");
+        assert_eq!(message("var counter = 0;\nprint total;"), "Undefined variable 'total'");
+    }
+
+    #[test]
+    fn globals_never_declared_but_close_to_a_declared_name_are_warned_about() {
+        let warnings = |source: &str| Compiler::new(source.to_string()).compile_nonempty_with_warnings().1.into_iter()
+            .filter(|warning| matches!(warning, CompileWarning::MisspeltVariable { .. }))
+            .collect::<Vec<_>>();
+        let misspelt = |name: &str, suggestion: &str, line| CompileWarning::MisspeltVariable { name: name.to_string(), suggestion: suggestion.to_string(), line };
+
+        assert_eq!(warnings("var counter = 0;\nprint countr;"), [misspelt("countr", "counter", 2)]);
+        assert_eq!(warnings("fun f() {\n var total = 1;\n print totl;\n}"), [misspelt("totl", "total", 3)]);
+        // Globals declared after they're used are known, and natives aren't known at all
+        assert_eq!(warnings("fun f() { return later; }\nvar later = 1;\nprint clok();"), []);
+        assert_eq!(warnings("var counter = 0;\nprint total;"), []);
+    }
+
+    #[test]
+    fn parameters_and_arguments_are_limited_to_255() {
+        let names = |count: usize| (0..count).map(|i| format!("p{}", i)).collect::<Vec<_>>().join(", ");
+        // `true` rather than number literals, which would each take a constant
+        let args = |count: usize| vec!["true"; count].join(", ");
+
+        let source = format!("fun f({}) {{ return p254; }} var r = f({});", names(255), args(255));
+        let (vm, result) = run_source(&source);
+        result.unwrap();
+        assert_eq!(vm.global("r"), Some(&Value::Boolean(true)));
+
+        assert_eq!(compile_error_messages(&format!("fun f({}) {{}}", names(256))), ["Can't have more than 255 parameters."]);
+        assert_eq!(compile_error_messages(&format!("fun f() {{}} f({});", args(256))), ["Can't have more than 255 arguments."]);
+    }
+
+    #[test]
+    fn locals_and_loops_past_one_byte_operands_use_long_forms() {
+        let declarations = (0..300).map(|i| format!("var l{};", i)).collect::<String>();
+        let source = format!("fun f() {{ {} l298 = 4; l299 = 5 + l298; fun g() {{ return l298; }} return l299 + g(); }} var r = f();", declarations);
+        let chunk = Compiler::new(source.clone()).compile().unwrap();
+        let f = chunk.constants().iter().find_map(|c| match c { Value::Function(f) => Some(f.clone()), _ => None }).unwrap();
+        assert!(f.chunk.code().contains(&(OpCode::GetLocalLong as u8)) && f.chunk.code().contains(&(OpCode::SetLocalLong as u8)));
+        let (vm, result) = run_source(&source);
+        result.unwrap();
+        assert_eq!(vm.global("r"), Some(&Value::Int(13)));
+
+        // A loop around more code than a two byte jump back can cross
+        let body = "i = i + 1; ".repeat(20_000);
+        let source = format!("var n = 0; for (var i = 0; i < 3; i = i + 1) {{ n = n + 1; {} }}", body);
+        let code = Compiler::new(source.clone()).compile().unwrap().code().to_vec();
+        assert!(code.contains(&(OpCode::LoopLong as u8)) && code.contains(&(OpCode::JumpLong as u8)));
+        let (vm, result) = run_source(&source);
+        result.unwrap();
+        assert_eq!(vm.global("n"), Some(&Value::Int(1)));
+
+        // Jumps forward over as much go through an island
+        let body = "n = n + 1; ".repeat(20_000);
+        let (vm, result) = run_source(&format!("var n = 0; if (n > 0) {{ {} }} else {{ n = -1; }} while (n < 2) {{ n = n + 1; {} }}", body, body));
+        result.unwrap();
+        assert_eq!(vm.global("n"), Some(&Value::Int(20_000)));
+
+        // Which can't be put in the middle of a statement
+        let sum = "+ n ".repeat(40_000);
+        assert_eq!(compile_error_messages(&format!("var n = 0; if (true) {{ n = n {}; }}", sum)), ["Too much code to jump over."]);
+    }
+
+    #[test]
+    fn fused_instructions_run_like_the_pairs_they_replace() {
+        let source = "fun add(a, b) { return a + b; } \
+            fun sum(n) { var total = 0; var i = 0; while (i < n) { var step = add(i, 1); total = total + step; i = i + 1; } return total; } \
+            var r = sum(10); var s = add(\"a\", 1);";
+        let chunk = Compiler::new(source.to_string()).compile().unwrap();
+        let mut code = chunk.code().to_vec();
+        for constant in chunk.constants() {
+            if let Value::Function(f) = constant {
+                code.extend(f.chunk.code());
+            }
+        }
+        for op_code in [OpCode::GetLocalAdd, OpCode::ConstantCall, OpCode::LessJumpIfFalse] {
+            assert!(code.contains(&(op_code.clone() as u8)), "No {} in the code", op_code);
+        }
+        let (vm, result) = run_source(source);
+        result.unwrap();
+        assert_eq!(vm.global("r"), Some(&Value::Int(55)));
+        assert_eq!(vm.global("s"), Some(&Value::String("a1".to_string())));
+
+        assert_eq!(error_message("fun f() { var a = 1; var b; return a + b; } f();"), "Right operand 'b' of '+' is nil");
+        assert_eq!(error_message("fun f() { var a; var b = 1; return a + b; } f();"), "Left operand 'a' of '+' is nil");
+        assert_eq!(error_message("fun g(x) {} print g(1) - 1;"), "Left operand 'g()' of '-' is nil");
+    }
+
+    #[test]
+    fn constants_past_the_first_256_are_loaded_with_constant_long() {
+        let additions = (1..=300).map(|i| format!("t = t + {};", i)).collect::<String>();
+        let source = format!("var t = 0; {}", additions);
+        let chunk = Compiler::new(source.clone()).compile().unwrap();
+        assert!(chunk.constants().len() > 300);
+        assert!(chunk.code().contains(&(OpCode::ConstantLong as u8)));
+
+        let (vm, result) = run_source(&source);
+        result.unwrap();
+        assert_eq!(vm.global("t"), Some(&Value::Int(45150)));
+    }
+
+    #[test]
+    fn names_past_the_first_256_constants_use_long_forms() {
+        let additions = (1..=300).map(|i| format!("s = s + {};", i)).collect::<String>();
+        let source = format!(r#"
+            var s = 0; {}
+            var late = 1;
+            late = late + 1;
+            const fixed = late;
+            class Base {{ init(x) {{ this.x = x; }} twice() {{ return this.x * 2; }} }}
+            class Late < Base {{
+                init(x) {{ super.init(x); }}
+                doubled {{ return super.twice(); }}
+                viaSuper() {{ var f = super.twice; return f(); }}
+                plus(n) {{ return this.x + n; }}
+            }}
+            fun lateFun() {{ return "called"; }}
+            var obj = Late(late);
+            obj.y = 5;
+            var results = [obj.doubled, obj.viaSuper(), obj.plus(obj.y), lateFun(), fixed];
+        "#, additions);
+        let chunk = Compiler::new(source.clone()).compile().unwrap();
+        for op_code in [OpCode::DefineGlobalLong, OpCode::GetGlobalLong, OpCode::SetGlobalLong, OpCode::DefineConstGlobalLong, OpCode::ClassLong,
+            OpCode::GetPropertyLong, OpCode::SetPropertyLong, OpCode::MethodLong, OpCode::GetterLong, OpCode::InvokeLong, OpCode::ClosureLong] {
+            assert!(chunk.code().contains(&(op_code.clone() as u8)), "No {} in the chunk", op_code);
+        }
+
+        let (vm, result) = run_source(&source);
+        result.unwrap();
+        assert_eq!(vm.global("late"), Some(&Value::Int(2)));
+        assert_eq!(vm.global("results"), Some(&Value::list(vec![
+            Value::Int(4), Value::Int(4), Value::Int(7), Value::String("called".to_string()), Value::Int(2)
+        ])));
+    }
+
+    #[test]
+    fn duplicate_parameter_names_are_compile_errors() {
+        assert_eq!(compile_error_messages("fun f(a, b, a) {}"), ["Duplicate parameter name 'a'."]);
+        assert_eq!(compile_error_messages("class C { m(x, ...x) {} }"), ["Duplicate parameter name 'x'."]);
+        run_source("fun f(a) { fun g(a) { return a; } return g(a); } f(1);").1.unwrap();
+    }
+
+    #[test]
+    fn locals_declared_twice_in_one_scope_are_compile_errors() {
+        assert_eq!(compile_error_messages("{ var a = 1; var a = 2; }"), ["Already a variable named 'a' in this scope."]);
+        assert_eq!(compile_error_messages("{ var a = 1; { fun b() {} class b {} } }"), ["Already a variable named 'b' in this scope."]);
+        assert_eq!(compile_error_messages("fun f(a) { const a = 2; }"), ["Already a variable named 'a' in this scope."]);
+        // Globals can be declared again, and locals can shadow ones in enclosing scopes
+        run_source("var a = 1; var a = 2; { var b = a; { var b = 3; } }").1.unwrap();
+    }
+
+    #[test]
+    fn sources_without_code_have_nothing_to_compile() {
+        for source in ["", "   \n\t", "// just a comment", "\n// one\n  // two\n"] {
+            assert!(Compiler::new(source.to_string()).compile_nonempty().unwrap().is_none(), "{:?}", source);
+            assert_eq!(run_source(source).1.unwrap(), Value::Nil);
+        }
+        assert!(Compiler::new("// a comment\nprint 1;".to_string()).compile_nonempty().unwrap().is_some());
+        assert!(Compiler::new("\"unterminated".to_string()).compile_nonempty().is_err());
+    }
+
+    #[test]
+    fn errors_in_a_block_do_not_hide_the_ones_after_them() {
+        let source = "
+            fun f() {
+                var a = ;
+                print a;
+                var b = );
+            }
+            {
+                var c = ]
+            }
+            print 1 +;";
+        let errors = match Compiler::new(source.to_string()).compile().unwrap_err().downcast::<CompileErrorCollection>() {
+            Ok(collection) => collection.errors,
+            Err(e) => panic!("Unexpected error {}", e)
+        };
+
+        let reported: Vec<(usize, &str)> = errors.iter()
+            .map(|e| match e {
+                CompileError::Parse { line, lexeme, .. } => (*line, lexeme.as_str()),
+                other => panic!("Unexpected error {}", other)
+            })
+            .collect();
+        assert_eq!(reported, vec![(3, ";"), (5, ")"), (8, "]"), (10, ";")]);
+    }
+
+    #[test]
+    fn compile_errors_span_the_source_they_are_about() {
+        let source = "var 123 = 1;\nprint 1 +;\nvar s = @;\nprint 2";
+        let errors = match Compiler::new(source.to_string()).compile().unwrap_err().downcast::<CompileErrorCollection>() {
+            Ok(collection) => collection.errors,
+            Err(e) => panic!("Unexpected error {}", e)
+        };
+
+        let spanned: Vec<&str> = errors.iter()
+            .map(|e| e.span().map(|(offset, len)| source.get(offset..offset + len).unwrap_or("")).unwrap())
+            .collect();
+        // The missing semicolon is reported at the end of the source, past its last byte
+        assert_eq!(spanned, ["123", ";", "@", ""]);
+    }
+
+    #[test]
+    fn compile_errors_have_codes_for_their_kind() {
+        let codes = |source: &str| match Compiler::new(source.to_string()).compile().unwrap_err().downcast::<CompileErrorCollection>() {
+            Ok(collection) => collection.errors.iter().map(CompileError::code).collect::<Vec<_>>(),
+            Err(e) => panic!("Unexpected error {}", e)
+        };
+
+        assert_eq!(codes("print 1 +;"), ["L0202"]);
+        assert_eq!(codes("print 1"), ["L0201"]);
+        assert_eq!(codes("class A < A {}"), ["L0306"]);
+        assert_eq!(codes("var s = @;"), ["L0101"]);
+        assert_eq!(codes("print \"open;"), ["L0102"]);
+        assert_eq!(codes("{ var a = 1; var a = 2; }"), ["L0302"]);
+        assert_eq!(codes("{ var a = a; }"), ["L0304"]);
+        assert_eq!(codes("return 1;"), ["L0501"]);
+        assert_eq!(codes("break;"), ["L0503"]);
+        assert_eq!(codes("print this;"), ["L0505"]);
+    }
+
+    #[test]
+    fn runtime_errors_have_codes_for_their_kind() {
+        let code = |source: &str| {
+            let mut vm = Vm::new(VmOptions::default());
+            assert!(vm.run(&Compiler::new(source.to_string()).compile().unwrap()).is_err());
+            vm.last_error().unwrap().code
+        };
+
+        assert_eq!(code("print counter;"), "L0703");
+        assert_eq!(code("counter = 1;"), "L0703");
+        assert_eq!(code("print -\"a\";"), "L0701");
+        assert_eq!(code("print true + 1;"), "L0701");
+        assert_eq!(code("fun f(a) {} f();"), "L0705");
+        assert_eq!(code("var x = 1; x();"), "L0706");
+        assert_eq!(code("class A {} print A().missing;"), "L0704");
+        assert_eq!(code("fun f() { f(); } f();"), "L0707");
+        // Codes of errors from a call keep through the call instruction placing them
+        assert_eq!(code("fun f() { return nil.x; } f();"), "L0709");
+        assert_eq!(code("throw 1;"), "L0800");
+    }
+
+    #[test]
+    fn switch_runs_only_the_matching_case() {
+        let (vm, result) = run_source("
+            fun describe(n) {
+                var result;
+                switch (n) {
+                    case 1: result = \"one\";
+                    case 1 + 1:
+                        var word = \"two\";
+                        result = word;
+                    default: result = \"many\";
+                }
+                return result;
+            }
+            var one = describe(1);
+            var two = describe(2);
+            var many = describe(7);
+            var hits = 0;
+            switch (\"x\") { case \"y\": hits = hits + 1; }
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("one"), Some(&Value::String("one".to_string())));
+        assert_eq!(vm.globals.get("two"), Some(&Value::String("two".to_string())));
+        assert_eq!(vm.globals.get("many"), Some(&Value::String("many".to_string())));
+        assert_eq!(vm.globals.get("hits"), Some(&Value::Number(0.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn break_inside_switch_leaves_the_loop() {
+        let (vm, result) = run_source("
+            var i = 0;
+            while (true) {
+                switch (i) { case 3: break; default: i = i + 1; }
+            }
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("i"), Some(&Value::Number(3.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn misplaced_default_fails_to_compile() {
+        assert!(Compiler::new("switch (1) { default: print 1; case 1: print 2; }".to_string()).compile().is_err());
+        assert!(Compiler::new("switch (1) { default: print 1; default: print 2; }".to_string()).compile().is_err());
+        assert!(Compiler::new("switch (1) { print 1; }".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn chunks_share_the_vm_constant_pool() {
+        let mut vm = Vm::new(VmOptions::default());
+        vm.eval("var count = 1; count = count + 1; count = count + 1;").unwrap();
+        let pool_len = vm.constant_pool().borrow().len();
+        assert_eq!(pool_len, 2);
+
+        assert_eq!(vm.eval("count = count + 1; return count;").unwrap(), Value::Number(4.0));
+        assert_eq!(vm.constant_pool().borrow().len(), pool_len);
+    }
+
+    #[test]
+    fn pooled_chunks_reuse_local_constant_slots() {
+        let pool = ConstantPool::shared();
+        let chunk = Compiler::new("var a = \"x\"; var b = \"x\"; fun f() { return a + a; }".to_string())
+            .with_constant_pool(pool.clone())
+            .compile()
+            .unwrap();
+
+        // a, "x", b, the function and f
+        assert_eq!(chunk.constants().len(), 5);
+        let function = chunk.constants().iter().find_map(|c| match c { Value::Function(f) => Some(f.clone()), _ => None }).unwrap();
+        assert_eq!(function.chunk.constants().len(), 1);
+        assert_eq!(function.chunk.pool_index(0), chunk.pool_index(0));
+    }
+
+    #[test]
+    fn print_err_consumes_its_value() {
+        let (vm, result) = run_source("printErr \"to stderr\"; var after = 1;");
+        result.unwrap();
+        assert_eq!(vm.globals.get("after"), Some(&Value::Number(1.0)));
+        assert!(vm.stack.is_empty());
+        assert!(Compiler::new("printErr;".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        let (vm, result) = run_source("
+            var g = 1;
+            var prefix = ++g;
+            var postfix = g++;
+            fun f() {
+                var local = 10;
+                local--;
+                fun inner() { return --local; }
+                return inner() + local;
+            }
+            var captured = f();
+            var looped = 0;
+            for (var i = 0; i < 4; i++) looped++;
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("prefix"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.globals.get("postfix"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.globals.get("g"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.globals.get("captured"), Some(&Value::Number(16.0)));
+        assert_eq!(vm.globals.get("looped"), Some(&Value::Number(4.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn increment_errors() {
+        assert_vm_error(run_source("var s = \"a\"; s++;").1);
+        assert!(Compiler::new("++1;".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn host_defined_globals_persist_across_runs() {
+        let chunk = Compiler::new("count = count + lineNo;".to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions::default());
+        vm.set_global("count", Value::Number(0.0));
+        for line_no in 1..=3 {
+            vm.set_global("lineNo", Value::Number(line_no as f64));
+            vm.run(&chunk).unwrap();
+        }
+        assert_eq!(vm.global("count"), Some(&Value::Number(6.0)));
+    }
+
+    #[test]
+    fn closures_capture_enclosing_locals() {
+        let (vm, result) = run_source("
+            fun makeCounter() {
+                var i = 0;
+                fun count() { i = i + 1; return i; }
+                return count;
+            }
+            var c = makeCounter();
+            c();
+            var second = c();
+            var other = makeCounter()();
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("second"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.globals.get("other"), Some(&Value::Number(1.0)));
+    }
+
+    #[test]
+    fn closures_share_captured_variables() {
+        let (vm, result) = run_source("
+            var get; var set;
+            {
+                var shared = 1;
+                fun g() { return shared; }
+                fun s(v) { shared = v; }
+                get = g; set = s;
+            }
+            set(5);
+            var value = get();
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("value"), Some(&Value::Number(5.0)));
+    }
+
+    #[test]
+    fn closures_capture_through_intermediate_functions() {
+        let (vm, result) = run_source("
+            fun outer() {
+                var x = \"outside\";
+                fun middle() {
+                    fun inner() { return x; }
+                    return inner;
+                }
+                return middle;
+            }
+            var value = outer()()();
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("value"), Some(&Value::String("outside".to_string())));
+    }
+
+    #[test]
+    fn open_upvalues_see_later_writes() {
+        let (vm, result) = run_source("
+            var value;
+            {
+                var a = 1;
+                fun f() { return a; }
+                a = 2;
+                value = f();
+            }
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("value"), Some(&Value::Number(2.0)));
+    }
+
+    #[test]
+    fn nested_blocks_keep_outer_locals() {
+        let (mut vm, result) = run_source("
+            var value;
+            { var a = 1; { var b = 2; } value = a; }
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("value"), Some(&Value::Number(1.0)));
+        assert!(drain_stack(&mut vm).is_empty());
+    }
+
+    #[test]
+    fn inner_locals_shadow_outer_ones_of_the_same_name() {
+        let (vm, result) = run_source("
+            var inner; var outer; var captured;
+            {
+                var a = 1;
+                { var a = 2; { var a = 3; inner = a; } a = a + 10; outer = a; }
+                fun f() { return a; }
+                captured = f();
+            }
+            fun g() { var b = 1; { var b = 2; fun h() { return b; } return h(); } }
+            var closed = g();
+        ");
+        result.unwrap();
+        assert_eq!(vm.global("inner"), Some(&Value::Int(3)));
+        assert_eq!(vm.global("outer"), Some(&Value::Int(12)));
+        assert_eq!(vm.global("captured"), Some(&Value::Int(1)));
+        assert_eq!(vm.global("closed"), Some(&Value::Int(2)));
+
+        // The shadowing local is declared, but not yet initialized, in its own initializer
+        assert_eq!(compile_error_messages("{ var a = 1; { var a = a; } }"), ["Use of uninitialized local variable a"]);
+    }
+
+    #[test]
+    fn classes_with_fields_and_methods() {
+        let (vm, result) = run_source("
+            class Point {
+                init(x, y) { this.x = x; this.y = y; }
+                sum() { return this.x + this.y; }
+                scaled(k) { return Point(this.x * k, this.y * k); }
+            }
+            var p = Point(1, 2);
+            var sum = p.sum();
+            var scaled = p.scaled(3).sum();
+            p.x = 10;
+            var x = p.x;
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.globals.get("scaled"), Some(&Value::Number(9.0)));
+        assert_eq!(vm.globals.get("x"), Some(&Value::Number(10.0)));
+    }
+
+    #[test]
+    fn bound_methods_keep_their_receiver() {
+        let (vm, result) = run_source("
+            class Greeter {
+                init(name) { this.name = name; }
+                greet() { return \"hi \" + this.name; }
+            }
+            var greet = Greeter(\"bob\").greet;
+            var greeting = greet();
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("greeting"), Some(&Value::String("hi bob".to_string())));
+    }
+
+    #[test]
+    fn this_is_captured_by_closures_in_methods() {
+        let (vm, result) = run_source("
+            class A {
+                init() { this.v = 7; }
+                getter() { fun f() { return this.v; } return f; }
+            }
+            var v = A().getter()();
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("v"), Some(&Value::Number(7.0)));
+    }
+
+    #[test]
+    fn initializer_returns_instance() {
+        let (vm, result) = run_source("
+            class A { init() { this.n = 1; return; } }
+            var a = A();
+            var again = a.init();
+            var same = a == again;
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("same"), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn fields_shadow_methods_on_invoke() {
+        let (vm, result) = run_source("
+            class A { f() { return 1; } }
+            fun two() { return 2; }
+            var a = A();
+            a.f = two;
+            var v = a.f();
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("v"), Some(&Value::Number(2.0)));
+    }
+
+    #[test]
+    fn getters_run_on_property_access() {
+        let (vm, result) = run_source("
+            class Circle {
+                init(r) { this.r = r; }
+                area { return 3 * this.r * this.r; }
+                scaled { return Circle(this.r * 2); }
+            }
+            var c = Circle(2);
+            var area = c.area;
+            var chained = c.scaled.area;
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("area"), Some(&Value::Number(12.0)));
+        assert_eq!(vm.globals.get("chained"), Some(&Value::Number(48.0)));
+    }
+
+    #[test]
+    fn fields_shadow_getters_and_getters_are_inherited() {
+        let (vm, result) = run_source("
+            class A { name { return \"a\"; } greet { return \"hi \" + this.name; } }
+            class B < A { name { return \"b\"; } parent { return super.name; } }
+            var b = B();
+            var greeting = b.greet;
+            var parent = b.parent;
+            b.name = \"field\";
+            var shadowed = b.name;
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("greeting"), Some(&Value::String("hi b".to_string())));
+        assert_eq!(vm.globals.get("parent"), Some(&Value::String("a".to_string())));
+        assert_eq!(vm.globals.get("shadowed"), Some(&Value::String("field".to_string())));
+    }
+
+    #[test]
+    fn invoking_a_getter_calls_what_it_returns() {
+        let (vm, result) = run_source("
+            class Adder {
+                init(n) { this.n = n; }
+                add { var n = this.n; fun add(x) { return x + n; } return add; }
+            }
+            class Sub < Adder { twice(x) { return super.add(super.add(x)); } }
+            var once = Adder(1).add(2);
+            var twice = Sub(3).twice(1);
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("once"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.globals.get("twice"), Some(&Value::Number(7.0)));
+    }
+
+    #[test]
+    fn methods_and_getters_replace_inherited_ones_of_the_same_name() {
+        let (vm, result) = run_source("
+            class A { size { return 1; } count() { return 2; } }
+            class B < A { size() { return 3; } count { return 4; } }
+            var size = B().size();
+            var count = B().count;
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("size"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.globals.get("count"), Some(&Value::Number(4.0)));
+    }
+
+    #[test]
+    fn initializer_cannot_be_a_getter() {
+        assert!(Compiler::new("class A { init { } }".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn errors_in_getters_can_be_caught() {
+        let (vm, result) = run_source("
+            class A { broken { throw \"oops\"; } }
+            var caught;
+            try { A().broken; } catch (e) { caught = e; }
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("caught"), Some(&Value::String("oops".to_string())));
+    }
+
+    #[test]
+    fn class_errors() {
+        assert_vm_error(run_source("class A {} A().missing;").1);
+        assert_vm_error(run_source("class A {} A(1);").1);
+        assert_vm_error(run_source("var a = 1; a.x = 2;").1);
+        assert_vm_error(run_source("var a = 1; a.x();").1);
+        assert!(Compiler::new("print this;".to_string()).compile().is_err());
+        assert!(Compiler::new("class A { init() { return 1; } }".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn rest_parameter_collects_the_remaining_arguments() {
+        let (vm, result) = run_source(r#"
+            fun log(level, ...args) { return [level, len(args)]; }
+            fun all(...args) { return args; }
+            class Logger {
+                init(...prefixes) { this.prefixes = prefixes; }
+                count(...items) { return len(this.prefixes) + len(items); }
+            }
+            var none = log("info");
+            var some = log("warn", 1, 2, 3);
+            var listed = all(1, 2);
+            var count = Logger("a", "b").count(1, 2, 3);
+        "#);
+        result.unwrap();
+        assert_eq!(vm.globals.get("none"), Some(&Value::list(vec![Value::String("info".to_string()), Value::Number(0.0)])));
+        assert_eq!(vm.globals.get("some"), Some(&Value::list(vec![Value::String("warn".to_string()), Value::Number(3.0)])));
+        assert_eq!(vm.globals.get("listed"), Some(&Value::list(vec![Value::Number(1.0), Value::Number(2.0)])));
+        assert_eq!(vm.globals.get("count"), Some(&Value::Number(5.0)));
+    }
+
+    #[test]
+    fn rest_parameter_errors() {
+        assert_vm_error(run_source("fun f(a, ...rest) {} f();").1);
+        assert_vm_error(run_source_with(VmOptions { max_collection_size: Some(2), ..VmOptions::default() }, "fun f(...rest) {} f(1, 2, 3);").1);
+        assert!(Compiler::new("fun f(...rest, a) {}".to_string()).compile().is_err());
+        assert!(Compiler::new("fun f(...) {}".to_string()).compile().is_err());
+        assert!(Compiler::new("var x = 1 ...".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn failed_assertion_reports_its_message_and_line() {
+        let (vm, result) = run_source("var x = 2;\nassert x == 2, \"fine\";\nassert x > 1;\nvar reached = true;");
+        result.unwrap();
+        assert_eq!(vm.globals.get("reached"), Some(&Value::Boolean(true)));
+
+        let err = run_source("var x = 2;\nassert x == 3, \"x is \" + \"wrong\";").1.unwrap_err();
+        let vm_error = err.downcast_ref::<VmError>().expect("Expected VmError");
+        assert_eq!(vm_error.message(), "Assertion failed: x is wrong");
+        assert!(vm_error.to_string().starts_with("[source line 2,"));
+
+        assert_vm_error(run_source("assert false;").1);
+        assert_vm_error(run_source("assert nil, \"nil is falsey\";").1);
+        let (vm, result) = run_source("assert 1;\nassert \"text\", \"truthy\";\nvar reached = true;");
+        result.unwrap();
+        assert_eq!(vm.globals.get("reached"), Some(&Value::Boolean(true)));
+        assert!(Compiler::new("assert true".to_string()).compile().is_err());
+        assert!(Compiler::new("var x = 1 assert true;".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn exact_float_equality_by_default() {
+        let (vm, result) = run_source("var eq = 0.1 + 0.2 == 0.3;");
+        result.unwrap();
+        assert_eq!(vm.globals.get("eq"), Some(&Value::Boolean(false)));
+    }
+
+    #[test]
+    fn equality_epsilon_mode() {
+        let chunk = Compiler::new("var eq = 0.1 + 0.2 == 0.3; var ne = 1 == 1.1; var s = \"a\" == \"a\";".to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions { equality_epsilon: Some(1e-9), ..Default::default() });
+        vm.run(&chunk).unwrap();
+        assert_eq!(vm.globals.get("eq"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.globals.get("ne"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.globals.get("s"), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn approx_equals_native() {
+        let (vm, result) = run_source("
+            var near = approxEquals(0.1 + 0.2, 0.3, 0.000001);
+            var far = approxEquals(1, 2, 0.5);
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("near"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.globals.get("far"), Some(&Value::Boolean(false)));
+
+        assert_vm_error(run_source("approxEquals(1, 2);").1);
+        assert_vm_error(run_source("approxEquals(1, \"2\", 3);").1);
+    }
+
+    #[test]
+    fn chunks_record_the_capabilities_of_gated_natives_they_refer_to() {
+        let mut vm = Vm::new(VmOptions::default());
+        vm.define_gated_native("readFile", 1, Capability::Fs, Rc::new(|_, _| Ok(Value::Nil)));
+        vm.define_gated_native("shell", 1, Capability::Exec, Rc::new(|_, _| Ok(Value::Nil)));
+        vm.define_gated_native("fetch", 1, Capability::Net, Rc::new(|_, _| Ok(Value::Nil)));
+        let compile = |source: &str| Compiler::new(source.to_string()).with_gated_natives(vm.gated_natives()).compile().unwrap();
+
+        // Natives referred to from nested functions count, but locals of the same name don't
+        let chunk = compile("fun f() { fun g() { return shell; } return readFile(\"a\"); } fun h(fetch) { return fetch; }");
+        assert_eq!(chunk.required_capabilities(), [Capability::Fs, Capability::Exec]);
+        assert!(compile("print clock();").required_capabilities().is_empty());
+
+        let chunk = compile("var text = readFile(\"a\");");
+        vm.run(&chunk).unwrap();
+        assert_eq!(vm.global("text"), Some(&Value::Nil));
+    }
+
+    #[test]
+    fn type_num_and_str_natives() {
+        let (vm, result) = run_source(r#"
+            var types = [type(1), type(1.5), type("s"), type(true), type(nil), type([]), type(clock)];
+            var nums = [num(" 42 "), num("2.5e1"), num(7), num("x"), num("inf"), num("")];
+            var strs = str(1.5) + str(nil) + str([1, "a"]);
+        "#);
+        result.unwrap();
+        assert_eq!(vm.global("types").unwrap().to_string(), "[number, number, string, bool, nil, list, function]");
+        assert!(matches!(vm.global("nums").unwrap(), Value::List(items)
+            if matches!(items.borrow().as_slice(), [Value::Int(42), Value::Number(n), Value::Int(7), Value::Nil, Value::Nil, Value::Nil] if *n == 25.0)));
+        assert_eq!(vm.global("strs"), Some(&Value::String("1.5nil[1, a]".to_string())));
+
+        assert_eq!(error_message("num(true);"), "num expects a number or string as argument 1, got bool");
+    }
+
+    #[test]
+    fn args_and_env_natives() {
+        let options = VmOptions { script_args: vec!["in.txt".to_string(), "-v".to_string()], ..VmOptions::default() };
+        let (vm, result) = run_source_with(options, "var a = args(); var path = env(\"PATH\"); var unset = env(\"LOX_TEST_UNSET_VARIABLE\");");
+        result.unwrap();
+        assert_eq!(vm.global("a").unwrap().to_string(), "[in.txt, -v]");
+        assert_eq!(vm.global("path"), std::env::var("PATH").ok().map(Value::String).as_ref());
+        assert_eq!(vm.global("unset"), Some(&Value::Nil));
+
+        assert_eq!(run_source("var a = args();").0.global("a").unwrap().to_string(), "[]");
+        assert_vm_error(run_source("env(1);").1);
+    }
+
+    #[test]
+    fn format_fills_placeholders_with_widths_and_precisions() {
+        let formatted = |args: &str| match run_source(&format!("var s = format({});", args)) {
+            (vm, Ok(_)) => vm.global("s").unwrap().to_string(),
+            (_, Err(e)) => panic!("format({}) failed: {}", args, e)
+        };
+        assert_eq!(formatted("\"{} and {}\", 1, \"two\""), "1 and two");
+        assert_eq!(formatted("\"[{:5}|{:5}]\", 42, \"ab\""), "[   42|ab   ]");
+        assert_eq!(formatted("\"[{:<5}|{:^6}|{:*>4}]\", 1.5, \"mid\", nil"), "[1.5  | mid  |*nil]");
+        assert_eq!(formatted("\"{:.2} {:8.3} {:.3}\", 3.14159, -2, \"truncated\""), "3.14   -2.000 tru");
+        assert_eq!(formatted("\"{:05} {:06.1}\", -7, 2.25"), "-0007 0002.2");
+        assert_eq!(formatted("\"{{{}}}\", true"), "{true}");
+        assert_eq!(formatted("\"none\""), "none");
+
+        for args in ["\"{} {}\", 1", "\"{}\", 1, 2", "\"{\", 1", "\"}\"", "\"{:x}\", 1", "\"{:05}\", \"s\"", "1"] {
+            assert_vm_error(run_source(&format!("format({});", args)).1);
+        }
+        assert_vm_error(run_source("format();").1);
+
+        let (_, result) = run_source("printf(\"{:>3}|\", 7);");
+        result.unwrap();
+    }
+
+    #[test]
+    fn input_native_reads_lines_from_the_input_stream() {
+        let chunk = Compiler::new("var a = input(\"? \"); var b = input(\"\"); var c = input(\"\"); var d = input(\"\");".to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions::default());
+        vm.set_input(Box::new(io::Cursor::new("first\r\n\nlast")));
+        vm.run(&chunk).unwrap();
+        assert_eq!(vm.global("a"), Some(&Value::String("first".to_string())));
+        assert_eq!(vm.global("b"), Some(&Value::String(String::new())));
+        assert_eq!(vm.global("c"), Some(&Value::String("last".to_string())));
+        assert_eq!(vm.global("d"), Some(&Value::Nil));
+
+        assert_vm_error(run_source("input(1);").1);
+    }
+
+    #[test]
+    fn output_is_cut_off_after_max_output_bytes() {
+        let mut vm = Vm::new(VmOptions { max_output_bytes: Some(6), ..VmOptions::default() });
+        assert_eq!(vm.limit_output("abc"), "abc");
+        assert_eq!(vm.limit_output("dé€f"), "dé\n[Output truncated after 6 bytes]\n");
+        assert_eq!(vm.limit_output("g"), "");
+        assert!(vm.output_truncated());
+
+        let (vm, result) = run_source_with(VmOptions { max_output_bytes: Some(4), ..VmOptions::default() },
+            "for (var i = 0; i < 3; i = i + 1) { print i; printErr i; } var done = true;");
+        result.unwrap();
+        assert!(vm.output_truncated());
+        assert_eq!(vm.global("done"), Some(&Value::Boolean(true)));
+
+        let (vm, result) = run_source_with(VmOptions { max_output_bytes: Some(4), ..VmOptions::default() }, "print 1;");
+        result.unwrap();
+        assert!(!vm.output_truncated());
+        assert!(!run_source("print 1;").0.output_truncated());
+    }
+
+    #[test]
+    fn clock_and_time_ms_count_from_the_vm_start() {
+        let (vm, result) = run_source("var start = clock(); var ms = time_ms(); var later = clock();");
+        result.unwrap();
+        let number = |name: &str| match vm.global(name) {
+            Some(Value::Number(n)) => *n,
+            other => panic!("Expected {} to be a number, got {:?}", name, other)
+        };
+        assert!(number("start") >= 0.0 && number("start") * 1000.0 <= number("ms"));
+        assert!(number("ms") <= number("later") * 1000.0);
+        assert!(number("later") <= vm.uptime().as_secs_f64());
+    }
+
+    #[test]
+    fn native_argument_errors_name_the_native_and_argument() {
+        let err = run_source("approxEquals(1, \"2\", 3);").1.unwrap_err();
+        assert!(err.to_string().contains("approxEquals expects a number as argument 2, got string"), "{}", err);
+
+        let mut vm = Vm::new(VmOptions::default());
+        vm.define_native("pick", 2, Rc::new(|_, args| {
+            let args = Args::new("pick", args);
+            Ok(if args.get_bool(0)? { Value::Int(args.get_int(1)?) } else { Value::Nil })
+        }));
+        assert_eq!(vm.eval("return pick(true, 2.0);").unwrap(), Value::Int(2));
+        let err = vm.eval("return pick(true, 2.5);").unwrap_err();
+        assert!(err.to_string().contains("pick expects an integer as argument 2, got number"), "{}", err);
+        assert!(Args::new("pick", &[]).get(0).is_err());
+    }
+
+    #[test]
+    fn inheritance_and_super_calls() {
+        let (vm, result) = run_source("
+            class A {
+                init(x) { this.x = x; }
+                name() { return \"A\"; }
+                describe() { return this.name() + this.x; }
+            }
+            class B < A {
+                init(x) { super.init(x + \"!\"); }
+                name() { return \"B\" + super.name(); }
+            }
+            class C < B {
+                name() { var parent = super.name; return \"C\" + parent(); }
+            }
+            var b = B(\"1\").describe();
+            var c = C(\"2\").describe();
+        ");
+        result.unwrap();
+        assert_eq!(vm.globals.get("b"), Some(&Value::String("BA1!".to_string())));
+        assert_eq!(vm.globals.get("c"), Some(&Value::String("CBA2!".to_string())));
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        assert_vm_error(run_source("var NotAClass = 1; class A < NotAClass {}").1);
+        assert_vm_error(run_source("class A {} class B < A { m() { return super.missing(); } } B().m();").1);
+        assert!(Compiler::new("class A < A {}".to_string()).compile().is_err());
+        assert!(Compiler::new("class A { m() { return super.m(); } }".to_string()).compile().is_err());
+        assert!(Compiler::new("fun f() { return super.m(); }".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn runtime_errors_carry_instruction_details() {
+        let (_, result) = run(|w| {
+            w.write_op_code(OpCode::Nil, 7);
+            w.write_op_code(OpCode::Negate, 7);
+        });
+        let err = result.unwrap_err();
+        let message = format!("{}", err.downcast_ref::<VmError>().unwrap());
+        assert!(message.starts_with("[source line 7, byte code offset 1, inst 'Negate']"), "{}", message);
+    }
+
+    #[test]
+    fn runtime_errors_carry_stack_trace() {
+        let (_, result) = run(|w| {
+            w.write_op_code(OpCode::Nil, 4);
+            w.write_op_code(OpCode::Negate, 4);
+        });
+        let err = result.unwrap_err();
+        let trace = err.downcast_ref::<VmError>().unwrap().trace().unwrap();
+        assert_eq!(trace.format(None), "[line 4] in script\n");
+    }
+
+    #[test]
+    fn long_stack_traces_are_elided() {
+        let frames = (0..1000).map(|i| TraceFrame::new("f", i)).collect();
+        let trace = StackTrace::new(frames);
+
+        let elided = trace.format(Some(20));
+        assert_eq!(elided.lines().count(), 21);
+        assert!(elided.contains("... 980 frames omitted ..."));
+        assert!(elided.starts_with("[line 0] in f\n"));
+        assert!(elided.ends_with("[line 999] in f\n"));
+
+        assert_eq!(trace.format(None).lines().count(), 1000);
+    }
+
+    #[test]
+    fn short_stack_traces_are_not_elided() {
+        let frames = (0..3).map(|i| TraceFrame::new("f", i)).collect();
+        let trace = StackTrace::new(frames);
+        assert_eq!(trace.format(Some(20)), trace.format(None));
+    }
+
+    #[test]
+    fn tracing_does_not_change_results() {
+        let build = |w: &mut InstructionWriter| {
+            let name = w.add_constant(Value::String("a".to_string())).unwrap();
+            num(w, 1.0);
+            num(w, 2.0);
+            w.write_op_code(OpCode::Add, 2);
+            w.write_op_code_with_index(OpCode::DefineGlobal, name, 2).unwrap();
+            w.write_op_code_with_index(OpCode::GetGlobal, name, 3).unwrap();
+            w.write_op_code(OpCode::True, 3);
+            let jump = w.write_jump_if_false(3);
+            w.patch_jump_to_chunk_end(jump).unwrap();
+        };
+
+        let (mut plain, plain_result) = run_with(false, build);
+        let (mut traced, traced_result) = run_with(true, build);
+        plain_result.unwrap();
+        traced_result.unwrap();
+        assert_eq!(plain.globals.get("a"), traced.globals.get("a"));
+        assert_eq!(drain_stack(&mut plain), drain_stack(&mut traced));
+    }
+
+    #[test]
+    fn tracing_with_source_handles_lines_past_the_end() {
+        let mut vm = Vm::new(VmOptions { trace: true, ..Default::default() });
+        vm.set_trace_source("var a = 1;");
+        let chunk = Compiler::new("var a = 1;\n\nvar b = a + 1;".to_string()).compile().unwrap();
+        vm.run(&chunk).unwrap();
+        assert_eq!(vm.global("b"), Some(&Value::Number(2.0)));
+    }
+
+    #[test]
+    fn printing_and_tracing_go_to_the_outputs_given() {
+        let (out, err) = (OutputBuffer::new(), OutputBuffer::new());
+        let mut vm = Vm::new(VmOptions { trace: true, ..Default::default() });
+        vm.set_output(out.writer());
+        vm.set_error_output(err.writer());
+        vm.run(&Compiler::new("print 1; printErr 2;".to_string()).compile().unwrap()).unwrap();
+
+        let text = out.text();
+        assert!(text.contains("1\n") && text.contains("PrintErr") && text.contains("Inline caches"), "{}", text);
+        assert_eq!(err.text(), "2\n");
+    }
+
+    #[test]
+    fn instruction_hooks_see_each_instruction_before_and_after_it_runs() {
+        let before = Rc::new(RefCell::new(Vec::new()));
+        let after = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::new(VmOptions::default());
+        let seen = before.clone();
+        vm.set_pre_instruction_hook(move |state, instruction| {
+            seen.borrow_mut().push((instruction.op_code.to_string(), state.function.to_string(), state.depth, state.line, state.stack.last().cloned()));
+        });
+        let seen = after.clone();
+        vm.set_post_instruction_hook(move |state, instruction| {
+            seen.borrow_mut().push((instruction.op_code.to_string(), state.stack.last().cloned()));
+        });
+        vm.run(&Compiler::new("fun f(a) { return a; }\nprint f(2);".to_string()).compile().unwrap()).unwrap();
+
+        let (before, after) = (before.borrow(), after.borrow());
+        assert_eq!(before.len(), after.len());
+        assert!(before.iter().any(|(op_code, function, depth, _, _)| op_code == "Return" && function == "f" && *depth == 2), "{:?}", before);
+        assert!(before.iter().any(|(op_code, function, _, line, top)| op_code == "Print" && function == "script" && *line == 2 && *top == Some(Value::Int(2))), "{:?}", before);
+        assert_eq!(after.last(), Some(&("Return".to_string(), None)));
+    }
+
+    #[test]
+    fn tracing_surfaces_bad_constant_index() {
+        let (_, result) = run_with(true, |w| { w.write_op_code_with_operand(OpCode::Constant, 9, 1); });
+        assert!(result.is_err());
+    }
+
+    fn checkpoint_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("lox-{}-{}.ckpt", name, std::process::id()))
+    }
+
+    const CHECKPOINTED_SOURCE: &str = "
+        class Counter {
+            init() { this.count = 0; }
+            add(n) { this.count = this.count + n; }
+        }
+        fun makeAdder(counter) {
+            var total = 0;
+            fun add(n) { total = total + n; counter.add(n); return total; }
+            return add;
+        }
+        var counter = Counter();
+        var add = makeAdder(counter);
+        var last = 0;
+        for (var i = 1; i <= 50; i = i + 1) { last = add(i); }
+        var count = counter.count;";
+
+    #[test]
+    fn resuming_a_periodic_checkpoint_finishes_the_script() {
+        let path = checkpoint_path("periodic");
+        let chunk = Compiler::new(CHECKPOINTED_SOURCE.to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions { checkpoint_path: Some(path.clone()), checkpoint_every: Some(500), ..Default::default() });
+        vm.run(&chunk).unwrap();
+
+        let checkpoint = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut resumed = Vm::new(VmOptions::default());
+        resumed.resume(&checkpoint).unwrap();
+        assert_eq!(resumed.global("last"), Some(&Value::Number(1275.0)));
+        assert_eq!(resumed.global("count"), vm.global("count"));
+        assert!(resumed.stack.is_empty());
+    }
+
+    #[test]
+    fn requested_checkpoint_is_saved_before_the_next_instruction() {
+        let path = checkpoint_path("requested");
+        let chunk = Compiler::new(CHECKPOINTED_SOURCE.to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions { checkpoint_path: Some(path.clone()), ..Default::default() });
+        vm.checkpoint_requester().store(true, std::sync::atomic::Ordering::Relaxed);
+        vm.run(&chunk).unwrap();
+
+        // Requested before anything ran, so resuming runs the whole script
+        let checkpoint = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut resumed = Vm::new(VmOptions::default());
+        resumed.resume(&checkpoint).unwrap();
+        assert_eq!(resumed.global("count"), Some(&Value::Number(1275.0)));
+    }
+
+    #[test]
+    fn resuming_garbage_fails() {
+        let mut vm = Vm::new(VmOptions::default());
+        assert!(vm.resume(b"not a checkpoint").is_err());
+    }
+
+    #[test]
+    fn exported_declarations_are_recorded_and_still_defined() {
+        let chunk = Compiler::new("
+            export var answer = 42;
+            export fun double(n) { return n * 2; }
+            export class Point {}
+            var hidden = double(answer);
+        ".to_string()).compile().unwrap();
+        assert_eq!(chunk.exports(), ["answer", "double", "Point"]);
+
+        let mut vm = Vm::new(VmOptions::default());
+        vm.run(&chunk).unwrap();
+        assert_eq!(vm.global("hidden"), Some(&Value::Number(84.0)));
+    }
+
+    #[test]
+    fn misplaced_or_repeated_exports_fail_to_compile() {
+        for source in [
+            "{ export var x = 1; }",
+            "fun f() { export var x = 1; }",
+            "export var x = 1; export fun x() {}",
+            "export print 1;",
+        ] {
+            assert!(Compiler::new(source.to_string()).compile().is_err(), "{}", source);
+        }
+    }
+
+    /// Writes each `(file, source)` into a fresh directory for a test to import from
+    fn module_dir(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("lox-{}-{}", name, std::process::id()));
+        for (file, source) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    fn run_script_in(dir: &Path, source: &str) -> (Vm, Result<Value>) {
+        let chunk = Compiler::new(source.to_string()).compile().expect("Compilation failed");
+        let mut vm = Vm::new(VmOptions::default());
+        vm.set_script_path(&dir.join("main.lox"));
+        let result = vm.run(&chunk);
+        (vm, result)
+    }
+
+    #[test]
+    fn import_runs_a_module_once_and_binds_only_its_exports() {
+        let dir = module_dir("import", &[("lib/counter.lox", "
+            var step = 10;
+            export var count = 0;
+            export fun advance(n) { return n + step; }
+            count = count + 1;
+        ")]);
+        let (vm, result) = run_script_in(&dir, r#"
+            import "lib/counter.lox";
+            import "lib/counter.lox";
+            var advanced = advance(5);
+            var step = "mine";
+        "#);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert_eq!(vm.modules.len(), 1);
+        assert_eq!(vm.global("count"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.global("advanced"), Some(&Value::Number(15.0)));
+        assert_eq!(vm.global("step"), Some(&Value::String("mine".to_string())));
+        assert_eq!(vm.modules[0].globals.get("step"), Some(&Value::Number(10.0)));
+    }
+
+    #[test]
+    fn stack_traces_name_the_module_of_imported_functions() {
+        let dir = module_dir("import-trace", &[("lib/fail.lox", "
+            export fun fail(x) {
+                return x + nil;
+            }
+        ")]);
+        let (vm, result) = run_script_in(&dir, "import \"lib/fail.lox\";\nfun outer() {\n    fail(1);\n}\nouter();");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let trace = result.unwrap_err().downcast_ref::<VmError>().unwrap().trace().unwrap().format(None);
+        let module = vm.modules[0].path.display().to_string();
+        assert_eq!(trace, format!("[line 3] in fail ({})\n[line 3] in outer\n[line 5] in script\n", module));
+        assert!(module.ends_with("fail.lox"));
+    }
+
+    #[test]
+    fn imports_are_relative_to_the_importing_module() {
+        let dir = module_dir("nested-import", &[
+            ("lib/outer.lox", r#"import "inner.lox"; export var doubled = twice(21);"#),
+            ("lib/inner.lox", "export fun twice(n) { return n * 2; }")
+        ]);
+        let (vm, result) = run_script_in(&dir, r#"import "lib/outer.lox";"#);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert_eq!(vm.global("doubled"), Some(&Value::Number(42.0)));
+        assert_eq!(vm.global("twice"), None);
+    }
+
+    #[test]
+    fn a_module_imported_by_different_paths_runs_once() {
+        let dir = module_dir("canonical-import", &[
+            ("lib/counter.lox", "export var loads = 1;"),
+            ("shared/uses.lox", r#"import "../lib/counter.lox"; export var seen = loads;"#)
+        ]);
+        let chunk = Compiler::new(r#"
+            import "lib/counter.lox";
+            import "./lib/../lib/counter.lox";
+            import "uses.lox";
+        "#.to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions { module_search_path: vec![dir.join("shared")], ..Default::default() });
+        vm.set_script_path(&dir.join("main.lox"));
+        let result = vm.run(&chunk);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert_eq!(vm.modules.len(), 2);
+        assert_eq!(vm.global("seen"), Some(&Value::Number(1.0)));
+    }
+
+    #[test]
+    fn failed_imports_are_runtime_errors() {
+        let dir = module_dir("bad-import", &[
+            ("a.lox", r#"import "b.lox";"#),
+            ("b.lox", r#"import "a.lox";"#),
+            ("broken.lox", "var = 1;"),
+            ("throws.lox", r#"throw "nope";"#)
+        ]);
+        let (_, circular) = run_script_in(&dir, r#"import "a.lox";"#);
+        let (_, missing) = run_script_in(&dir, r#"import "missing.lox";"#);
+        let (_, broken) = run_script_in(&dir, r#"import "broken.lox";"#);
+        let (vm, caught) = run_script_in(&dir, r#"
+            var caught;
+            try { import "throws.lox"; } catch (e) { caught = e; }
+        "#);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_vm_error(circular);
+        assert_vm_error(missing);
+        assert_vm_error(broken);
+        caught.unwrap();
+        assert_eq!(vm.global("caught"), Some(&Value::String("nope".to_string())));
+        assert!(Compiler::new("import 5;".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn a_module_importing_the_main_script_does_not_run_it_again() {
+        let main = r#"print "main"; import "other.lox";"#;
+        let dir = module_dir("import-main", &[("main.lox", main), ("other.lox", r#"import "main.lox"; export var x = 1;"#)]);
+        let chunk = Compiler::new(main.to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions::default());
+        vm.set_script_path(&dir.join("main.lox"));
+        vm.capture_output(true);
+        let result = vm.run(&chunk);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(vm.take_output().0, "main\n");
+        assert_eq!(vm.modules.len(), 1);
+        assert!(result.unwrap_err().to_string().contains("Circular import of"));
+    }
+
+    #[test]
+    fn resumed_checkpoint_keeps_module_globals() {
+        let dir = module_dir("checkpoint-import", &[("lib.lox", "
+            var total = 0;
+            export fun add(n) { total = total + n; return total; }
+        ")]);
+        let path = checkpoint_path("import");
+        let chunk = Compiler::new(r#"
+            import "lib.lox";
+            var last;
+            for (var i = 1; i <= 50; i = i + 1) { last = add(i); }
+        "#.to_string()).compile().unwrap();
+        let mut vm = Vm::new(VmOptions { checkpoint_path: Some(path.clone()), checkpoint_every: Some(300), ..Default::default() });
+        vm.set_script_path(&dir.join("main.lox"));
+        vm.run(&chunk).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let checkpoint = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut resumed = Vm::new(VmOptions::default());
+        resumed.resume(&checkpoint).unwrap();
+        assert_eq!(resumed.global("last"), Some(&Value::Number(1275.0)));
+        assert_eq!(resumed.modules[0].globals.get("total"), Some(&Value::Number(1275.0)));
+    }
+
+    #[test]
+    fn const_globals_are_readable_but_not_assignable() {
+        let (vm, result) = run_source("
+            const limit = 3;
+            fun twice() { return limit * 2; }
+            var doubled = twice();
+        ");
+        result.unwrap();
+        assert_eq!(vm.global("doubled"), Some(&Value::Number(6.0)));
+
+        // Globals are resolved at runtime, so the assignment compiles and then fails
+        let (vm, result) = run_source("
+            const limit = 3;
+            fun bump() { limit = limit + 1; }
+            bump();
+        ");
+        assert_vm_error(result);
+        assert_eq!(vm.global("limit"), Some(&Value::Number(3.0)));
+
+        let (_, result) = run_source("const limit = 3; limit++;");
+        assert_vm_error(result);
+
+        // A later declaration replaces the constant
+        let (vm, result) = run_source("const limit = 3; var limit = 4; limit = 5;");
+        result.unwrap();
+        assert_eq!(vm.global("limit"), Some(&Value::Number(5.0)));
+    }
+
+    #[test]
+    fn assigning_const_locals_fails_to_compile() {
+        for source in [
+            "{ const a = 1; a = 2; }",
+            "{ const a = 1; a++; }",
+            "{ const a = 1; --a; }",
+            "fun f() { const a = 1; fun g() { a = 2; } }",
+            "const a;",
+        ] {
+            assert!(Compiler::new(source.to_string()).compile().is_err(), "{}", source);
+        }
+
+        let (vm, result) = run_source("var total; { const a = 1; var b = a; b = b + 1; total = b; }");
+        result.unwrap();
+        assert_eq!(vm.global("total"), Some(&Value::Number(2.0)));
+    }
+
+    #[test]
+    fn exported_constants_stay_constant_in_importers() {
+        let dir = module_dir("const-import", &[("config.lox", "export const port = 80;")]);
+        let (vm, result) = run_script_in(&dir, r#"import "config.lox"; port = 81;"#);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_vm_error(result);
+        assert_eq!(vm.global("port"), Some(&Value::Number(80.0)));
+    }
+
+    #[test]
+    #[cfg(feature = "stdlib")]
+    fn math_natives() {
+        let (vm, result) = run_source("var r = sqrt(16) + abs(-2) + floor(2.7) + ceil(0.2) + min(4, 9) + max(-1, 0) + pow(2, 3); var trig = sin(0) + cos(0);");
+        result.unwrap();
+        assert_eq!(vm.global("r"), Some(&Value::Number(21.0)));
+        assert_eq!(vm.global("trig"), Some(&Value::Number(1.0)));
+        assert!(run_source("sqrt(\"4\");").1.is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "stdlib")]
+    fn string_natives_process_text() {
+        let (vm, result) = run_source(r#"
+            var s = "Hello, World";
+            var n = len(s);
+            var word = upper(substr(s, indexOf(s, "W"), 5));
+            var parts = split(lower(s), ", ");
+        "#);
+        result.unwrap();
+        assert_eq!(vm.global("n"), Some(&Value::Int(12)));
+        assert_eq!(vm.global("word"), Some(&Value::String("WORLD".to_string())));
+        assert_eq!(vm.global("parts").unwrap().to_string(), "[hello, world]");
+    }
+
+    #[test]
+    #[cfg(feature = "stdlib")]
+    fn standard_library_modules_are_loaded_from_precompiled_bytecode() {
+        let (vm, result) = run_source(r#"
+            import "std/list";
+            import "std/string";
+            import "std/list";
+            var total = sum([1, 2, 3]);
+            fun isBig(n) { return n > 3; }
+            var anyBig = any([1, 3, 4], isBig);
+            var joined = join(["a", "b", repeat("c", 2)], ", ");
+            var found = contains([1, 2], 2) and indexOf("abc", "c") == 2;
+        "#);
+        result.unwrap();
+        assert_eq!(vm.global("found"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.global("total"), Some(&Value::Number(6.0)));
+        assert_eq!(vm.global("anyBig"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.global("joined"), Some(&Value::String("a, b, cc".to_string())));
+        assert_eq!(vm.modules.len(), 2);
+        assert_eq!(vm.modules[0].path, Path::new("std/list"));
+        // Linked into the module they were imported as
+        assert_eq!(vm.modules[0].globals.get("reduce").map(|r| matches!(r, Value::Closure(c) if c.function.module == Some(0))), Some(true));
+    }
+
+    #[test]
+    #[cfg(feature = "stdlib")]
+    fn restored_snapshot_has_the_globals_and_modules_loaded_before_it() {
+        let (mut vm, result) = run_source("fun greet(name) { return \"hi \" + name; } const limit = 3;");
+        result.unwrap();
+        vm.load_stdlib_modules().unwrap();
+        let snapshot = vm.snapshot().unwrap();
+
+        let mut restored = Vm::new(VmOptions::default());
+        restored.restore_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.modules.len(), 2);
+        assert!(restored.const_globals.contains("limit"));
+        assert!(restored.global("reduce").is_none());
+
+        let chunk = Compiler::new(r#"import "std/list"; var total = sum([1, 2]); var greeting = greet("you");"#.to_string()).compile().unwrap();
+        restored.run(&chunk).unwrap();
+        assert_eq!(restored.modules.len(), 2);
+        assert_eq!(restored.global("total"), Some(&Value::Number(3.0)));
+        assert_eq!(restored.global("greeting"), Some(&Value::String("hi you".to_string())));
+    }
+
+    #[test]
+    fn checkpoint_taken_while_running_is_not_a_snapshot() {
+        let path = checkpoint_path("not-a-snapshot");
+        let (_, result) = run_source_with(VmOptions { checkpoint_path: Some(path.clone()), checkpoint_every: Some(10), ..VmOptions::default() }, CHECKPOINTED_SOURCE);
+        result.unwrap();
+        let checkpoint = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(Vm::new(VmOptions::default()).restore_snapshot(&checkpoint).is_err());
+    }
+
+    #[test]
+    fn word_operators_are_only_keywords_in_their_dialect() {
+        let (vm, result) = run_source("var not = 1; var mod = 2; var sum = not + mod;");
+        result.unwrap();
+        assert_eq!(vm.global("sum"), Some(&Value::Number(3.0)));
+
+        let dialect = Dialect { word_operators: true, ..Dialect::default() };
+        let dir = module_dir("word-operators", &[("parity.lox", "export fun isOdd(n) { return not (n mod 2 == 0); }")]);
+        let chunk = Compiler::new(r#"import "parity.lox"; var odd = isOdd(7) and not isOdd(4);"#.to_string())
+            .with_dialect(dialect).compile().unwrap();
+        let mut vm = Vm::new(VmOptions { dialect, ..VmOptions::default() });
+        vm.set_script_path(&dir.join("main.lox"));
+        let result = vm.run(&chunk);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert_eq!(vm.global("odd"), Some(&Value::Boolean(true)));
+        assert!(Compiler::new("var not = 1;".to_string()).with_dialect(dialect).compile().is_err());
+    }
+
+    #[test]
+    fn line_breaks_end_statements_with_implicit_semicolons() {
+        let source = "
+            var total = 1
+              + 2
+            class Point {
+                init(x, y) { this.x = x; this.y = y }
+                sum { return this.x + this.y }
+            }
+            fun make(a,
+                     b) {
+                return Point(a, b)
+            }
+            var sum = make(3,
+              4)
+              .sum
+            var items = [1,
+              2]
+            var n = 0
+            while (true) {
+                n++
+                if (n > 2) break
+            }
+            fun nothing() {
+                return
+            }
+            var none = nothing()
+            var last = items[1]";
+        let dialect = Dialect { implicit_semicolons: true, ..Dialect::default() };
+        let chunk = Compiler::new(source.to_string()).with_dialect(dialect).compile().unwrap();
+        let mut vm = Vm::new(VmOptions::default());
+        vm.run(&chunk).unwrap();
+
+        assert_eq!(vm.global("total"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.global("sum"), Some(&Value::Number(7.0)));
+        assert_eq!(vm.global("n"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.global("none"), Some(&Value::Nil));
+        assert_eq!(vm.global("last"), Some(&Value::Number(2.0)));
+
+        assert!(Compiler::new("var a = 1 var b = 2".to_string()).with_dialect(dialect).compile().is_err());
+        assert!(Compiler::new("var a = 1\nvar b = 2".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn implicit_semicolons_follow_the_continuation_rules() {
+        let dialect = Dialect { implicit_semicolons: true, ..Dialect::default() };
+        let run = |source: &str| {
+            let chunk = Compiler::new(source.to_string()).with_dialect(dialect).compile().unwrap();
+            let mut vm = Vm::new(VmOptions::default());
+            vm.run(&chunk).unwrap();
+            vm
+        };
+
+        // A leading '-' starts a statement of its own, while other binary operators carry on the last line's
+        let vm = run("var a = 1\n-2\nvar b = true\nand false\nvar c = 5\n  % 3");
+        assert_eq!(vm.global("a"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.global("b"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.global("c"), Some(&Value::Number(2.0)));
+
+        // Explicit semicolons, and line breaks after tokens that can't end a statement, are left alone
+        let vm = run("var d = 1;\nvar e =\n  2;\nvar f = d +\n  e");
+        assert_eq!(vm.global("f"), Some(&Value::Number(3.0)));
+    }
+
+    #[test]
+    fn blocks_and_ifs_are_expressions_in_their_dialect() {
+        let dialect = Dialect { expression_blocks: true, ..Dialect::default() };
+        let run = |source: &str| {
+            let chunk = Compiler::new(source.to_string()).with_dialect(dialect).compile().unwrap();
+            let mut vm = Vm::new(VmOptions::default());
+            vm.run(&chunk).unwrap();
+            vm
+        };
+
+        let vm = run("var c = true; var x = if (c) { 1 } else { 2 }; var y = if (!c) { 1 }; var z = { 3; };");
+        assert_eq!(vm.global("x"), Some(&Value::Int(1)));
+        assert_eq!(vm.global("y"), Some(&Value::Nil));
+        assert_eq!(vm.global("z"), Some(&Value::Nil));
+
+        // Locals declared in a block in the middle of an expression, or in a local's initializer, get the right slots
+        let vm = run(r#"
+            fun f(n) {
+                var s = "n";
+                var r = s + if (n > 1) { var t = n * 2; t } else if (n == 1) { "one" } else { print "none"; };
+                return [r, { var u = r; fun g() { return u; } g() }];
+            }
+            var a = f(3); var b = f(1); var c = f(0);"#);
+        assert_eq!(vm.global("a").unwrap().to_string(), "[n6, n6]");
+        assert_eq!(vm.global("b").unwrap().to_string(), "[none, none]");
+        assert_eq!(vm.global("c").unwrap().to_string(), "[nnil, nnil]");
+
+        // Breaking out of a loop from a block in an expression drops the values under it
+        let vm = run("var total = 0; for (var i = 0; i < 5; i = i + 1) { total = total + 1 + { if (i == 3) { break; } i * 10 }; }");
+        assert_eq!(vm.global("total"), Some(&Value::Int(33)));
+
+        assert!(Compiler::new("var x = { 1 };".to_string()).compile().is_err());
+    }
+
+}